@@ -0,0 +1,142 @@
+//
+// Module that offers the Bitcask API to async code.
+//
+// Every Bitcask operation is a blocking read, write, or fsync, so an async task that called one directly
+// would stall its executor's thread for the duration.  Instead each call is handed to a small pool of
+// threads of our own and the task is woken when it's done.  A caller that finds the pool's queue full
+// waits for room in it, so a flood of calls is held back rather than given threads without limit.
+// Nothing here depends on a particular runtime, the futures are plain std futures, so they work the same
+// under tokio, async-std, or a hand-rolled executor.
+//
+pub mod aio {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+    use crate::bitcask::bitcask::Bitcask;
+    use crate::error::error::BitcaskError;
+    use crate::pool::pool::{SaturationPolicy, ThreadPool};
+
+    // How many calls may wait for a thread before new ones have to wait to be queued.
+    const AIO_QUEUE_SIZE: usize = 1024;
+
+    //
+    // What a BlockingCall and the thread carrying it out share: the result once there is one, and whoever
+    // is waiting for it.
+    //
+    struct Completion<T> {
+	result: Option<T>,
+	waker: Option<Waker>,
+    }
+
+    //
+    // The future of one operation running on the pool.
+    //
+    pub struct BlockingCall<T> {
+	shared: Arc<Mutex<Completion<T>>>,
+    }
+    impl<T> Future for BlockingCall<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+	    let mut shared = self.shared.lock().unwrap();
+	    match shared.result.take() {
+		Some(result) => Poll::Ready(result),
+		None => {
+		    shared.waker = Some(cx.waker().clone());	// The latest one, the task may have moved
+		    Poll::Pending
+		},
+	    }
+	}
+    }
+
+    //
+    // A Bitcask shared between the caller and the threads doing its work.  The sync API is still there
+    // through cask(), for code that's happy to block.
+    //
+    pub struct AsyncBitcask {
+	cask: Arc<Bitcask>,
+	pool: ThreadPool,
+    }
+    impl AsyncBitcask {
+	//
+	// Wrap an open store, doing its work on 'threads' threads.
+	//
+	pub fn new(cask: Arc<Bitcask>, threads: usize) -> AsyncBitcask {
+	    AsyncBitcask { cask, pool: ThreadPool::new(threads, AIO_QUEUE_SIZE, SaturationPolicy::Block) }
+	}
+
+	//
+	// Open the store in 'dirpath', as Bitcask::new() does but on a pool thread.
+	//
+	pub async fn open(dirpath: &str, threads: usize) -> Result<AsyncBitcask, BitcaskError> {
+	    let dirpath = dirpath.to_string();
+	    let pool = ThreadPool::new(threads, AIO_QUEUE_SIZE, SaturationPolicy::Block);
+	    let cask = Self::run_on(&pool, move || Bitcask::new(&dirpath)).await?;
+	    Ok(AsyncBitcask { cask: Arc::from(cask), pool })
+	}
+
+	pub fn cask(&self) -> &Arc<Bitcask> {
+	    &self.cask
+	}
+
+	pub fn get(&self, key: impl AsRef<[u8]>) -> BlockingCall<Result<Option<Vec<u8>>, BitcaskError>> {
+	    let key = key.as_ref().to_vec();
+	    self.run(move |cask| cask.get(&key))
+	}
+
+	pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> BlockingCall<Result<bool, BitcaskError>> {
+	    let (key, value) = (key.as_ref().to_vec(), value.as_ref().to_vec());
+	    self.run(move |cask| cask.put(&key, &value))
+	}
+
+	pub fn put_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> BlockingCall<Result<bool, BitcaskError>> {
+	    let (key, value) = (key.as_ref().to_vec(), value.as_ref().to_vec());
+	    self.run(move |cask| cask.put_with_ttl(&key, &value, ttl))
+	}
+
+	pub fn delete(&self, key: impl AsRef<[u8]>) -> BlockingCall<Result<bool, BitcaskError>> {
+	    let key = key.as_ref().to_vec();
+	    self.run(move |cask| cask.delete(&key))
+	}
+
+	pub fn sync(&self) -> BlockingCall<Result<bool, BitcaskError>> {
+	    self.run(|cask| cask.sync())
+	}
+
+	pub fn merge(&self) -> BlockingCall<Result<bool, BitcaskError>> {
+	    self.run(|cask| cask.merge())
+	}
+
+	pub fn shutdown(&self) -> BlockingCall<Result<bool, BitcaskError>> {
+	    self.run(|cask| cask.shutdown())
+	}
+
+	//
+	// Run 'f' against the store on the pool, returning the future of its result.
+	//
+	fn run<T: Send + 'static>(&self, f: impl FnOnce(&Bitcask) -> T + Send + 'static) -> BlockingCall<T> {
+	    let cask = Arc::clone(&self.cask);
+	    Self::run_on(&self.pool, move || f(&cask))
+	}
+
+	fn run_on<T: Send + 'static>(pool: &ThreadPool, f: impl FnOnce() -> T + Send + 'static) -> BlockingCall<T> {
+	    let shared = Arc::new(Mutex::new(Completion { result: None, waker: None }));
+	    let completion = Arc::clone(&shared);
+	    let job = move || {
+		let result = f();
+		let waker = {
+		    let mut completion = completion.lock().unwrap();
+		    completion.result = Some(result);
+		    completion.waker.take()
+		};
+		if let Some(waker) = waker {
+		    waker.wake();
+		}
+	    };
+	    pool.submit(job);					// Never run on the caller's thread, that's the executor's
+	    BlockingCall { shared }
+	}
+    }
+}
@@ -0,0 +1,31 @@
+//
+// Serve a Bitcask database directory to Redis clients, see the server module.
+//
+//     chroma-bitcask-server <dbdir> [address]
+//
+// The address defaults to 127.0.0.1:6379, Redis's own port, so clients find it without being told.
+//
+use std::net::TcpListener;
+use std::process::ExitCode;
+use std::sync::Arc;
+use chroma_bitcask::Bitcask;
+use chroma_bitcask::server::server::serve;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+	eprintln!("usage: {} <dbdir> [address]", args[0]);
+	return ExitCode::from(2);
+    }
+    let address = args.get(2).map(String::as_str).unwrap_or("127.0.0.1:6379");
+    let result = Bitcask::new(&args[1])
+	.and_then(|cask| Ok((Arc::<Bitcask>::from(cask), TcpListener::bind(address)?)))
+	.and_then(|(cask, listener)| Ok(serve(cask, listener)?));
+    match result {
+	Ok(_) => ExitCode::SUCCESS,
+	Err(e) => {
+	    eprintln!("{}: {}", args[0], e);
+	    ExitCode::FAILURE
+	},
+    }
+}
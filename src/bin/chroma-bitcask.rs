@@ -0,0 +1,166 @@
+//
+// Poke at a Bitcask database directory from the command line, for operations and debugging.
+//
+//     chroma-bitcask <dbdir> get <key>
+//     chroma-bitcask <dbdir> put <key> <value> [<ttl seconds>]
+//     chroma-bitcask <dbdir> delete <key>
+//     chroma-bitcask <dbdir> list-keys
+//     chroma-bitcask <dbdir> merge
+//     chroma-bitcask <dbdir> stats
+//     chroma-bitcask <dbdir> export-json
+//     chroma-bitcask <dbdir> import-json
+//     chroma-bitcask <dbdir> export-csv [--values]
+//     chroma-bitcask <dbdir> migrate [<version>]
+//     chroma-bitcask <dbdir> fsck
+//
+// Keys are taken as the bytes of the argument.  list-keys prints each key on a line of its own, as text if
+// it's UTF-8 and as 0x-prefixed hex if it isn't.  get exits with 1 if the key doesn't exist.  export-json
+// writes every KV to stdout as a line of JSON, and import-json puts each such line read from stdin.
+// export-csv writes a line of CSV describing each KV to stdout, with its value too if asked.  The store is
+// shut down cleanly afterwards, so hints are brought up to date and the next open is quick.  migrate
+// rewrites the datafiles in the given format version, the current one if none is given, and needs the
+// store not to be open, so it doesn't open it itself.  fsck prints everything wrong with the datafiles and
+// hints files, a line for each, and exits with 1 if there's anything.
+//
+use std::process::ExitCode;
+use std::time::Duration;
+use chroma_bitcask::{Bitcask, BitcaskError};
+
+const USAGE: &str = "usage: chroma-bitcask <dbdir> get <key> | put <key> <value> [<ttl seconds>] | delete <key> | list-keys | merge | stats | export-json | import-json | export-csv [--values] | migrate [<version>] | fsck";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+	eprintln!("{}", USAGE);
+	return ExitCode::from(2);
+    }
+    let words: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+    if words[0] == "migrate" {
+	return migrate(&args[1], &words[1..]);
+    }
+    let result = Bitcask::new(&args[1]).and_then(|cask| {
+	let result = run(&cask, &words);
+	cask.shutdown()?;
+	result
+    });
+    match result {
+	Ok(code) => code,
+	Err(e) => {
+	    eprintln!("chroma-bitcask: {}: {}", args[1], e);
+	    ExitCode::FAILURE
+	},
+    }
+}
+
+//
+// Migrate the store in 'dir' to the version in 'words', if there is one, and say how many datafiles it took.
+//
+fn migrate(dir: &str, words: &[&str]) -> ExitCode {
+    let version = match words {
+	[] => Bitcask::format_descriptor().version,
+	[version] => match version.parse::<u32>() {
+	    Ok(version) => version,
+	    Err(_) => {
+		eprintln!("chroma-bitcask: bad version {}", version);
+		return ExitCode::from(2);
+	    },
+	},
+	_ => {
+	    eprintln!("{}", USAGE);
+	    return ExitCode::from(2);
+	},
+    };
+    match Bitcask::migrate(dir, version) {
+	Ok(count) => {
+	    println!("migrated {} datafiles to version {}", count, version);
+	    ExitCode::SUCCESS
+	},
+	Err(e) => {
+	    eprintln!("chroma-bitcask: {}: {}", dir, e);
+	    ExitCode::FAILURE
+	},
+    }
+}
+
+//
+// Key or value bytes as they're printed: as they are if they're text, otherwise in hex after "0x".
+//
+fn printable(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+	Ok(text) => text,
+	Err(e) => format!("0x{}", e.as_bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+    }
+}
+
+//
+// Carry out one subcommand, printing its results.
+fn run(cask: &Bitcask, words: &[&str]) -> Result<ExitCode, BitcaskError> {
+    match words {
+	["get", key] => match cask.get(key)? {
+	    Some(value) => println!("{}", printable(value)),
+	    None => return Ok(ExitCode::FAILURE),
+	},
+	["put", key, value] => { cask.put(key, value)?; },
+	["put", key, value, ttl] => {
+	    let Ok(secs) = ttl.parse::<u64>() else {
+		eprintln!("chroma-bitcask: bad ttl {}", ttl);
+		return Ok(ExitCode::from(2));
+	    };
+	    cask.put_with_ttl(key, value, Duration::from_secs(secs))?;
+	},
+	["delete", key] => { cask.delete(key)?; },
+	["list-keys"] => {
+	    let mut keys = cask.list_keys();
+	    keys.sort();
+	    for key in keys {
+		println!("{}", printable(key));
+	    }
+	},
+	["merge"] => { cask.merge()?; },
+	["fsck"] => {
+	    let report = cask.verify()?;
+	    for issue in &report.issues {
+		println!("{}: offset {}: {:?}: {}", issue.file, issue.offset, issue.kind, issue.reason);
+	    }
+	    println!("{} datafiles, {} hints files, {} good records, {} problems", report.datafiles, report.hintsfiles, report.records, report.issues.len());
+	    if !report.is_ok() {
+		return Ok(ExitCode::FAILURE);
+	    }
+	},
+	["export-json"] => { cask.export_json(std::io::stdout().lock())?; },
+	["import-json"] => { cask.import_json(std::io::stdin().lock())?; },
+	["export-csv"] => { cask.export_csv(std::io::stdout().lock(), false)?; },
+	["export-csv", "--values"] => { cask.export_csv(std::io::stdout().lock(), true)?; },
+	["stats"] => {
+	    let (idle_merges, idle_merges_yielded) = cask.idle_merge_counts();
+	    let stats = cask.stats()?;
+	    println!("id: {}", cask.id());
+	    println!("keys: {}", stats.keys);
+	    println!("datafiles: {}", stats.datafiles);
+	    println!("disk_bytes: {}", stats.disk_bytes);
+	    println!("blob_bytes: {}", stats.blob_bytes);
+	    if let Some(quota) = stats.disk_quota {
+		println!("disk_quota: {}", quota);
+	    }
+	    println!("live_bytes: {}", stats.live_bytes);
+	    println!("changes_dropped: {}", stats.changes_dropped);
+	    println!("pool_queue_depth: {}", stats.pool_queue_depth);
+	    println!("dead_bytes: {}", stats.dead_bytes);
+	    for (id, dead) in &stats.dead_bytes_per_datafile {
+		println!("dead_bytes.{}: {}", id, dead);
+	    }
+	    println!("active_file_id: {}", cask.active_file_id());
+	    println!("current_file_size: {}", stats.current_file_size);
+	    println!("cache: {} hits, {} misses", stats.cache_hits, stats.cache_misses);
+	    println!("compaction_debt: {:.3}", cask.compaction_debt());
+	    println!("needs_merge: {}", cask.needs_merge());
+	    println!("hints_rejected: {}", cask.hints_rejected());
+	    println!("idle_merges: {} ({} yielded)", idle_merges, idle_merges_yielded);
+	},
+	_ => {
+	    eprintln!("{}", USAGE);
+	    return Ok(ExitCode::from(2));
+	},
+    }
+    Ok(ExitCode::SUCCESS)
+}
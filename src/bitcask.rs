@@ -2,378 +2,6282 @@
 // Module that implements a "Bitcask" style Key/Value store.
 //
 pub mod bitcask {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
     use std::collections::HashMap;
+    use std::collections::HashSet;
     use std::collections::VecDeque;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+    use std::path::Path;
     use std::vec::Vec;
-    use std::fs::read_dir;
-    use std::fs::File;
-    use threadpool::ThreadPool;
-    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::sync::Condvar;
+    use std::sync::Mutex;
+    use std::sync::MutexGuard;
     use std::sync::RwLock;
+    use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
     use std::io;
-    use crc32fast;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use uuid::Uuid;
+    use crate::bloom::bloom::BloomFilter;
+    use crate::cache::cache::ValueCache;
+    use crate::blob::blob::{chunk_aad, BlobPointer, BlobStore, BLOB_CHUNK_SIZE, BLOB_FLAG_CHUNKED, BLOB_FLAG_SEALED, BLOB_POINTER_SIZE};
+    use crate::compress::compress::{lz4_pack, lz4_unpack, Compression};
+    use crate::crypto::crypto::{EncryptionConfig, RecordCipher};
+    use crate::diskmap::diskmap::DiskMap;
+    use crate::error::error::BitcaskError;
+    use crate::json::json::{hex, parse_object, quote, unhex, JsonValue};
+    #[cfg(feature = "metrics")]
+    use crate::metrics::metrics::Metrics;
+    use crate::pool::pool::{SaturationPolicy, Submitted, ThreadPool};
+    use crate::trace::trace::OpSpan;
+    use crate::bucket::bucket::Bucket;
+    use crate::storage::storage::{FileStorage, FilesystemProbe, MemoryStorage, StatfsProbe, Storage, StorageFile, StorageLock, StoragePrefix, StorageReader, SyncCoordinator};
 
     // A simple monotonically increasing integer that identifies each datafile or hintsfile.
     // We use it as the basename of each such file.  We track the higest basename we've seen and
     // just add 1 when we need a new datafile or corresponding hints file.
-    type BitcaskFileID = i32;
+    pub type BitcaskFileID = i32;
+
+    // The version of the on-disk format described by BITCASK_FORMAT below.
+    //   1: the original layout
+    //   2: the crc is an unsigned 32-bit value, the bits on disk are the same as in 1
+    //   3: every record carries a 64-bit sequence number, covered by the crc
+    //   4: datafiles start with a header giving their record alignment, and each whole record is padded out
+    //      to a multiple of it (for the old alignment of 4 that's the same bytes as padding just the value)
+    //   5: every record carries the time it was written, in milliseconds since the epoch, covered by the crc
+    //   6: keys are arbitrary byte strings, the header gives the key's length and the key follows it
+    //   7: the records of a write batch are bracketed by BEGIN and COMMIT records, otherwise the same as 6
+    //   8: every record carries the time it expires, 0 for never, covered by the crc, and so does every hint
+    //   9: every record has flags, covered by the crc, saying how its value is compressed, value_size is the stored size
+    //  10: hints files start with a header, and every hint carries the timestamp of its record, datafiles are as in 9
+    //  11: the last 4 bytes of the datafile header are flags, they were reserved and zero before
+    //  12: a record's flags can say its stored value is a BlobPointer, and the value is in a blob file
+    const BITCASK_FORMAT_VERSION: u32 = 12;
+
+    // The oldest version we can still read, every version since only added to it.
+    const BITCASK_OLDEST_READABLE_VERSION: u32 = 9;
+
+    // The header at the start of every datafile since version 4: magic, the format version the file was
+    // written with, its record alignment, and 4 reserved bytes, all zero padded out to the alignment so the
+    // first record is aligned too.  Datafiles without one have their records aligned to 4.
+    const BITCASK_DATAFILE_MAGIC: &[u8; 4] = b"BCDF";
+    const BITCASK_DATAFILE_HEADER_SIZE: usize = 16;
+
+    // The flags in a datafile header.  Whether a value is compressed is up to each record, see its flags.
+    const BITCASK_DATAFILE_FLAG_ENCRYPTED: u32 = 1;		// Its records are sealed, reading it needs the key
+    const BITCASK_DATAFILE_FLAGS_KNOWN: u32 = BITCASK_DATAFILE_FLAG_ENCRYPTED;
+
+    // The on-disk size of the fixed part of a BitcaskDatafileRecord: crc, key_size, op, value_size, seq, timestamp, expires, and flags.
+    const BITCASK_RECORD_HEADER_SIZE: usize = 44;
+
+    // Where each field of that fixed part lives, and what every record is padded out to a multiple of by default.
+    const BITCASK_RECORD_CRC_OFFSET: usize = 0;
+    const BITCASK_RECORD_KEY_SIZE_OFFSET: usize = 4;
+    const BITCASK_RECORD_OP_OFFSET: usize = 8;
+    const BITCASK_RECORD_VALUE_SIZE_OFFSET: usize = 12;
+    const BITCASK_RECORD_SEQ_OFFSET: usize = 16;
+    const BITCASK_RECORD_TIMESTAMP_OFFSET: usize = 24;
+    const BITCASK_RECORD_EXPIRES_OFFSET: usize = 32;
+    const BITCASK_RECORD_FLAGS_OFFSET: usize = 40;
+
+    // The bits of a record's flags.  Any others are for later versions, and a record with one set is rejected.
+    const BITCASK_RECORD_FLAG_LZ4: u32 = 1;			// The stored value is compressed, see the compress module
+    const BITCASK_RECORD_FLAG_SEALED: u32 = 2;			// The key and value are encrypted, see seal()
+    const BITCASK_RECORD_FLAG_BLOB: u32 = 4;			// The stored value is a BlobPointer, see the blob module
+    const BITCASK_RECORD_FLAGS_KNOWN: u32 = BITCASK_RECORD_FLAG_LZ4 | BITCASK_RECORD_FLAG_SEALED | BITCASK_RECORD_FLAG_BLOB;
+    const BITCASK_RECORD_ALIGNMENT: usize = 4;
+
+    // The largest value the versions that named hints files "<id+1>.data" could store, so the largest a
+    // hint they wrote can describe.
+    const BITCASK_LEGACY_MAX_VALUE_SIZE: usize = 4096;
+
+    // The file in which merge records the highest sequence number whose history it has compacted away.
+    const BITCASK_HORIZON_FILE: &str = "horizon";
+
+    // The file holding the store's UUID, the 16 raw bytes of it.
+    const BITCASK_ID_FILE: &str = "id";
+
+    // The file a store holds locked for as long as it's open for writing, see Storage::lock().
+    const BITCASK_LOCK_FILE: &str = "LOCK";
+
+    // What a merge's datafile and hints file are called while they're being written, on the end of the
+    // names they're given once they're complete.  Recovery removes any it finds, they're from a merge that
+    // never finished, and so was never used.
+    const BITCASK_MERGE_TMP_SUFFIX: &str = ".merge.tmp";
+
+    // Likewise for a datafile being rewritten by migrate(), which removes any it finds the next time.
+    const BITCASK_MIGRATE_TMP_SUFFIX: &str = ".migrate.tmp";
+
+    // And for a hints file being generated, which is done on the pool, while verify() or a backup may be
+    // looking, so nobody ever sees it half written.  Recovery removes any it finds just the same.
+    const BITCASK_HINTS_TMP_SUFFIX: &str = ".gen.tmp";
+
+    // The most threads datafiles are read on at once when a store is opened, from their hints or not.
+    const BITCASK_RECOVERY_THREADS: usize = 8;
+
+    // The threads an open store does its background merges and hints generation on, and how many jobs may
+    // wait for them before BitcaskOptions::saturation_policy() decides what happens to the next.
+    const BITCASK_POOL_THREADS: usize = 2;
+    const BITCASK_POOL_QUEUE_SIZE: usize = 64;
+
+    // And by repair(), just the same.
+    const BITCASK_REPAIR_TMP_SUFFIX: &str = ".repair.tmp";
+
+    // What the bytes repair() couldn't make sense of are kept in, on the end of their datafile's name.
+    const BITCASK_CORRUPT_SUFFIX: &str = ".corrupt";
+
+    // The file the keymap is saved in, see Bitcask::set_keydir_snapshot_interval(), what it's called until
+    // it's durable, and the magic it starts with, followed by the format version it was written in.
+    const BITCASK_SNAPSHOT_FILE: &str = "keydir.snapshot";
+    const BITCASK_SNAPSHOT_TMP_FILE: &str = "keydir.snapshot.tmp";
+    const BITCASK_SNAPSHOT_MAGIC: &[u8; 4] = b"BCKS";
+
+    // The file shutdown() leaves to say the store was closed cleanly, and what it knew then, so the next
+    // open needn't work it out again, see CleanShutdown.  The next writer to open the store removes it.
+    const BITCASK_CLEAN_FILE: &str = "clean";
+    const BITCASK_CLEAN_TMP_FILE: &str = "clean.tmp";
+    const BITCASK_CLEAN_MAGIC: &[u8; 4] = b"BCCS";
+
+    // The file a backup lists what it holds in, written last so a backup that has one is complete.
+    const BITCASK_MANIFEST_FILE: &str = "MANIFEST";
+
+    // How much of a file a backup copies at a time.
+    const BITCASK_BACKUP_CHUNK_SIZE: usize = 1 << 20;
+
+    // How many imported records we append before making them visible in the keymap.
+    const BITCASK_IMPORT_BATCH_SIZE: usize = 1024;
+
+    // How many change events we hold for drain_changes() before the oldest start being dropped.
+    const BITCASK_CHANGE_BUFFER_SIZE: usize = 1024;
+
+    // How many spare read buffers each thread keeps when read buffer pooling is on.
+    const BITCASK_READ_BUFFER_POOL_SIZE: usize = 4;
+
+    // Called with the live KVs of each datafile that ages out of the store, see set_max_datafile_age().
+    type Archiver = Box<dyn Fn(BitcaskFileID, &[(Vec<u8>, Vec<u8>)]) -> Result<bool, BitcaskError> + Send + Sync>;
+
+    //
+    // The time now, as records store it.
+    //
+    fn now_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+    }
+
+    //
+    // Where bytes 'start'..'start'+'len' of a value of 'size' bytes are, as much of them as it has.
+    //
+    fn clamp_range(size: u64, start: u64, len: u64) -> (u64, u64) {
+	(start.min(size), start.saturating_add(len).min(size))
+    }
+
+    fn byte_range(value: &[u8], start: u64, len: u64) -> &[u8] {
+	let (from, to) = clamp_range(value.len() as u64, start, len);
+	&value[from as usize..to as usize]
+    }
+
+    //
+    // 'text' as a CSV field, quoted if it has to be, with any quotes in it doubled.
+    //
+    fn csv_field(text: &str) -> String {
+	match text.contains([',', '"', '\n', '\r']) {
+	    true => format!("\"{}\"", text.replace('"', "\"\"")),
+	    false => text.to_string(),
+	}
+    }
+
+    //
+    // A key or value as a CSV field: as csv_field() has it if it's UTF-8, in 0x-prefixed hex if it isn't.
+    //
+    fn csv_bytes(bytes: &[u8]) -> String {
+	match std::str::from_utf8(bytes) {
+	    Ok(text) => csv_field(text),
+	    Err(_) => format!("0x{}", hex(bytes)),
+	}
+    }
+
+    //
+    // The expiry of a record written now to last 'ttl', never 0, which would mean it never expires.
+    //
+    fn expiry_after(ttl: Duration) -> u64 {
+	now_millis().saturating_add(ttl.as_millis().max(1) as u64)
+    }
+
+    //
+    // Pin a failure to decode the record at 'offset' in 'file' on that record, unless it's the device's
+    // failure, or already says what it is.
+    //
+    fn blame(file: &str, offset: i64, e: io::Error) -> io::Error {
+	if e.kind() != io::ErrorKind::InvalidData || e.get_ref().is_some_and(|inner| inner.is::<BitcaskError>()) {
+	    return e;
+	}
+	BitcaskError::Corruption { file: file.to_string(), offset, reason: format!("can't be decoded: {}", e) }.into()
+    }
+
+    thread_local! {
+	// This thread's spare read buffers, shared by every Bitcask it reads from.
+	static READ_BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    //
+    // Lend 'f' one of this thread's spare buffers, or a new one if there are none, and take it back after.
+    //
+    fn with_read_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+	let mut buf = READ_BUFFERS.with(|pool| pool.borrow_mut().pop()).unwrap_or_default();
+	let result = f(&mut buf);
+	READ_BUFFERS.with(|pool| {
+	    let mut pool = pool.borrow_mut();
+	    if pool.len() < BITCASK_READ_BUFFER_POOL_SIZE {
+		pool.push(buf);
+	    }
+	});
+	result
+    }
+
+    // The header at the start of every hints file since version 10: magic, and the format version the file
+    // was written with.  Hints files without one are from before, and so are the hints in them.
+    const BITCASK_HINTS_MAGIC: &[u8; 4] = b"BCHT";
+    const BITCASK_HINTS_HEADER_SIZE: usize = 8;
+
+    // The on-disk size of the fixed part of a BitcaskHintsfileRecord: key_size, op, value_size, offset, expires,
+    // and timestamp.  Before version 10 it stopped at expires.
+    const BITCASK_HINTS_RECORD_HEADER_SIZE: usize = 36;
+    const BITCASK_UNVERSIONED_HINTS_RECORD_HEADER_SIZE: usize = 28;
+
+    // Before keys were byte strings a hint record was the first 20 bytes of that, with the key where key_size is now.
+    const BITCASK_LEGACY_HINTS_RECORD_SIZE: usize = 20;
+
+    // A hints file with more than 1 in this many bad records is ignored and its datafile scanned instead.
+    const BITCASK_HINTS_INVALID_RATIO: usize = 16;
+
+    // By default needs_merge() is true once a merge would reclaim at least this fraction of the datafiles.
+    const BITCASK_MERGE_DEBT_THRESHOLD: f64 = 0.5;
+
+    // An idle merge copies this many records at a time, pausing for a moment after each batch so the
+    // device isn't saturated, and gives up if foreground traffic arrived during the batch.
+    const BITCASK_IDLE_MERGE_BATCH: usize = 64;
+    const BITCASK_IDLE_MERGE_PAUSE: Duration = Duration::from_millis(1);
+
+    // How many per-key write locks we stripe the keyspace across.
+    const BITCASK_KEY_LOCK_STRIPES: usize = 64;
+
+    // How many shards the keymap is split into, unless BitcaskOptions::keymap_shards() says otherwise.
+    const BITCASK_KEYMAP_SHARDS: usize = 16;
 
     //
     // Define the format of the in-memory database of keys and which data file contains their values.
     // NOTE: this is NOT an on-disk value, it can be changed without affecting data retention
     //
+    #[derive(Clone, Copy)]
     struct BitcaskKeymapEntry {
 	value_size: i32,			// The size of the value of that key
 	fileid: BitcaskFileID,			// Which datafile contains that K/V pair
 	offset: i64,				// The byte offset of that K/V pair within that datafile
+	generation: u64,			// The seq of that record, 0 if it came from a hint and we haven't read it
+	expires: u64,				// When that KV stops existing, in ms since the epoch, 0 for never
+	timestamp: u64,				// When that record was written, likewise, 0 if it came from a hint that didn't say
     }
     impl BitcaskKeymapEntry {
-	pub fn new(value_size: i32, fileid: BitcaskFileID, offset: i64) -> Box<BitcaskKeymapEntry> {
+	pub fn new(value_size: i32, fileid: BitcaskFileID, offset: i64, generation: u64, expires: u64, timestamp: u64) -> Box<BitcaskKeymapEntry> {
 	    Box::new(BitcaskKeymapEntry {
-		value_size: value_size,
-		fileid: fileid,
-		offset: offset,
+		value_size,
+		fileid,
+		offset,
+		generation,
+		expires,
+		timestamp,
 	    })
 	}
-    }
 
-    // We need to know the type of operation in the on-disk records of the data files.
-    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
-    enum BitcaskDatafileRectype {
-	PUT,
-	DELETE,
+	//
+	// Has the KV passed its expiry as of 'now'?  Until a merge purges it it's still here, but it's absent.
+	//
+	fn is_expired(&self, now: u64) -> bool {
+	    self.expires != 0 && self.expires <= now
+	}
     }
 
     //
-    // Define the format and operations on one of the data files used by Bitcask.
-    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
+    // The keymap itself, hashed by default, or ordered by key so it can answer range queries without
+    // looking at every key, at the cost of slower lookups, see Bitcask::set_ordered_index().  Or hashed in
+    // files in the store's directory, for more keys than there's memory for, see BitcaskOptions::disk_index().
     //
-    struct BitcaskDatafileRecord {
-	crc: i32,
-	key: i32,
-	op: BitcaskDatafileRectype,
-	value_size: i32,			// This is the actual number of bytes in the value, but the total size of this record 
-						// will be SILENTLY rounded up to the next multiple of 4.
-	value: [u8; 4096],			// This is a maximally-sized structure, it will be shrunk-to-fit when on-disk
-						// (modulo the rounding up).  NOTE: this fixed-size is an ugly limitation but one that
-						// I need to live with until I figure out how to do variable sized buffers in rust
+    enum BitcaskKeymap {
+	Hashed(HashMap<Vec<u8>, BitcaskKeymapEntry>),
+	Ordered(BTreeMap<Vec<u8>, BitcaskKeymapEntry>),
+	Disk(DiskMap<BitcaskKeymapEntry>),
     }
-    impl BitcaskDatafileRecord {
-	pub fn new(key: i32, op: BitcaskDatafileRectype, value_size: i32, value: String) -> Box<BitcaskDatafileRecord> {
-	    Box::new(BitcaskDatafileRecord{
-		key: key,			// Copy the key into place
-		op: op,				// Copy the operation into place (PUT or DELETE)
-		value_size: value_size,		// The actual number of valid bytes in the value
-		value = value,			// GROT - Copy the string into place
-		crc: crc32fast::hash(rec),	// GROT - Need way to limit the "byte slice" to just the valid bytes
-	    })
+    impl BitcaskKeymap {
+	fn get(&self, key: &[u8]) -> Option<&BitcaskKeymapEntry> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => map.get(key),
+		BitcaskKeymap::Ordered(map) => map.get(key),
+		BitcaskKeymap::Disk(map) => map.get(key),
+	    }
 	}
-    }
 
-    //
-    // Track all the extant data files.
-    //
-    struct BitcaskDatafile {
-	name: String,				// The relative pathname of the data file
-	ID: BitcaskFileID,			// What "number" is it?
-	file_lock: RwLock<File>,		// Protects the File structure to ensure seeks() go with reads()/writes()
-    }
-    impl BitcaskDatafile {
-	//
-	// Create a new data file.
-	//
-	pub fn new(dirpath: String, ID: BitcaskFileID) -> Result<Box<BitcaskDatafile>,io::Error> {
-	    let filename = format("{}/{}.data", dirpath, ID+1);
-	    Ok(Box::new(BitcaskDatafile {
-		name: filename.clone(),
-		ID: ID + 1,
-		file: File::create(&filename)?,
-	    }))
+	fn get_mut(&mut self, key: &[u8]) -> Option<&mut BitcaskKeymapEntry> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => map.get_mut(key),
+		BitcaskKeymap::Ordered(map) => map.get_mut(key),
+		BitcaskKeymap::Disk(map) => map.get_mut(key),
+	    }
 	}
 
 	//
-	// Open an existing data file.
+	// Only an index on disk can fail, when its files can't grow, the disk being full say.  Then nothing
+	// has changed.
 	//
-	pub fn open(dirpath: String, ID: BitcaskFileID) -> Result<Box<BitcaskDatafile>,io::Error> {
-	    let filename = format("{}/{}.data", dirpath, ID);
-	    Ok(Box::new(BitcaskDatafile {
-		name: filename.clone(),
-		ID: ID,
-		file: File::open(&filename)?,
-	    }))
+	fn insert(&mut self, key: Vec<u8>, entry: BitcaskKeymapEntry) -> Result<Option<BitcaskKeymapEntry>, io::Error> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => Ok(map.insert(key, entry)),
+		BitcaskKeymap::Ordered(map) => Ok(map.insert(key, entry)),
+		BitcaskKeymap::Disk(map) => map.insert(&key, entry),
+	    }
 	}
 
-	//
-	// Read a BitcaskDatafileRecord from the given offset in the data file.
-	//
-	pub fn get(self: Self, offset: i64) -> Result<Box<BitcaskKeymapEntry>,Self::Error> {
-	    let mut rec = Box<BitcaskDatafileRecord;			// Max sized, will read more than what we really want
-	    {
-		let f = self.file_lock.write().unwrap();		// Protect the data structure while we do our seek and read
-		f.seek(SeekFrom::Start(offset))?;
-		let bytecount = f.read(&rec)?;
-	    }								// Drop the reader lock
-	    Ok(rec)
+	fn remove(&mut self, key: &[u8]) -> Option<BitcaskKeymapEntry> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => map.remove(key),
+		BitcaskKeymap::Ordered(map) => map.remove(key),
+		BitcaskKeymap::Disk(map) => map.remove(key),
+	    }
+	}
+
+	fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &BitcaskKeymapEntry)> + '_> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => Box::new(map.iter().map(|(key, entry)| (key.as_slice(), entry))),
+		BitcaskKeymap::Ordered(map) => Box::new(map.iter().map(|(key, entry)| (key.as_slice(), entry))),
+		BitcaskKeymap::Disk(map) => Box::new(map.iter()),
+	    }
+	}
+
+	fn into_entries(self) -> Box<dyn Iterator<Item = (Vec<u8>, BitcaskKeymapEntry)>> {
+	    match self {
+		BitcaskKeymap::Hashed(map) => Box::new(map.into_iter()),
+		BitcaskKeymap::Ordered(map) => Box::new(map.into_iter()),
+		BitcaskKeymap::Disk(map) => Box::new(map.into_entries()),
+	    }
+	}
+
+	fn empty(ordered: bool) -> BitcaskKeymap {
+	    match ordered {
+		true => BitcaskKeymap::Ordered(BTreeMap::new()),
+		false => BitcaskKeymap::Hashed(HashMap::new()),
+	    }
 	}
 
 	//
-	// Create a BitcaskDatafileRecord for a new KV, append it to the datafile, and optionally flush it out.
+	// An empty map of the same kind, on disk in the same place if this one is.
 	//
-	pub fn put(self: Self, key: i32, value: &String, flush: bool) -> Result<i64,Self::Error> {
-	    let mut rec = BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::PUT, i32::try_from(value.len())?, value);
-	    let offset: i64 = 0;					// Establish scope outside the lock hold region
-	    {
-		let f = self.file_lock.write().unwrap();		// Protect the data structure while we do our seek and write
-		let offset = f.seek(SeekFrom::End(0))?;			// Capture the offset of this new record
-		let bytecount = f.write(&rec)?;
-	    }								// Drop the reader lock
-	    if flush == true {						// Happens outside the lock
-		Self::sync(self);					// Ensure on-disk stability, if requested
+	fn empty_like(&self) -> Result<BitcaskKeymap, io::Error> {
+	    match self {
+		BitcaskKeymap::Disk(map) => Ok(BitcaskKeymap::Disk(DiskMap::new(map.dir())?)),
+		map => Ok(BitcaskKeymap::empty(map.is_ordered())),
 	    }
-	    Ok(offset)
+	}
+
+	fn is_ordered(&self) -> bool {
+	    matches!(self, BitcaskKeymap::Ordered(_))
+	}
+
+	fn is_on_disk(&self) -> bool {
+	    matches!(self, BitcaskKeymap::Disk(_))
 	}
 
 	//
-	// Create a BitcaskDatafileRecord for deleting a KV, append it to the datafile, and optionally flush it out.
+	// The same entries, in whichever kind of map is asked for.
 	//
-	pub fn delete(self: Self, key: i32, flush: bool) -> Result<i64,io::Error> {
-	    let mut rec = BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::DELETE, 0, &String::new());
-	    let offset: i64 = 0;					// Establish scope outside the lock hold region
-	    {
-		let f = self.file_lock.write().unwrap();		// Protect the data structure while we do our seek and write
-		let offset = f.seek(SeekFrom::End(0))?;			// Capture the offset of this new record
-		let bytecount = f.write(&rec)?;
-	    }								// Drop the reader lock
-	    if flush == true {						// Happens outside the lock
-		Self::sync(self);					// Ensure on-disk stability, if requested
+	fn into_kind(self, ordered: bool) -> BitcaskKeymap {
+	    match (self, ordered) {
+		(BitcaskKeymap::Hashed(map), true) => BitcaskKeymap::Ordered(map.into_iter().collect()),
+		(BitcaskKeymap::Ordered(map), false) => BitcaskKeymap::Hashed(map.into_iter().collect()),
+		(map, _) => map,
 	    }
-	    Ok(offset)
 	}
 
 	//
-	// Flush out any cached bytes for a datafile
+	// The keys within the bounds that haven't expired as of 'now', in order.  A hashed map has to look at
+	// every key to find them.
 	//
-	pub fn sync(self: Self) -> Result<bool,io::Error>  {
-	    self.file.sync_all()?;
-	    Ok(true)
+	fn range_keys(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>), now: u64) -> Vec<Vec<u8>> {
+	    let live = |entry: &BitcaskKeymapEntry| !entry.is_expired(now);
+	    match self {
+		BitcaskKeymap::Ordered(map) => map.range::<[u8], _>(bounds).filter(|(_, entry)| live(entry)).map(|(key, _)| key.clone()).collect(),
+		map => {
+		    let mut keys: Vec<Vec<u8>> = map.iter()
+			.filter(|(key, entry)| bounds.contains(*key) && live(entry))
+			.map(|(key, _)| key.to_vec())
+			.collect();
+		    keys.sort();
+		    keys
+		},
+	    }
 	}
     }
 
     //
-    // TODO: Need a full-on utility class for hints files.
-    // This class is not persistent, a hint file is either read at boot time and then forgotten,
-    // or it is generated from a data file without regard to anything else going on in the system.
+    // The keymap split into shards by a hash of the key, each behind a lock of its own, so that writers of
+    // different keys don't all queue up for the same one.  Anything that's after one key locks only that
+    // key's shard.  Anything that needs the whole map, or a batch's worth of it, locks what it needs in
+    // shard order, so two of them can't deadlock.
     //
-    struct BitcaskHintsfile {
-	name: String,				// The relative pathname of the hints file
-	ID: BitcaskFileID,			// What "number" is it?
-	file_lock: RwLock<File>,		// Protects the File structure to ensure seeks() go with reads()/writes()
+    struct BitcaskKeymapShards {
+	shards: Vec<RwLock<BitcaskKeymap>>,
     }
-    impl BitcaskHintsfile {
+    impl BitcaskKeymapShards {
 	//
-	// Create a new hints file.
+	// Only a map on disk can fail to be split, by failing to make the files of the shards.
 	//
-	pub fn new(dirpath: String, ID: BitcaskFileID) -> Result<Box<BitcaskHintsfile>,io::Error> {
-	    let filename = format("{}/{}.data", dirpath, ID+1);
-	    Ok(Box::new(BitcaskHintsfile {
-		name: filename.clone(),
-		ID: ID,
-		file: File::create(&filename)?,
-	    }))
+	fn new(keymap: BitcaskKeymap, count: usize) -> Result<BitcaskKeymapShards, io::Error> {
+	    let mut shards: Vec<BitcaskKeymap> = (0..count.max(1)).map(|_| keymap.empty_like()).collect::<Result<_, io::Error>>()?;
+	    let count = shards.len();
+	    for (key, entry) in keymap.into_entries() {
+		shards[keymap_shard(&key, count)].insert(key, entry)?;
+	    }
+	    Ok(BitcaskKeymapShards { shards: shards.into_iter().map(RwLock::new).collect() })
 	}
-    
-	//
-	// Define the format and operations on one of the hint files used by Bitcask.
-	// This file is a very quick way to repopulate the in-memroy keymap structure.
-	//
-	struct BitcaskHintsfileRecord {
-	    key: i32,				// The key of a KV we're storing
-	    op: BitcaskDatafileRectype,		// Is this a PUT or a DELETE?
-	    value_size: i32,			// The size of the value for that KV
-	    offset: i64,				// the offset within the data file where that KV is stored
+
+	fn is_on_disk(&self) -> bool {
+	    self.shards[0].read().unwrap().is_on_disk()
 	}
-	impl BitcaskHintsfileRecord {
-	    pub fn new(key: i32, op: BitcaskDatafileRectype, value_size: i32, value: String, offset: i64) -> Box<BitcaskHintsfileRecord> {
-		Box::new(BitcaskHintsfileRecord{
-		    key: key,			// Copy the key into place
-		    op: op,				// Copy the operation into place (PUT or DELETE)
-		    value_size: value_size,		// The actual number of valid bytes in the value
-		    offset: offset,			// the offset within the file of that record for that key
-		}) 
-	    }
+
+	fn read(&self, key: &[u8]) -> RwLockReadGuard<'_, BitcaskKeymap> {
+	    self.shards[keymap_shard(key, self.shards.len())].read().unwrap()
 	}
 
-	// Generate a hint file by sumarizing all the operations in the data file by recording the *surviving* PUT and DELETE operations.
-	// Read through the datafile, recording each op (and its  key and the byte offset of the record) into an in-memory HashMap.
-	// If this is a DELETE, remove any existing PUTs for from the hint summary that key and record the DELETE in the hint summary.
-	// If this is a PUT, remove any existing DELETEs or PUTs for that key from the hint summary, record the new PUT key and byte offset.
-	// The hints file is 'datafile.name' with ".data" changesd to ".hints".
-	pub fn hintsfile_generate(datafile: &BitcaskDatafile) -> Result<bool,io::Error> {
-	    // We use Self::appendrec again to get the compact binary representation of the op, key, and possibly value.
-	    // offset = appendrec(BitcaskDatafileRectype::PUT, key, value>)?;
-	    Ok(true)
+	fn write(&self, key: &[u8]) -> RwLockWriteGuard<'_, BitcaskKeymap> {
+	    self.shards[keymap_shard(key, self.shards.len())].write().unwrap()
 	}
 
-	// Read all the "*.hints" files into the in-memory keymap structure.
-	// The saved_hintQ must be in sorted order so that DELETE records that follow PUT record in
-	// time will make the key go away, if they were not processed in order keys would stick 
-	// around after they were deleted.
-	pub fn hintsfile_import(keymap: &HashMap<i32, BitcaskKeymapEntry>, filename: String) -> Result<bool,io::Error> {
-	    Ok(true)
+	fn read_all(&self) -> BitcaskKeymapGuard<RwLockReadGuard<'_, BitcaskKeymap>> {
+	    BitcaskKeymapGuard { guards: self.shards.iter().map(|shard| Some(shard.read().unwrap())).collect() }
+	}
+
+	fn write_all(&self) -> BitcaskKeymapGuard<RwLockWriteGuard<'_, BitcaskKeymap>> {
+	    BitcaskKeymapGuard { guards: self.shards.iter().map(|shard| Some(shard.write().unwrap())).collect() }
 	}
 
 	//
-	// This is a work routine and is lower priority than some other work, so queuing it up for later.
-	// There's very likely a much simpler way to identify this fill-in-the-missing-file task,
-	// but I don't have time to google for good library solutions right now.
-	// This would need to run during single-threaded mode either startup or shutdown processing.
-	// If I separated out the "read them in" part, then this could happen in a separate thread while
-	// normal processing was going on, but we'd need to collect the lists of files in one thread
-	// and then fork off separate thread(s?) to "generate" each hints file, and retain the list of existing
-	// hints files in the master thread so we could read them in and populate the in-memory keymap.
+	// Write-lock the shards of just the given keys.  Looking at any other key through the guard panics.
 	//
-	// This is the core data-resiliency routine.  It recovers from crashes and outages by
-	// depending upon the log-structure of the data files.  It generates any missing "*.hints"
-	// files so that the next crash/reboot will start faster.  Any existing, partially complete,
-	// data file becomes a read-only part of the dataset until merge time.
+	fn write_keys<'a>(&self, keys: impl Iterator<Item = &'a [u8]>) -> BitcaskKeymapGuard<RwLockWriteGuard<'_, BitcaskKeymap>> {
+	    let mut wanted = vec![false; self.shards.len()];
+	    for key in keys {
+		wanted[keymap_shard(key, self.shards.len())] = true;
+	    }
+	    BitcaskKeymapGuard {
+		guards: self.shards.iter().zip(wanted).map(|(shard, wanted)| wanted.then(|| shard.write().unwrap())).collect(),
+	    }
+	}
+
 	//
-	pub fn hintsfile_find_missing_files(cask: &Bitcask, dirpath: &String) -> Result<bool,std::io::Error> {
-	    //
-	    // Identify all existing "*.data" and ".*hints" files in the database directory
-	    //
-	    let mut dataQ: VecDeque::<String> = VecDeque::<String>::new();		// Set up parallel queues
-	    let mut hintQ: VecDeque::<String> = VecDeque::<String>::new();
-	    for entry in read_dir(dirpath.clone())? {
-		let entry = entry?;
-		let filename = entry.file_name().to_string_lossy().to_string();		// The OsString type is difficult to work with
-		if entry.metadata()?.is_file() {
-		    if filename.ends_with(".data") {
-			dataQ.push_back(filename);
-		    } else if filename.ends_with(".hints") {
-			hintQ.push_back(filename);
-		    }
+	// All the entries in one map again, in memory, leaving the shards empty.  Only a map on disk can fail
+	// to take them.
+	//
+	fn take(&mut self) -> Result<BitcaskKeymap, io::Error> {
+	    let ordered = self.shards[0].get_mut().unwrap().is_ordered();
+	    let mut keymap = BitcaskKeymap::empty(ordered);
+	    for shard in &mut self.shards {
+		for (key, entry) in std::mem::replace(shard.get_mut().unwrap(), BitcaskKeymap::empty(ordered)).into_entries() {
+		    keymap.insert(key, entry)?;
 		}
 	    }
-	    Ok(true)
+	    Ok(keymap)
 	}
     }
 
+    fn keymap_shard(key: &[u8], shards: usize) -> usize {
+	let mut hasher = DefaultHasher::new();
+	key.hash(&mut hasher);
+	hasher.finish() as usize % shards
+    }
+
     //
-    // The core logic that ties the Bitcask components together.
+    // Some or all of the keymap's shards, locked, with the map's own methods working across them.  The
+    // shards are indexed as in BitcaskKeymapShards, None for one that isn't locked.
     //
-    #[derive(Default)]
-    pub struct Bitcask {
-	keymap: RwLock<HashMap<i32, BitcaskKeymapEntry>>,		// Protects the in-memory HashMap of all extant KV pairs
-	current: RwLock<BitcaskDatafile>,				// Protects changes to the 'current' field (not the datafile itself)
-	datafiles: RwLock<HashMap<BitcaskFileID, BitcaskDatafile>>,	// Protects all the map of the archived data files
-	dirpath: String,						// The directory storing everything
-	maxID: BitcaskFileID,						// The highest ID of an existing datafile
+    struct BitcaskKeymapGuard<G> {
+	guards: Vec<Option<G>>,
     }
-    impl Bitcask {
-	//
-	// Create a new Bitcask and (re)fill it by (generating and) reading all hintfiles in the database directory.
-	//
-	pub fn new(dirpath: &String) -> Result<Box<Bitcask>,io::Error> {
-	    let cask = Box::new(Bitcask { });
-	    cask.keymap = RwLock::new(HashMap::new());
-	    cask.datafiles = RwLock::new(HashMap::new());
-	    cask.current = RwLock::new(BitcaskDatafile::new(dirpath, ID)?;
-	    cask.dirpath = dirpath.clone();
-	    //hintsfile_find_missing_files(&cask, dirpath)?;
-	    //hintsfile_import(keymap: &HashMap<i32, BitcaskKeymapEntry>, filename: String)?;
-	    Ok(cask)
+    impl<G: Deref<Target = BitcaskKeymap>> BitcaskKeymapGuard<G> {
+	fn shard(&self, key: &[u8]) -> &BitcaskKeymap {
+	    self.guards[keymap_shard(key, self.guards.len())].as_deref().expect("keymap shard isn't locked")
 	}
 
-	// 
-	pub fn get(self: &Self, key: i32) -> Result<String,io::Error> {
-	    let entry = 0;						// Establish scope outside the lock hold region
-	    {
-		let map = self.keymap.read().unwrap();			// Protect the data structure while we do our lookup
-		map.get(key, entry)?;					// Get the KV location from the index
-	    }								// Drop the reader lock
-	    let value = 0;						// Establish scope outside the lock hold region
-	    {
-		let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our lookup
-		let value = df.get(key, value, true)?;			// Get the KV from the datafile location
-	    }								// Drop the reader lock
-	    Ok(value)
+	fn get(&self, key: &[u8]) -> Option<&BitcaskKeymapEntry> {
+	    self.shard(key).get(key)
+	}
+
+	fn iter(&self) -> impl Iterator<Item = (&[u8], &BitcaskKeymapEntry)> + '_ {
+	    self.guards.iter().flatten().flat_map(|shard| shard.iter())
 	}
 
 	//
-	// Insert a new KV or update an existing KV
+	// An empty map of the same kind as the shards.
 	//
-	pub fn put(self: &Self, key: i32, value: &String) -> Result<bool,io::Error> {
-	    {
-		let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our lookup
-		let entry = df.put(key, value, true)?;			// Append a PUT record
-	    }								// Drop the reader lock
-	    Ok(true)
+	fn empty_like(&self) -> Result<BitcaskKeymap, io::Error> {
+	    self.guards.iter().flatten().next().expect("no keymap shard is locked").empty_like()
 	}
 
 	//
-	// Delete a (potentially) existing KV
-	// 
-	pub fn delete(self: &Self, key: i32) -> Result<bool,io::Error>  {
-	    {
-		let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our delete
-		let entry = df.delete(key, true)?;			// Append a DELETE record
-	    }								// Drop the reader lock
-	    {
-		let map = self.keymap.write().unwrap();			// Protect the data structure while we do our insert
-		map.remove(&key)?;					// Remove it from the index
-	    }								// Drop the writer lock
-	    Ok(true)
+	// As for BitcaskKeymap, each shard's keys are in order, so it only remains to put them together.
+	//
+	fn range_keys(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>), now: u64) -> Vec<Vec<u8>> {
+	    let mut keys: Vec<Vec<u8>> = self.guards.iter().flatten().flat_map(|shard| shard.range_keys(bounds, now)).collect();
+	    keys.sort();
+	    keys
+	}
+    }
+    impl<G: DerefMut<Target = BitcaskKeymap>> BitcaskKeymapGuard<G> {
+	fn shard_mut(&mut self, key: &[u8]) -> &mut BitcaskKeymap {
+	    let n = keymap_shard(key, self.guards.len());
+	    self.guards[n].as_deref_mut().expect("keymap shard isn't locked")
 	}
 
-	//
-	// Return a Vec<i32> containing all the keys in the database
-	// 
-	pub fn list_keys(self: Self) -> Box<Vec<i32>> {
-	    let keyvec = Box::<Vec::<i32>>;				// Establish scope outside the lock hold region
-	    {
-		let map = self.keymap.read().unwrap();			// Protect the data structure while we do our iterator
-		for key in map.keys() {
-		    keyvec.push(*key);
-		}
-	    }								// Drop the reader lock
-	    keyvec
+	fn get_mut(&mut self, key: &[u8]) -> Option<&mut BitcaskKeymapEntry> {
+	    self.shard_mut(key).get_mut(key)
+	}
+
+	fn insert(&mut self, key: Vec<u8>, entry: BitcaskKeymapEntry) -> Result<Option<BitcaskKeymapEntry>, io::Error> {
+	    self.shard_mut(&key).insert(key, entry)
+	}
+
+	fn remove(&mut self, key: &[u8]) -> Option<BitcaskKeymapEntry> {
+	    self.shard_mut(key).remove(key)
 	}
 
 	//
-	// Sync out the currently open data file.
-	// 
-	pub fn sync(self: &Self) -> Result<bool,io::Error> {
-	    {
-		let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our delete
-		df.sync()?;
-	    }								// Drop the reader lock
+	// Swap in the entries of 'keymap' for everything there was, for a guard holding every shard.
+	//
+	fn replace(&mut self, keymap: BitcaskKeymap) -> Result<bool, io::Error> {
+	    for shard in self.guards.iter_mut() {
+		let shard = shard.as_mut().expect("keymap shard isn't locked");
+		**shard = keymap.empty_like()?;
+	    }
+	    for (key, entry) in keymap.into_entries() {
+		self.insert(key, entry)?;
+	    }
 	    Ok(true)
 	}
+    }
+
+    // We need to know the type of operation in the on-disk records of the data files.
+    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
+    //
+    // BEGIN and COMMIT bracket the PUTs and DELETEs of a write batch.  They have no key or value, and the seq
+    // of the batch, which every record in it shares.
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum BitcaskDatafileRectype {
+	PUT,
+	DELETE,
+	BEGIN,
+	COMMIT,
+    }
+    impl BitcaskDatafileRectype {
+	fn from_disk(op: i32) -> Result<BitcaskDatafileRectype, io::Error> {
+	    match op {
+		0 => Ok(BitcaskDatafileRectype::PUT),
+		1 => Ok(BitcaskDatafileRectype::DELETE),
+		2 => Ok(BitcaskDatafileRectype::BEGIN),
+		3 => Ok(BitcaskDatafileRectype::COMMIT),
+		_ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown record type {}", op))),
+	    }
+	}
+    }
+
+    //
+    // Define the format and operations on one of the data files used by Bitcask.
+    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
+    //
+    // On disk each field of the header is stored little-endian in the order declared below, followed by
+    // exactly key_size bytes of key, value_size bytes of value, and then zero padding out to the datafile's
+    // alignment.  The crc covers the key_size, op, value_size, seq, timestamp, expires, flags, the key, and
+    // the value.  The value is as stored, compressed if the flags say so, and value_size is its stored size.
+    //
+    // A sealed record's key and value are instead, together, the nonce, the ciphertext of the key followed by
+    // the value, and the tag, as RecordCipher::seal() produces them with the rest of the header as the
+    // associated data.  key_size is still the size of the key, and value_size makes up the difference, so it
+    // counts the cipher's overhead too; the sizes are all that's left in the clear.
+    //
+    // The seq of a record comes from a single counter per store, and within any datafile written by put(),
+    // delete(), or an import it only ever goes up.  A merged datafile instead holds copies of older records
+    // in no particular order, all of them at or below the merge's horizon.
+    //
+    #[derive(Clone)]
+    struct BitcaskDatafileRecord {
+	crc: u32,
+	key_size: i32,				// The number of bytes in the key
+	op: BitcaskDatafileRectype,
+	value_size: i32,			// This is the actual number of bytes in the value, but the total size of this record
+						// will be SILENTLY rounded up to the datafile's alignment.
+	seq: u64,				// Where this mutation falls in the history of the store
+	timestamp: u64,				// When it was written, copies made by merges and imports keep the original's
+	expires: u64,				// When a PUT stops counting, in ms since the epoch, 0 for never
+	flags: u32,				// BITCASK_RECORD_FLAG_*
+	key: Vec<u8>,				// Exactly key_size bytes, or none at all in a record decoded from just a header
+	value: Vec<u8>,				// Exactly value_size bytes, or none at all in a record decoded from just a header,
+						// or the cipher's overhead less once a sealed record has been unsealed
+    }
+    impl BitcaskDatafileRecord {
+	pub fn new(key: &[u8], op: BitcaskDatafileRectype, value: impl AsRef<[u8]>, seq: u64) -> Result<Box<BitcaskDatafileRecord>, io::Error> {
+	    let bytes = value.as_ref();
+	    if key.len() > i32::MAX as usize {
+		return Err(BitcaskError::KeyTooLarge { size: key.len(), limit: i32::MAX as usize }.into());
+	    }
+	    if bytes.len() > i32::MAX as usize {
+		return Err(BitcaskError::ValueTooLarge { size: bytes.len(), limit: i32::MAX as usize }.into());
+	    }
+	    let mut rec = Box::new(BitcaskDatafileRecord{
+		crc: 0,
+		key_size: key.len() as i32,
+		op,				// Copy the operation into place (PUT, DELETE, or a batch marker)
+		value_size: bytes.len() as i32,	// The actual number of valid bytes in the value
+		seq,
+		timestamp: now_millis(),
+		expires: 0,			// See with_expiry()
+		flags: 0,			// See compressed()
+		key: key.to_vec(),		// Copy the key into place
+		value: bytes.to_vec(),
+	    });
+	    rec.crc = rec.checksum();				// Doesn't cover the padding
+	    Ok(rec)
+	}
 
 	//
-	// Close the current datafile and start a new one.
-	// We defer creating the hint files until shutdown or reboot, but we could fork a thread to do it if we wanted to.
+	// Make the record expire at 'expires', in ms since the epoch, 0 for never.
 	//
-	pub fn rotate(self: &Self) -> Result<bool,io::Error> {
-	    {
-		let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our rotation
-		let map = self.datafiles.write().unwrap();		// Protect the data structure while we do our insert
-		map.insert(self.current.ID, self.current)?;		// Move the current/closing datafile to the readonly archive
-		let self.current = BitcaskDatafile::new(dirpath, ID)?;	// Create a new current datafile to write to
-	    }								// Drop both of the locks
-	    // Self::generate_hints_file(&cask, &self.dirpath, datafile: &String, hintfile: &String)?;
+	fn with_expiry(mut self: Box<Self>, expires: u64) -> Box<BitcaskDatafileRecord> {
+	    self.expires = expires;
+	    self.crc = self.checksum();
+	    self
+	}
 
-	    Ok(true)
+	//
+	// Store the value compressed the given way, if that makes it smaller.
+	//
+	fn compressed(mut self: Box<Self>, compression: Compression) -> Box<BitcaskDatafileRecord> {
+	    let packed = match compression {
+		Compression::None => None,
+		Compression::Lz4 => lz4_pack(&self.value),
+	    };
+	    if let Some(packed) = packed {
+		self.value_size = packed.len() as i32;		// Smaller than the value, which fit
+		self.value = packed;
+		self.flags |= BITCASK_RECORD_FLAG_LZ4;
+		self.crc = self.checksum();
+	    }
+	    self
 	}
 
 	//
-	// Shutdown the whole system.
+	// Store 'ptr' in place of the value, which is in a blob file.
 	//
-	pub fn shutdown(self: &Self) -> Result<bool,io::Error> {
-	    // TODO: join() all the threads
-	    self.sync(Self)?;
-	    // TODO: close all the data files
-	    // Self::generate_hints_file(&cask, &self.dirpath, datafile: &String, hintfile: &String)?;
+	fn with_blob(mut self: Box<Self>, ptr: &BlobPointer) -> Box<BitcaskDatafileRecord> {
+	    self.value = ptr.to_bytes();
+	    self.value_size = self.value.len() as i32;
+	    self.flags |= BITCASK_RECORD_FLAG_BLOB;
+	    self.crc = self.checksum();
+	    self
+	}
+
+	//
+	// Encrypt the key and value, already in their stored form, leaving the record as it will be on disk.
+	//
+	fn sealed(mut self: Box<Self>, cipher: &RecordCipher) -> Result<Box<BitcaskDatafileRecord>, io::Error> {
+	    self.flags |= BITCASK_RECORD_FLAG_SEALED;
+	    self.value_size = i32::try_from(self.value.len() + RecordCipher::OVERHEAD)
+		.map_err(|_| BitcaskError::ValueTooLarge { size: self.value.len(), limit: i32::MAX as usize - RecordCipher::OVERHEAD })?;
+	    let mut payload = std::mem::take(&mut self.key);
+	    payload.extend_from_slice(&self.value);
+	    let mut sealed = cipher.seal(&payload, &self.header_fields())?;
+	    self.value = sealed.split_off(self.key_size as usize);	// See the comment on the format for the split
+	    self.key = sealed;
+	    self.crc = self.checksum();
+	    Ok(self)
+	}
+
+	//
+	// Decrypt the key and value of a sealed record read from disk, checking they belong with its header.
+	// The header is left as it was, so value_size, and the crc, are still those of the sealed record.
+	//
+	fn unseal(&mut self, cipher: Option<&RecordCipher>) -> Result<bool, io::Error> {
+	    if self.flags & BITCASK_RECORD_FLAG_SEALED == 0 {
+		return Ok(true);
+	    }
+	    let Some(cipher) = cipher else {
+		return Err(BitcaskError::InvalidArgument("record is encrypted and no key was given".to_string()).into());
+	    };
+	    let mut sealed = std::mem::take(&mut self.key);
+	    sealed.append(&mut self.value);
+	    let mut payload = cipher.open(&sealed, &self.header_fields())?;
+	    self.value = payload.split_off(self.key_size as usize);
+	    self.key = payload;
+	    Ok(true)
+	}
+
+	//
+	// The header as stored on disk, less the crc: what the crc covers ahead of the key and value, and what a
+	// sealed record's ciphertext is bound to.
+	//
+	fn header_fields(&self) -> Vec<u8> {
+	    let mut buf = Vec::with_capacity(BITCASK_RECORD_HEADER_SIZE - 4);
+	    buf.extend_from_slice(&self.key_size.to_le_bytes());
+	    buf.extend_from_slice(&(self.op as i32).to_le_bytes());
+	    buf.extend_from_slice(&self.value_size.to_le_bytes());
+	    buf.extend_from_slice(&self.seq.to_le_bytes());
+	    buf.extend_from_slice(&self.timestamp.to_le_bytes());
+	    buf.extend_from_slice(&self.expires.to_le_bytes());
+	    buf.extend_from_slice(&self.flags.to_le_bytes());
+	    buf
+	}
+
+	//
+	// Compute the CRC of the record as it would be stored on disk.
+	//
+	fn checksum(&self) -> u32 {
+	    let mut hasher = crc32fast::Hasher::new();
+	    hasher.update(&self.header_fields());
+	    hasher.update(&self.key);
+	    hasher.update(&self.value);
+	    hasher.finalize()
+	}
+
+	//
+	// Does the stored CRC match the contents?
+	//
+	fn crc_ok(&self) -> bool {
+	    self.crc == self.checksum()
+	}
+
+	//
+	// The number of bytes this record occupies on disk in a datafile with the given alignment, including the padding.
+	//
+	fn disk_size(key_size: usize, value_size: i32, alignment: usize) -> usize {
+	    (BITCASK_RECORD_HEADER_SIZE + key_size + value_size as usize).div_ceil(alignment) * alignment
+	}
+
+	//
+	// Produce the compact on-disk representation of the record.
+	//
+	fn to_bytes(&self, alignment: usize) -> Vec<u8> {
+	    let mut buf = vec![0u8; Self::disk_size(self.key.len(), self.value_size, alignment)];	// Comes pre-padded
+	    buf[BITCASK_RECORD_CRC_OFFSET..BITCASK_RECORD_CRC_OFFSET+4].copy_from_slice(&self.crc.to_le_bytes());
+	    let mut field = |i: usize, val: i32| buf[i..i+4].copy_from_slice(&val.to_le_bytes());
+	    field(BITCASK_RECORD_KEY_SIZE_OFFSET, self.key_size);
+	    field(BITCASK_RECORD_OP_OFFSET, self.op as i32);
+	    field(BITCASK_RECORD_VALUE_SIZE_OFFSET, self.value_size);
+	    buf[BITCASK_RECORD_SEQ_OFFSET..BITCASK_RECORD_SEQ_OFFSET+8].copy_from_slice(&self.seq.to_le_bytes());
+	    buf[BITCASK_RECORD_TIMESTAMP_OFFSET..BITCASK_RECORD_TIMESTAMP_OFFSET+8].copy_from_slice(&self.timestamp.to_le_bytes());
+	    buf[BITCASK_RECORD_EXPIRES_OFFSET..BITCASK_RECORD_EXPIRES_OFFSET+8].copy_from_slice(&self.expires.to_le_bytes());
+	    buf[BITCASK_RECORD_FLAGS_OFFSET..BITCASK_RECORD_FLAGS_OFFSET+4].copy_from_slice(&self.flags.to_le_bytes());
+	    let value_start = BITCASK_RECORD_HEADER_SIZE + self.key.len();
+	    buf[BITCASK_RECORD_HEADER_SIZE..value_start].copy_from_slice(&self.key);
+	    buf[value_start..value_start + self.value_size as usize].copy_from_slice(&self.value);
+	    buf
+	}
+
+	//
+	// Decode the on-disk header, returning a record with an empty key and value and their sizes filled in.
+	//
+	fn from_header(hdr: &[u8]) -> Result<Box<BitcaskDatafileRecord>, io::Error> {
+	    let field = |i: usize| i32::from_le_bytes([hdr[i], hdr[i+1], hdr[i+2], hdr[i+3]]);
+	    let key_size = field(BITCASK_RECORD_KEY_SIZE_OFFSET);
+	    if key_size < 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad key size {}", key_size)));
+	    }
+	    let value_size = field(BITCASK_RECORD_VALUE_SIZE_OFFSET);
+	    if value_size < 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad value size {}", value_size)));
+	    }
+	    let flags = Self::flags_of(hdr)?;
+	    Ok(Box::new(BitcaskDatafileRecord{
+		crc: u32::from_le_bytes(hdr[BITCASK_RECORD_CRC_OFFSET..BITCASK_RECORD_CRC_OFFSET+4].try_into().unwrap()),
+		key_size,
+		op: BitcaskDatafileRectype::from_disk(field(BITCASK_RECORD_OP_OFFSET))?,
+		value_size,
+		seq: u64::from_le_bytes(hdr[BITCASK_RECORD_SEQ_OFFSET..BITCASK_RECORD_SEQ_OFFSET+8].try_into().unwrap()),
+		timestamp: u64::from_le_bytes(hdr[BITCASK_RECORD_TIMESTAMP_OFFSET..BITCASK_RECORD_TIMESTAMP_OFFSET+8].try_into().unwrap()),
+		expires: u64::from_le_bytes(hdr[BITCASK_RECORD_EXPIRES_OFFSET..BITCASK_RECORD_EXPIRES_OFFSET+8].try_into().unwrap()),
+		flags,
+		key: Vec::new(),
+		value: Vec::new(),
+	    }))
+	}
+
+	//
+	// Decode the flags of an on-disk header, refusing any we don't know how to handle.
+	//
+	fn flags_of(hdr: &[u8]) -> Result<u32, io::Error> {
+	    let flags = u32::from_le_bytes(hdr[BITCASK_RECORD_FLAGS_OFFSET..BITCASK_RECORD_FLAGS_OFFSET+4].try_into().unwrap());
+	    if flags & !BITCASK_RECORD_FLAGS_KNOWN != 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown record flags {:#x}", flags)));
+	    }
+	    Ok(flags)
+	}
+
+	//
+	// Turn a stored value back into the value that was put, unless it's in a blob file, then the store that
+	// has the blob file has to, see Bitcask::value_of().
+	//
+	fn unpack(flags: u32, stored: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+	    if flags & BITCASK_RECORD_FLAG_BLOB != 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "the value is in a blob file"));
+	    }
+	    if flags & BITCASK_RECORD_FLAG_LZ4 != 0 {
+		return lz4_unpack(&stored);
+	    }
+	    Ok(stored)
+	}
+
+	//
+	// Fill in the key and value of a record decoded by from_header() from the bytes that followed the header,
+	// which may carry padding after them.
+	//
+	fn set_body(&mut self, mut body: Vec<u8>) {
+	    body.truncate(self.key_size as usize + self.value_size as usize);
+	    self.value = body.split_off(self.key_size as usize);
+	    self.key = body;
+	}
+
+	//
+	// Return the value, uncompressed.
+	//
+	fn value_bytes(&self) -> Result<Vec<u8>, io::Error> {
+	    Self::unpack(self.flags, self.value.clone())
+	}
+    }
+
+    //
+    // One field of the on-disk record header, as described by a FormatDescriptor.
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct FormatField {
+	pub name: &'static str,
+	pub offset: usize,			// Byte offset within the record header
+	pub size: usize,			// Width in bytes
+	pub kind: &'static str,			// How to interpret those bytes, e.g. "i32"
+    }
+
+    //
+    // A machine-readable description of the on-disk datafile format, for tools that want to parse the
+    // datafiles directly.  It's built from the same constants the encoder and decoder use, so it can't
+    // drift from what put() actually writes.
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct FormatDescriptor {
+	pub magic: &'static [u8],		// The bytes every datafile starts with, empty if there are none
+	pub version: u32,			// Bumped whenever the layout changes
+	pub file_header_size: usize,		// Bytes before the first record in a datafile with the default alignment
+	pub file_flags: &'static [(&'static str, u32)],	// The bits of the u32 at offset 12 of the file header
+	pub record_header_size: usize,		// Bytes before the key in each record, the value follows the key
+	pub record_header: &'static [FormatField],
+	pub record_types: &'static [(&'static str, i32)],	// The values of the "op" field
+	pub alignment: usize,			// Each record is zero padded out to a multiple of this, unless the file header says otherwise
+	pub endianness: &'static str,		// Of every integer field
+	pub checksum: &'static str,		// The algorithm that produces the "crc" field
+	pub checksum_covers: &'static [&'static str],	// What the checksum is computed over, in order
+    }
+
+    const BITCASK_FORMAT: FormatDescriptor = FormatDescriptor {
+	magic: BITCASK_DATAFILE_MAGIC,
+	version: BITCASK_FORMAT_VERSION,
+	file_header_size: BITCASK_DATAFILE_HEADER_SIZE,
+	file_flags: &[("encrypted", BITCASK_DATAFILE_FLAG_ENCRYPTED)],
+	record_header_size: BITCASK_RECORD_HEADER_SIZE,
+	record_header: &[
+	    FormatField { name: "crc", offset: BITCASK_RECORD_CRC_OFFSET, size: 4, kind: "u32" },
+	    FormatField { name: "key_size", offset: BITCASK_RECORD_KEY_SIZE_OFFSET, size: 4, kind: "i32" },
+	    FormatField { name: "op", offset: BITCASK_RECORD_OP_OFFSET, size: 4, kind: "i32" },
+	    FormatField { name: "value_size", offset: BITCASK_RECORD_VALUE_SIZE_OFFSET, size: 4, kind: "i32" },
+	    FormatField { name: "seq", offset: BITCASK_RECORD_SEQ_OFFSET, size: 8, kind: "u64" },
+	    FormatField { name: "timestamp", offset: BITCASK_RECORD_TIMESTAMP_OFFSET, size: 8, kind: "u64" },
+	    FormatField { name: "expires", offset: BITCASK_RECORD_EXPIRES_OFFSET, size: 8, kind: "u64" },
+	    FormatField { name: "flags", offset: BITCASK_RECORD_FLAGS_OFFSET, size: 4, kind: "u32" },
+	],
+	record_types: &[("PUT", BitcaskDatafileRectype::PUT as i32), ("DELETE", BitcaskDatafileRectype::DELETE as i32),
+			("BEGIN", BitcaskDatafileRectype::BEGIN as i32), ("COMMIT", BitcaskDatafileRectype::COMMIT as i32)],
+	alignment: BITCASK_RECORD_ALIGNMENT,
+	endianness: "little",
+	checksum: "crc32",
+	checksum_covers: &["key_size", "op", "value_size", "seq", "timestamp", "expires", "flags", "key", "value"],
+    };
+
+    impl FormatDescriptor {
+	//
+	// Render the descriptor as a JSON object so external parsers can configure themselves from it.
+	//
+	pub fn to_json(&self) -> String {
+	    let quote = |s: &str| format!("\"{}\"", s);
+	    let fields: Vec<String> = self.record_header.iter()
+		.map(|f| format!("{{\"name\": {}, \"offset\": {}, \"size\": {}, \"kind\": {}}}", quote(f.name), f.offset, f.size, quote(f.kind)))
+		.collect();
+	    let types: Vec<String> = self.record_types.iter().map(|(name, val)| format!("{}: {}", quote(name), val)).collect();
+	    let flags: Vec<String> = self.file_flags.iter().map(|(name, val)| format!("{}: {}", quote(name), val)).collect();
+	    let covers: Vec<String> = self.checksum_covers.iter().map(|name| quote(name)).collect();
+	    format!("{{\"magic\": [{}], \"version\": {}, \"file_header_size\": {}, \"file_flags\": {{{}}}, \"record_header_size\": {}, \
+		     \"record_header\": [{}], \"record_types\": {{{}}}, \"alignment\": {}, \"endianness\": {}, \
+		     \"checksum\": {}, \"checksum_covers\": [{}]}}",
+		    self.magic.iter().map(|b| b.to_string()).collect::<Vec<String>>().join(", "),
+		    self.version, self.file_header_size, flags.join(", "), self.record_header_size, fields.join(", "), types.join(", "),
+		    self.alignment, quote(self.endianness), quote(self.checksum), covers.join(", "))
+	}
+    }
+
+    //
+    // Which changes a subscriber wants to hear about.
+    //
+    enum ChangeFilter {
+	All,
+	Key(Vec<u8>),
+	Prefix(Vec<u8>),
+    }
+    impl ChangeFilter {
+	fn matches(&self, key: &[u8]) -> bool {
+	    match self {
+		ChangeFilter::All => true,
+		ChangeFilter::Key(want) => key == want.as_slice(),
+		ChangeFilter::Prefix(prefix) => key.starts_with(prefix),
+	    }
+	}
+    }
+
+    //
+    // What kind of mutation a ChangeEvent describes.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ChangeOp {
+	Put,
+	Delete,
+    }
+
+    //
+    // One committed put or delete, as delivered to subscribers.  'seq' is the record's sequence number, which
+    // orders all the mutations of the store, and (fileid, offset) is where the record now lives, the same
+    // location the keymap holds, so an external index can point straight back into the datafiles.
+    // For a delete that's the tombstone.  NOTE: a merge relocates records without emitting events.
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct ChangeEvent {
+	pub key: Vec<u8>,
+	pub op: ChangeOp,
+	pub value: Option<Vec<u8>>,		// None for a delete, and for a put_reader(), whose value we never had all of
+	pub seq: u64,
+	pub fileid: BitcaskFileID,
+	pub offset: i64,
+    }
+
+    //
+    // What the index knows about one KV, see Bitcask::key_metadata().
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct KeyMetadata {
+	pub timestamp: u64,			// When the value was put, in ms since the epoch, copies made by merges keep it
+	pub expires: u64,			// When it expires, likewise, 0 for never
+	pub value_size: usize,			// As stored, so compressed or sealed if it is
+	pub file_id: BitcaskFileID,		// Where its record is now
+	pub offset: i64,
+    }
+
+    //
+    // What's wrong with one place in one file, as Bitcask::verify() found it.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum VerifyIssueKind {
+	Framing,				// A record header that makes no sense, nothing after it can be read
+	Truncated,				// A record that runs past the end of the file
+	Checksum,				// A whole record whose checksum doesn't match
+	Encryption,				// A record or hints file that won't decrypt
+	Hint,					// A hint that doesn't describe its datafile, or a hints file that can't be read
+    }
+
+    //
+    // 'offset' is into 'file', so for a hint it's where in the hints file the hint is, not the datafile
+    // offset it claims, which 'reason' gives.
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct VerifyIssue {
+	pub file: String,
+	pub offset: i64,
+	pub kind: VerifyIssueKind,
+	pub reason: String,
+    }
+
+    // The hints read from a hints file, None if it can't be trusted, see BitcaskHintsfile::hintsfile_read().
+    type LoadedHints = Option<Vec<Box<BitcaskHintsfileRecord>>>;
+
+    // What BitcaskDatafile::verify() found at each offset a record starts at, None for a bad one.
+    type VerifiedRecords = HashMap<i64, Option<(Vec<u8>, BitcaskDatafileRectype, i32)>>;
+
+    //
+    // What Bitcask::verify() looked at and everything it found wrong, in file order.
+    //
+    #[derive(Clone, Default, PartialEq, Eq, Debug)]
+    pub struct VerifyReport {
+	pub datafiles: usize,
+	pub hintsfiles: usize,
+	pub records: usize,			// Those that were whole and checked out
+	pub issues: Vec<VerifyIssue>,
+    }
+    impl VerifyReport {
+	pub fn is_ok(&self) -> bool {
+	    self.issues.is_empty()
+	}
+
+	fn issue(&mut self, file: &str, offset: i64, kind: VerifyIssueKind, reason: String) {
+	    self.issues.push(VerifyIssue { file: file.to_string(), offset, kind, reason });
+	}
+    }
+
+    //
+    // What Bitcask::repair() did: how many datafiles it had to rewrite, or set aside whole, how many bytes
+    // it took out of them, and the sidecar files it put those in.
+    //
+    #[derive(Clone, Default, PartialEq, Eq, Debug)]
+    pub struct RepairReport {
+	pub datafiles: usize,
+	pub corrupt_bytes: u64,
+	pub sidecars: Vec<String>,
+	pub hintsfiles: usize,			// Regenerated, for every datafile we can
+    }
+
+    //
+    // A snapshot of how big the store is and how much of it is garbage, see Bitcask::stats().  Byte counts
+    // are of the datafiles, the hints files aren't counted.
+    //
+    #[derive(Clone, Default, PartialEq, Eq, Debug)]
+    pub struct Stats {
+	pub keys: usize,			// Live keys, those deleted or expired don't count
+	pub datafiles: usize,			// Including the current one
+	pub disk_bytes: u64,			// The total size of the datafiles, headers and all
+	pub live_bytes: u64,			// The bytes of records the keymap points at
+	pub dead_bytes: u64,			// The bytes a full merge would reclaim
+	pub dead_bytes_per_datafile: BTreeMap<BitcaskFileID, u64>,
+	pub current_file_size: u64,		// The size of the datafile taking writes
+	pub cache_hits: u64,			// Gets answered from the value cache, 0 without one
+	pub cache_misses: u64,			// Gets that had to read the datafiles even so
+	pub blob_bytes: u64,			// The total size of the blob files
+	pub disk_quota: Option<u64>,		// What disk_bytes and blob_bytes may come to, see Bitcask::set_disk_quota()
+	pub changes_dropped: u64,		// Change events discarded unread, see Bitcask::changes_dropped()
+	pub pool_queue_depth: usize,		// Background jobs waiting for a thread
+    }
+
+    //
+    // What Bitcask::backup() copied, as its MANIFEST records it: which store it's a copy of, how far into
+    // its history the copy goes, and every file copied with how many bytes of it were taken.
+    //
+    #[derive(Clone, Default, PartialEq, Eq, Debug)]
+    pub struct BackupManifest {
+	pub id: Uuid,				// The store backed up, the copy shares its ID
+	pub sequence: u64,			// The newest record in the backup
+	pub highest_fileid: BitcaskFileID,	// The newest datafile in the backup
+	pub files: Vec<(String, u64)>,
+    }
+    impl BackupManifest {
+	fn to_text(&self) -> String {
+	    let mut text = format!("id {}\nsequence {}\nhighest_fileid {}\n", self.id, self.sequence, self.highest_fileid);
+	    for (name, len) in &self.files {
+		text.push_str(&format!("file {} {}\n", name, len));
+	    }
+	    text
+	}
+    }
+
+    //
+    // A group of puts and deletes that Bitcask::write() applies all together or, if it crashes part way
+    // through, not at all.  They're applied in the order they were added, so a later op on a key wins.
+    //
+    #[derive(Clone, Default, Debug)]
+    pub struct WriteBatch {
+	ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,	// None for a delete
+    }
+    impl WriteBatch {
+	pub fn new() -> WriteBatch {
+	    WriteBatch { ops: Vec::new() }
+	}
+
+	pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut WriteBatch {
+	    self.ops.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+	    self
+	}
+
+	pub fn delete(&mut self, key: impl AsRef<[u8]>) -> &mut WriteBatch {
+	    self.ops.push((key.as_ref().to_vec(), None));
+	    self
+	}
+
+	pub fn len(&self) -> usize {
+	    self.ops.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+	    self.ops.is_empty()
+	}
+    }
+
+    //
+    // When a write is flushed to stable storage.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub enum SyncPolicy {
+	#[default]
+	Always,					// Before the write returns, the way it has always been
+	Every(Duration),			// By a thread of our own, at most this long after the write returns
+	OnRotate,				// When the datafile it went to is sealed by rotation, or sync() or shutdown
+	Never,					// Whenever the OS gets round to it, or sync() or shutdown
+    }
+
+    //
+    // The thread that flushes the datafile writes have gone to, every so often, for SyncPolicy::Every.  It
+    // holds only the file and not the store, and it's stopped by stop(), or by dropping the BitcaskFlusher
+    // without waiting for it, when it flushes one last time and goes.
+    //
+    struct BitcaskFlusher {
+	dirty: Arc<Mutex<Option<Arc<dyn StorageFile>>>>,	// Written to since the last flush, if anything was
+	thread: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,	// Until it's stopped, dropping the Sender stops it
+    }
+    impl BitcaskFlusher {
+	fn new(interval: Duration) -> BitcaskFlusher {
+	    let dirty: Arc<Mutex<Option<Arc<dyn StorageFile>>>> = Arc::new(Mutex::new(None));
+	    let (stop, stopped) = channel::<()>();
+	    let pending = Arc::clone(&dirty);
+	    let interval = interval.max(Duration::from_millis(1));
+	    let handle = std::thread::spawn(move || loop {
+		let stopping = !matches!(stopped.recv_timeout(interval), Err(std::sync::mpsc::RecvTimeoutError::Timeout));
+		let file = pending.lock().unwrap().take();
+		if let Some(file) = file {
+		    if let Err(e) = file.sync() {
+			log::warn!("periodic sync failed: {}", e);
+		    }
+		}
+		if stopping {
+		    return;
+		}
+	    });
+	    BitcaskFlusher { dirty, thread: Mutex::new(Some((stop, handle))) }
+	}
+
+	//
+	// Stop the thread, and wait for its last flush.
+	//
+	fn stop(&self) {
+	    if let Some((stop, handle)) = self.thread.lock().unwrap().take() {
+		drop(stop);
+		let _ = handle.join();
+	    }
+	}
+
+	//
+	// Have 'file' flushed next time round.  Anything written to the file before was flushed by its rotation.
+	//
+	fn mark(&self, file: &Arc<dyn StorageFile>) {
+	    let mut dirty = self.dirty.lock().unwrap();
+	    if !dirty.as_ref().is_some_and(|dirty| Arc::ptr_eq(dirty, file)) {
+		*dirty = Some(Arc::clone(file));
+	    }
+	}
+    }
+
+    //
+    // When needs_merge() says a merge is worth it, and so when start_background_merge() and start_idle_merge()
+    // run one: as soon as any threshold that's set is crossed.  The default is the compaction_debt() of
+    // BITCASK_MERGE_DEBT_THRESHOLD and nothing else.
+    //
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub struct MergePolicy {
+	pub debt: Option<f64>,			// The fraction of all the datafiles' bytes a merge would reclaim
+	pub file_dead_ratio: Option<f64>,	// The fraction of any one sealed datafile's bytes that are dead
+	pub dead_bytes: Option<u64>,		// The bytes a merge would reclaim, however many there are altogether
+    }
+    impl Default for MergePolicy {
+	fn default() -> MergePolicy {
+	    MergePolicy { debt: Some(BITCASK_MERGE_DEBT_THRESHOLD), file_dead_ratio: None, dead_bytes: None }
+	}
+    }
+
+    //
+    // Which sealed datafiles apply_retention() retires, see Bitcask::set_retention_policy(): the oldest ones,
+    // for as long as either threshold that's set says they're past it.  The default retires nothing.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub struct RetentionPolicy {
+	pub max_datafiles: Option<usize>,	// Keep at most this many sealed datafiles, the newest
+	pub max_age: Option<Duration>,		// Keep no sealed datafile whose newest record is older than this
+    }
+
+    //
+    // What a read does with a record that fails its checksum or can't be decoded.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub enum CorruptionPolicy {
+	#[default]
+	Fail,					// Fail with BitcaskError::Corruption
+	Skip,					// Log it and carry on as if the key didn't exist
+    }
+
+    //
+    // How Bitcask::open_with() opens a store.  BitcaskOptions::new() gives what Bitcask::new() does, and
+    // each setter changes one thing:
+    //
+    //     let mut options = BitcaskOptions::new(dirpath);
+    //     options.create_if_missing(false).sync(SyncPolicy::Never);
+    //     let cask = Bitcask::open_with(&options)?;
+    //
+    #[derive(Clone, Debug)]
+    pub struct BitcaskOptions {
+	dirpath: String,
+	max_datafile_size: Option<u64>,		// If set, rotate the current datafile once it's grown this big
+	rotation_interval: Option<Duration>,	// If set, rotate the current datafile once it's been current this long
+	sync: SyncPolicy,
+	read_only: bool,			// Refuse every write, and create nothing, not even the directory
+	create_if_missing: bool,		// Start a new store if there isn't one, rather than failing with NotFound
+	compression: Compression,
+	max_value_size: usize,			// The biggest value a put will take, in bytes before compression
+	corruption_policy: CorruptionPolicy,
+	keymap_shards: usize,			// How many locks the keymap is split across, see Bitcask::set_keymap_shards()
+	cache_size: usize,			// The value cache's budget in bytes, 0 for none
+	bloom_filter: bool,
+	disk_index: bool,			// Keep the keymap in scratch files in the directory rather than in memory
+	disk_quota: Option<u64>,		// If set, the most bytes of datafiles writes may take the store to
+	blob_threshold: Option<usize>,		// If set, values this big or bigger go in blob files
+	strict_filesystem: bool,		// Refuse to write to a store on a filesystem we can't trust, rather than warn
+	saturation_policy: SaturationPolicy,	// What's done with background work when the pool's queue is full
+    }
+    impl BitcaskOptions {
+	pub fn new(dirpath: &str) -> BitcaskOptions {
+	    BitcaskOptions {
+		dirpath: dirpath.to_string(),
+		max_datafile_size: None,
+		rotation_interval: None,
+		sync: SyncPolicy::Always,
+		read_only: false,
+		create_if_missing: true,
+		compression: Compression::None,
+		max_value_size: i32::MAX as usize,
+		corruption_policy: CorruptionPolicy::Fail,
+		keymap_shards: BITCASK_KEYMAP_SHARDS,
+		cache_size: 0,
+		bloom_filter: false,
+		disk_index: false,
+		disk_quota: None,
+		blob_threshold: None,
+		strict_filesystem: false,
+		saturation_policy: SaturationPolicy::Inline,
+	    }
+	}
+
+	pub fn max_datafile_size(&mut self, max: Option<u64>) -> &mut BitcaskOptions {
+	    self.max_datafile_size = max;
+	    self
+	}
+
+	pub fn rotation_interval(&mut self, interval: Option<Duration>) -> &mut BitcaskOptions {
+	    self.rotation_interval = interval;
+	    self
+	}
+
+	pub fn sync(&mut self, sync: SyncPolicy) -> &mut BitcaskOptions {
+	    self.sync = sync;
+	    self
+	}
+
+	pub fn read_only(&mut self, read_only: bool) -> &mut BitcaskOptions {
+	    self.read_only = read_only;
+	    self
+	}
+
+	pub fn create_if_missing(&mut self, create: bool) -> &mut BitcaskOptions {
+	    self.create_if_missing = create;
+	    self
+	}
+
+	pub fn compression(&mut self, compression: Compression) -> &mut BitcaskOptions {
+	    self.compression = compression;
+	    self
+	}
+
+	pub fn max_value_size(&mut self, max: usize) -> &mut BitcaskOptions {
+	    self.max_value_size = max;
+	    self
+	}
+
+	pub fn corruption_policy(&mut self, policy: CorruptionPolicy) -> &mut BitcaskOptions {
+	    self.corruption_policy = policy;
+	    self
+	}
+
+	pub fn keymap_shards(&mut self, shards: usize) -> &mut BitcaskOptions {
+	    self.keymap_shards = shards;
+	    self
+	}
+
+	pub fn cache_size(&mut self, budget: usize) -> &mut BitcaskOptions {
+	    self.cache_size = budget;
+	    self
+	}
+
+	pub fn bloom_filter(&mut self, enabled: bool) -> &mut BitcaskOptions {
+	    self.bloom_filter = enabled;
+	    self
+	}
+
+	//
+	// Hash the keymap in files in the store's directory, so it's the page cache that holds it rather than
+	// the process, and a store with more keys than there's memory for can still be opened.  Lookups that
+	// miss the cache go to the device.  The files are unlinked as soon as they're made and built afresh on
+	// every open, so nothing is left behind, but the directory has to be writable even for a read-only
+	// store.  The keymap can't be ordered, or have its shards changed, once it's open.  Only on Linux, with
+	// the mmap feature, elsewhere opening fails with Unsupported.
+	//
+	pub fn disk_index(&mut self, enabled: bool) -> &mut BitcaskOptions {
+	    self.disk_index = enabled;
+	    self
+	}
+
+	pub fn disk_quota(&mut self, quota: Option<u64>) -> &mut BitcaskOptions {
+	    self.disk_quota = quota;
+	    self
+	}
+
+	pub fn blob_threshold(&mut self, threshold: Option<usize>) -> &mut BitcaskOptions {
+	    self.blob_threshold = threshold;
+	    self
+	}
+
+	//
+	// Refuse to open the store for writing with UnsupportedFilesystem if its directory is on a filesystem
+	// whose flushes and locks can't be trusted, NFS and the like, rather than just warn.  Opening it
+	// read-only only ever warns.
+	//
+	pub fn strict_filesystem(&mut self, strict: bool) -> &mut BitcaskOptions {
+	    self.strict_filesystem = strict;
+	    self
+	}
+
+	//
+	// What happens to a background merge or hints generation that arrives while the store's pool has a full
+	// queue: with Inline, the default, the thread that set it off does it there and then, and with Block it
+	// waits for room in the queue, either way slowing down to the pool's pace; with Defer it's dropped, with
+	// a warning, and tried again later, on the next tick of the maintenance thread for a merge, at
+	// shutdown() for hints.
+	//
+	pub fn saturation_policy(&mut self, policy: SaturationPolicy) -> &mut BitcaskOptions {
+	    self.saturation_policy = policy;
+	    self
+	}
+    }
+
+    //
+    // Track all the extant data files.
+    //
+    struct BitcaskDatafile {
+	name: String,				// The name of the data file within the storage backend
+	id: BitcaskFileID,			// What "number" is it?
+	file: Arc<dyn StorageFile>,		// The backend's handle, which serializes its own reads and writes
+	records: AtomicUsize,			// Slots handed out by reserve(), only meaningful for the current datafile
+	written: AtomicU64,			// How big our own appends have made it, header and all, likewise
+	synced: AtomicU64,			// How much of that a sync_write() is known to have made stable
+	syncing: Mutex<bool>,			// Whether a sync_write() is syncing it for everyone waiting, see group_sync()
+	synced_more: Condvar,			// Signalled whenever one finishes
+	alignment: usize,			// What this file's records are padded out to, from its header
+	start: u64,				// The offset of the first record, just past the header and its padding
+	newest: AtomicU64,			// The newest record's timestamp, 0 until newest_timestamp() works it out
+	cipher: Option<Arc<RecordCipher>>,	// If set, records appended are sealed with it, and sealed ones read opened with it
+    }
+    impl BitcaskDatafile {
+	//
+	// Create a new data file, numbered one higher than the given ID, with records padded to 'alignment'.
+	//
+	pub fn new(storage: &dyn Storage, id: BitcaskFileID, alignment: usize, cipher: Option<Arc<RecordCipher>>) -> Result<Box<BitcaskDatafile>, io::Error> {
+	    Self::create(storage, format!("{}.data", id+1), id, alignment, cipher)
+	}
+
+	//
+	// As new(), but the file is called 'filename' until it's renamed, see rename().
+	//
+	fn create(storage: &dyn Storage, filename: String, id: BitcaskFileID, alignment: usize, cipher: Option<Arc<RecordCipher>>) -> Result<Box<BitcaskDatafile>, io::Error> {
+	    let file: Arc<dyn StorageFile> = storage.create(&filename)?.into();
+	    let mut header = vec![0u8; BITCASK_DATAFILE_HEADER_SIZE.div_ceil(alignment) * alignment];
+	    header[0..4].copy_from_slice(BITCASK_DATAFILE_MAGIC);
+	    header[4..8].copy_from_slice(&BITCASK_FORMAT_VERSION.to_le_bytes());
+	    header[8..12].copy_from_slice(&(alignment as u32).to_le_bytes());
+	    let flags = if cipher.is_some() { BITCASK_DATAFILE_FLAG_ENCRYPTED } else { 0 };
+	    header[12..16].copy_from_slice(&flags.to_le_bytes());
+	    file.append(&header)?;
+	    Ok(Box::new(BitcaskDatafile {
+		file,
+		name: filename,
+		id: id + 1,
+		records: AtomicUsize::new(0),
+		written: AtomicU64::new(header.len() as u64),
+		synced: AtomicU64::new(0),
+		syncing: Mutex::new(false),
+		synced_more: Condvar::new(),
+		alignment,
+		start: header.len() as u64,
+		newest: AtomicU64::new(0),
+		cipher,
+	    }))
+	}
+
+	//
+	// Open an existing data file.
+	//
+	pub fn open(storage: &dyn Storage, id: BitcaskFileID, cipher: Option<Arc<RecordCipher>>) -> Result<Box<BitcaskDatafile>, io::Error> {
+	    let filename = format!("{}.data", id);
+	    let file = storage.open(&filename)?.into();
+	    Self::from_file(filename, id, file, cipher)
+	}
+
+	//
+	// Wrap an already open data file, taking its alignment from its header if it has one.
+	//
+	fn from_file(name: String, id: BitcaskFileID, file: Arc<dyn StorageFile>, cipher: Option<Arc<RecordCipher>>) -> Result<Box<BitcaskDatafile>, io::Error> {
+	    let size = file.size()?;
+	    let (alignment, start, flags) = Self::read_header(&name, &*file)?;
+	    if flags & BITCASK_DATAFILE_FLAG_ENCRYPTED != 0 && cipher.is_none() {
+		return Err(BitcaskError::InvalidFormat { reason: "is encrypted, and no key was given".to_string(), file: name }.into());
+	    }
+	    Ok(Box::new(BitcaskDatafile {
+		file,
+		name,
+		id,
+		records: AtomicUsize::new(0),
+		written: AtomicU64::new(size),
+		synced: AtomicU64::new(0),
+		syncing: Mutex::new(false),
+		synced_more: Condvar::new(),
+		alignment,
+		start: start as u64,
+		newest: AtomicU64::new(0),
+		cipher,
+	    }))
+	}
+
+	//
+	// Read a BitcaskDatafileRecord with a key of 'key_size' bytes from the given offset in the data file.
+	//
+	pub fn get(&self, offset: i64, key_size: usize, value_size: i32) -> Result<Box<BitcaskDatafileRecord>, io::Error> {
+	    let mut buf = vec![0u8; BitcaskDatafileRecord::disk_size(key_size, value_size, self.alignment)];
+	    self.file.read_exact_at(offset as u64, &mut buf)?;
+	    let decode = || {
+		let mut rec = BitcaskDatafileRecord::from_header(&buf[..BITCASK_RECORD_HEADER_SIZE])?;
+		if rec.key_size as usize != key_size || rec.value_size != value_size {
+		    return Err(self.corruption(offset, "has an unexpected size"));
+		}
+		buf.drain(..BITCASK_RECORD_HEADER_SIZE);		// What's left is the key, the value, and the padding
+		rec.set_body(buf);
+		if !rec.crc_ok() {
+		    return Err(self.corruption(offset, "has a bad checksum"));
+		}
+		rec.unseal(self.cipher.as_deref())?;
+		Ok(rec)
+	    };
+	    decode().map_err(|e| blame(&self.name, offset, e))
+	}
+
+	fn corruption(&self, offset: i64, reason: &str) -> io::Error {
+	    BitcaskError::Corruption { file: self.name.clone(), offset, reason: reason.to_string() }.into()
+	}
+
+	//
+	// Read just the value of the record at the given offset into 'buf', uncompressed, returning its length.
+	// The whole record is read into 'buf' and the value slid down over the header, so once 'buf' has grown
+	// to fit the largest record it's used for there are no more allocations, unless the value is compressed.
+	// If the value is in a blob file it's the BlobPointer that's left in 'buf', and we say so.
+	//
+	pub fn get_into(&self, offset: i64, key_size: usize, value_size: i32, buf: &mut Vec<u8>) -> Result<(usize, bool), io::Error> {
+	    buf.clear();
+	    buf.resize(BitcaskDatafileRecord::disk_size(key_size, value_size, self.alignment), 0);
+	    self.file.read_exact_at(offset as u64, buf)?;
+	    self.decode_value(offset, key_size, value_size, buf).map_err(|e| blame(&self.name, offset, e))
+	}
+
+	//
+	// Read just bytes 'start'..'start'+'len' of the value of the record at the given offset, fewer if the
+	// value isn't that long, if it's stored as it was put.  None if it's compressed, sealed, or in a blob
+	// file, then the record has to be read whole.  The checksum is of the whole record, so only its sizes
+	// can be checked.
+	//
+	pub fn get_range(&self, offset: i64, key_size: usize, value_size: i32, start: u64, len: u64) -> Result<Option<Vec<u8>>, io::Error> {
+	    let read = || {
+		let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+		self.file.read_exact_at(offset as u64, &mut hdr)?;
+		let rec = BitcaskDatafileRecord::from_header(&hdr)?;
+		if rec.key_size as usize != key_size || rec.value_size != value_size {
+		    return Err(self.corruption(offset, "has an unexpected size"));
+		}
+		if rec.flags & (BITCASK_RECORD_FLAG_LZ4 | BITCASK_RECORD_FLAG_SEALED | BITCASK_RECORD_FLAG_BLOB) != 0 {
+		    return Ok(None);
+		}
+		let (from, to) = clamp_range(value_size as u64, start, len);
+		let mut buf = vec![0u8; (to - from) as usize];
+		self.file.read_exact_at(offset as u64 + (BITCASK_RECORD_HEADER_SIZE + key_size) as u64 + from, &mut buf)?;
+		Ok(Some(buf))
+	    };
+	    read().map_err(|e| blame(&self.name, offset, e))
+	}
+
+	fn decode_value(&self, offset: i64, key_size: usize, value_size: i32, buf: &mut Vec<u8>) -> Result<(usize, bool), io::Error> {
+	    let field = |i: usize| i32::from_le_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]]);	// Not from_header(), that boxes a whole record
+	    BitcaskDatafileRectype::from_disk(field(BITCASK_RECORD_OP_OFFSET))?;
+	    if field(BITCASK_RECORD_KEY_SIZE_OFFSET) as usize != key_size || field(BITCASK_RECORD_VALUE_SIZE_OFFSET) != value_size {
+		return Err(self.corruption(offset, "has an unexpected size"));
+	    }
+	    let crc = u32::from_le_bytes(buf[BITCASK_RECORD_CRC_OFFSET..BITCASK_RECORD_CRC_OFFSET + 4].try_into().unwrap());
+	    if crc != crc32fast::hash(&buf[BITCASK_RECORD_CRC_OFFSET + 4..BITCASK_RECORD_HEADER_SIZE + key_size + value_size as usize]) {
+		return Err(self.corruption(offset, "has a bad checksum"));	// The header fields are laid out as checksum() takes them
+	    }
+	    let flags = BitcaskDatafileRecord::flags_of(buf)?;
+	    if flags & BITCASK_RECORD_FLAG_SEALED != 0 {		// Opening it needs the key too, so take the slow way
+		let mut rec = BitcaskDatafileRecord::from_header(&buf[..BITCASK_RECORD_HEADER_SIZE])?;
+		rec.set_body(buf.split_off(BITCASK_RECORD_HEADER_SIZE));
+		rec.unseal(self.cipher.as_deref())?;
+		*buf = rec.value;
+	    } else {
+		let value_start = BITCASK_RECORD_HEADER_SIZE + key_size;
+		buf.copy_within(value_start..value_start + value_size as usize, 0);
+		buf.truncate(value_size as usize);
+	    }
+	    if flags & BITCASK_RECORD_FLAG_BLOB != 0 {
+		return Ok((buf.len(), true));
+	    }
+	    if flags & BITCASK_RECORD_FLAG_LZ4 != 0 {
+		*buf = BitcaskDatafileRecord::unpack(flags, std::mem::take(buf))?;
+	    }
+	    Ok((buf.len(), false))
+	}
+
+	//
+	// Produce the on-disk representation of a record for this datafile.  If we have a cipher the PUTs and
+	// DELETEs are sealed, including any being copied from elsewhere, which were opened when they were read,
+	// and which are sealed again under a new nonce.  Batch markers have nothing to hide and never are.
+	//
+	fn encode(&self, rec: &BitcaskDatafileRecord) -> Result<Vec<u8>, io::Error> {
+	    let opened = rec.flags & BITCASK_RECORD_FLAG_SEALED != 0;
+	    match &self.cipher {
+		Some(cipher) if matches!(rec.op, BitcaskDatafileRectype::PUT | BitcaskDatafileRectype::DELETE) => {
+		    let mut plain = Box::new(rec.clone());
+		    plain.flags &= !BITCASK_RECORD_FLAG_SEALED;
+		    plain.value_size = plain.value.len() as i32;
+		    Ok(plain.sealed(cipher)?.to_bytes(self.alignment))
+		},
+		None if opened => Err(BitcaskError::InvalidArgument(format!("{}: can't write an encrypted record without a key", self.name)).into()),
+		_ => Ok(rec.to_bytes(self.alignment)),
+	    }
+	}
+
+	//
+	// The value_size a record will have once encode() has stored it here.
+	//
+	fn stored_value_size(&self, rec: &BitcaskDatafileRecord) -> i32 {
+	    match &self.cipher {
+		Some(_) if matches!(rec.op, BitcaskDatafileRectype::PUT | BitcaskDatafileRectype::DELETE) => (rec.value.len() + RecordCipher::OVERHEAD) as i32,
+		_ => rec.value.len() as i32,
+	    }
+	}
+
+	//
+	// Append an already-built BitcaskDatafileRecord to the datafile, and optionally flush it out.
+	//
+	fn append(&self, rec: &BitcaskDatafileRecord, flush: bool) -> Result<i64, io::Error> {
+	    let bytes = self.encode(rec)?;
+	    let offset = self.file.append(&bytes)?;			// Capture the offset of this new record
+	    self.written.fetch_max(offset + bytes.len() as u64, Ordering::SeqCst);
+	    if flush {
+		self.sync()?;						// Ensure on-disk stability, if requested
+	    }
+	    Ok(offset as i64)
+	}
+
+	//
+	// Append several already-built records with a single write, so nothing else can land between them,
+	// returning the offset of each.
+	//
+	fn append_all(&self, recs: &[Box<BitcaskDatafileRecord>], flush: bool) -> Result<Vec<i64>, io::Error> {
+	    let encoded: Vec<Vec<u8>> = recs.iter().map(|rec| self.encode(rec)).collect::<Result<_, io::Error>>()?;
+	    let bytes = encoded.concat();
+	    let mut offset = self.file.append(&bytes)? as i64;
+	    self.written.fetch_max(offset as u64 + bytes.len() as u64, Ordering::SeqCst);
+	    if flush {
+		self.sync()?;
+	    }
+	    Ok(encoded.iter().map(|bytes| {
+		let at = offset;
+		offset += bytes.len() as i64;
+		at
+	    }).collect())
+	}
+
+	//
+	// Create a BitcaskDatafileRecord for a new KV, expiring at 'expires' unless that's 0, append it to the
+	// datafile, and optionally flush it out.  Returns its offset, the size of the value as stored, and its
+	// timestamp.
+	//
+	pub fn put(&self, key: &[u8], value: &[u8], seq: u64, expires: u64, compression: Compression, flush: bool) -> Result<(i64, i32, u64), io::Error> {
+	    let rec = BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::PUT, value, seq)?.with_expiry(expires).compressed(compression);
+	    Ok((self.append(&rec, flush)?, self.stored_value_size(&rec), rec.timestamp))
+	}
+
+	//
+	// As put(), but for a KV whose value is already in a blob file, where 'ptr' says.
+	//
+	pub fn put_blob(&self, key: &[u8], ptr: &BlobPointer, seq: u64, expires: u64, flush: bool) -> Result<(i64, i32, u64), io::Error> {
+	    let rec = BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::PUT, "", seq)?.with_expiry(expires).with_blob(ptr);
+	    Ok((self.append(&rec, flush)?, self.stored_value_size(&rec), rec.timestamp))
+	}
+
+	//
+	// Create a BitcaskDatafileRecord for deleting a KV, append it to the datafile, and optionally flush it out.
+	// Its value is empty, so only the header and the key go to disk, followed by padding to the alignment,
+	// unless it's sealed.  Returns what put() does.
+	//
+	pub fn delete(&self, key: &[u8], seq: u64, flush: bool) -> Result<(i64, i32, u64), io::Error> {
+	    let rec = BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::DELETE, "", seq)?;
+	    Ok((self.append(&rec, flush)?, self.stored_value_size(&rec), rec.timestamp))
+	}
+
+	//
+	// Claim room for one more record, unless the file already holds 'limit' of them.
+	//
+	fn reserve(&self, limit: Option<usize>) -> bool {
+	    let n = self.records.fetch_add(1, Ordering::SeqCst);
+	    if limit.is_some_and(|limit| n >= limit) {
+		self.records.fetch_sub(1, Ordering::SeqCst);
+		return false;
+	    }
+	    true
+	}
+
+	//
+	// The record alignment, where the records start, and the flags of the datafile 'file', going by its
+	// header, or if it has none, as from_file() takes it.
+	//
+	fn read_header(name: &str, file: &dyn StorageFile) -> Result<(usize, usize, u32), io::Error> {
+	    let mut header = [0u8; BITCASK_DATAFILE_HEADER_SIZE];
+	    if file.size()? < header.len() as u64 || file.read_exact_at(0, &mut header).is_err() || &header[0..4] != BITCASK_DATAFILE_MAGIC {
+		return Ok((BITCASK_RECORD_ALIGNMENT, 0, 0));	// From before datafiles had headers
+	    }
+	    let field = |i: usize| u32::from_le_bytes(header[i..i+4].try_into().unwrap());
+	    let alignment = field(8) as usize;
+	    if !(BITCASK_OLDEST_READABLE_VERSION..=BITCASK_FORMAT_VERSION).contains(&field(4)) || alignment < BITCASK_RECORD_ALIGNMENT || !alignment.is_power_of_two() {
+		return Err(BitcaskError::InvalidFormat { reason: format!("version {} alignment {} is not a format we can read", field(4), alignment), file: name.to_string() }.into());
+	    }
+	    if field(12) & !BITCASK_DATAFILE_FLAGS_KNOWN != 0 {
+		return Err(BitcaskError::InvalidFormat { reason: format!("has flags {:#x} we don't know", field(12)), file: name.to_string() }.into());
+	    }
+	    Ok((alignment, BITCASK_DATAFILE_HEADER_SIZE.div_ceil(alignment) * alignment, field(12)))
+	}
+
+	//
+	// How long the record at 'offset' of 'file', which is 'size' bytes, is, if a whole one with a good
+	// checksum starts there.  It needs no key, see valid_length().
+	//
+	fn good_record_at(file: &dyn StorageFile, offset: u64, size: u64, alignment: usize) -> Result<Option<u64>, io::Error> {
+	    let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+	    if offset + hdr.len() as u64 > size {
+		return Ok(None);
+	    }
+	    file.read_exact_at(offset, &mut hdr)?;
+	    let Ok(mut rec) = BitcaskDatafileRecord::from_header(&hdr) else {
+		return Ok(None);
+	    };
+	    let disk_size = BitcaskDatafileRecord::disk_size(rec.key_size as usize, rec.value_size, alignment) as u64;
+	    if offset + disk_size > size {
+		return Ok(None);
+	    }
+	    let mut body = vec![0u8; disk_size as usize - BITCASK_RECORD_HEADER_SIZE];
+	    file.read_exact_at(offset + hdr.len() as u64, &mut body)?;
+	    rec.set_body(body);
+	    Ok(rec.crc_ok().then_some(disk_size))
+	}
+
+	//
+	// Walk every record in the datafile in order, handing each one and its offset to the caller.
+	//
+	pub fn scan<F>(&self, mut f: F) -> Result<bool, io::Error>
+	where F: FnMut(i64, &BitcaskDatafileRecord) -> Result<bool, io::Error> {
+	    for item in self.records()? {
+		let (offset, rec) = item?;
+		f(offset, &rec)?;
+	    }
+	    Ok(true)
+	}
+
+	//
+	// The records of the datafile, as scan() sees them, but read one at a time as they're asked for.  Only
+	// those already there when this is called are read, later appends are left for the next reader.
+	//
+	fn records(&self) -> Result<BitcaskDatafileRecords, io::Error> {
+	    Ok(BitcaskDatafileRecords {
+		name: self.name.clone(),
+		alignment: self.alignment,
+		cipher: self.cipher.clone(),
+		size: self.file.size()?,
+		offset: self.start as i64,
+		reader: BufReader::new(StorageReader::new(Arc::clone(&self.file), self.start)),
+		failed: false,
+	    })
+	}
+
+	//
+	// As scan(), but only hand over the PUTs and DELETEs that took effect: those outside any batch straight
+	// away, and those of a batch when its COMMIT is reached, followed by the COMMIT itself.  A batch with no
+	// COMMIT, which a crash part way through writing one leaves at the end of the datafile, never happened,
+	// and only its BEGIN is handed over, last, so the caller still knows where the file's records end.
+	//
+	pub fn scan_committed<F>(&self, mut f: F) -> Result<bool, io::Error>
+	where F: FnMut(i64, &BitcaskDatafileRecord) -> Result<bool, io::Error> {
+	    let mut begin: Option<(i64, BitcaskDatafileRecord)> = None;	// The BEGIN of the batch we're in, if we're in one
+	    let mut pending: Vec<(i64, BitcaskDatafileRecord)> = Vec::new();
+	    self.scan(|offset, rec| {
+		match rec.op {
+		    BitcaskDatafileRectype::BEGIN => {				// An unfinished one before it can't be, see above
+			begin = Some((offset, rec.clone()));
+			pending.clear();
+		    },
+		    BitcaskDatafileRectype::COMMIT => {
+			for (offset, rec) in pending.drain(..) {
+			    f(offset, &rec)?;
+			}
+			begin = None;
+			f(offset, rec)?;
+		    },
+		    _ if begin.is_some() => pending.push((offset, rec.clone())),
+		    _ => { f(offset, rec)?; },
+		}
+		Ok(true)
+	    })?;
+	    if let Some((offset, begin)) = begin {
+		f(offset, &begin)?;
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Return the current length of the datafile in bytes.
+	//
+	pub fn size(&self) -> Result<u64, io::Error> {
+	    self.file.size()
+	}
+
+	//
+	// Return the timestamp of the newest record in the datafile, 0 if it's empty.  This scans the file the
+	// first time, so it's meant for archived datafiles, which don't change.
+	//
+	fn newest_timestamp(&self) -> Result<u64, io::Error> {
+	    let cached = self.newest.load(Ordering::SeqCst);
+	    if cached != 0 {
+		return Ok(cached);
+	    }
+	    let mut newest: u64 = 0;
+	    self.scan(|_, rec| { newest = newest.max(rec.timestamp); Ok(true) })?;
+	    self.newest.store(newest, Ordering::SeqCst);
+	    Ok(newest)
+	}
+
+	//
+	// How much of the datafile is whole records, going by their sizes and checksums and nothing else, so it
+	// needs no key even if they're sealed.  Whatever follows is what a crash part way through an append
+	// leaves behind: part of a record, or one that doesn't match its checksum because not all of it made
+	// it to disk.
+	//
+	fn valid_length(&self) -> Result<u64, io::Error> {
+	    let size = self.size()?;
+	    let mut reader = BufReader::new(StorageReader::new(Arc::clone(&self.file), self.start));
+	    let mut offset = self.start;
+	    let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+	    while offset + BITCASK_RECORD_HEADER_SIZE as u64 <= size {
+		reader.read_exact(&mut hdr)?;
+		let Ok(mut rec) = BitcaskDatafileRecord::from_header(&hdr) else {
+		    break;
+		};
+		let disk_size = BitcaskDatafileRecord::disk_size(rec.key_size as usize, rec.value_size, self.alignment) as u64;
+		if offset + disk_size > size {
+		    break;
+		}
+		let mut body = vec![0u8; disk_size as usize - BITCASK_RECORD_HEADER_SIZE];
+		reader.read_exact(&mut body)?;
+		rec.set_body(body);
+		if !rec.crc_ok() {
+		    break;
+		}
+		offset += disk_size;
+	    }
+	    Ok(offset)
+	}
+
+	//
+	// Check every record of the datafile, adding what's wrong with them to 'report', and return what's at
+	// each offset that holds a record, None if it's a bad one, for checking hints against.  A record that
+	// fails its checksum is skipped, trusting its sizes to find the next, but one whose header can't be
+	// parsed, or that runs off the end, leaves nothing to go on, and is the last.
+	//
+	fn verify(&self, report: &mut VerifyReport) -> Result<VerifiedRecords, io::Error> {
+	    let size = self.size()?;
+	    let mut reader = BufReader::new(StorageReader::new(Arc::clone(&self.file), self.start));
+	    let mut records = HashMap::new();
+	    let mut offset = self.start;
+	    let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+	    while offset < size {
+		if offset + BITCASK_RECORD_HEADER_SIZE as u64 > size {
+		    report.issue(&self.name, offset as i64, VerifyIssueKind::Truncated, format!("{} bytes follow the last record", size - offset));
+		    break;
+		}
+		reader.read_exact(&mut hdr)?;
+		let mut rec = match BitcaskDatafileRecord::from_header(&hdr) {
+		    Ok(rec) => rec,
+		    Err(e) => {
+			report.issue(&self.name, offset as i64, VerifyIssueKind::Framing, format!("{}, the last {} bytes can't be read", e, size - offset));
+			break;
+		    },
+		};
+		let disk_size = BitcaskDatafileRecord::disk_size(rec.key_size as usize, rec.value_size, self.alignment) as u64;
+		if offset + disk_size > size {
+		    report.issue(&self.name, offset as i64, VerifyIssueKind::Truncated, format!("is {} bytes, only {} are left", disk_size, size - offset));
+		    break;
+		}
+		let mut body = vec![0u8; disk_size as usize - BITCASK_RECORD_HEADER_SIZE];
+		reader.read_exact(&mut body)?;
+		rec.set_body(body);
+		if !rec.crc_ok() {
+		    report.issue(&self.name, offset as i64, VerifyIssueKind::Checksum, "has a bad checksum".to_string());
+		    records.insert(offset as i64, None);
+		} else if let Err(e) = rec.unseal(self.cipher.as_deref()) {
+		    report.issue(&self.name, offset as i64, VerifyIssueKind::Encryption, format!("can't be decrypted: {}", e));
+		    records.insert(offset as i64, None);
+		} else {
+		    records.insert(offset as i64, Some((rec.key, rec.op, rec.value_size)));
+		    report.records += 1;
+		}
+		offset += disk_size;
+	    }
+	    Ok(records)
+	}
+
+	//
+	// This datafile only as far as it's whole records, for reading one that may be part way through an
+	// append without cutting anything off it.
+	//
+	fn whole_records(self: Box<Self>) -> Result<Box<BitcaskDatafile>, io::Error> {
+	    let valid = self.valid_length()?;
+	    if valid == self.size()? {
+		return Ok(self);
+	    }
+	    log::info!("{}: reading it up to the torn write at offset {}", self.name, valid);
+	    Self::from_file(self.name.clone(), self.id, Arc::new(StoragePrefix::new(Arc::clone(&self.file), valid)), self.cipher.clone())
+	}
+
+	//
+	// Fail with InvalidFormat unless the datafile starts with a header, or is empty.  from_file() takes one
+	// without for a datafile from before version 4, but we can't read those any more, so it's a file that
+	// isn't ours, at least not as a datafile.
+	//
+	fn require_header(&self) -> Result<bool, io::Error> {
+	    if self.start == 0 && self.size()? > 0 {
+		return Err(BitcaskError::InvalidFormat { file: self.name.clone(), reason: "doesn't start with a datafile header".to_string() }.into());
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Give a datafile made by create() the name it would have had from new(), carrying on from the handle
+	// the backend gives the new name, since the old one needn't work any more.
+	//
+	fn rename(&mut self, storage: &dyn Storage) -> Result<bool, io::Error> {
+	    let name = format!("{}.data", self.id);
+	    storage.rename(&self.name, &name)?;
+	    self.file = storage.open(&name)?.into();
+	    self.name = name;
+	    Ok(true)
+	}
+
+	//
+	// Nothing more will be appended to this datafile, so read it however the backend reads such files
+	// quickest, see Storage::map().  That's only ever quicker, so if it can't be done we carry on as we were.
+	//
+	fn seal(&mut self, storage: &dyn Storage) {
+	    match storage.map(&self.name, Arc::clone(&self.file)) {
+		Ok(file) => self.file = file,
+		Err(e) => log::warn!("{}: couldn't be mapped, reading it as before: {}", self.name, e),
+	    }
+	}
+
+	//
+	// Return how many bytes of the datafile are records, i.e. everything but the header.
+	//
+	pub fn record_bytes(&self) -> Result<u64, io::Error> {
+	    Ok(self.file.size()?.saturating_sub(self.start))
+	}
+
+	//
+	// Flush out any cached bytes for a datafile
+	//
+	pub fn sync(&self) -> Result<bool, io::Error>  {
+	    self.file.sync()
+	}
+    }
+
+    //
+    // A sequential reader of one datafile's records, see BitcaskDatafile::records().  It stops for good at
+    // the first error, since after a bad record there's no telling where the next one starts.
+    //
+    struct BitcaskDatafileRecords {
+	name: String,
+	alignment: usize,
+	cipher: Option<Arc<RecordCipher>>,
+	size: u64,				// How much of the file there was to read when we started
+	offset: i64,				// Where the next record starts
+	reader: BufReader<StorageReader>,
+	failed: bool,
+    }
+    impl BitcaskDatafileRecords {
+	fn read_next(&mut self) -> Result<Option<(i64, Box<BitcaskDatafileRecord>)>, io::Error> {
+	    if self.offset as u64 >= self.size {
+		return Ok(None);
+	    }
+	    let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+	    match self.reader.read_exact(&mut hdr) {
+		Ok(()) => {},
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	    }
+	    let offset = self.offset;
+	    let mut rec = BitcaskDatafileRecord::from_header(&hdr).map_err(|e| blame(&self.name, offset, e))?;
+	    let disk_size = BitcaskDatafileRecord::disk_size(rec.key_size as usize, rec.value_size, self.alignment);
+	    if offset as u64 + disk_size as u64 > self.size {	// Don't believe a torn size enough to allocate it
+		return Err(BitcaskError::Truncated { file: self.name.clone(), offset }.into());
+	    }
+	    let mut body = vec![0u8; disk_size - BITCASK_RECORD_HEADER_SIZE];
+	    self.reader.read_exact(&mut body)?;
+	    rec.set_body(body);
+	    if !rec.crc_ok() {
+		return Err(BitcaskError::Corruption { file: self.name.clone(), offset, reason: "has a bad checksum".to_string() }.into());
+	    }
+	    rec.unseal(self.cipher.as_deref()).map_err(|e| blame(&self.name, offset, e))?;
+	    self.offset += disk_size as i64;
+	    Ok(Some((offset, rec)))
+	}
+    }
+    impl Iterator for BitcaskDatafileRecords {
+	type Item = Result<(i64, Box<BitcaskDatafileRecord>), io::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+	    if self.failed {
+		return None;
+	    }
+	    let next = self.read_next();
+	    self.failed = next.is_err();
+	    next.transpose()
+	}
+    }
+
+    //
+    // TODO: Need a full-on utility class for hints files.
+    // This class is not persistent, a hint file is either read at boot time and then forgotten,
+    // or it is generated from a data file without regard to anything else going on in the system.
+    //
+    #[allow(dead_code)]				// Nothing looks at the name or ID yet, they're for debugging
+    struct BitcaskHintsfile {
+	name: String,				// The name of the hints file within the storage backend
+	id: BitcaskFileID,			// What "number" is it?
+	file: Box<dyn StorageFile>,		// The backend's handle, which serializes its own reads and writes
+    }
+
+    //
+    // Define the format and operations on one of the hint files used by Bitcask.
+    // This file is a very quick way to repopulate the in-memroy keymap structure.
+    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
+    //
+    // On disk the key's size and then the other fields are stored little-endian in the order declared below,
+    // BITCASK_HINTS_RECORD_HEADER_SIZE bytes in all, followed by the key itself.  There's no padding.  The
+    // records follow the file's header, BITCASK_HINTS_HEADER_SIZE bytes of it.
+    //
+    struct BitcaskHintsfileRecord {
+	key: Vec<u8>,				// The key of a KV we're storing
+	op: BitcaskDatafileRectype,		// Is this a PUT or a DELETE?
+	value_size: i32,			// The size of the value for that KV
+	offset: i64,				// the offset within the data file where that KV is stored
+	expires: u64,				// When that KV expires, as its record says
+	timestamp: u64,				// When that record was written, as it says too, 0 in a hint from before version 10
+    }
+    impl BitcaskHintsfileRecord {
+	pub fn new(key: Vec<u8>, op: BitcaskDatafileRectype, value_size: i32, offset: i64, expires: u64, timestamp: u64) -> Box<BitcaskHintsfileRecord> {
+	    Box::new(BitcaskHintsfileRecord{
+		key,				// Move the key into place
+		op,				// Copy the operation into place (PUT, DELETE, or a batch marker)
+		value_size,			// The actual number of valid bytes in the value
+		offset,				// the offset within the file of that record for that key
+		expires,
+		timestamp,
+	    })
+	}
+
+	//
+	// Produce the compact on-disk representation of the record.
+	//
+	fn to_bytes(&self) -> Vec<u8> {
+	    let mut buf = Vec::with_capacity(BITCASK_HINTS_RECORD_HEADER_SIZE + self.key.len());
+	    buf.extend_from_slice(&(self.key.len() as i32).to_le_bytes());
+	    buf.extend_from_slice(&(self.op as i32).to_le_bytes());
+	    buf.extend_from_slice(&self.value_size.to_le_bytes());
+	    buf.extend_from_slice(&self.offset.to_le_bytes());
+	    buf.extend_from_slice(&self.expires.to_le_bytes());
+	    buf.extend_from_slice(&self.timestamp.to_le_bytes());
+	    buf.extend_from_slice(&self.key);
+	    buf
+	}
+
+	//
+	// How long the hint record at the start of 'buf' is, with a fixed part of 'header_size' bytes, or None
+	// if it doesn't fit in 'buf'.
+	//
+	fn size_of(buf: &[u8], header_size: usize) -> Option<usize> {
+	    if buf.len() < header_size {
+		return None;
+	    }
+	    let key_size = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+	    let size = header_size.checked_add(usize::try_from(key_size).ok()?)?;
+	    (size <= buf.len()).then_some(size)
+	}
+
+	//
+	// Decode one on-disk hint record, exactly size_of() bytes of it, with a fixed part of 'header_size'
+	// bytes: BITCASK_HINTS_RECORD_HEADER_SIZE, or if it's from before version 10, without the timestamp.
+	//
+	fn from_bytes(buf: &[u8], header_size: usize) -> Result<Box<BitcaskHintsfileRecord>, io::Error> {
+	    let expires = u64::from_le_bytes(buf[20..28].try_into().unwrap());
+	    let mut hint = Self::from_fields(buf, buf[header_size..].to_vec(), expires)?;
+	    if header_size == BITCASK_HINTS_RECORD_HEADER_SIZE {
+		hint.timestamp = u64::from_le_bytes(buf[28..36].try_into().unwrap());
+	    }
+	    Ok(hint)
+	}
+
+	//
+	// Decode one hint record as the versions with integer keys wrote them, always BITCASK_LEGACY_HINTS_RECORD_SIZE
+	// bytes, taking the key to be the bytes of the integer.  Nothing expired back then.
+	//
+	fn from_legacy_bytes(buf: &[u8]) -> Result<Box<BitcaskHintsfileRecord>, io::Error> {
+	    Self::from_fields(buf, buf[0..4].to_vec(), 0)
+	}
+
+	fn from_fields(buf: &[u8], key: Vec<u8>, expires: u64) -> Result<Box<BitcaskHintsfileRecord>, io::Error> {
+	    let field = |i: usize| i32::from_le_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]]);
+	    let mut offset = [0u8; 8];
+	    offset.copy_from_slice(&buf[12..20]);
+	    Ok(Self::new(key, BitcaskDatafileRectype::from_disk(field(4))?, field(8), i64::from_le_bytes(offset), expires, 0))
+	}
+
+	//
+	// Does the datafile record this hint describes actually fit within the records of a datafile of the given length?
+	//
+	fn is_plausible(&self, datafile: &BitcaskDatafile, datafile_size: u64) -> bool {
+	    if self.offset < datafile.start as i64 || self.value_size < 0 {
+		return false;
+	    }
+	    match (self.offset as u64).checked_add(BitcaskDatafileRecord::disk_size(self.key.len(), self.value_size, datafile.alignment) as u64) {
+		Some(end) => end <= datafile_size,
+		None => false,
+	    }
+	}
+    }
+
+    impl BitcaskHintsfile {
+	//
+	// Create a new hints file for the datafile with the given ID, with 'suffix' on the end of the file's
+	// name until the caller renames it.  The hints are bound to the name it'll have then, see write().
+	//
+	fn create(storage: &dyn Storage, id: BitcaskFileID, suffix: &str) -> Result<Box<BitcaskHintsfile>, io::Error> {
+	    let filename = format!("{}.hints", id);
+	    Ok(Box::new(BitcaskHintsfile {
+		file: storage.create(&format!("{}{}", filename, suffix))?,
+		name: filename,
+		id,
+	    }))
+	}
+
+	//
+	// Write out the given hints, in the order they'll be applied, and make them durable.  They go out in a
+	// single append, so a crash leaves at most one torn record at the end, which import counts as bad.
+	// The hints of an encrypted datafile have its keys in them, so with a cipher they're sealed, as a whole,
+	// and bound to the file's name; then a torn file fails authentication and is ignored altogether.
+	//
+	pub fn write(&self, hints: &[Box<BitcaskHintsfileRecord>], cipher: Option<&RecordCipher>) -> Result<bool, io::Error> {
+	    let mut buf: Vec<u8> = BITCASK_HINTS_MAGIC.iter().copied().chain(BITCASK_FORMAT_VERSION.to_le_bytes()).collect();
+	    buf.extend(hints.iter().flat_map(|hint| hint.to_bytes()));
+	    if let Some(cipher) = cipher {
+		buf = cipher.seal(&buf, self.name.as_bytes())?;
+	    }
+	    self.file.append(&buf)?;
+	    self.file.sync()
+	}
+
+	// Generate a hint file by sumarizing all the operations in the data file by recording the *surviving* PUT and DELETE operations.
+	// Read through the datafile, recording each op (and its  key and the byte offset of the record) into an in-memory HashMap.
+	// If this is a DELETE, remove any existing PUTs for from the hint summary that key and record the DELETE in the hint summary.
+	// If this is a PUT, remove any existing DELETEs or PUTs for that key from the hint summary, record the new PUT key and byte offset.
+	// The hints file is 'datafile.name' with ".data" changesd to ".hints".
+	// The hints are written in the order of the records they describe, which is the order import applies them.
+	// Only committed batches are summarized, and the last batch marker gets a hint of its own, which import
+	// ignores but which tells it the hints reach the end of a datafile that finishes with one.
+	pub fn hintsfile_generate(storage: &dyn Storage, datafile: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    let mut surviving: HashMap<Vec<u8>, Box<BitcaskHintsfileRecord>> = HashMap::new();
+	    let mut marker: Option<Box<BitcaskHintsfileRecord>> = None;
+	    datafile.scan_committed(|offset, rec| {
+		let hint = BitcaskHintsfileRecord::new(rec.key.clone(), rec.op, rec.value_size, offset, rec.expires, rec.timestamp);
+		match rec.op {
+		    BitcaskDatafileRectype::BEGIN | BitcaskDatafileRectype::COMMIT => marker = Some(hint),
+		    _ => { surviving.insert(rec.key.clone(), hint); },
+		}
+		Ok(true)
+	    })?;
+	    let mut hints: Vec<Box<BitcaskHintsfileRecord>> = surviving.into_values().chain(marker).collect();
+	    hints.sort_by_key(|hint| hint.offset);
+	    let hintsfile = Self::create(storage, datafile.id, BITCASK_HINTS_TMP_SUFFIX)?;
+	    hintsfile.write(&hints, datafile.cipher.as_deref())?;
+	    storage.rename(&format!("{}{}", hintsfile.name, BITCASK_HINTS_TMP_SUFFIX), &hintsfile.name)?;
+	    Ok(true)
+	}
+
+	// Read all the "*.hints" files into the in-memory keymap structure.
+	// The saved_hintQ must be in sorted order so that DELETE records that follow PUT record in
+	// time will make the key go away, if they were not processed in order keys would stick
+	// around after they were deleted.
+	//
+	// This reads one hints file, 'file', and the caller applies them; it can read several at once but must
+	// apply them in order, see PartialKeymap.  A hint is only believed if the record it
+	// describes starts at a non-negative offset and ends within 'datafile'; bad hints are skipped and added
+	// to 'rejected'.  If more than 1 in BITCASK_HINTS_INVALID_RATIO of them are bad we don't trust the file
+	// at all, and return None so the caller can scan the datafile instead.
+	//
+	// Nor do we trust hints that don't reach the end of the datafile.  The last record in a datafile is
+	// always the last word on its key, or the last batch marker, so complete hints always describe it; if
+	// they don't, the datafile has grown since they were generated, or they were cut short, and either way
+	// some records have no hint.
+	fn hintsfile_read(file: &dyn StorageFile, filename: &str, datafile: &BitcaskDatafile, rejected: &mut usize) -> Result<LoadedHints, io::Error> {
+	    let mut buf: Vec<u8> = vec![0u8; file.size()? as usize];
+	    file.read_exact_at(0, &mut buf)?;
+	    if let Some(cipher) = &datafile.cipher {
+		match cipher.open(&buf, filename.as_bytes()) {
+		    Ok(opened) => buf = opened,
+		    Err(_) => {					// Torn, from before we had a key, or under another one
+			log::warn!("{}: can't be decrypted, scanning {} instead", filename, datafile.name);
+			return Ok(None);
+		    },
+		}
+	    }
+	    let datafile_size = datafile.size()?;
+	    let (mut pos, header_size) = match buf.strip_prefix(BITCASK_HINTS_MAGIC) {
+		Some(rest) if rest.len() >= 4 => {
+		    let version = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+		    if version > BITCASK_FORMAT_VERSION {
+			log::warn!("{}: is version {}, newer than we can read, scanning {} instead", filename, version, datafile.name);
+			return Ok(None);
+		    }
+		    (BITCASK_HINTS_HEADER_SIZE, BITCASK_HINTS_RECORD_HEADER_SIZE)
+		},
+		_ => (0, BITCASK_UNVERSIONED_HINTS_RECORD_HEADER_SIZE),	// From before hints files had a header
+	    };
+	    let mut hints: Vec<Box<BitcaskHintsfileRecord>> = Vec::new();
+	    let mut invalid: usize = 0;
+	    while pos < buf.len() {
+		let Some(size) = BitcaskHintsfileRecord::size_of(&buf[pos..], header_size) else {
+		    invalid += 1;				// A torn record at the end counts as one bad hint
+		    break;
+		};
+		match BitcaskHintsfileRecord::from_bytes(&buf[pos..pos + size], header_size) {
+		    Ok(hint) if hint.is_plausible(datafile, datafile_size) => hints.push(hint),
+		    _ => invalid += 1,
+		}
+		pos += size;
+	    }
+	    *rejected += invalid;
+	    if invalid * BITCASK_HINTS_INVALID_RATIO > hints.len() + invalid {
+		return Ok(None);
+	    }
+	    let covered = hints.iter()
+		.map(|hint| hint.offset as u64 + BitcaskDatafileRecord::disk_size(hint.key.len(), hint.value_size, datafile.alignment) as u64)
+		.max()
+		.unwrap_or(datafile.start);
+	    if covered != datafile_size {
+		log::warn!("{}: doesn't cover all of {}, scanning it instead", filename, datafile.name);
+		return Ok(None);
+	    }
+	    Ok(Some(hints))
+	}
+
+	//
+	// Check the hints file 'filename' against 'records', what verify() found in 'datafile', adding what's
+	// wrong to 'report': every hint must describe the record at its offset, and together they must reach
+	// the end of the datafile, as import requires.  A hint of a bad record isn't blamed for it, the record
+	// has been already.
+	//
+	fn hintsfile_verify(storage: &dyn Storage, filename: &str, datafile: &BitcaskDatafile,
+			    records: &VerifiedRecords, report: &mut VerifyReport) -> Result<bool, io::Error> {
+	    let file = storage.open(filename)?;
+	    let mut buf: Vec<u8> = vec![0u8; file.size()? as usize];
+	    file.read_exact_at(0, &mut buf)?;
+	    if let Some(cipher) = &datafile.cipher {
+		match cipher.open(&buf, filename.as_bytes()) {
+		    Ok(opened) => buf = opened,
+		    Err(e) => {
+			report.issue(filename, 0, VerifyIssueKind::Encryption, format!("can't be decrypted: {}", e));
+			return Ok(false);
+		    },
+		}
+	    }
+	    let (mut pos, header_size) = match buf.strip_prefix(BITCASK_HINTS_MAGIC) {
+		Some(rest) if rest.len() >= 4 => {
+		    let version = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+		    if version > BITCASK_FORMAT_VERSION {
+			report.issue(filename, 4, VerifyIssueKind::Hint, format!("is version {}, newer than we can read", version));
+			return Ok(false);
+		    }
+		    (BITCASK_HINTS_HEADER_SIZE, BITCASK_HINTS_RECORD_HEADER_SIZE)
+		},
+		_ => (0, BITCASK_UNVERSIONED_HINTS_RECORD_HEADER_SIZE),
+	    };
+	    let datafile_size = datafile.size()?;
+	    let mut covered = datafile.start;
+	    while pos < buf.len() {
+		let Some(size) = BitcaskHintsfileRecord::size_of(&buf[pos..], header_size) else {
+		    report.issue(filename, pos as i64, VerifyIssueKind::Truncated, format!("{} bytes follow the last hint", buf.len() - pos));
+		    break;
+		};
+		match BitcaskHintsfileRecord::from_bytes(&buf[pos..pos + size], header_size) {
+		    Ok(hint) => {
+			match records.get(&hint.offset) {
+			    Some(Some((key, op, value_size))) if *key == hint.key && *op == hint.op && *value_size == hint.value_size => {},
+			    Some(None) => {},
+			    Some(Some(_)) => report.issue(filename, pos as i64, VerifyIssueKind::Hint, format!("doesn't match the record at offset {} of {}", hint.offset, datafile.name)),
+			    None => report.issue(filename, pos as i64, VerifyIssueKind::Hint, format!("points at offset {} of {}, where no record starts", hint.offset, datafile.name)),
+			}
+			if hint.is_plausible(datafile, datafile_size) {
+			    covered = covered.max(hint.offset as u64 + BitcaskDatafileRecord::disk_size(hint.key.len(), hint.value_size, datafile.alignment) as u64);
+			}
+		    },
+		    Err(e) => report.issue(filename, pos as i64, VerifyIssueKind::Hint, format!("can't be decoded: {}", e)),
+		}
+		pos += size;
+	    }
+	    if covered < datafile_size {
+		report.issue(filename, buf.len() as i64, VerifyIssueKind::Hint, format!("only covers {} up to offset {}", datafile.name, covered));
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Older versions named hints files "<id+1>.data", so recovery may find one of those posing as a datafile.
+	// A real datafile starts with a record whose CRC checks out; a hints file is a whole number of hint
+	// records that all look sane.  Only something that fails the first test and passes the second counts.
+	//
+	pub fn is_misnamed_hintsfile(datafile: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    let size = datafile.size()? as usize;
+	    if datafile.start > 0 || size == 0 || !size.is_multiple_of(BITCASK_LEGACY_HINTS_RECORD_SIZE) {
+		return Ok(false);				// Hints files never had a datafile header
+	    }
+	    if size >= BITCASK_RECORD_HEADER_SIZE {
+		let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+		datafile.file.read_exact_at(0, &mut hdr)?;
+		if let Ok(first) = BitcaskDatafileRecord::from_header(&hdr) {
+		    if datafile.get(0, first.key_size as usize, first.value_size).is_ok_and(|rec| rec.crc_ok()) {
+			return Ok(false);			// A genuine datafile record
+		    }
+		}
+	    }
+	    let mut buf: Vec<u8> = vec![0u8; size];
+	    datafile.file.read_exact_at(0, &mut buf)?;
+	    Ok(buf.chunks_exact(BITCASK_LEGACY_HINTS_RECORD_SIZE).all(|chunk| {
+		BitcaskHintsfileRecord::from_legacy_bytes(chunk).is_ok_and(|hint| {
+		    hint.offset >= 0 && (hint.offset as usize).is_multiple_of(BITCASK_RECORD_ALIGNMENT)
+			&& hint.value_size >= 0 && hint.value_size as usize <= BITCASK_LEGACY_MAX_VALUE_SIZE
+		})
+	    }))
+	}
+
+	//
+	// Move a file out of the way by copying it to "<name>.quarantine", where recovery won't look at it
+	// but a human can, and then removing the original.
+	//
+	pub fn quarantine(storage: &dyn Storage, name: &str) -> Result<String, io::Error> {
+	    let newname = format!("{}.quarantine", name);
+	    storage.rename(name, &newname)?;
+	    Ok(newname)
+	}
+
+	//
+	// This is a work routine and is lower priority than some other work, so queuing it up for later.
+	// There's very likely a much simpler way to identify this fill-in-the-missing-file task,
+	// but I don't have time to google for good library solutions right now.
+	// This would need to run during single-threaded mode either startup or shutdown processing.
+	// If I separated out the "read them in" part, then this could happen in a separate thread while
+	// normal processing was going on, but we'd need to collect the lists of files in one thread
+	// and then fork off separate thread(s?) to "generate" each hints file, and retain the list of existing
+	// hints files in the master thread so we could read them in and populate the in-memory keymap.
+	//
+	// This is the core data-resiliency routine.  It recovers from crashes and outages by
+	// depending upon the log-structure of the data files.  It generates any missing "*.hints"
+	// files so that the next crash/reboot will start faster.  Any existing, partially complete,
+	// data file becomes a read-only part of the dataset until merge time.
+	// As it stands this only covers the archived datafiles, which don't change; the current one is the
+	// caller's business.  Returns how many hints files were generated.
+	//
+	pub fn hintsfile_find_missing_files(cask: &Bitcask, storage: &dyn Storage) -> Result<usize, std::io::Error> {
+	    //
+	    // Identify all existing ".*hints" files in the database directory
+	    //
+	    let hint_q: Vec<String> = storage.list()?.into_iter().filter(|filename| filename.ends_with(".hints")).collect();
+	    let datafiles = cask.datafiles.read().unwrap();	// Hold off merges, they remove datafiles
+	    let mut generated: usize = 0;
+	    for df in datafiles.values() {
+		if !hint_q.contains(&format!("{}.hints", df.id)) {
+		    Self::hintsfile_generate(storage, df)?;
+		    generated += 1;
+		}
+	    }
+	    Ok(generated)
+	}
+    }
+
+    //
+    // Walks the datafiles in ID order for Bitcask::entries(), handing over each PUT the keymap still points
+    // at.  Going by ID order, rather than a list taken at the start, means the output of a merge that runs
+    // meanwhile is walked too, so no live KV slips through by being moved out of a datafile not yet reached.
+    //
+    struct BitcaskLiveRecords<'a> {
+	cask: &'a Bitcask,
+	fileid: BitcaskFileID,			// The datafile being walked, or the last one finished
+	offset: i64,				// Where in it the record last returned is
+	records: Option<BitcaskDatafileRecords>,	// None between datafiles
+	now: u64,				// What counts as expired is fixed when we start
+    }
+    impl Iterator for BitcaskLiveRecords<'_> {
+	type Item = Result<Box<BitcaskDatafileRecord>, io::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+	    loop {
+		let Some(records) = &mut self.records else {
+		    self.fileid = self.cask.datafile_after(self.fileid)?;
+		    match self.cask.with_datafile(self.fileid, |df| df.records()) {
+			Ok(records) => self.records = records,		// None if a merge removed it just now, move on
+			Err(e) => return Some(Err(e)),
+		    }
+		    continue;
+		};
+		let (offset, rec) = match records.next() {
+		    Some(Ok(item)) => item,
+		    Some(Err(e)) => return Some(Err(e)),
+		    None => {
+			self.records = None;
+			continue;
+		    },
+		};
+		if rec.op != BitcaskDatafileRectype::PUT {
+		    continue;
+		}
+		let live = self.cask.keymap.read(&rec.key).get(&rec.key)
+		    .is_some_and(|entry| entry.fileid == self.fileid && entry.offset == offset && !entry.is_expired(self.now));
+		if live {
+		    self.offset = offset;
+		    return Some(Ok(rec));
+		}
+	    }
+	}
+    }
+
+    //
+    // What one datafile has to say about the keys in it, from its hints or by scanning it, for recovery to
+    // apply in datafile order: the entry of every key it has the last word on, None for one it deletes.
+    // Datafiles can be read into these all at once, it's only applying them that has to wait its turn.
+    //
+    struct PartialKeymap {
+	id: BitcaskFileID,
+	entries: HashMap<Vec<u8>, Option<BitcaskKeymapEntry>>,
+	scanned: bool,				// Its hints were missing, or couldn't be trusted
+	max_seq: u64,				// The newest record scanned, 0 if none was
+	rejected: usize,			// Bad hints skipped, see hintsfile_read()
+    }
+    impl PartialKeymap {
+	//
+	// Read 'df' from its hints, if it's given its hints file and they can be trusted, or else by scanning it.
+	//
+	fn load(df: &BitcaskDatafile, hints: Option<(Box<dyn StorageFile>, String)>) -> Result<PartialKeymap, io::Error> {
+	    let mut partial = PartialKeymap { id: df.id, entries: HashMap::new(), scanned: false, max_seq: 0, rejected: 0 };
+	    if let Some((file, hintsname)) = hints {
+		if let Some(hints) = BitcaskHintsfile::hintsfile_read(&*file, &hintsname, df, &mut partial.rejected)? {
+		    for hint in hints {
+			match hint.op {
+			    BitcaskDatafileRectype::PUT => {
+				let entry = BitcaskKeymapEntry::new(hint.value_size, df.id, hint.offset, 0, hint.expires, hint.timestamp);	// Hints don't have the seq
+				partial.entries.insert(hint.key, Some(*entry));
+			    },
+			    BitcaskDatafileRectype::DELETE => { partial.entries.insert(hint.key, None); },
+			    BitcaskDatafileRectype::BEGIN | BitcaskDatafileRectype::COMMIT => {},	// Only there for the check in hintsfile_read()
+			}
+		    }
+		    return Ok(partial);
+		}
+	    }
+	    partial.scanned = true;					// No (trustworthy) hints, do it the slow way
+	    df.scan_committed(|offset, rec| {
+		partial.max_seq = partial.max_seq.max(rec.seq);
+		match rec.op {
+		    BitcaskDatafileRectype::PUT => {
+			let entry = BitcaskKeymapEntry::new(rec.value_size, df.id, offset, rec.seq, rec.expires, rec.timestamp);
+			partial.entries.insert(rec.key.clone(), Some(*entry));
+		    },
+		    BitcaskDatafileRectype::DELETE => { partial.entries.insert(rec.key.clone(), None); },
+		    BitcaskDatafileRectype::BEGIN | BitcaskDatafileRectype::COMMIT => {},
+		}
+		Ok(true)
+	    })?;
+	    Ok(partial)
+	}
+
+	fn apply(self, keymap: &mut BitcaskKeymap) -> Result<(), io::Error> {
+	    for (key, entry) in self.entries {
+		match entry {
+		    Some(entry) => { keymap.insert(key, entry)?; },
+		    None => { keymap.remove(&key); },
+		}
+	    }
+	    Ok(())
+	}
+    }
+
+    //
+    // A keymap saved by Bitcask::save_keydir_snapshot(), as it was once the datafile 'fileid' was 'offset'
+    // bytes long; every datafile before it is in it whole, and none after it at all.
+    //
+    struct KeymapSnapshot {
+	entries: Vec<(Vec<u8>, BitcaskKeymapEntry)>,
+	fileid: BitcaskFileID,
+	offset: u64,
+    }
+    impl KeymapSnapshot {
+	//
+	// Decode a snapshot, checksum and all, returning the datafiles it was taken of, with their sizes, and
+	// the snapshot itself, or None if it's damaged or isn't one.
+	//
+	fn from_bytes(buf: &[u8]) -> Option<(Vec<(BitcaskFileID, u64)>, KeymapSnapshot)> {
+	    fn field<const N: usize>(buf: &[u8], pos: &mut usize) -> Option<[u8; N]> {
+		let bytes = buf.get(*pos..*pos + N)?.try_into().ok()?;
+		*pos += N;
+		Some(bytes)
+	    }
+	    let (body, crc) = buf.split_at_checked(buf.len().checked_sub(4)?)?;
+	    if !body.starts_with(BITCASK_SNAPSHOT_MAGIC) || crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().ok()?) {
+		return None;
+	    }
+	    let mut pos = BITCASK_SNAPSHOT_MAGIC.len();
+	    if u32::from_le_bytes(field(body, &mut pos)?) > BITCASK_FORMAT_VERSION {
+		return None;
+	    }
+	    let mut files: Vec<(BitcaskFileID, u64)> = Vec::new();
+	    for _ in 0..u64::from_le_bytes(field(body, &mut pos)?) {
+		files.push((i32::from_le_bytes(field(body, &mut pos)?), u64::from_le_bytes(field(body, &mut pos)?)));
+	    }
+	    let &(fileid, offset) = files.last()?;
+	    let mut entries: Vec<(Vec<u8>, BitcaskKeymapEntry)> = Vec::new();
+	    for _ in 0..u64::from_le_bytes(field(body, &mut pos)?) {
+		let key_size = u32::from_le_bytes(field(body, &mut pos)?) as usize;
+		let key = body.get(pos..pos.checked_add(key_size)?)?.to_vec();
+		pos += key_size;
+		let entry = BitcaskKeymapEntry {
+		    fileid: i32::from_le_bytes(field(body, &mut pos)?),
+		    offset: i64::from_le_bytes(field(body, &mut pos)?),
+		    value_size: i32::from_le_bytes(field(body, &mut pos)?),
+		    generation: u64::from_le_bytes(field(body, &mut pos)?),
+		    expires: u64::from_le_bytes(field(body, &mut pos)?),
+		    timestamp: u64::from_le_bytes(field(body, &mut pos)?),
+		};
+		entries.push((key, entry));
+	    }
+	    (pos == body.len()).then_some((files, KeymapSnapshot { entries, fileid, offset }))
+	}
+    }
+
+    //
+    // What shutdown() knew when it was done: the newest seq, and each datafile's size and dead bytes.  Until
+    // the store is written to again that's all still true, which the sizes being the same shows, and the
+    // next open takes it from here rather than checking the newest datafile for a torn write, then reading
+    // it for its seqs, which hints don't have, and counting up the dead bytes of them all.
+    //
+    struct CleanShutdown {
+	seq: u64,
+	files: BTreeMap<BitcaskFileID, (u64, u64)>,	// The size and dead bytes of each
+    }
+    impl CleanShutdown {
+	fn to_bytes(&self) -> Vec<u8> {
+	    let mut buf: Vec<u8> = BITCASK_CLEAN_MAGIC.iter().copied().chain(BITCASK_FORMAT_VERSION.to_le_bytes()).collect();
+	    buf.extend_from_slice(&self.seq.to_le_bytes());
+	    buf.extend_from_slice(&(self.files.len() as u64).to_le_bytes());
+	    for (id, (size, dead)) in &self.files {
+		buf.extend_from_slice(&id.to_le_bytes());
+		buf.extend_from_slice(&size.to_le_bytes());
+		buf.extend_from_slice(&dead.to_le_bytes());
+	    }
+	    let crc = crc32fast::hash(&buf);
+	    buf.extend_from_slice(&crc.to_le_bytes());
+	    buf
+	}
+
+	//
+	// As to_bytes() wrote it, or None if it's damaged, or isn't one.
+	//
+	fn from_bytes(buf: &[u8]) -> Option<CleanShutdown> {
+	    fn field<const N: usize>(buf: &[u8], pos: &mut usize) -> Option<[u8; N]> {
+		let bytes = buf.get(*pos..*pos + N)?.try_into().ok()?;
+		*pos += N;
+		Some(bytes)
+	    }
+	    let (body, crc) = buf.split_at_checked(buf.len().checked_sub(4)?)?;
+	    if !body.starts_with(BITCASK_CLEAN_MAGIC) || crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().ok()?) {
+		return None;
+	    }
+	    let mut pos = BITCASK_CLEAN_MAGIC.len();
+	    if u32::from_le_bytes(field(body, &mut pos)?) > BITCASK_FORMAT_VERSION {
+		return None;
+	    }
+	    let seq = u64::from_le_bytes(field(body, &mut pos)?);
+	    let mut files: BTreeMap<BitcaskFileID, (u64, u64)> = BTreeMap::new();
+	    for _ in 0..u64::from_le_bytes(field(body, &mut pos)?) {
+		let id = i32::from_le_bytes(field(body, &mut pos)?);
+		files.insert(id, (u64::from_le_bytes(field(body, &mut pos)?), u64::from_le_bytes(field(body, &mut pos)?)));
+	    }
+	    (pos == body.len()).then_some(CleanShutdown { seq, files })
+	}
+
+	//
+	// Is datafile 'id' the size it was?
+	//
+	fn unchanged(&self, id: BitcaskFileID, size: u64) -> bool {
+	    self.files.get(&id).is_some_and(|&(was, _)| was == size)
+	}
+    }
+
+    //
+    // The core logic that ties the Bitcask components together.
+    //
+    pub struct Bitcask {
+	keymap: BitcaskKeymapShards,					// Protects the in-memory map of all extant KV pairs, shard by shard
+	numbering: Mutex<()>,						// Held from taking a sequence number until its record is appended
+	current: RwLock<BitcaskDatafile>,				// Protects changes to the 'current' field (not the datafile itself)
+	datafiles: Arc<RwLock<HashMap<BitcaskFileID, BitcaskDatafile>>>,	// Protects all the map of the archived data files
+	merge_lock: Mutex<()>,						// Only one merge may run at a time
+	rotate_lock: Mutex<()>,						// Only one rotation may run at a time
+	dead_bytes: Mutex<HashMap<BitcaskFileID, u64>>,			// Reclaimable bytes per datafile, taken after the keymap lock
+	alignments: RwLock<HashMap<BitcaskFileID, usize>>,		// The record alignment of every datafile, taken last of all
+	alignment: usize,						// The record alignment for datafiles we create
+	compression: Compression,					// How new records store their values
+	cipher: Option<Arc<RecordCipher>>,				// If set, what every datafile and hints file we write is sealed with
+	syncer: Option<Arc<SyncCoordinator>>,				// If set, schedules our fsyncs along with other stores'
+	max_records_per_file: Option<usize>,				// If set, rotate the current datafile once it has this many records
+	tombstone_retention: Option<Duration>,				// If set, merges keep DELETE records younger than this
+	snapshot_interval: Option<Duration>,				// If set, rotations save the keymap if it was last saved this long ago
+	snapshotted: AtomicU64,						// When it was, in ms since 'opened', 0 for never
+	max_datafile_size: Option<u64>,					// If set, rotate the current datafile once it's this big
+	rotation_interval: Option<Duration>,				// If set, rotate the current datafile once it's been current this long
+	current_since: AtomicU64,					// When it became current, in ms since 'opened'
+	max_value_size: usize,						// Puts of bigger values are refused
+	disk_quota: Option<u64>,					// If set, puts that would take the datafiles past this are refused
+	blob_threshold: Option<usize>,					// If set, put() writes values this big to blob files
+	blobs: BlobStore,						// The blob files, taken last of all
+	sync_policy: SyncPolicy,					// Whether writes wait for their fsync
+	merge_policy: MergePolicy,					// When needs_merge() is true
+	flusher: Option<BitcaskFlusher>,				// Set for SyncPolicy::Every, does its flushing
+	corruption_policy: CorruptionPolicy,				// What get() does with a record it can't believe
+	read_only: bool,						// If set, every write is refused and the current datafile is a scratch one
+	pool_read_buffers: bool,					// If set, get() reads through a per-thread buffer pool
+	cache: Option<Mutex<ValueCache>>,				// If set, recently read values, taken after the keymap lock
+	bloom: Option<RwLock<BloomFilter>>,				// If set, every key there is and some there aren't, taken after the keymap lock
+	archival: Option<(Duration, Archiver)>,				// If set, datafiles this old are handed over and removed
+	retention: Option<(RetentionPolicy, Option<Box<dyn Storage>>)>,	// If set, which datafiles are retired, and where they're archived
+	sequence: AtomicU64,						// The last sequence number handed out, bumped under the numbering lock
+	horizon: AtomicU64,						// Merges have compacted away the history up to here
+	subscribers: Mutex<Vec<(ChangeFilter, Sender<ChangeEvent>)>>,	// Everyone who wants to hear about changes, and which
+	changes: Mutex<VecDeque<ChangeEvent>>,				// Ring buffer of events waiting for drain_changes()
+	changes_dropped: AtomicU64,					// Events pushed out of the ring before anyone drained them
+	key_locks: Vec<Mutex<()>>,					// Striped by key hash, serializes writers of the same key
+	storage: Arc<dyn Storage>,					// Where all the files live
+	storage_lock: Mutex<Option<StorageLock>>,			// Keeps other processes out until shutdown, None if read-only
+	hints_rejected: usize,						// How many bad hint records we skipped at startup
+	shut_down: AtomicBool,						// Set once shutdown() has finished
+	opened: Instant,						// When we were opened, what last_op counts from
+	ops: AtomicU64,							// Foreground reads and writes so far
+	last_op: AtomicU64,						// When the latest of them started, in ms since 'opened'
+	idle_merges: AtomicU64,						// Idle merges started
+	idle_merges_yielded: AtomicU64,					// Idle merges abandoned because traffic resumed
+	yielded: Mutex<Option<(BitcaskFileID, u64)>>,			// The empty current datafile the last of them left, and the sequence then
+	maintenance: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,	// The idle or background merge thread, dropping the Sender stops it
+	pool: ThreadPool,						// Where background merges and hints generation run
+	id: Uuid,							// Who we are, wherever our files happen to live
+	#[cfg(feature = "metrics")]
+	metrics: Metrics,						// What we've done, for metrics()
+    }
+    impl Bitcask {
+	//
+	// Open the Bitcask in the database directory, creating it if there's none, with each existing datafile a
+	// read-only part of the dataset.  The keymap is taken from the keydir snapshot as far as it goes, and
+	// from there on from each datafile's hints, several datafiles at once, scanning those whose hints are
+	// missing or can't be trusted and giving them hints for next time.  After a clean shutdown() its note
+	// saves the rest of the scanning too: the newest datafile needn't be checked for a torn tail, nor read
+	// for the last seq, and the dead bytes needn't be counted.  Then a fresh datafile takes new writes.
+	//
+	pub fn new(dirpath: &str) -> Result<Box<Bitcask>, BitcaskError> {
+	    let storage = FileStorage::new(dirpath);
+	    storage.check_filesystem(&StatfsProbe, false)?;	// Only warn, see BitcaskOptions::strict_filesystem() to refuse
+	    Self::with_storage(Box::new(storage))
+	}
+
+	//
+	// As above, but with the files kept in the given storage backend rather than a directory.
+	//
+	pub fn with_storage(storage: Box<dyn Storage>) -> Result<Box<Bitcask>, BitcaskError> {
+	    Ok(Self::open(storage, None, false, true, BITCASK_KEYMAP_SHARDS, None)?)
+	}
+
+	//
+	// Open the store in 'dirpath' to read, but never write: every put, delete and merge fails with ReadOnly,
+	// nothing in the directory is created or changed, and it doesn't matter if a writer has it open.  What's
+	// read is the store as it was when this was called, a write part way through is read as not there yet.
+	//
+	pub fn open_read_only(dirpath: &str) -> Result<Box<Bitcask>, BitcaskError> {
+	    Self::open_with(BitcaskOptions::new(dirpath).read_only(true))
+	}
+
+	//
+	// Open the store in the options' directory, configured by them, see BitcaskOptions.  A read-only store is
+	// read as it stands, hints and all, and has nothing written to it: it has a current datafile like any
+	// other, but it's a scratch one in memory that stays empty.
+	//
+	pub fn open_with(options: &BitcaskOptions) -> Result<Box<Bitcask>, BitcaskError> {
+	    Self::open_with_probe(options, &StatfsProbe)
+	}
+
+	//
+	// As open_with(), asking 'probe' what filesystem the directory is on.
+	//
+	pub(crate) fn open_with_probe(options: &BitcaskOptions, probe: &dyn FilesystemProbe) -> Result<Box<Bitcask>, BitcaskError> {
+	    if !Path::new(&options.dirpath).is_dir() {
+		if options.read_only || !options.create_if_missing {
+		    return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such directory", options.dirpath)).into());
+		}
+		std::fs::create_dir_all(&options.dirpath)?;
+	    }
+	    let storage = FileStorage::new(&options.dirpath);
+	    storage.check_filesystem(probe, options.strict_filesystem && !options.read_only)?;	// A reader can't hurt anything
+	    let index = options.disk_index.then(|| Path::new(&options.dirpath));
+	    let mut cask = Self::open(Box::new(storage), None, options.read_only, options.create_if_missing, options.keymap_shards, index)?;
+	    cask.max_datafile_size = options.max_datafile_size;
+	    cask.rotation_interval = options.rotation_interval;
+	    cask.max_value_size = options.max_value_size;
+	    cask.disk_quota = options.disk_quota;
+	    cask.blob_threshold = options.blob_threshold;
+	    cask.set_sync_policy(options.sync);
+	    cask.compression = options.compression;
+	    cask.corruption_policy = options.corruption_policy;
+	    cask.set_cache_size(options.cache_size);
+	    cask.set_bloom_filter(options.bloom_filter);
+	    cask.pool = ThreadPool::new(BITCASK_POOL_THREADS, BITCASK_POOL_QUEUE_SIZE, options.saturation_policy);
+	    Ok(cask)
+	}
+
+	//
+	// As new(), but encrypting everything written from now on under the given config, so that the keys and
+	// values are unreadable to anyone with the files but not the key, and any tampering with them is caught
+	// when they're read.  Records written before encryption was turned on are still read, and a merge seals
+	// them too.  A store with encrypted records can't be opened without the key, or with the wrong one.
+	//
+	pub fn new_encrypted(dirpath: &str, config: EncryptionConfig) -> Result<Box<Bitcask>, BitcaskError> {
+	    let storage = FileStorage::new(dirpath);
+	    storage.check_filesystem(&StatfsProbe, false)?;
+	    Self::with_storage_encrypted(Box::new(storage), config)
+	}
+
+	pub fn with_storage_encrypted(storage: Box<dyn Storage>, config: EncryptionConfig) -> Result<Box<Bitcask>, BitcaskError> {
+	    Ok(Self::open(storage, Some(Arc::new(config.cipher())), false, true, BITCASK_KEYMAP_SHARDS, None)?)
+	}
+
+	//
+	// The keymap is split into 'shards', and kept in files in 'index' if it's given.
+	//
+	fn open(storage: Box<dyn Storage>, cipher: Option<Arc<RecordCipher>>, read_only: bool, create: bool, shards: usize, index: Option<&Path>) -> Result<Box<Bitcask>, io::Error> {
+	    let mut span = OpSpan::start("recover");
+	    let mut keymap = match index {
+		Some(dir) => BitcaskKeymap::Disk(DiskMap::new(dir)?),
+		None => BitcaskKeymap::Hashed(HashMap::new()),
+	    };
+	    let mut datafiles: HashMap<BitcaskFileID, BitcaskDatafile> = HashMap::new();
+	    let mut max_id: BitcaskFileID = 0;
+	    let mut hints_rejected: usize = 0;
+	    let mut dead_bytes: HashMap<BitcaskFileID, u64> = HashMap::new();
+	    let mut max_seq: u64 = 0;
+	    let mut horizon: u64 = 0;
+	    let mut unhinted: Vec<BitcaskFileID> = Vec::new();
+	    let names = storage.list()?;
+	    let missing = !names.iter().any(|name| name.ends_with(".data") || name.ends_with(".hints") || name == BITCASK_ID_FILE);
+	    if missing && (read_only || !create) {
+		return Err(io::Error::new(io::ErrorKind::NotFound, "there's no store here"));
+	    }
+	    let storage_lock = match read_only {
+		true => None,						// Readers don't change anything, a writer may come and go
+		false => Some(storage.lock(BITCASK_LOCK_FILE).map_err(|e| match e.kind() {
+		    io::ErrorKind::WouldBlock => BitcaskError::Locked(e.to_string()).into(),
+		    _ => e,
+		})?),
+	    };
+	    let names = match read_only {
+		true => names,
+		false => storage.list()?,				// Maybe changed by whoever had the lock before us
+	    };
+	    let blobs = BlobStore::open(&*storage, &names)?;
+	    if !read_only {
+		for name in names.iter().filter(|name| name.ends_with(BITCASK_MERGE_TMP_SUFFIX)) {
+		    log::info!("{}: is left over from a merge that never finished, removing it", name);
+		    storage.remove(name)?;
+		}
+		for name in names.iter().filter(|name| name.ends_with(BITCASK_HINTS_TMP_SUFFIX)) {
+		    log::info!("{}: is a hints file that was never finished, removing it", name);
+		    storage.remove(name)?;
+		}
+	    }
+	    let names: Vec<String> = names.into_iter()
+		.filter(|name| !name.ends_with(BITCASK_MERGE_TMP_SUFFIX) && !name.ends_with(BITCASK_HINTS_TMP_SUFFIX))
+		.collect();
+	    let fresh = !names.iter().any(|name| name.ends_with(".data") || name.ends_with(".hints"));
+	    let id = Self::load_id(&*storage, &names, read_only)?;
+	    let mut clean = match names.iter().any(|name| name == BITCASK_CLEAN_FILE) {
+		true => Self::load_clean_shutdown(&*storage, read_only)?,
+		false => None,
+	    };
+	    if !fresh {							// A brand new store has nothing to recover, don't even look
+		let ids = Self::datafile_ids(&names);
+		let newest = ids.last().copied();
+		let mut opened: Vec<(Box<BitcaskDatafile>, bool)> = Vec::new();	// And whether to try its hints
+		for id in ids {
+		    let mut df = BitcaskDatafile::open(&*storage, id, cipher.clone())?;
+		    max_id = id;					// Never reuse the ID, even of a file we set aside
+		    if BitcaskHintsfile::is_misnamed_hintsfile(&df)? {
+			if read_only {
+			    log::warn!("{}: is a misnamed hints file, skipping it", df.name);
+			    continue;
+			}
+			let newname = BitcaskHintsfile::quarantine(&*storage, &df.name)?;
+			log::warn!("{}: is a misnamed hints file, moved it to {}", df.name, newname);
+			continue;					// The datafiles themselves will be scanned instead
+		    }
+		    df.require_header()?;
+		    if read_only && Some(id) == newest {
+			df = df.whole_records()?;			// A writer may be appending to it as we speak
+		    } else {
+			df.seal(&*storage);
+		    }
+		    let hintsname = format!("{}.hints", id);
+		    let whole = clean.as_ref().is_some_and(|clean| df.size().is_ok_and(|size| clean.unchanged(id, size)));	// As shutdown() left it
+		    let torn = !read_only && Some(id) == newest && !whole && Self::recover_datafile(&*storage, &df, names.contains(&hintsname))?;
+		    let hinted = !torn && names.contains(&hintsname);
+		    opened.push((df, hinted));
+		}
+		let snapshot = match names.iter().any(|name| name == BITCASK_SNAPSHOT_FILE) {
+		    true => Self::load_snapshot(&*storage, cipher.as_deref(), &opened)?,
+		    false => None,
+		};
+		let from = match snapshot {
+		    Some(snapshot) => {
+			for (key, entry) in snapshot.entries {
+			    keymap.insert(key, entry)?;
+			}
+			Some((snapshot.fileid, snapshot.offset))
+		    },
+		    None => None,
+		};
+		unhinted = Self::load_keymap(&*storage, &opened, from, &mut keymap, &mut hints_rejected, &mut max_seq)?;
+		if let Some(shutdown) = &clean {
+		    let same = shutdown.files.len() == opened.len() && opened.iter().all(|(df, _)| df.size().is_ok_and(|size| shutdown.unchanged(df.id, size)));
+		    if !same {
+			log::info!("{}: doesn't describe the datafiles there are now, ignoring it", BITCASK_CLEAN_FILE);
+			clean = None;
+		    }
+		}
+		for (df, _) in opened {
+		    datafiles.insert(df.id, *df);
+		}
+		// Hints don't carry sequence numbers, but the newest records are all in the newest non-empty datafile
+		if let Some(shutdown) = &clean {
+		    max_seq = max_seq.max(shutdown.seq);
+		} else if let Some(newest) = datafiles.values().filter(|df| df.record_bytes().is_ok_and(|size| size > 0)).max_by_key(|df| df.id) {
+		    newest.scan(|_, rec| { max_seq = max_seq.max(rec.seq); Ok(true) })?;
+		}
+		horizon = Self::load_horizon(&*storage, &names)?;
+		if horizon != u64::MAX {
+		    max_seq = max_seq.max(horizon);
+		}
+		// Whatever part of a datafile the keymap doesn't point at is dead, from here on we keep count as we go
+		dead_bytes = match clean {
+		    Some(shutdown) => shutdown.files.into_iter().map(|(id, (_, dead))| (id, dead)).collect(),
+		    None => Self::count_dead_bytes(datafiles.values(), &keymap)?,
+		};
+	    }
+	    span.record("datafiles", datafiles.len() as u64);
+	    span.record("replayed", unhinted.len() as u64);
+	    let current = match read_only {
+		true => BitcaskDatafile::new(&MemoryStorage::new(), max_id, BITCASK_RECORD_ALIGNMENT, None)?,
+		false => BitcaskDatafile::new(&*storage, max_id, BITCASK_RECORD_ALIGNMENT, cipher.clone())?,
+	    };
+	    let mut alignments: HashMap<BitcaskFileID, usize> = datafiles.values().map(|df| (df.id, df.alignment)).collect();
+	    alignments.insert(current.id, current.alignment);
+	    let cask = Box::new(Bitcask {
+		keymap: BitcaskKeymapShards::new(keymap, shards)?,
+		numbering: Mutex::new(()),
+		current: RwLock::new(*current),
+		datafiles: Arc::new(RwLock::new(datafiles)),
+		merge_lock: Mutex::new(()),
+		rotate_lock: Mutex::new(()),
+		dead_bytes: Mutex::new(dead_bytes),
+		alignments: RwLock::new(alignments),
+		alignment: BITCASK_RECORD_ALIGNMENT,
+		compression: Compression::None,
+		cipher,
+		syncer: None,
+		max_records_per_file: None,
+		tombstone_retention: None,
+		snapshot_interval: None,
+		snapshotted: AtomicU64::new(0),
+		max_datafile_size: None,
+		rotation_interval: None,
+		current_since: AtomicU64::new(0),
+		max_value_size: i32::MAX as usize,
+		disk_quota: None,
+		blob_threshold: None,
+		blobs,
+		sync_policy: SyncPolicy::Always,
+		merge_policy: MergePolicy::default(),
+		flusher: None,
+		corruption_policy: CorruptionPolicy::Fail,
+		read_only,
+		pool_read_buffers: false,
+		cache: None,
+		bloom: None,
+		archival: None,
+		retention: None,
+		sequence: AtomicU64::new(max_seq),
+		horizon: AtomicU64::new(horizon),
+		subscribers: Mutex::new(Vec::new()),
+		changes: Mutex::new(VecDeque::with_capacity(BITCASK_CHANGE_BUFFER_SIZE)),
+		changes_dropped: AtomicU64::new(0),
+		key_locks: (0..BITCASK_KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+		storage: Arc::from(storage),
+		storage_lock: Mutex::new(storage_lock),
+		hints_rejected,
+		shut_down: AtomicBool::new(false),
+		opened: Instant::now(),
+		ops: AtomicU64::new(0),
+		last_op: AtomicU64::new(0),
+		idle_merges: AtomicU64::new(0),
+		idle_merges_yielded: AtomicU64::new(0),
+		yielded: Mutex::new(None),
+		maintenance: Mutex::new(None),
+		pool: ThreadPool::new(BITCASK_POOL_THREADS, BITCASK_POOL_QUEUE_SIZE, SaturationPolicy::Inline),
+		id,
+		#[cfg(feature = "metrics")]
+		metrics: Metrics::new(),
+	    });
+	    if !read_only {
+		let datafiles = cask.datafiles.read().unwrap();
+		for id in unhinted {					// So next time won't be the slow way too
+		    BitcaskHintsfile::hintsfile_generate(&*cask.storage, &datafiles[&id])?;
+		}
+	    }
+	    Ok(cask)
+	}
+
+	//
+	// Fill 'keymap' from 'datafiles', each from its hints if it's marked as having them and they can be
+	// trusted, or by scanning it.  With hundreds of datafiles that's most of the time it takes to open a
+	// store, so they're read several at once on a pool of threads, each into a PartialKeymap, which are
+	// applied here in datafile order as they come in, so later records win.  If 'from' is set, 'keymap'
+	// is a snapshot already holding everything before that datafile and offset, so only what follows is
+	// read; a datafile's hints are all or nothing, so a datafile the snapshot covers part of is read
+	// whole if it has them.  Returns the IDs of those that had to be scanned.
+	//
+	fn load_keymap(storage: &dyn Storage, datafiles: &[(Box<BitcaskDatafile>, bool)], from: Option<(BitcaskFileID, u64)>,
+		       keymap: &mut BitcaskKeymap, rejected: &mut usize, max_seq: &mut u64) -> Result<Vec<BitcaskFileID>, io::Error> {
+	    let mut jobs = Vec::new();
+	    for (df, hinted) in datafiles.iter().filter(|(df, _)| from.is_none_or(|(fileid, _)| df.id >= fileid)) {
+		let hintsname = format!("{}.hints", df.id);
+		let hints = match hinted {
+		    true => Some((storage.open(&hintsname)?, hintsname)),
+		    false => None,
+		};
+		let mut df = BitcaskDatafile::from_file(df.name.clone(), df.id, Arc::clone(&df.file), df.cipher.clone())?;	// One of its own, to take along
+		if let Some((fileid, offset)) = from {
+		    if df.id == fileid && !hinted {
+			df.start = offset;				// Just the tail the snapshot doesn't have
+		    }
+		}
+		jobs.push((df, hints));
+	    }
+	    let mut scanned: Vec<BitcaskFileID> = Vec::new();
+	    let mut apply = |partial: PartialKeymap| {
+		*rejected += partial.rejected;
+		*max_seq = (*max_seq).max(partial.max_seq);
+		if partial.scanned {
+		    scanned.push(partial.id);
+		}
+		partial.apply(keymap)
+	    };
+	    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(BITCASK_RECOVERY_THREADS).min(jobs.len());
+	    if threads < 2 {
+		for (df, hints) in jobs {
+		    apply(PartialKeymap::load(&df, hints)?)?;
+		}
+		return Ok(scanned);
+	    }
+	    let ids: Vec<BitcaskFileID> = jobs.iter().map(|(df, _)| df.id).collect();
+	    let pool = ThreadPool::new(threads, jobs.len(), SaturationPolicy::Inline);
+	    let (tx, rx) = channel();
+	    for (df, hints) in jobs {
+		let tx = tx.clone();
+		pool.submit(move || {
+		    let _ = tx.send((df.id, PartialKeymap::load(&df, hints)));
+		});
+	    }
+	    drop(tx);							// So we hear when they're all done
+	    let mut order = ids.into_iter();
+	    let mut next = order.next();
+	    let mut arrived: HashMap<BitcaskFileID, PartialKeymap> = HashMap::new();
+	    for (id, partial) in rx {
+		arrived.insert(id, partial?);
+		while let Some(partial) = next.and_then(|id| arrived.remove(&id)) {
+		    apply(partial)?;
+		    next = order.next();
+		}
+	    }
+	    Ok(scanned)
+	}
+
+	//
+	// Cut a torn write off the end of a datafile, which is where a crash part way through an append leaves
+	// one.  Only the newest datafile can have been written to when we stopped, so it's the only one looked
+	// at; a bad record anywhere else is corruption and is left for the scan to report.  The hints file goes
+	// too, it may speak for what was cut.  Returns whether anything was.
+	//
+	fn recover_datafile(storage: &dyn Storage, df: &BitcaskDatafile, hinted: bool) -> Result<bool, io::Error> {
+	    let (valid, size) = (df.valid_length()?, df.size()?);
+	    if valid == size {
+		return Ok(false);
+	    }
+	    log::warn!("{}: has a torn write at offset {}, cutting off the last {} bytes", df.name, valid, size - valid);
+	    if hinted {
+		storage.remove(&format!("{}.hints", df.id))?;
+	    }
+	    storage.truncate(&df.name, valid)?;
+	    Ok(true)
+	}
+
+	//
+	// Rebuild the keymap entries for one datafile by reading every record in it, skipping unfinished batches.
+	//
+	fn replay_datafile(keymap: &mut BitcaskKeymap, df: &BitcaskDatafile, max_seq: &mut u64) -> Result<bool, io::Error> {
+	    df.scan_committed(|offset, rec| {
+		*max_seq = (*max_seq).max(rec.seq);
+		match rec.op {
+		    BitcaskDatafileRectype::PUT => {
+			keymap.insert(rec.key.clone(), *BitcaskKeymapEntry::new(rec.value_size, df.id, offset, rec.seq, rec.expires, rec.timestamp))?;
+		    },
+		    BitcaskDatafileRectype::DELETE => {
+			keymap.remove(&rec.key);
+		    },
+		    BitcaskDatafileRectype::BEGIN | BitcaskDatafileRectype::COMMIT => {},
+		}
+		Ok(true)
+	    })
+	}
+
+	//
+	// Work out how many bytes of each of the given datafiles the keymap doesn't point at.
+	//
+	fn count_dead_bytes<'a>(datafiles: impl Iterator<Item = &'a BitcaskDatafile>, keymap: &BitcaskKeymap)
+				-> Result<HashMap<BitcaskFileID, u64>, io::Error> {
+	    let mut dead_bytes: HashMap<BitcaskFileID, u64> = HashMap::new();
+	    let mut alignments: HashMap<BitcaskFileID, usize> = HashMap::new();
+	    for df in datafiles {
+		dead_bytes.insert(df.id, df.record_bytes()?);
+		alignments.insert(df.id, df.alignment);
+	    }
+	    for (key, entry) in keymap.iter() {
+		if let Some(dead) = dead_bytes.get_mut(&entry.fileid) {
+		    *dead -= BitcaskDatafileRecord::disk_size(key.len(), entry.value_size, alignments[&entry.fileid]) as u64;
+		}
+	    }
+	    Ok(dead_bytes)
+	}
+
+	//
+	// Read back the keymap snapshot, if it's intact and still describes 'datafiles', as recovery left them:
+	// the same datafiles up to the one it ends in, the same size, but for that one, which mustn't be any
+	// shorter.  Anything else means it's been overtaken, by a merge say, or a crash lost records it had,
+	// and the keymap is read from the datafiles as if there were no snapshot.
+	//
+	fn load_snapshot(storage: &dyn Storage, cipher: Option<&RecordCipher>, datafiles: &[(Box<BitcaskDatafile>, bool)]) -> Result<Option<KeymapSnapshot>, io::Error> {
+	    let file = storage.open(BITCASK_SNAPSHOT_FILE)?;
+	    let mut buf: Vec<u8> = vec![0u8; file.size()? as usize];
+	    file.read_exact_at(0, &mut buf)?;
+	    if let Some(cipher) = cipher {
+		match cipher.open(&buf, BITCASK_SNAPSHOT_FILE.as_bytes()) {
+		    Ok(opened) => buf = opened,
+		    Err(_) => {
+			log::warn!("{}: can't be decrypted, reading the datafiles instead", BITCASK_SNAPSHOT_FILE);
+			return Ok(None);
+		    },
+		}
+	    }
+	    let Some((files, snapshot)) = KeymapSnapshot::from_bytes(&buf) else {
+		log::warn!("{}: is damaged, reading the datafiles instead", BITCASK_SNAPSHOT_FILE);
+		return Ok(None);
+	    };
+	    let mut found: Vec<(BitcaskFileID, u64)> = Vec::new();
+	    for (df, _) in datafiles.iter().filter(|(df, _)| df.id <= snapshot.fileid) {
+		found.push((df.id, df.size()?));
+	    }
+	    let last = files.len() - 1;
+	    let agrees = found.len() == files.len() && found.iter().zip(&files).enumerate()
+		.all(|(i, (found, file))| found.0 == file.0 && (found.1 == file.1 || (i == last && found.1 >= file.1)));
+	    if !agrees {
+		log::info!("{}: no longer describes the datafiles, reading them instead", BITCASK_SNAPSHOT_FILE);
+		return Ok(None);
+	    }
+	    Ok(Some(snapshot))
+	}
+
+	//
+	// Remove the keymap snapshot and the note of a clean shutdown, if they're there, before changing
+	// datafiles in a way they wouldn't notice.
+	//
+	fn remove_snapshot(storage: &dyn Storage) -> Result<bool, io::Error> {
+	    let names = storage.list()?;
+	    for name in [BITCASK_SNAPSHOT_FILE, BITCASK_CLEAN_FILE] {
+		if names.iter().any(|have| have == name) {
+		    storage.remove(name)?;
+		}
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Read back what shutdown() left, if it's intact.  A writer removes it, it won't be true for long.
+	//
+	fn load_clean_shutdown(storage: &dyn Storage, read_only: bool) -> Result<Option<CleanShutdown>, io::Error> {
+	    let file = storage.open(BITCASK_CLEAN_FILE)?;
+	    let mut buf = vec![0u8; file.size()? as usize];
+	    file.read_exact_at(0, &mut buf)?;
+	    if !read_only {
+		storage.remove(BITCASK_CLEAN_FILE)?;
+	    }
+	    let clean = CleanShutdown::from_bytes(&buf);
+	    if clean.is_none() {
+		log::warn!("{}: is damaged, ignoring it", BITCASK_CLEAN_FILE);
+	    }
+	    Ok(clean)
+	}
+
+	//
+	// Read back the horizon the last merge recorded, 0 if there has never been a merge.  A horizon file we
+	// can't make sense of means we no longer know how much history survives, so we assume none of it does.
+	//
+	fn load_horizon(storage: &dyn Storage, names: &[String]) -> Result<u64, io::Error> {
+	    if !names.iter().any(|name| name == BITCASK_HORIZON_FILE) {
+		return Ok(0);
+	    }
+	    let file = storage.open(BITCASK_HORIZON_FILE)?;
+	    let mut buf = [0u8; 8];
+	    if file.size()? != buf.len() as u64 || file.read_exact_at(0, &mut buf).is_err() {
+		log::warn!("{}: is malformed, truncate_to_sequence() will refuse to run", BITCASK_HORIZON_FILE);
+		return Ok(u64::MAX);
+	    }
+	    Ok(u64::from_le_bytes(buf))
+	}
+
+	//
+	// Read back the store's UUID, or if it doesn't have one yet (it's new, or older than UUIDs), make one up
+	// and write it down, unless we're read-only and it's made up afresh every time.  Unlike the horizon,
+	// there's no safe guess for an ID we can't read.
+	//
+	fn load_id(storage: &dyn Storage, names: &[String], read_only: bool) -> Result<Uuid, io::Error> {
+	    if names.iter().any(|name| name == BITCASK_ID_FILE) {
+		let file = storage.open(BITCASK_ID_FILE)?;
+		let mut buf = [0u8; 16];
+		if file.size()? != buf.len() as u64 {
+		    return Err(BitcaskError::InvalidFormat { file: BITCASK_ID_FILE.to_string(), reason: "is malformed".to_string() }.into());
+		}
+		file.read_exact_at(0, &mut buf)?;
+		return Ok(Uuid::from_bytes(buf));
+	    }
+	    let id = Uuid::new_v4();
+	    if read_only {
+		return Ok(id);
+	    }
+	    let file = storage.create(BITCASK_ID_FILE)?;
+	    file.append(id.as_bytes())?;
+	    file.sync()?;
+	    Ok(id)
+	}
+
+	//
+	// The store's UUID, fixed when it was created.  It lives with the data, so it follows the store if its
+	// files are moved, and it's what tells two stores apart when their paths don't.
+	//
+	pub fn id(&self) -> Uuid {
+	    self.id
+	}
+
+	//
+	// Durably record a new horizon, replacing the old one.
+	//
+	fn save_horizon(&self, horizon: u64) -> Result<bool, io::Error> {
+	    let file = self.storage.create(BITCASK_HORIZON_FILE)?;
+	    file.append(&horizon.to_le_bytes())?;
+	    file.sync()?;
+	    self.horizon.store(horizon, Ordering::SeqCst);
+	    Ok(true)
+	}
+
+	//
+	// How many hint records were rejected as implausible while opening the database.
+	//
+	pub fn hints_rejected(&self) -> usize {
+	    self.hints_rejected
+	}
+
+	//
+	// Return the IDs of all the "*.data" files among the given names, in ascending order.
+	//
+	fn datafile_ids(names: &[String]) -> Vec<BitcaskFileID> {
+	    let mut ids: Vec<BitcaskFileID> = names.iter()
+		.filter_map(|name| name.strip_suffix(".data").and_then(|base| base.parse::<BitcaskFileID>().ok()))
+		.collect();
+	    ids.sort();
+	    ids
+	}
+
+	//
+	// Read the record at the given location, whether it's in the current datafile or an archived one.
+	// Returns None if that datafile no longer exists, which means a merge moved the record elsewhere.
+	//
+	fn read_record(&self, fileid: BitcaskFileID, offset: i64, key_size: usize, value_size: i32) -> Result<Option<Box<BitcaskDatafileRecord>>, io::Error> {
+	    self.with_datafile(fileid, |df| df.get(offset, key_size, value_size))
+	}
+
+	//
+	// The value of a PUT read from one of our datafiles, fetched from its blob file if it's in one.
+	//
+	fn value_of(&self, rec: &BitcaskDatafileRecord) -> Result<Vec<u8>, io::Error> {
+	    if rec.flags & BITCASK_RECORD_FLAG_BLOB == 0 {
+		return rec.value_bytes();
+	    }
+	    self.read_blob(&self.blobs, &rec.key, &rec.value)
+	}
+
+	//
+	// As df.get_into(), fetching the value from its blob file if it's in one.
+	//
+	fn read_into(&self, df: &BitcaskDatafile, key: &[u8], offset: i64, value_size: i32, buf: &mut Vec<u8>) -> Result<usize, io::Error> {
+	    let (len, blob) = df.get_into(offset, key.len(), value_size, buf)?;
+	    if !blob {
+		return Ok(len);
+	    }
+	    *buf = self.read_blob(&self.blobs, key, buf)?;
+	    Ok(buf.len())
+	}
+
+	//
+	// As df.get_range(), but for any value, reading the record whole only if it has to, and then of a value
+	// in a blob file only the chunks the range is in if it was streamed in, see put_reader(), or all of it if
+	// it's sealed in one go.
+	//
+	fn read_range(&self, df: &BitcaskDatafile, key: &[u8], offset: i64, value_size: i32, start: u64, len: u64) -> Result<Vec<u8>, io::Error> {
+	    if let Some(bytes) = df.get_range(offset, key.len(), value_size, start, len)? {
+		return Ok(bytes);
+	    }
+	    let mut buf = Vec::new();
+	    let (_, blob) = df.get_into(offset, key.len(), value_size, &mut buf)?;
+	    if !blob {
+		return Ok(byte_range(&buf, start, len).to_vec());
+	    }
+	    let Some(ptr) = BlobPointer::from_bytes(&buf) else {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed blob pointer"));
+	    };
+	    if ptr.flags & BLOB_FLAG_SEALED == 0 {
+		let (from, to) = clamp_range(ptr.len, start, len);
+		return self.blobs.read_at(&ptr, from, (to - from) as usize);
+	    }
+	    let Some(cipher) = self.cipher.as_ref().filter(|_| ptr.flags & BLOB_FLAG_CHUNKED != 0) else {
+		return Ok(byte_range(&self.read_blob(&self.blobs, key, &buf)?, start, len).to_vec());
+	    };
+	    let (chunk, stored) = (BLOB_CHUNK_SIZE as u64, (BLOB_CHUNK_SIZE + RecordCipher::OVERHEAD) as u64);
+	    let size = ptr.len.saturating_sub(ptr.len.div_ceil(stored) * RecordCipher::OVERHEAD as u64);	// Every chunk has its overhead
+	    let (from, to) = clamp_range(size, start, len);
+	    let mut value = Vec::with_capacity((to - from) as usize);
+	    for index in from / chunk..to.div_ceil(chunk) {
+		let at = index * stored;
+		let plain = cipher.open(&self.blobs.read_at(&ptr, at, (ptr.len - at).min(stored) as usize)?, &chunk_aad(key, index))?;
+		let base = index * chunk;
+		let (lo, hi) = (from.max(base) - base, to.min(base + plain.len() as u64) - base);
+		value.extend_from_slice(&plain[lo as usize..hi as usize]);
+	    }
+	    Ok(value)
+	}
+
+	//
+	// What the BlobPointer 'stored', the stored value of a record of 'key', points at in 'blobs'.
+	//
+	fn read_blob(&self, blobs: &BlobStore, key: &[u8], stored: &[u8]) -> Result<Vec<u8>, io::Error> {
+	    let mut value = Vec::new();
+	    self.stream_blob(blobs, key, stored, &mut value)?;
+	    Ok(value)
+	}
+
+	//
+	// As read_blob(), but writing the value to 'out' a piece at a time, and returning its length.  See
+	// BlobStore::stream() for what a bad checksum means for 'out'.
+	//
+	fn stream_blob(&self, blobs: &BlobStore, key: &[u8], stored: &[u8], out: &mut dyn Write) -> Result<u64, io::Error> {
+	    let Some(ptr) = BlobPointer::from_bytes(stored) else {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed blob pointer"));
+	    };
+	    if ptr.flags & BLOB_FLAG_SEALED == 0 {
+		blobs.stream(&ptr, BLOB_CHUNK_SIZE, |bytes| out.write_all(bytes))?;
+		return Ok(ptr.len);
+	    }
+	    let Some(cipher) = &self.cipher else {
+		return Err(BitcaskError::InvalidArgument("blob is encrypted and no key was given".to_string()).into());
+	    };
+	    if ptr.flags & BLOB_FLAG_CHUNKED == 0 {			// Sealed in one go, so it can only be opened in one go
+		let value = cipher.open(&blobs.read(&ptr)?, key)?;
+		out.write_all(&value)?;
+		return Ok(value.len() as u64);
+	    }
+	    let (mut index, mut len) = (0u64, 0u64);
+	    blobs.stream(&ptr, BLOB_CHUNK_SIZE + RecordCipher::OVERHEAD, |sealed| {
+		let chunk = cipher.open(sealed, &chunk_aad(key, index))?;
+		index += 1;
+		len += chunk.len() as u64;
+		out.write_all(&chunk)
+	    })?;
+	    Ok(len)
+	}
+
+	//
+	// Write 'value' to the blob file of datafile 'id', if set_blob_threshold() says it's big enough to,
+	// sealed for 'key' if we have a cipher, and say where it went.
+	//
+	fn spill(&self, id: BitcaskFileID, key: &[u8], value: &[u8]) -> Result<Option<BlobPointer>, io::Error> {
+	    if self.blob_threshold.is_none_or(|threshold| value.len() < threshold) {
+		return Ok(None);
+	    }
+	    let ptr = match &self.cipher {
+		Some(cipher) => self.blobs.write(&*self.storage, id, &cipher.seal(value, key)?, BLOB_FLAG_SEALED)?,
+		None => self.blobs.write(&*self.storage, id, value, 0)?,
+	    };
+	    Ok(Some(ptr))
+	}
+
+	//
+	// A copy of 'rec', read from another store whose blob files are 'blobs', with its value in the record.
+	//
+	fn inlined(&self, rec: &BitcaskDatafileRecord, blobs: &BlobStore) -> Result<BitcaskDatafileRecord, io::Error> {
+	    let mut rec = rec.clone();
+	    if rec.flags & BITCASK_RECORD_FLAG_BLOB != 0 {
+		rec.value = self.read_blob(blobs, &rec.key, &rec.value)?;
+		rec.value_size = rec.value.len() as i32;
+		rec.flags &= !BITCASK_RECORD_FLAG_BLOB;
+		rec.crc = rec.checksum();
+	    }
+	    Ok(rec)
+	}
+
+	//
+	// Run 'f' on the given datafile, whether it's the current one or an archived one, with it locked in place.
+	// Returns None if that datafile no longer exists.
+	//
+	fn with_datafile<R>(&self, fileid: BitcaskFileID, f: impl FnOnce(&BitcaskDatafile) -> Result<R, io::Error>) -> Result<Option<R>, io::Error> {
+	    {
+		let df = self.current.read().unwrap();		// Protect changes to 'current' while we do our lookup
+		if df.id == fileid {
+		    return Ok(Some(f(&df)?));
+		}
+	    }							// Drop the reader lock
+	    let files = self.datafiles.read().unwrap();		// Protect the archive while we do our lookup
+	    match files.get(&fileid) {
+		Some(df) => Ok(Some(f(df)?)),
+		None => Ok(None),
+	    }
+	}
+
+	//
+	// Remove a datafile from storage, and its hints file first if it has one so the hints never outlive it.
+	//
+	fn remove_datafile(&self, df: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    match self.storage.remove(&format!("{}.hints", df.id)) {
+		Ok(_) => {},
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+		Err(e) => return Err(e),
+	    }
+	    self.storage.remove(&df.name)
+	}
+
+	//
+	// Account for a record with the given key and value sizes in the given datafile no longer being referenced by the keymap.
+	//
+	fn note_dead(&self, fileid: BitcaskFileID, key_size: usize, value_size: i32) {
+	    let alignment = self.alignments.read().unwrap().get(&fileid).copied().unwrap_or(BITCASK_RECORD_ALIGNMENT);
+	    *self.dead_bytes.lock().unwrap().entry(fileid).or_insert(0) += BitcaskDatafileRecord::disk_size(key_size, value_size, alignment) as u64;
+	}
+
+	//
+	// Return the write lock for the stripe the key hashes to.  Every modification of a single key holds
+	// its stripe, so a read-modify-write of a key can hold it across the read and the write and know that
+	// no other put or delete of that key slips in between.  Keys in different stripes never contend here.
+	//
+	fn key_lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+	    self.key_locks[self.stripe(key)].lock().unwrap()
+	}
+
+	fn stripe(&self, key: &[u8]) -> usize {
+	    let mut hasher = DefaultHasher::new();
+	    key.hash(&mut hasher);
+	    hasher.finish() as usize % self.key_locks.len()
+	}
+
+	//
+	// Return the location of a KV from the index, if it exists and hasn't expired
+	//
+	fn lookup(&self, key: &[u8]) -> Option<(BitcaskFileID, i64, i32)> {
+	    if self.bloom.as_ref().is_some_and(|bloom| !bloom.read().unwrap().might_contain(key)) {
+		return None;
+	    }
+	    let now = now_millis();
+	    let map = self.keymap.read(key);			// Protect the data structure while we do our lookup
+	    map.get(key).filter(|entry| !entry.is_expired(now)).map(|entry| (entry.fileid, entry.offset, entry.value_size))
+	}
+
+	//
+	// Fetch the value of an existing KV
+	//
+	pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    if self.pool_read_buffers {
+		return with_read_buffer(|buf| Ok(self.get_into(key, buf)?.map(|len| buf[..len].to_vec())));
+	    }
+	    #[cfg(feature = "metrics")]
+	    Metrics::count(&self.metrics.gets, 1);
+	    let mut span = OpSpan::start("get");
+	    span.record("key_size", key.len() as u64);
+	    loop {
+		let Some((fileid, offset, value_size)) = self.lookup(key) else {
+		    return Ok(None);
+		};
+		span.record("file_id", fileid as u64);
+		if let Some(value) = self.cached(key, fileid, offset) {
+		    return Ok(Some(value));
+		}
+		match self.read_record(fileid, offset, key.len(), value_size) {
+		    Ok(Some(rec)) => {
+			let value = self.value_of(&rec)?;
+			self.cache_value(key, fileid, offset, &value);
+			return Ok(Some(value));
+		    },
+		    Ok(None) => {},				// The datafile went away underneath us during a merge, look up the new location
+		    Err(e) => return self.tolerate(e),
+		}
+	    }
+	}
+
+	//
+	// Fetch the value of an existing KV along with its generation, which is bigger after every put of the
+	// key than it was before, even across a delete.  It's the seq of the put that wrote the value, the same
+	// one its ChangeEvent carried, so it survives reopening, merges, and imports.
+	//
+	pub fn get_with_metadata(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    loop {
+		let Some((fileid, offset, value_size)) = self.lookup(key) else {
+		    return Ok(None);
+		};
+		match self.read_record(fileid, offset, key.len(), value_size) {
+		    Ok(Some(rec)) => return Ok(Some((self.value_of(&rec)?, rec.seq))),
+		    Ok(None) => {},				// The datafile went away underneath us during a merge, look up the new location
+		    Err(e) => return self.tolerate(e),
+		}
+	    }
+	}
+
+	//
+	// The value the cache has for 'key', if it was read from where the keymap says the key is now.
+	//
+	fn cached(&self, key: &[u8], fileid: BitcaskFileID, offset: i64) -> Option<Vec<u8>> {
+	    self.cache.as_ref()?.lock().unwrap().get(key, (fileid, offset))
+	}
+
+	fn cache_value(&self, key: &[u8], fileid: BitcaskFileID, offset: i64, value: &[u8]) {
+	    if let Some(cache) = &self.cache {
+		cache.lock().unwrap().insert(key, (fileid, offset), value);
+	    }
+	}
+
+	//
+	// Forget the cached value of 'key'.  One that's out of date would never be handed out anyway, this
+	// is only so it doesn't take up room.
+	//
+	fn uncache(&self, key: &[u8]) {
+	    if let Some(cache) = &self.cache {
+		cache.lock().unwrap().remove(key);
+	    }
+	}
+
+	//
+	// Hand back the failure to read a value, unless it's corruption and the policy says to skip it, when
+	// it's logged and the key reads as absent.
+	//
+	fn tolerate<T>(&self, e: io::Error) -> Result<Option<T>, BitcaskError> {
+	    let e = BitcaskError::from(e);
+	    if self.corruption_policy == CorruptionPolicy::Skip && e.is_corruption() {
+		log::warn!("{}, reading it as absent", e);
+		return Ok(None);
+	    }
+	    Err(e)
+	}
+
+	//
+	// Return the generation of a key, 0 if it doesn't exist.  The caller holds the key's stripe.
+	//
+	fn generation(&self, key: &[u8]) -> Result<u64, io::Error> {
+	    let now = now_millis();
+	    loop {
+		let (fileid, offset, value_size) = {
+		    let map = self.keymap.read(key);		// Protect the data structure while we do our lookup
+		    match map.get(key) {
+			None => return Ok(0),
+			Some(entry) if entry.is_expired(now) => return Ok(0),	// As good as deleted
+			Some(entry) if entry.generation != 0 => return Ok(entry.generation),
+			Some(entry) => (entry.fileid, entry.offset, entry.value_size),
+		    }
+		};
+		if let Some(rec) = self.read_record(fileid, offset, key.len(), value_size)? {	// Only a hint told us where it is
+		    return Ok(rec.seq);
+		}
+	    }
+	}
+
+	//
+	// Put 'value' only if the key's generation is still 'expected', as returned by get_with_metadata(), or 0
+	// to put it only if the key doesn't exist.  Returns Ok(false), writing nothing, if the key has moved on.
+	// Of any number of writers racing with the same expected generation exactly one succeeds.
+	//
+	pub fn compare_and_swap_generation(&self, key: impl AsRef<[u8]>, expected: u64, value: impl AsRef<[u8]>) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the key between the check and the put
+	    if self.generation(key)? != expected {
+		return Ok(false);
+	    }
+	    Ok(self.put_locked(key, value.as_ref(), 0)?)
+	}
+
+	//
+	// Put 'new', or delete the key if it's None, only if the key's value is still 'expected', None meaning
+	// the key doesn't exist.  Returns Ok(false), writing nothing, if it isn't.  The check and the write are
+	// made holding the key's stripe, so of any number of writers racing from the same value exactly one
+	// succeeds.
+	//
+	pub fn compare_and_swap(&self, key: impl AsRef<[u8]>, expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the key between the check and the write
+	    let mut buf = Vec::new();
+	    let current = self.get_into(key, &mut buf)?.map(|len| &buf[..len]);
+	    if current != expected {
+		return Ok(false);
+	    }
+	    Ok(self.replace_locked(key, current.is_some(), new)?)
+	}
+
+	//
+	// Read-modify-write a key: 'f' is handed its value, None if it doesn't exist, and returns the value to
+	// put in its place, or None to delete it.  The key's stripe is held from the read to the write, so no
+	// other writer of the key can get in between, and 'f' is called exactly once.  Returns the value the
+	// key is left with.
+	//
+	pub fn update(&self, key: impl AsRef<[u8]>, f: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>) -> Result<Option<Vec<u8>>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the key between the read and the write
+	    let mut buf = Vec::new();
+	    let current = self.get_into(key, &mut buf)?.map(|len| &buf[..len]);
+	    let exists = current.is_some();
+	    let new = f(current);
+	    self.replace_locked(key, exists, new.as_deref())?;
+	    Ok(new)
+	}
+
+	//
+	// Add 'bytes' to the end of a key's value, creating it with just them if it doesn't exist, and return
+	// the length the value comes to.  The read and the put are made holding the key's stripe, so racing
+	// appends all land, in some order.  Each append writes the whole value again: fine for the short
+	// event lists it's meant for, but a value grown by many appends costs its length in dead bytes each time.
+	//
+	pub fn append(&self, key: impl AsRef<[u8]>, bytes: impl AsRef<[u8]>) -> Result<usize, BitcaskError> {
+	    let key = key.as_ref();
+	    let bytes = bytes.as_ref();
+	    self.touch();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the value between the read and the put
+	    let mut buf = Vec::new();
+	    let len = self.get_into(key, &mut buf)?.unwrap_or(0);
+	    buf.truncate(len);
+	    buf.extend_from_slice(bytes);
+	    self.put_locked(key, &buf, 0)?;
+	    Ok(buf.len())
+	}
+
+	//
+	// Add 'delta' to a counter, a key whose value is an i64 as 8 little-endian bytes, creating it at 0 if it
+	// doesn't exist, and return what it comes to.  The read and the put are made holding the key's stripe, so
+	// racing increments are never lost.  A value that isn't 8 bytes, or a result that would overflow, is an
+	// InvalidArgument, and nothing is written.
+	//
+	pub fn incr(&self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64, BitcaskError> {
+	    self.add_counter(key.as_ref(), |count| count.checked_add(delta))
+	}
+
+	pub fn decr(&self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64, BitcaskError> {
+	    self.add_counter(key.as_ref(), |count| count.checked_sub(delta))
+	}
+
+	fn add_counter(&self, key: &[u8], f: impl FnOnce(i64) -> Option<i64>) -> Result<i64, BitcaskError> {
+	    self.touch();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the count between the read and the put
+	    let mut buf = Vec::new();
+	    let count = match self.get_into(key, &mut buf)? {
+		Some(len) => <[u8; 8]>::try_from(&buf[..len]).map(i64::from_le_bytes)
+		    .map_err(|_| BitcaskError::InvalidArgument("the value is not a counter".to_string()))?,
+		None => 0,
+	    };
+	    let count = f(count).ok_or_else(|| BitcaskError::InvalidArgument("the counter would overflow".to_string()))?;
+	    self.put_locked(key, &count.to_le_bytes(), 0)?;
+	    Ok(count)
+	}
+
+	//
+	// Put 'new', or delete the key if it's None, for a caller holding its stripe who knows whether it
+	// 'exists'.  There's nothing to write to delete one that doesn't.
+	//
+	fn replace_locked(&self, key: &[u8], exists: bool, new: Option<&[u8]>) -> Result<bool, io::Error> {
+	    match new {
+		Some(value) => self.put_locked(key, value, 0),
+		None if exists => self.delete_locked(key),
+		None => Ok(true),
+	    }
+	}
+
+	//
+	// Return where the current value of a key lives on disk, as (fileid, offset), if it exists.
+	//
+	pub fn location(&self, key: impl AsRef<[u8]>) -> Option<(BitcaskFileID, i64)> {
+	    self.lookup(key.as_ref()).map(|(fileid, offset, _)| (fileid, offset))
+	}
+
+	//
+	// Does a key exist?  Answered from the index alone, the datafiles aren't read.
+	//
+	pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+	    self.touch();
+	    self.lookup(key.as_ref()).is_some()
+	}
+
+	//
+	// When a key's value was written and when it expires, and where it's kept, or None if it doesn't exist.
+	// From the index alone, unless the key's hint is from before hints had timestamps, when its record's
+	// header is read for the timestamp.
+	//
+	pub fn key_metadata(&self, key: impl AsRef<[u8]>) -> Result<Option<KeyMetadata>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    let now = now_millis();
+	    loop {
+		let Some(entry) = self.keymap.read(key).get(key).filter(|entry| !entry.is_expired(now))
+		    .map(|entry| KeyMetadata { timestamp: entry.timestamp, expires: entry.expires, value_size: entry.value_size as usize, file_id: entry.fileid, offset: entry.offset }) else {
+		    return Ok(None);
+		};
+		if entry.timestamp != 0 {
+		    return Ok(Some(entry));
+		}
+		if let Some(rec) = self.read_record(entry.file_id, entry.offset, key.len(), entry.value_size as i32)? {
+		    return Ok(Some(KeyMetadata { timestamp: rec.timestamp, ..entry }));
+		}						// The datafile went away underneath us during a merge, look again
+	    }
+	}
+
+	//
+	// How many keys exist, those deleted or expired don't count, and whether there are none, again from the
+	// index alone.
+	//
+	pub fn len(&self) -> usize {
+	    let now = now_millis();
+	    let map = self.keymap.read_all();			// Protect the data structure while we count
+	    map.iter().filter(|(_, entry)| !entry.is_expired(now)).count()
+	}
+
+	pub fn is_empty(&self) -> bool {
+	    let now = now_millis();
+	    let map = self.keymap.read_all();
+	    let empty = !map.iter().any(|(_, entry)| !entry.is_expired(now));
+	    empty
+	}
+
+	//
+	// Report which of the given keys exist, position by position, with one look at the index and no reads
+	// of the datafiles.
+	//
+	pub fn exists_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+	    self.touch();
+	    let now = now_millis();
+	    let map = self.keymap.read_all();			// Protect the data structure while we do our lookups
+	    keys.iter().map(|key| map.get(key.as_ref()).is_some_and(|entry| !entry.is_expired(now))).collect()
+	}
+
+	//
+	// Fetch the values of many keys at once, position by position, None for those that don't exist.  The
+	// keymap is looked at once for all of them, and the values are read in the order they lie in the
+	// datafiles rather than the order they were asked for, which is kinder to the disk.
+	//
+	pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, BitcaskError> {
+	    self.touch();
+	    let now = now_millis();
+	    let mut located: Vec<(BitcaskFileID, i64, i32, usize)> = {
+		let map = self.keymap.read_all();		// Protect the data structure while we do our lookups
+		keys.iter().enumerate()
+		    .filter_map(|(i, key)| map.get(key.as_ref()).filter(|entry| !entry.is_expired(now)).map(|entry| (entry.fileid, entry.offset, entry.value_size, i)))
+		    .collect()
+	    };							// Drop the reader locks
+	    located.sort_unstable();
+	    let mut values: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+	    let mut moved: Vec<usize> = Vec::new();
+	    {
+		let current = self.current.read().unwrap();	// Protect changes to 'current' while we do our reads
+		let files = self.datafiles.read().unwrap();	// Protect the archive likewise
+		for (fileid, offset, value_size, i) in located {
+		    let key = keys[i].as_ref();
+		    if let Some(value) = self.cached(key, fileid, offset) {
+			values[i] = Some(value);
+			continue;
+		    }
+		    let Some(df) = (if current.id == fileid { Some(&*current) } else { files.get(&fileid) }) else {
+			moved.push(i);				// A merge has finished with it since we looked
+			continue;
+		    };
+		    let mut buf = Vec::new();
+		    values[i] = match self.read_into(df, key, offset, value_size, &mut buf) {
+			Ok(_) => Some(buf),
+			Err(e) => self.tolerate(e)?,
+		    };
+		}
+	    }							// Drop the reader locks
+	    for i in moved {
+		let mut buf = Vec::new();
+		if self.get_into(keys[i].as_ref(), &mut buf)?.is_some() {
+		    values[i] = Some(buf);
+		}
+	    }
+	    Ok(values)
+	}
+
+	//
+	// Fetch the value of an existing KV into a caller-provided buffer, returning its length in bytes.
+	// 'buf' is cleared and resized to fit, so a loop can reuse one buffer instead of allocating per call.
+	//
+	pub fn get_into(&self, key: impl AsRef<[u8]>, buf: &mut Vec<u8>) -> Result<Option<usize>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    #[cfg(feature = "metrics")]
+	    Metrics::count(&self.metrics.gets, 1);
+	    let mut span = OpSpan::start("get");
+	    span.record("key_size", key.len() as u64);
+	    loop {
+		let Some((fileid, offset, value_size)) = self.lookup(key) else {
+		    buf.clear();
+		    return Ok(None);
+		};
+		span.record("file_id", fileid as u64);
+		if let Some(value) = self.cached(key, fileid, offset) {
+		    buf.clear();
+		    buf.extend_from_slice(&value);
+		    return Ok(Some(buf.len()));
+		}
+		match self.with_datafile(fileid, |df| self.read_into(df, key, offset, value_size, buf)) {
+		    Ok(Some(len)) => {
+			self.cache_value(key, fileid, offset, buf);
+			return Ok(Some(len));
+		    },
+		    Ok(None) => {},				// The datafile went away underneath us during a merge, look up the new location
+		    Err(e) => {
+			buf.clear();
+			return self.tolerate(e);
+		    },
+		}
+	    }
+	}
+
+	//
+	// Fetch just bytes 'offset'..'offset'+'len' of the value of an existing KV, fewer if the value isn't that
+	// long, none at all if it doesn't reach 'offset'.  Only those bytes are read where the value is stored as
+	// it was put, inline or in a blob file, so a header or a slice of a big value costs what it's long, not
+	// what the value is.  A checksum covers a whole value, so a range read that way goes unchecked.  A value
+	// that's compressed, or encrypted in one go, still has to be read whole.  Ranges are of bytes, so they
+	// needn't start or end on a character.
+	//
+	pub fn get_range(&self, key: impl AsRef<[u8]>, offset: u64, len: u64) -> Result<Option<Vec<u8>>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    loop {
+		let Some((fileid, at, value_size)) = self.lookup(key) else {
+		    return Ok(None);
+		};
+		if let Some(value) = self.cached(key, fileid, at) {
+		    return Ok(Some(byte_range(&value, offset, len).to_vec()));
+		}
+		match self.with_datafile(fileid, |df| self.read_range(df, key, at, value_size, offset, len)) {
+		    Ok(Some(bytes)) => return Ok(Some(bytes)),
+		    Ok(None) => {},				// The datafile went away underneath us during a merge, look up the new location
+		    Err(e) => return self.tolerate(e),
+		}
+	    }
+	}
+
+	//
+	// Write the value of an existing KV to 'writer', returning its length, or None if the key doesn't exist.
+	// A value in a blob file goes across BLOB_CHUNK_SIZE bytes at a time, so one put with put_reader() can be
+	// got back without ever being all in memory.  Its checksum can't be checked until the last of it has been
+	// read, so if that fails 'writer' has already had the whole damaged value, and must throw it away.
+	//
+	pub fn get_writer(&self, key: impl AsRef<[u8]>, mut writer: impl Write) -> Result<Option<u64>, BitcaskError> {
+	    let key = key.as_ref();
+	    self.touch();
+	    loop {
+		let Some((fileid, offset, value_size)) = self.lookup(key) else {
+		    return Ok(None);
+		};
+		if let Some(value) = self.cached(key, fileid, offset) {
+		    writer.write_all(&value)?;
+		    return Ok(Some(value.len() as u64));
+		}
+		match self.read_record(fileid, offset, key.len(), value_size) {
+		    Ok(Some(rec)) if rec.flags & BITCASK_RECORD_FLAG_BLOB != 0 => return Ok(Some(self.stream_blob(&self.blobs, key, &rec.value, &mut writer)?)),
+		    Ok(Some(rec)) => {
+			let value = rec.value_bytes()?;		// It was small enough to be in the record, so it's small enough for memory
+			writer.write_all(&value)?;
+			return Ok(Some(value.len() as u64));
+		    },
+		    Ok(None) => {},				// The datafile went away underneath us during a merge, look up the new location
+		    Err(e) => return self.tolerate(e),
+		}
+	    }
+	}
+
+	//
+	// Insert a new KV or update an existing KV
+	//
+	pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Serialize with any other writer of this key
+	    Ok(self.put_locked(key, value.as_ref(), 0)?)
+	}
+
+	//
+	// As put(), with the value the next 'len' bytes of 'reader', which are read and written BLOB_CHUNK_SIZE
+	// at a time, so a value needn't fit in memory.  The value always goes to a blob file, whatever
+	// set_blob_threshold() says, as a record has to be written in one go.  If we have a cipher each chunk is
+	// sealed on its own, see chunk_aad().  A reader that runs out early leaves the KV as it was.
+	//
+	pub fn put_reader(&self, key: impl AsRef<[u8]>, mut reader: impl Read, len: u64) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Serialize with any other writer of this key
+	    Ok(self.put_reader_locked(key, &mut reader, len)?)
+	}
+
+	fn put_reader_locked(&self, key: &[u8], reader: &mut dyn Read, len: u64) -> Result<bool, io::Error> {
+	    self.writable()?;
+	    if len > self.max_value_size as u64 {
+		return Err(BitcaskError::ValueTooLarge { size: usize::try_from(len).unwrap_or(usize::MAX), limit: self.max_value_size }.into());
+	    }
+	    self.make_room(len + BitcaskDatafileRecord::disk_size(key.len(), BLOB_POINTER_SIZE as i32, self.alignment) as u64)?;
+	    self.touch();
+	    let mut span = OpSpan::start("put");
+	    span.record("key_size", key.len() as u64);
+	    span.record("bytes", len);
+	    let id = self.current.read().unwrap().id;		// Not held while we stream, rotations and merges needn't wait for us
+	    let flags = if self.cipher.is_some() { BLOB_FLAG_SEALED | BLOB_FLAG_CHUNKED } else { 0 };
+	    let mut blob = self.blobs.writer(&*self.storage, id, flags)?;
+	    let mut buf = vec![0u8; BLOB_CHUNK_SIZE];
+	    let (mut left, mut index) = (len, 0u64);
+	    while left > 0 {
+		let size = left.min(BLOB_CHUNK_SIZE as u64) as usize;
+		reader.read_exact(&mut buf[..size])?;
+		left -= size as u64;
+		match &self.cipher {
+		    Some(cipher) => blob.write(&cipher.seal(&buf[..size], &chunk_aad(key, index))?)?,
+		    None => blob.write(&buf[..size])?,
+		}
+		index += 1;
+	    }
+	    let ptr = blob.finish()?;
+	    let df = self.reserve_current()?;			// Protect changes to 'current' while we do our append
+	    span.record("file_id", df.id as u64);
+	    let (offset, seq) = self.index_put(&df, key, 0, |seq| df.put_blob(key, &ptr, seq, 0, false))?;
+	    drop(blob);						// The record points at the blob now, a merge will keep it
+	    self.sync_write(&df)?;
+	    self.publish(ChangeEvent { key: key.to_vec(), op: ChangeOp::Put, value: None, seq, fileid: df.id, offset });
+	    Ok(true)
+	}
+
+	//
+	// As put(), but the KV only lasts for 'ttl'.  After that it's absent, as if it had been deleted, until a
+	// merge gets rid of it for good.  Expiry goes by the wall clock, so it survives reopening the store.
+	//
+	pub fn put_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Serialize with any other writer of this key
+	    Ok(self.put_locked(key, value.as_ref(), expiry_after(ttl))?)
+	}
+
+	//
+	// Insert a new KV only if the key doesn't exist, returning Ok(false), writing nothing, if it does.  An
+	// expired KV counts as absent.  The check and the put are made holding the key's stripe, so of any
+	// number of writers racing to create the same key exactly one succeeds.
+	//
+	pub fn put_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Nobody else can create the key between the check and the put
+	    if self.lookup(key).is_some() {
+		return Ok(false);
+	    }
+	    Ok(self.put_locked(key, value.as_ref(), 0)?)
+	}
+
+	//
+	// Give an existing KV a new 'ttl' from now, or take its expiry away if 'ttl' is None, by writing its value
+	// again.  Returns Ok(false), writing nothing, if the key doesn't exist.
+	//
+	pub fn expire(&self, key: impl AsRef<[u8]>, ttl: Option<Duration>) -> Result<bool, BitcaskError> {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Nobody else can change the value between the read and the put
+	    let Some(value) = self.get(key)? else {
+		return Ok(false);
+	    };
+	    Ok(self.put_locked(key, &value, ttl.map_or(0, expiry_after))?)
+	}
+
+	//
+	// The put itself, for a caller holding the key's stripe.  'expires' is as for a record.
+	//
+	fn put_locked(&self, key: &[u8], value: &[u8], expires: u64) -> Result<bool, io::Error> {
+	    self.writable()?;
+	    if value.len() > self.max_value_size {
+		return Err(BitcaskError::ValueTooLarge { size: value.len(), limit: self.max_value_size }.into());
+	    }
+	    self.make_room(BitcaskDatafileRecord::disk_size(key.len(), value.len() as i32, self.alignment) as u64)?;
+	    self.touch();
+	    let mut span = OpSpan::start("put");
+	    span.record("key_size", key.len() as u64);
+	    span.record("bytes", value.len() as u64);
+	    {
+		let df = self.reserve_current()?;		// Protect changes to 'current' while we do our append
+		span.record("file_id", df.id as u64);
+		let blob = self.spill(df.id, key, value)?;	// Before the locks, it's flushed out then and there
+		let (offset, seq) = self.index_put(&df, key, expires, |seq| match &blob {	// Append a PUT record
+		    Some(ptr) => df.put_blob(key, ptr, seq, expires, false),
+		    None => df.put(key, value, seq, expires, self.compression, false),
+		})?;
+		self.sync_write(&df)?;				// Ensure on-disk stability outside the index lock
+		self.publish(ChangeEvent { key: key.to_vec(), op: ChangeOp::Put, value: Some(value.to_vec()), seq, fileid: df.id, offset });
+	    }							// Drop the reader lock
+	    Ok(true)
+	}
+
+	//
+	// Append the PUT record 'append' makes of 'key' to 'df', the current datafile, given its seq, and point
+	// the keymap at it, returning its offset and seq.  'expires' is the record's.
+	//
+	fn index_put(&self, df: &BitcaskDatafile, key: &[u8], expires: u64, append: impl FnOnce(u64) -> Result<(i64, i32, u64), io::Error>) -> Result<(i64, u64), io::Error> {
+	    let mut map = self.keymap.write(key);		// Hold the index across the append so it agrees with the log order
+	    let _numbering = self.numbering.lock().unwrap();	// And keep the numbers going up along the log
+	    let seq = self.sequence.load(Ordering::SeqCst) + 1;
+	    let (offset, stored, timestamp) = append(seq)?;
+	    self.bloom_insert(key);
+	    self.sequence.store(seq, Ordering::SeqCst);		// Only once it's in the log, a failed append doesn't use up a number
+	    if let Some(old) = map.insert(key.to_vec(), *BitcaskKeymapEntry::new(stored, df.id, offset, seq, expires, timestamp))? {
+		self.note_dead(old.fileid, key.len(), old.value_size);
+		self.uncache(key);
+	    }
+	    Ok((offset, seq))
+	}
+
+	//
+	// Delete a (potentially) existing KV
+	//
+	pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool, BitcaskError>  {
+	    let key = key.as_ref();
+	    let _stripe = self.key_lock(key);			// Serialize with any other writer of this key
+	    Ok(self.delete_locked(key)?)
+	}
+
+	//
+	// The delete itself, for a caller holding the key's stripe.
+	//
+	fn delete_locked(&self, key: &[u8]) -> Result<bool, io::Error> {
+	    self.writable()?;
+	    self.touch();
+	    let mut span = OpSpan::start("delete");
+	    span.record("key_size", key.len() as u64);
+	    {
+		let df = self.reserve_current()?;		// Protect changes to 'current' while we do our delete
+		span.record("file_id", df.id as u64);
+		let (offset, seq) = {
+		    let mut map = self.keymap.write(key);	// Protect the data structure while we do our removal
+		    let _numbering = self.numbering.lock().unwrap();
+		    let seq = self.sequence.load(Ordering::SeqCst) + 1;
+		    let (offset, stored, _) = df.delete(key, seq, false)?;	// Append a DELETE record
+		    self.sequence.store(seq, Ordering::SeqCst);
+		    if let Some(old) = map.remove(key) {	// Remove it from the index
+			self.note_dead(old.fileid, key.len(), old.value_size);
+			self.uncache(key);
+		    }
+		    self.note_dead(df.id, key.len(), stored);	// A merge has no use for the tombstone itself
+		    (offset, seq)
+		};						// Drop the writer lock
+		self.sync_write(&df)?;
+		self.publish(ChangeEvent { key: key.to_vec(), op: ChangeOp::Delete, value: None, seq, fileid: df.id, offset });
+	    }							// Drop the reader lock
+	    Ok(true)
+	}
+
+	//
+	// Apply every put and delete in the batch atomically.  They're appended between a BEGIN and a COMMIT
+	// record in a single write, and recovery throws away a batch whose COMMIT never made it to disk.
+	// The whole batch shares one sequence number, and it's never split across datafiles, counting as
+	// just one record against set_max_records_per_file().
+	//
+	pub fn write(&self, batch: &WriteBatch) -> Result<bool, BitcaskError> {
+	    self.writable()?;
+	    if let Some(size) = batch.ops.iter().filter_map(|(_, value)| value.as_ref().map(Vec::len)).find(|size| *size > self.max_value_size) {
+		return Err(BitcaskError::ValueTooLarge { size, limit: self.max_value_size });
+	    }
+	    if batch.is_empty() {
+		return Ok(true);
+	    }
+	    let adding: u64 = batch.ops.iter()
+		.filter_map(|(key, value)| value.as_ref().map(|value| BitcaskDatafileRecord::disk_size(key.len(), value.len() as i32, self.alignment) as u64))
+		.sum();
+	    if adding > 0 {
+		self.make_room(adding)?;				// A batch of nothing but deletes always goes through
+	    }
+	    self.touch();
+	    let mut stripes: Vec<usize> = batch.ops.iter().map(|(key, _)| self.stripe(key)).collect();
+	    stripes.sort_unstable();					// Always take them in the same order, or two batches could deadlock
+	    stripes.dedup();
+	    let _stripes: Vec<MutexGuard<'_, ()>> = stripes.iter().map(|&i| self.key_locks[i].lock().unwrap()).collect();
+	    {
+		let df = self.reserve_current()?;			// Protect changes to 'current' while we do our append
+		let (offsets, seq) = {
+		    let mut map = self.keymap.write_keys(batch.ops.iter().map(|(key, _)| key.as_slice()));	// Hold the index across the append so it agrees with the log order
+		    let _numbering = self.numbering.lock().unwrap();
+		    let seq = self.sequence.load(Ordering::SeqCst) + 1;
+		    let mut recs = vec![BitcaskDatafileRecord::new(&[], BitcaskDatafileRectype::BEGIN, "", seq)?];
+		    for (key, value) in &batch.ops {
+			recs.push(match value {
+			    Some(value) => BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::PUT, value, seq)?.compressed(self.compression),
+			    None => BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::DELETE, "", seq)?,
+			});
+		    }
+		    recs.push(BitcaskDatafileRecord::new(&[], BitcaskDatafileRectype::COMMIT, "", seq)?);
+		    let offsets = df.append_all(&recs, false)?;
+		    self.sequence.store(seq, Ordering::SeqCst);
+		    self.note_dead(df.id, 0, 0);			// Nothing ever looks at the markers again
+		    self.note_dead(df.id, 0, 0);
+		    for (((key, value), &offset), rec) in batch.ops.iter().zip(&offsets[1..]).zip(&recs[1..]) {
+			let old = match value {
+			    Some(_) => {
+				self.bloom_insert(key);
+				map.insert(key.clone(), *BitcaskKeymapEntry::new(df.stored_value_size(rec), df.id, offset, seq, 0, rec.timestamp))?
+			    },
+			    None => {
+				self.note_dead(df.id, key.len(), df.stored_value_size(rec));
+				map.remove(key)
+			    },
+			};
+			if let Some(old) = old {
+			    self.note_dead(old.fileid, key.len(), old.value_size);
+			    self.uncache(key);
+			}
+		    }
+		    (offsets, seq)
+		};							// Drop the writer lock
+		self.sync_write(&df)?;
+		for ((key, value), &offset) in batch.ops.iter().zip(&offsets[1..]) {
+		    let op = if value.is_some() { ChangeOp::Put } else { ChangeOp::Delete };
+		    self.publish(ChangeEvent { key: key.clone(), op, value: value.clone(), seq, fileid: df.id, offset });
+		}
+	    }								// Drop the reader lock
+	    Ok(true)
+	}
+
+	//
+	// Ask to be told about every put and delete from now on, however it was made: singly, in a batch, by an
+	// import, or replicated from a primary.  Merges move KVs without changing them, and what archiving
+	// removes isn't published either.  Events are sent once the mutation is durable, so two different keys
+	// can arrive out of 'seq' order, but the changes to any one key arrive in order.  Dropping the Receiver
+	// unsubscribes.
+	//
+	pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+	    self.subscribe_to(ChangeFilter::All)
+	}
+
+	//
+	// As subscribe(), but only told about changes to 'key', say to pick up a new setting without polling.
+	//
+	pub fn watch(&self, key: impl AsRef<[u8]>) -> Receiver<ChangeEvent> {
+	    self.subscribe_to(ChangeFilter::Key(key.as_ref().to_vec()))
+	}
+
+	//
+	// As subscribe(), but only told about changes to the keys that start with 'prefix'.
+	//
+	pub fn watch_prefix(&self, prefix: impl AsRef<[u8]>) -> Receiver<ChangeEvent> {
+	    self.subscribe_to(ChangeFilter::Prefix(prefix.as_ref().to_vec()))
+	}
+
+	fn subscribe_to(&self, filter: ChangeFilter) -> Receiver<ChangeEvent> {
+	    let (tx, rx) = channel();
+	    self.subscribers.lock().unwrap().push((filter, tx));
+	    rx
+	}
+
+	//
+	// Hand an event to every subscriber who wants it, forgetting those who have gone away, and buffer it for
+	// pollers.  A watcher who has gone away is only noticed the next time a key it watched changes.
+	//
+	fn publish(&self, event: ChangeEvent) {
+	    #[cfg(feature = "metrics")]
+	    {
+		let ops = if event.op == ChangeOp::Put { &self.metrics.puts } else { &self.metrics.deletes };
+		Metrics::count(ops, 1);
+		Metrics::count(&self.metrics.bytes_written, (event.key.len() + event.value.as_ref().map_or(0, Vec::len)) as u64);
+	    }
+	    {
+		let mut subscribers = self.subscribers.lock().unwrap();
+		subscribers.retain(|(filter, tx)| !filter.matches(&event.key) || tx.send(event.clone()).is_ok());
+	    }
+	    let mut changes = self.changes.lock().unwrap();
+	    if changes.len() == BITCASK_CHANGE_BUFFER_SIZE {
+		changes.pop_front();				// A slow poller loses the oldest
+		self.changes_dropped.fetch_add(1, Ordering::SeqCst);
+	    }
+	    changes.push_back(event);
+	}
+
+	//
+	// The pull alternative to subscribe(): take every change event buffered since the last drain, as
+	// (seq, event) in sequence order.  Only the most recent BITCASK_CHANGE_BUFFER_SIZE are kept, see
+	// changes_dropped() for how many a poller missed.
+	//
+	pub fn drain_changes(&self) -> Vec<(u64, ChangeEvent)> {
+	    let mut drained: Vec<(u64, ChangeEvent)> = {
+		let mut changes = self.changes.lock().unwrap();
+		changes.drain(..).map(|event| (event.seq, event)).collect()
+	    };
+	    drained.sort_by_key(|(seq, _)| *seq);		// Publishing happens after the sync, which can reorder keys
+	    drained
+	}
+
+	//
+	// How many change events were discarded because drain_changes() wasn't called often enough.
+	//
+	pub fn changes_dropped(&self) -> u64 {
+	    self.changes_dropped.load(Ordering::SeqCst)
+	}
+
+	//
+	// Return a Vec containing all the keys in the database
+	//
+	pub fn list_keys(&self) -> Vec<Vec<u8>> {
+	    let mut keyvec = Vec::<Vec<u8>>::new();		// Establish scope outside the lock hold region
+	    let now = now_millis();
+	    {
+		let map = self.keymap.read_all();		// Protect the data structure while we do our iterator
+		for (key, entry) in map.iter() {
+		    if !entry.is_expired(now) {
+			keyvec.push(key.to_vec());
+		    }
+		}
+	    }							// Drop the reader lock
+	    keyvec
+	}
+
+	//
+	// As list_keys(), but only the keys that start with 'prefix'.
+	//
+	pub fn list_keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+	    let now = now_millis();
+	    let map = self.keymap.read_all();
+	    map.iter().filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now)).map(|(key, _)| key.to_vec()).collect()
+	}
+
+	//
+	// How many live keys start with 'prefix', and the bytes of the records they're in, from the index alone.
+	//
+	pub fn prefix_usage(&self, prefix: &[u8]) -> (usize, u64) {
+	    let now = now_millis();
+	    let map = self.keymap.read_all();
+	    map.iter().filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+		.fold((0, 0), |(keys, bytes), (key, entry)| (keys + 1, bytes + BitcaskDatafileRecord::disk_size(key.len(), entry.value_size, self.alignment) as u64))
+	}
+
+	//
+	// The keyspace named 'name', see Bucket.  Nothing is written, a bucket is there as soon as it's asked for.
+	//
+	pub fn bucket(&self, name: &str) -> Result<Bucket<'_>, BitcaskError> {
+	    Bucket::new(self, name)
+	}
+
+	//
+	// Every key in the database, read as they're asked for rather than all at once, see entries().
+	//
+	pub fn keys(&self) -> impl Iterator<Item = Result<Vec<u8>, BitcaskError>> + '_ {
+	    self.live_records().map(|item| item.map(|rec| rec.key).map_err(BitcaskError::from))
+	}
+
+	//
+	// Every value in the database, see entries().
+	//
+	pub fn values(&self) -> impl Iterator<Item = Result<Vec<u8>, BitcaskError>> + '_ {
+	    self.live_records().map(|item| Ok(self.value_of(&*item?)?))
+	}
+
+	//
+	// Every KV in the database, read from the datafiles as it's asked for, so nothing like all of it is
+	// in memory at once.  They come in the order they lie in the datafiles, oldest datafile first.
+	//
+	// Nothing is locked in between items, so puts, deletes, and merges carry on while it runs.  A KV that's
+	// there throughout is always seen, but one that's written, or moved by a merge, after it has been seen
+	// can be seen again, and one that's written or deleted part way through may or may not be seen at all.
+	//
+	pub fn entries(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+	    self.live_records().map(|item| {
+		let rec = item?;
+		let value = self.value_of(&rec)?;
+		Ok((rec.key, value))
+	    })
+	}
+
+	//
+	// Fold every KV in the database into 'acc', as Erlang's bitcask:fold/3 does: 'f' is handed the
+	// accumulator and each key and value in turn, in datafile order, and returns the next accumulator.
+	// They're read one at a time, as entries() reads them, and with the same guarantees.  The first error
+	// stops the fold.
+	//
+	pub fn fold<F, Acc>(&self, mut f: F, acc: Acc) -> Result<Acc, BitcaskError>
+	where
+	    F: FnMut(Acc, Vec<u8>, Vec<u8>) -> Acc,
+	{
+	    self.touch();
+	    self.entries().try_fold(acc, |acc, item| item.map(|(key, value)| f(acc, key, value)))
+	}
+
+	//
+	// Everything the store has counted since it was opened, along with how many keys it holds now, in the
+	// Prometheus text exposition format, for a /metrics endpoint to serve.  Every name starts "bitcask_".
+	//
+	#[cfg(feature = "metrics")]
+	pub fn metrics(&self) -> String {
+	    let mut out = String::new();
+	    self.metrics.render(&mut out, "bitcask_");
+	    out.push_str(&format!("# HELP bitcask_keys Live keys.\n# TYPE bitcask_keys gauge\nbitcask_keys {}\n", self.len()));
+	    out
+	}
+
+	//
+	// Keep the keymap ordered by key, so range() and scan_prefix() only look at the keys they return, or
+	// go back to hashing it, which makes every other lookup quicker.  The keymap is rebuilt to suit.
+	//
+	pub fn set_ordered_index(&mut self, ordered: bool) {
+	    if self.keymap.is_on_disk() {
+		log::warn!("the keymap is on disk, it stays hashed");
+		return;
+	    }
+	    let count = self.keymap.shards.len();
+	    self.keymap = self.keymap.take().and_then(|keymap| BitcaskKeymapShards::new(keymap.into_kind(ordered), count)).expect("a keymap in memory can always be split");
+	}
+
+	//
+	// Split the keymap into this many shards, each with a lock of its own.  More shards let more writers
+	// of different keys in at once, at the cost of anything that looks at the whole keymap, such as
+	// list_keys() or a merge, having more locks to take.  The default is 16.  A keymap on disk keeps the
+	// shards it was opened with, see BitcaskOptions::keymap_shards().
+	//
+	pub fn set_keymap_shards(&mut self, shards: usize) {
+	    if self.keymap.is_on_disk() {
+		log::warn!("the keymap is on disk, it stays in {} shards", self.keymap.shards.len());
+		return;
+	    }
+	    self.keymap = self.keymap.take().and_then(|keymap| BitcaskKeymapShards::new(keymap, shards)).expect("a keymap in memory can always be split");
+	}
+
+	//
+	// The KVs whose keys fall within 'range', in order of their keys.  The keys are picked out up front,
+	// their values are read as they're asked for, and any deleted in the meantime are left out.  Without
+	// the ordered index this has to look at every key in the database.
+	//
+	pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+	    let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+	    let (start, end) = (bound(range.start_bound()), bound(range.end_bound()));
+	    let keys = self.keymap.read_all().range_keys((start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice)), now_millis());
+	    keys.into_iter().filter_map(|key| match self.get(&key) {
+		Ok(Some(value)) => Some(Ok((key, value))),
+		Ok(None) => None,
+		Err(e) => Some(Err(e)),
+	    })
+	}
+
+	//
+	// The KVs whose keys start with 'prefix', in order, as range() hands them over.
+	//
+	pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+	    let start = prefix.as_ref().to_vec();
+	    let mut end = start.clone();				// The first key after all those with the prefix
+	    while end.last() == Some(&u8::MAX) {
+		end.pop();
+	    }
+	    let end = match end.last_mut() {
+		Some(last) => {
+		    *last += 1;
+		    Bound::Excluded(end)
+		},
+		None => Bound::Unbounded,				// No such key, every key from the prefix on has it
+	    };
+	    self.range((Bound::Included(start), end))
+	}
+
+	fn live_records(&self) -> BitcaskLiveRecords<'_> {
+	    BitcaskLiveRecords { cask: self, fileid: 0, offset: 0, records: None, now: now_millis() }
+	}
+
+	//
+	// The ID of the oldest datafile, archived or current, newer than 'after', if there is one.
+	//
+	fn datafile_after(&self, after: BitcaskFileID) -> Option<BitcaskFileID> {
+	    let current = self.active_file_id();
+	    let archived = self.datafiles.read().unwrap().keys().filter(|id| **id > after).min().copied();
+	    archived.or((current > after).then_some(current))
+	}
+
+	//
+	// Have get() read into a buffer borrowed from a small per-thread pool rather than allocating its
+	// intermediate buffers afresh on every call.  The String handed back is still the caller's own.
+	//
+	pub fn set_read_buffer_pool(&mut self, enabled: bool) {
+	    self.pool_read_buffers = enabled;
+	}
+
+	//
+	// Keep the most recently read values in memory, up to 'budget' bytes of keys and values, so that gets
+	// of them needn't read the datafiles.  Puts and deletes forget what they replace.  0, the default,
+	// means no cache.  Any values already cached are forgotten.
+	//
+	pub fn set_cache_size(&mut self, budget: usize) {
+	    self.cache = (budget > 0).then(|| Mutex::new(ValueCache::new(budget)));
+	}
+
+	//
+	// Keep a Bloom filter of the keys, so a get of a key that doesn't exist can usually tell without
+	// taking any keymap lock.  It's worth it when a good many gets miss.  The filter is rebuilt by every
+	// merge, which is when deleted keys stop counting against it.
+	//
+	pub fn set_bloom_filter(&mut self, enabled: bool) {
+	    self.bloom = enabled.then(|| RwLock::new(Self::build_bloom(&self.keymap.read_all())));
+	}
+
+	fn build_bloom<G: Deref<Target = BitcaskKeymap>>(map: &BitcaskKeymapGuard<G>) -> BloomFilter {
+	    let keys: Vec<&[u8]> = map.iter().map(|(key, _)| key).collect();
+	    let bloom = BloomFilter::new(keys.len() * 2);		// Room to grow before the next merge
+	    for key in keys {
+		bloom.insert(key);
+	    }
+	    bloom
+	}
+
+	//
+	// Build the filter afresh from the keymap, for a caller holding every shard of it, so no key can be
+	// added in the meantime and be left out.
+	//
+	fn rebuild_bloom<G: Deref<Target = BitcaskKeymap>>(&self, map: &BitcaskKeymapGuard<G>) {
+	    if let Some(bloom) = &self.bloom {
+		*bloom.write().unwrap() = Self::build_bloom(map);
+	    }
+	}
+
+	//
+	// Add a key to the filter, before it's in the keymap, so that nobody can find it in one but not the other.
+	//
+	fn bloom_insert(&self, key: &[u8]) {
+	    if let Some(bloom) = &self.bloom {
+		bloom.read().unwrap().insert(key);
+	    }
+	}
+
+	//
+	// Move datafiles out of the store once their newest record is older than 'age'.  A sweep (archive_aged(),
+	// which every merge also runs first) hands the live KVs of each such datafile to 'archiver', then drops
+	// them from the keymap and removes the datafile.  Only the oldest datafiles go, in order, stopping at
+	// the first one that's still too young, so a DELETE can never be archived while the PUT it hides stays.
+	//
+	// The consistency model: a KV is in the hot store until its datafile is removed, with get() seeing it as
+	// usual up to then, and gone after.  The archiver hears about every KV at least once; if it fails the
+	// datafile is left where it is, and if we crash between it succeeding and the datafile being removed, it
+	// is called again for the same KVs by the next sweep.  A KV that's overwritten while the sweep runs may
+	// still be archived but its newer value stays in the store.  Removals aren't published to subscribers.
+	// The history in an archived datafile is gone, so truncate_to_sequence() can't rewind past it.
+	//
+	pub fn set_max_datafile_age(&mut self, age: Duration,
+				    archiver: impl Fn(BitcaskFileID, &[(Vec<u8>, Vec<u8>)]) -> Result<bool, BitcaskError> + Send + Sync + 'static) {
+	    self.archival = Some((age, Box::new(archiver)));
+	}
+
+	//
+	// Cap how many sealed datafiles the store keeps, or how old they get, for workloads that only ever add
+	// and so would grow without end.  A sweep (apply_retention(), which every merge also runs first) retires
+	// the oldest datafiles past the policy, in order, stopping at the first one that isn't: it carries the
+	// KVs still live there forward into the current datafile, the same values with the same expiries but new
+	// sequence numbers, unannounced to subscribers, then copies the datafile whole into 'archive', if there
+	// is one, and removes it.  Nothing live is lost, so what's freed is the dead bytes, and a store whose
+	// data is mostly live is mostly rewritten.
+	//
+	// As with set_max_datafile_age(), going oldest first means no DELETE is retired while the PUT it hides
+	// stays, and the history in a retired datafile is gone, so truncate_to_sequence() can't rewind past it.
+	// If we crash after a datafile's KVs are carried forward but before it's removed, the next sweep carries
+	// forward nothing and archives it again.  None turns retention off.
+	//
+	pub fn set_retention_policy(&mut self, policy: Option<RetentionPolicy>, archive: Option<Box<dyn Storage>>) {
+	    self.retention = policy.map(|policy| (policy, archive));
+	}
+
+	//
+	// Pad the records of the datafiles we create from now on to a multiple of 'alignment', a power of two
+	// and at least 4.  Each datafile records its own alignment in its header, so files written with
+	// different settings live side by side, and a merge rewrites everything it compacts at the new one.
+	// The current datafile is started over if nothing has been written to it yet, otherwise it keeps its
+	// alignment until it's rotated out.
+	//
+	pub fn set_record_alignment(&mut self, alignment: usize) -> Result<bool, BitcaskError> {
+	    self.writable()?;
+	    if alignment < BITCASK_RECORD_ALIGNMENT || !alignment.is_power_of_two() {
+		return Err(BitcaskError::InvalidArgument(format!("bad record alignment {}", alignment)));
+	    }
+	    self.alignment = alignment;
+	    let current = self.current.get_mut().unwrap();
+	    if current.alignment != alignment && current.size()? == current.start {
+		*current = *BitcaskDatafile::new(&*self.storage, current.id - 1, alignment, self.cipher.clone())?;	// Replaces the empty file
+		self.alignments.get_mut().unwrap().insert(current.id, alignment);
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Compress the values of the records we write from now on, those that get smaller for it anyway.
+	// Each record says how its value is stored, so whatever was written before is still read back fine.
+	//
+	pub fn set_compression(&mut self, compression: Compression) {
+	    self.compression = compression;
+	}
+
+	//
+	// Choose what get() and its relatives do when a record fails its checksum, see CorruptionPolicy.
+	// Scans, merges and the like always fail, there's no skipping a record they'd otherwise copy or drop.
+	//
+	pub fn set_corruption_policy(&mut self, policy: CorruptionPolicy) {
+	    self.corruption_policy = policy;
+	}
+
+	//
+	// Cap the number of records in each datafile, the current one is rotated as soon as it's full.
+	// None (the default) means no cap.
+	//
+	pub fn set_max_records_per_file(&mut self, max: Option<usize>) {
+	    self.max_records_per_file = max;
+	}
+
+	//
+	// Cap the size of each datafile, the current one is rotated as soon as it's reached 'max' bytes.  The
+	// write that takes it there still goes in whole, so a datafile can overshoot by up to one record, or
+	// one batch.  None (the default) means no cap.
+	//
+	pub fn set_max_datafile_size(&mut self, max: Option<u64>) {
+	    self.max_datafile_size = max;
+	}
+
+	//
+	// Rotate the current datafile once it's been current for 'interval', so that however little is written,
+	// a crash never leaves more than that much unhinted for the next open to scan, and each datafile holds
+	// a stretch of time a backup can go by.  It's the first write after the interval that rotates, an idle
+	// store has nothing to rotate for, and a datafile with nothing in it is never rotated out.  None (the
+	// default) means no interval.
+	//
+	pub fn set_rotation_interval(&mut self, interval: Option<Duration>) {
+	    self.rotation_interval = interval;
+	}
+
+	//
+	// Cap the bytes the datafiles and blob files take up, disk_bytes and blob_bytes in stats().  A put or
+	// batch that would take them past 'quota' first runs a merge, there and then, if that would reclaim
+	// enough to make room, and fails with QuotaExceeded if there's still none.  Deletes always go through,
+	// they're how room is made.  The merge itself needs room for the live data while it runs, and hints
+	// files and the like aren't counted, so leave some headroom below what the disk can really take.  None
+	// (the default) means no quota.
+	//
+	pub fn set_disk_quota(&mut self, quota: Option<u64>) {
+	    self.disk_quota = quota;
+	}
+
+	//
+	// Have put() and the like write values of 'threshold' bytes or more to a blob file, see the blob module,
+	// with just a pointer to it in the record, so a merge copies the pointer rather than the value.  The
+	// blob is flushed out before its record is written, whatever the sync policy, so no record can point at
+	// a blob a crash lost; an encrypted store seals it for its key.  A blob file goes at the first merge that
+	// finds nothing pointing into it, until then overwriting or deleting a big value frees nothing.  Batches
+	// keep their values in the records, as does a follower, whatever its threshold, and the values of
+	// records already written stay where they are.  None (the default) keeps every value in its record.
+	//
+	pub fn set_blob_threshold(&mut self, threshold: Option<usize>) {
+	    self.blob_threshold = threshold;
+	}
+
+	//
+	// Have merges keep the tombstones of keys that are still deleted for 'retention' after the delete, rather
+	// than dropping them at the first merge, so a follower that reads the datafiles now and again still sees
+	// the delete as such.  None (the default) keeps none, and a deleted key costs nothing once it's merged.
+	//
+	pub fn set_tombstone_retention(&mut self, retention: Option<Duration>) {
+	    self.tombstone_retention = retention;
+	}
+
+	//
+	// Save the keymap to keydir.snapshot at the first rotation once 'interval' has passed since it was last
+	// saved, and at shutdown, so the next open only has to read what's been written since, see
+	// save_keydir_snapshot().  None (the default) saves none, and open reads every hints file.
+	//
+	pub fn set_keydir_snapshot_interval(&mut self, interval: Option<Duration>) {
+	    self.snapshot_interval = interval;
+	}
+
+	//
+	// Save the keymap, as it is now, along with which datafiles it covers and how far into the current
+	// one, checksummed, and sealed if the store is encrypted.  Writers are held off while it's copied
+	// out.  It's written under a temporary name and renamed over the last one once it's durable.
+	//
+	pub fn save_keydir_snapshot(&self) -> Result<bool, BitcaskError> {
+	    self.writable()?;
+	    let _merging = self.merge_lock.lock().unwrap();	// Keep the datafiles where they are while we look
+	    Ok(self.save_snapshot_locked()?)
+	}
+
+	//
+	// As save_keydir_snapshot(), for a caller that holds the merge lock already.
+	//
+	fn save_snapshot_locked(&self) -> Result<bool, io::Error> {
+	    let mut buf: Vec<u8> = BITCASK_SNAPSHOT_MAGIC.iter().copied().chain(BITCASK_FORMAT_VERSION.to_le_bytes()).collect();
+	    {
+		let df = self.current.read().unwrap();		// No rotation while we pick the moment
+		let map = self.keymap.read_all();		// Writers append under their shard, so none can now
+		let written = {
+		    let _numbering = self.numbering.lock().unwrap();	// Between appends, so it's whole records
+		    df.written.load(Ordering::SeqCst)
+		};
+		let mut files: Vec<(BitcaskFileID, u64)> = Vec::new();
+		for archived in self.datafiles.read().unwrap().values() {
+		    files.push((archived.id, archived.size()?));
+		}
+		files.sort();
+		files.push((df.id, written));
+		buf.extend_from_slice(&(files.len() as u64).to_le_bytes());
+		for (id, size) in files {
+		    buf.extend_from_slice(&id.to_le_bytes());
+		    buf.extend_from_slice(&size.to_le_bytes());
+		}
+		buf.extend_from_slice(&(map.iter().count() as u64).to_le_bytes());
+		for (key, entry) in map.iter() {
+		    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+		    buf.extend_from_slice(key);
+		    buf.extend_from_slice(&entry.fileid.to_le_bytes());
+		    buf.extend_from_slice(&entry.offset.to_le_bytes());
+		    buf.extend_from_slice(&entry.value_size.to_le_bytes());
+		    buf.extend_from_slice(&entry.generation.to_le_bytes());
+		    buf.extend_from_slice(&entry.expires.to_le_bytes());
+		    buf.extend_from_slice(&entry.timestamp.to_le_bytes());
+		}
+	    }							// Drop the locks, writes can go on
+	    let crc = crc32fast::hash(&buf);
+	    buf.extend_from_slice(&crc.to_le_bytes());
+	    if let Some(cipher) = &self.cipher {
+		buf = cipher.seal(&buf, BITCASK_SNAPSHOT_FILE.as_bytes())?;	// It has every key in it
+	    }
+	    let file = self.storage.create(BITCASK_SNAPSHOT_TMP_FILE)?;
+	    file.append(&buf)?;
+	    file.sync()?;
+	    self.storage.rename(BITCASK_SNAPSHOT_TMP_FILE, BITCASK_SNAPSHOT_FILE)?;
+	    self.snapshotted.store((self.opened.elapsed().as_millis() as u64).max(1), Ordering::SeqCst);
+	    Ok(true)
+	}
+
+	//
+	// Save the keymap if set_keydir_snapshot_interval() says it's time, unless a merge is running, then
+	// it'll be time at the next rotation just the same.
+	//
+	fn snapshot_if_due(&self) {
+	    let Some(interval) = self.snapshot_interval else {
+		return;
+	    };
+	    let last = self.snapshotted.load(Ordering::SeqCst);
+	    if last != 0 && (self.opened.elapsed().as_millis() as u64).saturating_sub(last) < interval.as_millis() as u64 {	// 'last' is 1 for one saved in the first ms
+		return;
+	    }
+	    let Ok(_merging) = self.merge_lock.try_lock() else {
+		return;
+	    };
+	    if let Err(e) = self.save_snapshot_locked() {
+		log::warn!("{}: couldn't be saved, the next open will read the datafiles: {}", BITCASK_SNAPSHOT_FILE, e);
+	    }
+	}
+
+	//
+	// Choose when a merge is worth it, see MergePolicy.
+	//
+	pub fn set_merge_policy(&mut self, policy: MergePolicy) {
+	    self.merge_policy = policy;
+	}
+
+	//
+	// Return the current datafile, read-locked, with room reserved in it for one more record.
+	// If it's full, rotate it first.
+	//
+	fn reserve_current(&self) -> Result<RwLockReadGuard<'_, BitcaskDatafile>, io::Error> {
+	    loop {
+		let df = self.current.read().unwrap();
+		let written = df.written.load(Ordering::SeqCst);
+		let full = self.max_datafile_size.is_some_and(|max| written >= max && written > df.start);	// Never rotate out an empty one
+		let since = (self.opened.elapsed().as_millis() as u64).saturating_sub(self.current_since.load(Ordering::SeqCst));
+		let old = self.rotation_interval.is_some_and(|interval| since >= interval.as_millis() as u64 && written > df.start);
+		if !full && !old && df.reserve(self.max_records_per_file) {
+		    return Ok(df);
+		}
+		let full = df.id;
+		drop(df);					// Rotation needs the writer lock
+		self.rotate_from(full)?;
+	    }
+	}
+
+	//
+	// Share fsync scheduling with other stores.  Stores handed the same coordinator have their flushes
+	// batched together, each still waits for its own data to be stable before a write returns.
+	//
+	pub fn set_sync_coordinator(&mut self, syncer: Arc<SyncCoordinator>) {
+	    self.syncer = Some(syncer);
+	}
+
+	//
+	// Choose when writes are flushed to stable storage, see SyncPolicy.  Whatever the policy, sync() and
+	// shutdown() flush everything written so far, and a merge's output is flushed before it's used.
+	//
+	pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+	    self.sync_policy = policy;
+	    self.flusher = match policy {
+		SyncPolicy::Every(interval) => Some(BitcaskFlusher::new(interval)),
+		_ => None,
+	    };
+	}
+
+	//
+	// Flush one of our datafiles, through the coordinator if we have one.
+	//
+	fn sync_datafile(&self, df: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    #[cfg(feature = "metrics")]
+	    let started = Instant::now();
+	    let result = match &self.syncer {
+		Some(syncer) => syncer.sync(&df.file),
+		None => df.sync(),
+	    };
+	    #[cfg(feature = "metrics")]
+	    self.metrics.fsync.observe(started.elapsed());
+	    result
+	}
+
+	//
+	// Flush the datafile a write just went to, if the sync policy says the write has to wait for it.
+	//
+	fn sync_write(&self, df: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    match (self.sync_policy, &self.flusher) {
+		(SyncPolicy::Always, _) => self.group_sync(df),
+		(SyncPolicy::Every(_), Some(flusher)) => {
+		    flusher.mark(&df.file);
+		    Ok(false)
+		},
+		_ => Ok(false),
+	    }
+	}
+
+	//
+	// Flush a datafile for a write that has just gone to it, by group commit: writers that arrive while a
+	// flush is under way queue up behind it, and the next flush, which one of them does, is for all of
+	// them.  Anyone whose write a flush that started after it already covered doesn't wait at all.  Each
+	// writer still returns only once its own write is stable, so one fsync does for any number of them.
+	//
+	fn group_sync(&self, df: &BitcaskDatafile) -> Result<bool, io::Error> {
+	    let ours = df.written.load(Ordering::SeqCst);		// Our write is somewhere before here
+	    let mut syncing = df.syncing.lock().unwrap();
+	    loop {
+		if df.synced.load(Ordering::SeqCst) >= ours {
+		    return Ok(true);					// Somebody else's sync covered ours
+		}
+		if !*syncing {
+		    break;
+		}
+		syncing = df.synced_more.wait(syncing).unwrap();
+	    }
+	    *syncing = true;						// It's up to us, for everyone who's waiting
+	    drop(syncing);
+	    let through = df.written.load(Ordering::SeqCst);
+	    let result = self.sync_datafile(df);
+	    if result.is_ok() {
+		df.synced.fetch_max(through, Ordering::SeqCst);
+	    }
+	    *df.syncing.lock().unwrap() = false;
+	    df.synced_more.notify_all();				// Whoever it didn't cover, one of them goes next
+	    result
+	}
+
+	//
+	// Fail with ReadOnly if we were opened read-only, and ShutDown if we've been shut down, for everything
+	// that would change the files.
+	//
+	fn writable(&self) -> Result<(), io::Error> {
+	    if self.read_only {
+		return Err(BitcaskError::ReadOnly.into());
+	    }
+	    match self.shut_down.load(Ordering::SeqCst) {
+		true => Err(BitcaskError::ShutDown.into()),
+		false => Ok(()),
+	    }
+	}
+
+	//
+	// Sync out the currently open data file.
+	//
+	pub fn sync(&self) -> Result<bool, BitcaskError> {
+	    {
+		let df = self.current.read().unwrap();		// Protect changes to 'current' while we do our sync
+		self.sync_datafile(&df)?;
+	    }							// Drop the reader lock
+	    Ok(true)
+	}
+
+	//
+	// Describe the on-disk format this code reads and writes.
+	//
+	pub fn format_descriptor() -> FormatDescriptor {
+	    BITCASK_FORMAT
+	}
+
+	//
+	// Bring the datafiles of the store in 'dirpath' up to format version 'target_version', for now only
+	// ever the current one, returning how many had to be rewritten.  The store mustn't be open.  Every
+	// version we can read has the same records as the current one, so each datafile of an older one is
+	// copied, record for record, behind a current header, under a temporary name, and renamed over the
+	// original once it's durable: a crash leaves each datafile either as it was or migrated.  Hints files
+	// are regenerated in the current layout, except for encrypted datafiles, which would need the key, and
+	// whose old hints still work.  Datafiles older than we can read can't be migrated either, they're an
+	// InvalidFormat.
+	//
+	pub fn migrate(dirpath: &str, target_version: u32) -> Result<usize, BitcaskError> {
+	    Self::migrate_storage(&FileStorage::new(dirpath), target_version)
+	}
+
+	pub fn migrate_storage(storage: &dyn Storage, target_version: u32) -> Result<usize, BitcaskError> {
+	    if target_version != BITCASK_FORMAT_VERSION {
+		return Err(BitcaskError::InvalidArgument(format!("can only migrate to version {}, not {}", BITCASK_FORMAT_VERSION, target_version)));
+	    }
+	    let _lock = Self::lock_offline(storage)?;
+	    Self::remove_snapshot(storage)?;					// Records may move
+	    let names = storage.list()?;
+	    for name in names.iter().filter(|name| name.ends_with(BITCASK_MIGRATE_TMP_SUFFIX)) {
+		storage.remove(name)?;					// From a migration that crashed, the original is intact
+	    }
+	    let mut migrated: usize = 0;
+	    for id in Self::datafile_ids(&names) {
+		if Self::migrate_datafile(storage, id)? {
+		    migrated += 1;
+		}
+	    }
+	    Ok(migrated)
+	}
+
+	//
+	// Keep everyone else out of 'storage' while we work on it with the store closed, see Storage::lock().
+	//
+	fn lock_offline(storage: &dyn Storage) -> Result<StorageLock, BitcaskError> {
+	    storage.lock(BITCASK_LOCK_FILE).map_err(|e| match e.kind() {
+		io::ErrorKind::WouldBlock => BitcaskError::Locked(e.to_string()),
+		_ => BitcaskError::from(e),
+	    })
+	}
+
+	//
+	// Salvage what can be salvaged of the store in 'dirpath', which mustn't be open, so that it opens again.
+	// Every datafile is read without a key, going by record sizes and checksums: whatever lies between the
+	// whole records is copied to a sidecar, "<name>.corrupt", as the offset and length it was found at,
+	// both u64s, and the bytes themselves, and the datafile is rewritten without it, under a temporary
+	// name and renamed into place once it's durable.  Scanning for the next whole record goes an alignment
+	// at a time, so one bad sector costs the records it touches and nothing else.  A datafile whose header
+	// can't be read is renamed to be a sidecar of its own, whole.  Hints are regenerated for every datafile
+	// that isn't encrypted, and dropped for those rewritten that are, recovery scans them instead.  What
+	// was lost is gone, including the rest of a batch part of which was.
+	//
+	pub fn repair(dirpath: &str) -> Result<RepairReport, BitcaskError> {
+	    Self::repair_storage(&FileStorage::new(dirpath))
+	}
+
+	pub fn repair_storage(storage: &dyn Storage) -> Result<RepairReport, BitcaskError> {
+	    let _lock = Self::lock_offline(storage)?;
+	    Self::remove_snapshot(storage)?;					// Records are going to move
+	    let names = storage.list()?;
+	    for name in names.iter().filter(|name| name.ends_with(BITCASK_REPAIR_TMP_SUFFIX)) {
+		storage.remove(name)?;					// From a repair that crashed, the original is intact
+	    }
+	    let mut report = RepairReport::default();
+	    for id in Self::datafile_ids(&names) {
+		Self::repair_datafile(storage, id, &names, &mut report)?;
+	    }
+	    Ok(report)
+	}
+
+	//
+	// Repair one datafile, see repair(), adding what was done to 'report'.
+	//
+	fn repair_datafile(storage: &dyn Storage, id: BitcaskFileID, names: &[String], report: &mut RepairReport) -> Result<bool, io::Error> {
+	    let name = format!("{}.data", id);
+	    let hintsname = format!("{}.hints", id);
+	    let mut sidecar = format!("{}{}", name, BITCASK_CORRUPT_SUFFIX);
+	    for n in 1.. {
+		if !names.contains(&sidecar) {
+		    break;
+		}
+		sidecar = format!("{}-{}{}", name, n, BITCASK_CORRUPT_SUFFIX);	// One from an earlier repair, keep it
+	    }
+	    let file: Arc<dyn StorageFile> = storage.open(&name)?.into();
+	    let size = file.size()?;
+	    let (alignment, start, flags) = match BitcaskDatafile::read_header(&name, &*file) {
+		Ok(header) => header,
+		Err(e) if e.kind() == io::ErrorKind::InvalidData => (BITCASK_RECORD_ALIGNMENT, 0, 0),
+		Err(e) => return Err(e),
+	    };
+	    if start == 0 && size > 0 {					// No header, or none we can read, see require_header()
+		drop(file);
+		storage.rename(&name, &sidecar)?;
+		log::warn!("{}: has no header we can read, set it aside whole as {}", name, sidecar);
+		if names.contains(&hintsname) {
+		    storage.remove(&hintsname)?;
+		}
+		report.datafiles += 1;
+		report.corrupt_bytes += size;
+		report.sidecars.push(sidecar);
+		return Ok(true);
+	    }
+	    let mut good: Vec<(u64, u64)> = Vec::new();			// The offset and length of every whole record
+	    let mut offset = start as u64;
+	    while offset < size {
+		match BitcaskDatafile::good_record_at(&*file, offset, size, alignment)? {
+		    Some(len) => {
+			good.push((offset, len));
+			offset += len;
+		    },
+		    None => offset += alignment as u64,
+		}
+	    }
+	    let mut bad: Vec<(u64, u64)> = Vec::new();			// And of everything between them
+	    let mut end = start as u64;
+	    for &(offset, len) in good.iter().chain(std::iter::once(&(size, 0))) {
+		if offset > end {
+		    bad.push((end, offset - end));
+		}
+		end = offset + len;
+	    }
+	    if !bad.is_empty() {
+		let corrupt = storage.create(&sidecar)?;
+		for &(offset, len) in &bad {
+		    let mut bytes = vec![0u8; len as usize];
+		    file.read_exact_at(offset, &mut bytes)?;
+		    corrupt.append(&offset.to_le_bytes())?;
+		    corrupt.append(&len.to_le_bytes())?;
+		    corrupt.append(&bytes)?;
+		}
+		corrupt.sync()?;					// Kept before anything is taken out of the datafile
+		let tmpname = format!("{}{}", name, BITCASK_REPAIR_TMP_SUFFIX);
+		let tmp = storage.create(&tmpname)?;
+		let mut header = vec![0u8; start];
+		file.read_exact_at(0, &mut header)?;
+		tmp.append(&header)?;
+		for &(offset, len) in &good {
+		    let mut bytes = vec![0u8; len as usize];
+		    file.read_exact_at(offset, &mut bytes)?;
+		    tmp.append(&bytes)?;
+		}
+		tmp.sync()?;
+		drop(file);
+		storage.rename(&tmpname, &name)?;
+		let lost: u64 = bad.iter().map(|&(_, len)| len).sum();
+		log::warn!("{}: took out {} bytes in {} places, kept in {}", name, lost, bad.len(), sidecar);
+		report.datafiles += 1;
+		report.corrupt_bytes += lost;
+		report.sidecars.push(sidecar);
+	    }
+	    if flags & BITCASK_DATAFILE_FLAG_ENCRYPTED != 0 {
+		if !bad.is_empty() && names.contains(&hintsname) {
+		    storage.remove(&hintsname)?;				// It describes offsets that have moved
+		}
+		return Ok(true);
+	    }
+	    match BitcaskDatafile::open(storage, id, None).and_then(|df| BitcaskHintsfile::hintsfile_generate(storage, &df)) {
+		Ok(_) => report.hintsfiles += 1,
+		Err(e) => {
+		    log::warn!("{}: couldn't regenerate hints, the next open will scan it: {}", name, e);
+		    if storage.list()?.contains(&hintsname) {
+			storage.remove(&hintsname)?;
+		    }
+		},
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Rewrite one datafile in the current format, see migrate(), unless it's in it already.
+	//
+	fn migrate_datafile(storage: &dyn Storage, id: BitcaskFileID) -> Result<bool, io::Error> {
+	    let name = format!("{}.data", id);
+	    let file: Arc<dyn StorageFile> = storage.open(&name)?.into();
+	    let size = file.size()?;
+	    let mut header = [0u8; BITCASK_DATAFILE_HEADER_SIZE];
+	    if size >= header.len() as u64 {
+		file.read_exact_at(0, &mut header)?;
+		if &header[0..4] == BITCASK_DATAFILE_MAGIC && u32::from_le_bytes(header[4..8].try_into().unwrap()) == BITCASK_FORMAT_VERSION {
+		    return Ok(false);
+		}
+	    }
+	    let df = BitcaskDatafile::from_file(name.clone(), id, Arc::clone(&file), None)?;	// Older versions had no flags, so no key is needed
+	    df.require_header()?;
+	    if size == 0 {
+		return Ok(false);
+	    }
+	    let sealed = size >= df.start + BITCASK_RECORD_HEADER_SIZE as u64 && {
+		let mut hdr = [0u8; BITCASK_RECORD_HEADER_SIZE];
+		file.read_exact_at(df.start, &mut hdr)?;
+		BitcaskDatafileRecord::from_header(&hdr).is_ok_and(|rec| rec.flags & BITCASK_RECORD_FLAG_SEALED != 0)	// They all are or none are
+	    };
+	    let tmpname = format!("{}{}", name, BITCASK_MIGRATE_TMP_SUFFIX);
+	    let tmp = storage.create(&tmpname)?;
+	    let mut header = vec![0u8; df.start as usize];
+	    header[0..4].copy_from_slice(BITCASK_DATAFILE_MAGIC);
+	    header[4..8].copy_from_slice(&BITCASK_FORMAT_VERSION.to_le_bytes());
+	    header[8..12].copy_from_slice(&(df.alignment as u32).to_le_bytes());
+	    header[12..16].copy_from_slice(&(if sealed { BITCASK_DATAFILE_FLAG_ENCRYPTED } else { 0 }).to_le_bytes());
+	    tmp.append(&header)?;
+	    let mut buf = vec![0u8; BITCASK_BACKUP_CHUNK_SIZE];
+	    let mut offset = df.start;
+	    while offset < size {
+		let chunk = &mut buf[..(size - offset).min(BITCASK_BACKUP_CHUNK_SIZE as u64) as usize];
+		file.read_exact_at(offset, chunk)?;
+		tmp.append(chunk)?;
+		offset += chunk.len() as u64;
+	    }
+	    tmp.sync()?;							// The copy must be safe before it replaces the original
+	    drop((df, file));
+	    storage.rename(&tmpname, &name)?;
+	    log::info!("{}: migrated to format version {}", name, BITCASK_FORMAT_VERSION);
+	    if !sealed {
+		let df = BitcaskDatafile::open(storage, id, None)?;
+		if let Err(e) = BitcaskHintsfile::hintsfile_generate(storage, &df) {
+		    log::warn!("{}: couldn't regenerate hints, the next open will scan it: {}", name, e);
+		}
+	    }
+	    Ok(true)
+	}
+
+	//
+	// Return the ID of the current datafile, the one new writes are appended to.
+	//
+	pub fn active_file_id(&self) -> BitcaskFileID {
+	    let df = self.current.read().unwrap();			// Protect changes to 'current' while we do our lookup
+	    df.id
+	}
+
+	//
+	// The fraction of all the bytes on disk that a full merge would reclaim: overwritten and deleted values
+	// and the tombstones themselves.  0.0 is a perfectly compact store and it approaches 1.0 as the garbage
+	// piles up.  This is cheap, it comes from counters kept up to date by every write and not from a scan.
+	//
+	pub fn compaction_debt(&self) -> f64 {
+	    let mut total = self.current.read().unwrap().record_bytes().unwrap_or(0);
+	    for df in self.datafiles.read().unwrap().values() {
+		total += df.record_bytes().unwrap_or(0);
+	    }
+	    let dead: u64 = self.dead_bytes.lock().unwrap().values().sum();
+	    if total == 0 {
+		return 0.0;
+	    }
+	    (dead as f64 / total as f64).min(1.0)
+	}
+
+	//
+	// Gather up the figures in a Stats, from the same counters compaction_debt() uses, so it's cheap, apart
+	// from counting the keys that haven't expired.
+	//
+	pub fn stats(&self) -> Result<Stats, BitcaskError> {
+	    let now = now_millis();
+	    let keys = self.keymap.read_all().iter().filter(|(_, entry)| !entry.is_expired(now)).count();
+	    let (mut disk_bytes, mut record_bytes, current_file_size, mut datafiles) = {
+		let current = self.current.read().unwrap();
+		(current.size()?, current.record_bytes()?, current.size()?, 1)
+	    };
+	    for df in self.datafiles.read().unwrap().values() {
+		disk_bytes += df.size()?;
+		record_bytes += df.record_bytes()?;
+		datafiles += 1;
+	    }
+	    let dead_bytes_per_datafile: BTreeMap<BitcaskFileID, u64> = self.dead_bytes.lock().unwrap().iter().map(|(id, dead)| (*id, *dead)).collect();
+	    let dead_bytes: u64 = dead_bytes_per_datafile.values().sum();
+	    let (cache_hits, cache_misses) = self.cache.as_ref().map_or((0, 0), |cache| cache.lock().unwrap().counts());
+	    Ok(Stats {
+		keys,
+		datafiles,
+		disk_bytes,
+		live_bytes: record_bytes.saturating_sub(dead_bytes),
+		dead_bytes,
+		dead_bytes_per_datafile,
+		current_file_size,
+		cache_hits,
+		cache_misses,
+		blob_bytes: self.blobs.bytes(),
+		disk_quota: self.disk_quota,
+		changes_dropped: self.changes_dropped(),
+		pool_queue_depth: self.pool.queue_depth(),
+	    })
+	}
+
+	//
+	// How many bytes the datafiles and blob files take up, without asking the storage: what's been written
+	// to each.
+	//
+	fn disk_usage(&self) -> u64 {
+	    let current = self.current.read().unwrap().written.load(Ordering::SeqCst);
+	    current + self.datafiles.read().unwrap().values().map(|df| df.written.load(Ordering::SeqCst)).sum::<u64>() + self.blobs.bytes()
+	}
+
+	//
+	// Make sure there's room under set_disk_quota() for 'adding' more bytes of datafile, merging to make it
+	// if a merge would, or fail with QuotaExceeded.
+	//
+	fn make_room(&self, adding: u64) -> Result<(), io::Error> {
+	    let Some(quota) = self.disk_quota else {
+		return Ok(());
+	    };
+	    let usage = self.disk_usage();
+	    if usage + adding <= quota {
+		return Ok(());
+	    }
+	    let dead: u64 = self.dead_bytes.lock().unwrap().values().sum();
+	    if usage.saturating_sub(dead) + adding <= quota {
+		log::warn!("{} of {} quota bytes used, merging to make room", usage, quota);
+		self.merge_unless(|_| true, None)?;
+	    }
+	    let usage = self.disk_usage();
+	    match usage + adding <= quota {
+		true => Ok(()),
+		false => Err(BitcaskError::QuotaExceeded { usage, quota }.into()),
+	    }
+	}
+
+	//
+	// Close the current datafile and start a new one.  The closed datafile gets its hints file straight away,
+	// on the store's pool once other writers are let go, so the next open needn't scan it.
+	//
+	// Rotations are serialized by rotate_lock.  A caller that had to wait for somebody else's rotation finds
+	// the datafile it wanted to close already closed, so it returns Ok(false) rather than rotating again and
+	// leaving an empty datafile behind.
+	//
+	pub fn rotate(&self) -> Result<bool, BitcaskError> {
+	    Ok(self.rotate_from(self.active_file_id())?)
+	}
+
+	//
+	// Rotate, but only if 'seen' is still the current datafile.
+	//
+	fn rotate_from(&self, seen: BitcaskFileID) -> Result<bool, io::Error> {
+	    self.writable()?;
+	    let rotating = self.rotate_lock.lock().unwrap();	// Only one rotation at a time
+	    if self.active_file_id() != seen {
+		return Ok(false);				// Somebody else rotated while we waited, that'll do
+	    }
+	    let mut span = OpSpan::start("rotate");
+	    span.record("file_id", seen as u64);
+	    let newdf = BitcaskDatafile::new(&*self.storage, seen, self.alignment, self.cipher.clone())?;	// Nobody else can take this ID while we hold the lock
+	    self.install_current(*newdf)?;
+	    drop(rotating);
+	    let (storage, datafiles) = (Arc::clone(&self.storage), Arc::clone(&self.datafiles));
+	    self.pool.submit(move || {					// Deferred, it's shutdown that makes them
+		if let Some(olddf) = datafiles.read().unwrap().get(&seen) {	// Unless a merge has consumed it already
+		    if let Err(e) = BitcaskHintsfile::hintsfile_generate(&*storage, olddf) {
+			log::warn!("{}: couldn't generate hints, shutdown will try again: {}", olddf.name, e);	// The rotation itself is done
+		    }
+		}
+	    });
+	    self.snapshot_if_due();
+	    Ok(true)
+	}
+
+	//
+	// Swap a freshly created datafile in as 'current' and move the old one to the readonly archive.
+	// The caller must hold rotate_lock, it's the only thing keeping IDs unique.  Writers are only held off
+	// for the swap itself, the new file was created and the old one is flushed outside the 'current' lock.
+	// Returns the last sequence number written to the old datafiles, every later one goes to the new one.
+	//
+	fn install_current(&self, newdf: BitcaskDatafile) -> Result<u64, io::Error> {
+	    self.alignments.write().unwrap().insert(newdf.id, newdf.alignment);
+	    let (oldid, seq) = {
+		let mut df = self.current.write().unwrap();	// Protect changes to 'current' while we do our rotation
+		let seq = self.sequence.load(Ordering::SeqCst);	// Writers take their number under the 'current' read lock
+		let mut olddf = std::mem::replace(&mut *df, newdf);
+		self.current_since.store(self.opened.elapsed().as_millis() as u64, Ordering::SeqCst);
+		olddf.seal(&*self.storage);
+		let oldid = olddf.id;
+		let mut map = self.datafiles.write().unwrap();	// Protect the data structure while we do our insert
+		map.insert(oldid, olddf);			// Move the current/closing datafile to the readonly archive
+		(oldid, seq)
+	    };							// Drop both of the locks
+	    if let Some(olddf) = self.datafiles.read().unwrap().get(&oldid) {
+		if self.sync_policy != SyncPolicy::Never {
+		    self.sync_datafile(olddf)?;			// A merge may already have consumed it, then there's nothing to flush
+		}
+	    }
+	    Ok(seq)
+	}
+
+	//
+	// Compact all the archived datafiles into a single new datafile containing only the live KVs,
+	// then delete the archived datafiles.
+	//
+	pub fn merge(&self) -> Result<bool, BitcaskError> {
+	    self.merge_with_filter(|_| true)
+	}
+
+	//
+	// Merge as above, but also drop any live KV whose key fails the 'keep' predicate, deleting it as part of
+	// the compaction.  A DELETE record is written into the merged datafile for each dropped key so that
+	// the key can't come back if we crash before the old datafiles are removed.
+	//
+	// The current datafile is sealed first, so everything written before the merge started is compacted.
+	// The merged datafile takes the next ID and a new current datafile the one after that, which keeps
+	// the replay order at startup correct: the merged records are older than anything written during
+	// or after the merge.  Concurrent puts and deletes are fine, the keymap is only updated for a key
+	// if it still points at the record we copied.
+	//
+	// Tombstones in the merged datafiles are never carried forward.  Every datafile older than any of
+	// those DELETE records is part of the merge, so once the old datafiles are gone there is no PUT left
+	// that a tombstone could be hiding, and the keymap already reflects the delete.  The old datafiles are
+	// removed oldest first so that a crash part way through can't strand a DELETE in a removed file while
+	// the PUT it was hiding survives in an older one.  The DELETE records written for dropped keys only live
+	// in the merged datafile until the next merge, which drops them by the same reasoning.  The exception is
+	// a tombstone younger than set_tombstone_retention() asks for, of a key that's still deleted, which is
+	// copied as it is, for whoever reads the datafiles to find out about deletes, see replicate_from().
+	//
+	// Once the merge is done, the history up to the moment the current datafile was sealed (the horizon) is
+	// gone for good, so the horizon is recorded before any input is removed for truncate_to_sequence() to check.
+	//
+	// The merged datafile gets a hints file, so opening the store afterwards needn't scan it.  The inputs'
+	// own hints files go with them.  Both are written under temporary names, see BITCASK_MERGE_TMP_SUFFIX,
+	// and only renamed once they're durable, the datafile first: until then a crash leaves nothing recovery
+	// would read, and after it the merged datafile is complete, scanned if its hints didn't make it.  No
+	// input is removed until both have their proper names.
+	//
+	// KVs that have expired by the time the merge starts are dropped just as if 'keep' had said so.
+	//
+	pub fn merge_with_filter(&self, keep: impl Fn(&[u8]) -> bool) -> Result<bool, BitcaskError> {
+	    Ok(self.merge_unless(keep, None)?)
+	}
+
+	//
+	// The merge itself.  If 'give_way' is given the merge is throttled, and abandoned as soon as it returns
+	// true at the end of a batch, in which case the merged datafile is thrown away, nothing else has changed
+	// (beyond the rotation), and we return Ok(false).  If nothing has been written since, the next merge
+	// takes the current datafile that rotation left as its own new one, and the merged datafile's ID that
+	// was thrown away, so merges that keep yielding to reads don't pile up empty datafiles or use up IDs.
+	//
+	fn merge_unless(&self, keep: impl Fn(&[u8]) -> bool, give_way: Option<&dyn Fn() -> bool>) -> Result<bool, io::Error> {
+	    self.writable()?;
+	    let _merging = self.merge_lock.lock().unwrap();	// Only one merge at a time
+	    #[cfg(feature = "metrics")]
+	    Metrics::count(&self.metrics.merges, 1);
+	    let mut span = OpSpan::start("merge");
+	    self.archive_aged_locked()?;			// No sense compacting what's about to leave
+	    self.apply_retention_locked()?;
+
+	    let (mut output, horizon) = {
+		let _rotating = self.rotate_lock.lock().unwrap();	// Our two new IDs must not collide with a rotation
+		let id = self.active_file_id();
+		let unwritten = self.yielded.lock().unwrap().take()
+		    .filter(|&(current, seq)| current == id && seq == self.sequence.load(Ordering::SeqCst));
+		let previous = if unwritten.is_some() { id - 2 } else { id };
+		let output = BitcaskDatafile::create(&*self.storage, format!("{}.data{}", previous + 1, BITCASK_MERGE_TMP_SUFFIX), previous, self.alignment, self.cipher.clone())?;	// Merging rewrites at the current alignment
+		self.alignments.write().unwrap().insert(output.id, output.alignment);
+		let horizon = match unwritten {
+		    Some((_, seq)) => seq,				// Everything up to it is in the datafiles before ours
+		    None => {
+			let newdf = BitcaskDatafile::new(&*self.storage, output.id, self.alignment, self.cipher.clone())?;
+			self.install_current(*newdf)?
+		    },
+		};
+		(output, horizon)
+	    };
+	    let inputs: Vec<BitcaskFileID> = {
+		let map = self.datafiles.read().unwrap();
+		let mut ids: Vec<BitcaskFileID> = map.keys().filter(|id| **id < output.id).copied().collect();
+		ids.sort();
+		ids
+	    };
+
+	    // Capture the location of every live KV that lives in one of the datafiles we're merging
+	    let now = now_millis();
+	    let live: Vec<(Vec<u8>, BitcaskFileID, i64, i32, bool)> = {
+		let map = self.keymap.read_all();		// Protect the data structure while we do our iterator
+		map.iter()
+		    .filter(|(_, entry)| entry.fileid < output.id)
+		    .map(|(key, entry)| (key.to_vec(), entry.fileid, entry.offset, entry.value_size, entry.is_expired(now)))
+		    .collect()
+	    };							// Drop the reader lock
+
+	    let mut relocated: Vec<(Vec<u8>, BitcaskFileID, i64, i32, i64)> = Vec::new();
+	    let mut dropped: Vec<(Vec<u8>, BitcaskFileID, i64)> = Vec::new();
+	    let mut blobs_kept: HashSet<BitcaskFileID> = HashSet::new();
+	    let mut hints: Vec<Box<BitcaskHintsfileRecord>> = Vec::new();
+	    for (n, (key, fileid, offset, value_size, expired)) in live.into_iter().enumerate() {
+		if let Some(give_way) = give_way {
+		    if n > 0 && n.is_multiple_of(BITCASK_IDLE_MERGE_BATCH) {
+			if give_way() {
+			    self.storage.remove(&output.name)?;	// Nobody has seen it, we're the only ones who know it's there
+			    self.dead_bytes.lock().unwrap().remove(&output.id);
+			    self.alignments.write().unwrap().remove(&output.id);
+			    if self.sequence.load(Ordering::SeqCst) == horizon {
+				*self.yielded.lock().unwrap() = Some((output.id + 1, horizon));	// For the next merge to take up
+			    }
+			    return Ok(false);
+			}
+			std::thread::sleep(BITCASK_IDLE_MERGE_PAUSE);
+		    }
+		}
+		if keep(&key) && !expired {
+		    let rec = {
+			let map = self.datafiles.read().unwrap();	// Inputs can't go away, only this merge removes them
+			map[&fileid].get(offset, key.len(), value_size)?
+		    };
+		    if rec.flags & BITCASK_RECORD_FLAG_BLOB != 0 {
+			blobs_kept.extend(BlobPointer::from_bytes(&rec.value).map(|ptr| ptr.id));	// The value stays where it is
+		    }
+		    let newoffset = output.append(&rec, false)?;
+		    let stored = output.stored_value_size(&rec);		// Not value_size if it's only now being sealed
+		    hints.push(BitcaskHintsfileRecord::new(key.clone(), BitcaskDatafileRectype::PUT, stored, newoffset, rec.expires, rec.timestamp));
+		    relocated.push((key, fileid, offset, stored, newoffset));
+		} else {
+		    let (newoffset, stored, timestamp) = output.delete(&key, horizon, false)?;	// Takes effect as of the horizon, like the rest of the merge
+		    hints.push(BitcaskHintsfileRecord::new(key.clone(), BitcaskDatafileRectype::DELETE, stored, newoffset, 0, timestamp));
+		    self.note_dead(output.id, key.len(), stored);
+		    dropped.push((key, fileid, offset));
+		}
+	    }
+	    if let Some(retention) = self.tombstone_retention {
+		for rec in self.recent_tombstones(&inputs, now.saturating_sub(retention.as_millis() as u64))? {
+		    if self.keymap.read(&rec.key).get(&rec.key).is_some() {
+			continue;				// Put again since, the PUT says all there is to say
+		    }
+		    let newoffset = output.append(&rec, false)?;
+		    let stored = output.stored_value_size(&rec);
+		    hints.push(BitcaskHintsfileRecord::new(rec.key.clone(), BitcaskDatafileRectype::DELETE, stored, newoffset, 0, rec.timestamp));
+		    self.note_dead(output.id, rec.key.len(), stored);
+		}
+	    }
+	    self.sync_datafile(&output)?;			// The merged data must be stable before it's used
+	    let hintsfile = BitcaskHintsfile::create(&*self.storage, output.id, BITCASK_MERGE_TMP_SUFFIX)?;
+	    hintsfile.write(&hints, output.cipher.as_deref())?;
+	    output.rename(&*self.storage)?;
+	    self.storage.rename(&format!("{}{}", hintsfile.name, BITCASK_MERGE_TMP_SUFFIX), &hintsfile.name)?;
+	    span.record("file_id", output.id as u64);
+	    span.record("inputs", inputs.len() as u64);
+	    span.record("bytes", output.size()?);
+
+	    {
+		let mut map = self.keymap.write_all();		// Protect the data structure while we do our updates
+		for (key, fileid, offset, value_size, newoffset) in relocated {
+		    match map.get_mut(&key) {
+			Some(entry) if entry.fileid == fileid && entry.offset == offset => {
+			    entry.fileid = output.id;
+			    entry.offset = newoffset;
+			    entry.value_size = value_size;
+			},
+			_ => self.note_dead(output.id, key.len(), value_size),	// Overwritten while we copied it
+		    }
+		}
+		for (key, fileid, offset) in dropped {
+		    if map.get(&key).is_some_and(|entry| entry.fileid == fileid && entry.offset == offset) {
+			map.remove(&key);
+		    }
+		}
+		self.rebuild_bloom(&map);
+	    }							// Drop the writer lock
+	    self.save_horizon(horizon)?;
+
+	    let output_id = output.id;
+	    {
+		let mut map = self.datafiles.write().unwrap();	// Protect the data structure while we swap the files
+		output.seal(&*self.storage);
+		map.insert(output.id, *output);
+		for id in inputs {					// Oldest first, see above
+		    if let Some(df) = map.remove(&id) {
+			self.remove_datafile(&df)?;
+			self.dead_bytes.lock().unwrap().remove(&id);
+			self.alignments.write().unwrap().remove(&id);
+		    }
+		}
+	    }							// Drop the writer lock
+	    self.blobs.remove_unless(&*self.storage, |id| id >= output_id || blobs_kept.contains(&id))?;	// Newer datafiles only point into their own, or a streamed one's
+	    Ok(true)
+	}
+
+	//
+	// The newest DELETE of each key among the datafiles 'inputs', if it was written at 'since' or later.
+	//
+	fn recent_tombstones(&self, inputs: &[BitcaskFileID], since: u64) -> Result<Vec<BitcaskDatafileRecord>, io::Error> {
+	    let mut newest: HashMap<Vec<u8>, BitcaskDatafileRecord> = HashMap::new();
+	    for id in inputs {
+		let map = self.datafiles.read().unwrap();	// Inputs can't go away, only this merge removes them
+		map[id].scan_committed(|_, rec| {
+		    if rec.op == BitcaskDatafileRectype::DELETE && rec.timestamp >= since
+			&& newest.get(&rec.key).is_none_or(|seen| seen.seq < rec.seq) {
+			newest.insert(rec.key.clone(), rec.clone());
+		    }
+		    Ok(true)
+		})?;
+	    }
+	    Ok(newest.into_values().collect())
+	}
+
+	//
+	// Would a merge be worth it?  True once a threshold set by set_merge_policy() is crossed, by default once
+	// it would reclaim at least half of the bytes in the datafiles.  Cheap, from the dead byte counters.
+	//
+	pub fn needs_merge(&self) -> bool {
+	    let policy = self.merge_policy;
+	    if policy.debt.is_some_and(|debt| self.compaction_debt() >= debt) {
+		return true;
+	    }
+	    if policy.dead_bytes.is_some_and(|max| self.dead_bytes.lock().unwrap().values().sum::<u64>() >= max) {
+		return true;
+	    }
+	    let Some(ratio) = policy.file_dead_ratio else {
+		return false;
+	    };
+	    let sizes: Vec<(BitcaskFileID, u64)> = self.datafiles.read().unwrap().values().map(|df| (df.id, df.record_bytes().unwrap_or(0))).collect();
+	    let dead = self.dead_bytes.lock().unwrap();
+	    sizes.iter().any(|(id, size)| *size > 0 && dead.get(id).is_some_and(|dead| *dead as f64 / *size as f64 >= ratio))
+	}
+
+	//
+	// Note the start of a foreground read or write, for the idle merge to see.
+	//
+	fn touch(&self) {
+	    self.ops.fetch_add(1, Ordering::SeqCst);
+	    self.last_op.store(self.opened.elapsed().as_millis() as u64, Ordering::SeqCst);
+	}
+
+	//
+	// Merge in the background whenever nothing has read or written the store for 'idle' and needs_merge()
+	// says it's worth it.  The merge is throttled and yields, throwing its work away, the moment a get,
+	// put, delete, or the like arrives; it tries again after the next idle period.  The thread holds only a
+	// weak reference, so it doesn't keep the store alive, and shutdown() stops it.  Calling this again
+	// replaces the thread with one for the new 'idle', as does start_background_merge().
+	//
+	pub fn start_idle_merge(self: &Arc<Self>, idle: Duration) {
+	    self.start_maintenance((idle / 4).max(Duration::from_millis(1)), move |cask| {
+		let now = cask.opened.elapsed().as_millis() as u64;
+		if now.saturating_sub(cask.last_op.load(Ordering::SeqCst)) < idle.as_millis() as u64 || !cask.needs_merge() {
+		    return;
+		}
+		let ops = cask.ops.load(Ordering::SeqCst);
+		cask.idle_merges.fetch_add(1, Ordering::SeqCst);
+		match cask.merge_unless(|_| true, Some(&|| cask.ops.load(Ordering::SeqCst) != ops)) {
+		    Ok(true) => {},
+		    Ok(false) => { cask.idle_merges_yielded.fetch_add(1, Ordering::SeqCst); },
+		    Err(e) => log::warn!("idle merge failed: {}", e),
+		}
+	    });
+	}
+
+	//
+	// Check every 'interval' whether needs_merge() says a merge is worth it, and if so run one in the
+	// background there and then, whatever else is going on.  For stores that are never idle for long enough
+	// for start_idle_merge().  The merge is a full merge(), taking the same locks, so foreground writers are
+	// only held off while it swaps files and updates the keymap.  Calling this again, or start_idle_merge(),
+	// replaces the thread, and shutdown() stops it.
+	//
+	pub fn start_background_merge(self: &Arc<Self>, interval: Duration) {
+	    self.start_maintenance(interval, |cask| {
+		if cask.needs_merge() {
+		    if let Err(e) = cask.merge() {
+			log::warn!("background merge failed: {}", e);
+		    }
+		}
+	    });
+	}
+
+	//
+	// Start the maintenance thread, replacing any that's running, to hand 'task' to the pool every 'poll' for
+	// as long as the store is around.  A tick that finds the last one still queued or running skips it.
+	//
+	fn start_maintenance(self: &Arc<Self>, poll: Duration, task: impl Fn(&Bitcask) + Send + Sync + 'static) {
+	    let (stop, stopped) = channel::<()>();
+	    let cask = Arc::downgrade(self);
+	    let task = Arc::new(task);
+	    let busy = Arc::new(AtomicBool::new(false));
+	    let handle = std::thread::spawn(move || {
+		while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(poll) {
+		    let Some(cask) = cask.upgrade() else {
+			return;						// The store is gone
+		    };
+		    if busy.swap(true, Ordering::SeqCst) {
+			continue;
+		    }
+		    let (on, task, done) = (Arc::clone(&cask), Arc::clone(&task), Arc::clone(&busy));
+		    if let Submitted::Deferred(_) = cask.pool.submit(move || {
+			task(&on);
+			done.store(false, Ordering::SeqCst);
+		    }) {
+			busy.store(false, Ordering::SeqCst);		// The next tick tries again
+		    }
+		}
+	    });
+	    self.stop_maintenance();
+	    *self.maintenance.lock().unwrap() = Some((stop, handle));
+	}
+
+	//
+	// Stop the maintenance thread, if there is one, and wait for it to finish what it's doing.
+	//
+	fn stop_maintenance(&self) {
+	    let running = self.maintenance.lock().unwrap().take();
+	    if let Some((stop, handle)) = running {
+		drop(stop);
+		if handle.thread().id() != std::thread::current().id() {	// It may be the one dropping the store
+		    let _ = handle.join();
+		}
+	    }
+	}
+
+	//
+	// How many idle merges have been started, and how many of those yielded to foreground traffic.
+	//
+	pub fn idle_merge_counts(&self) -> (u64, u64) {
+	    (self.idle_merges.load(Ordering::SeqCst), self.idle_merges_yielded.load(Ordering::SeqCst))
+	}
+
+	//
+	// Archive the datafiles that have outlived the age given to set_max_datafile_age(), see there for how.
+	// Returns how many datafiles were archived, always 0 if no age is set.
+	//
+	pub fn archive_aged(&self) -> Result<usize, BitcaskError> {
+	    let _merging = self.merge_lock.lock().unwrap();	// Merges move records between the files we're looking at
+	    Ok(self.archive_aged_locked()?)
+	}
+
+	//
+	// The sweep itself, for a caller holding merge_lock.
+	//
+	fn archive_aged_locked(&self) -> Result<usize, io::Error> {
+	    self.writable()?;
+	    let Some((age, archiver)) = &self.archival else {
+		return Ok(0);
+	    };
+	    let now = now_millis();
+	    let cutoff = now.saturating_sub(age.as_millis() as u64);
+	    let mut ids: Vec<BitcaskFileID> = self.datafiles.read().unwrap().keys().copied().collect();
+	    ids.sort();
+	    let mut archived: usize = 0;
+	    for id in ids {						// Oldest first, see set_max_datafile_age()
+		let newest = self.with_datafile(id, |df| df.newest_timestamp())?;
+		if newest.is_none_or(|newest| newest >= cutoff) {
+		    break;
+		}
+		let live: Vec<(Vec<u8>, i64, i32)> = {
+		    let map = self.keymap.read_all();			// Protect the data structure while we do our iterator
+		    map.iter()
+			.filter(|(_, entry)| entry.fileid == id && !entry.is_expired(now))	// Expired KVs aren't live
+			.map(|(key, entry)| (key.to_vec(), entry.offset, entry.value_size))
+			.collect()
+		};							// Drop the reader lock
+		let mut kvs: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(live.len());
+		let mut max_seq: u64 = 0;
+		{
+		    let map = self.datafiles.read().unwrap();		// Only a merge could remove it, and we're the merge
+		    let df = &map[&id];
+		    for (key, offset, value_size) in &live {
+			kvs.push((key.clone(), self.value_of(&*df.get(*offset, key.len(), *value_size)?)?));
+		    }
+		    df.scan(|_, rec| { max_seq = max_seq.max(rec.seq); Ok(true) })?;
+		}
+		kvs.sort_by(|(a, _), (b, _)| a.cmp(b));
+		archiver(id, &kvs)?;
+		{
+		    let mut map = self.keymap.write_all();		// Protect the data structure while we do our removals
+		    for (key, offset, _) in live {
+			if map.get(&key).is_some_and(|entry| entry.fileid == id && entry.offset == offset) {
+			    map.remove(&key);				// Unless it was overwritten since
+			}
+		    }
+		}							// Drop the writer lock
+		self.retire_datafile(id, max_seq)?;
+		archived += 1;
+	    }
+	    Ok(archived)
+	}
+
+	//
+	// Retire the datafiles that set_retention_policy() says are past it, see there for how.  Returns how many
+	// were retired, always 0 if there's no policy.
+	//
+	pub fn apply_retention(&self) -> Result<usize, BitcaskError> {
+	    let _merging = self.merge_lock.lock().unwrap();	// Merges move records between the files we're looking at
+	    Ok(self.apply_retention_locked()?)
+	}
+
+	//
+	// The sweep itself, for a caller holding merge_lock.
+	//
+	fn apply_retention_locked(&self) -> Result<usize, io::Error> {
+	    self.writable()?;
+	    let Some((policy, archive)) = &self.retention else {
+		return Ok(0);
+	    };
+	    let now = now_millis();
+	    let mut ids: Vec<BitcaskFileID> = self.datafiles.read().unwrap().keys().copied().collect();
+	    ids.sort();
+	    let mut retired: usize = 0;
+	    for (n, &id) in ids.iter().enumerate() {		// Oldest first, see set_retention_policy()
+		let too_many = policy.max_datafiles.is_some_and(|max| ids.len() - n > max);
+		let too_old = match policy.max_age {
+		    Some(age) => {
+			let cutoff = now.saturating_sub(age.as_millis() as u64);
+			self.with_datafile(id, |df| df.newest_timestamp())?.is_some_and(|newest| newest < cutoff)
+		    },
+		    None => false,
+		};
+		if !too_many && !too_old {
+		    break;
+		}
+		let live: Vec<(Vec<u8>, i64, i32)> = {
+		    let map = self.keymap.read_all();			// Protect the data structure while we do our iterator
+		    map.iter()
+			.filter(|(_, entry)| entry.fileid == id && !entry.is_expired(now))	// Expired KVs aren't live
+			.map(|(key, entry)| (key.to_vec(), entry.offset, entry.value_size))
+			.collect()
+		};							// Drop the reader lock
+		for (key, offset, value_size) in live {
+		    let rec = {
+			let map = self.datafiles.read().unwrap();	// Only a merge could remove it, and we're the merge
+			map[&id].get(offset, key.len(), value_size)?
+		    };
+		    self.carry_forward(&rec, id, offset)?;
+		}
+		let mut max_seq: u64 = 0;
+		{
+		    let map = self.datafiles.read().unwrap();
+		    let df = &map[&id];
+		    if let Some(archive) = archive {
+			Self::copy_file(&*df.file, df.size()?, &**archive, &df.name)?;
+		    }
+		    df.scan(|_, rec| { max_seq = max_seq.max(rec.seq); Ok(true) })?;
+		}
+		self.retire_datafile(id, max_seq)?;
+		retired += 1;
+	    }
+	    Ok(retired)
+	}
+
+	//
+	// Append a PUT of the value and expiry in 'rec' to the current datafile, and point the keymap at it, if
+	// it still points at 'offset' in datafile 'fileid' for that key.  Returns whether it did.  For a sweep
+	// holding merge_lock, which mustn't take stripes, so the check is made under the keymap lock instead.
+	// Nothing has changed as far as anyone reading the store can tell, so subscribers don't hear of it.
+	//
+	fn carry_forward(&self, rec: &BitcaskDatafileRecord, fileid: BitcaskFileID, offset: i64) -> Result<bool, io::Error> {
+	    let value = self.value_of(rec)?;
+	    let df = self.reserve_current()?;			// Protect changes to 'current' while we do our append
+	    let blob = self.spill(df.id, &rec.key, &value)?;	// A copy of its own, merges don't look for pointers in newer datafiles
+	    {
+		let mut map = self.keymap.write(&rec.key);	// Hold the index across the check and the append
+		if !map.get(&rec.key).is_some_and(|entry| entry.fileid == fileid && entry.offset == offset) {
+		    return Ok(false);				// Overwritten or deleted since, nothing to carry
+		}
+		let _numbering = self.numbering.lock().unwrap();
+		let seq = self.sequence.load(Ordering::SeqCst) + 1;
+		let (newoffset, stored, timestamp) = match &blob {
+		    Some(ptr) => df.put_blob(&rec.key, ptr, seq, rec.expires, false)?,
+		    None => df.put(&rec.key, &value, seq, rec.expires, self.compression, false)?,
+		};
+		self.sequence.store(seq, Ordering::SeqCst);
+		if let Some(old) = map.insert(rec.key.clone(), *BitcaskKeymapEntry::new(stored, df.id, newoffset, seq, rec.expires, timestamp))? {
+		    self.note_dead(old.fileid, rec.key.len(), old.value_size);
+		    self.uncache(&rec.key);
+		}
+	    }							// Drop the writer lock
+	    self.sync_write(&df)?;
+	    Ok(true)
+	}
+
+	//
+	// Remove sealed datafile 'id', whose records go up to sequence 'max_seq', once nothing in the keymap
+	// points into it, recording first that its history is gone.
+	//
+	fn retire_datafile(&self, id: BitcaskFileID, max_seq: u64) -> Result<(), io::Error> {
+	    if max_seq > self.horizon.load(Ordering::SeqCst) {
+		self.save_horizon(max_seq)?;				// Before the history is gone
+	    }
+	    let mut map = self.datafiles.write().unwrap();		// Protect the data structure while we remove the file
+	    if let Some(df) = map.remove(&id) {
+		self.remove_datafile(&df)?;
+		self.dead_bytes.lock().unwrap().remove(&id);
+		self.alignments.write().unwrap().remove(&id);
+	    }
+	    Ok(())
+	}
+
+	//
+	// Cross-check the keymap against the datafiles, returning the keys whose entries don't point at a PUT of
+	// that key and size in a datafile that still exists.  An empty result means the two agree, anything else
+	// means a merge (or import) left the index half-swapped.  This checks locations, not checksums.
+	//
+	pub fn validate_keymap(&self) -> Result<Vec<Vec<u8>>, BitcaskError> {
+	    let _merging = self.merge_lock.lock().unwrap();	// Keep the datafiles where they are while we look
+	    let entries: Vec<(Vec<u8>, BitcaskFileID, i64, i32)> = {
+		let map = self.keymap.read_all();		// Protect the data structure while we do our iterator
+		map.iter().map(|(key, entry)| (key.to_vec(), entry.fileid, entry.offset, entry.value_size)).collect()
+	    };							// Drop the reader lock
+	    let mut bad: Vec<Vec<u8>> = Vec::new();
+	    for (key, fileid, offset, value_size) in entries {
+		let found = match self.read_record(fileid, offset, key.len(), value_size) {
+		    Ok(Some(rec)) => rec.key == key && rec.op == BitcaskDatafileRectype::PUT,
+		    Ok(None) => false,				// Points into a datafile that's gone
+		    Err(e) if matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => false,
+		    Err(e) => return Err(e.into()),
+		};
+		if !found {
+		    bad.push(key);
+		}
+	    }
+	    bad.sort();
+	    Ok(bad)
+	}
+
+	//
+	// Check everything on disk: the framing and checksum of every record in every datafile, and every hints
+	// file against its datafile, as they are now, see VerifyReport.  This only reads, so it repairs nothing
+	// and reads nothing through the keymap; validate_keymap() checks that.  The current datafile is
+	// checked up to its last whole append.  A hints file being written for a datafile that's only just
+	// been rotated out may be caught part way, and look cut short.
+	//
+	pub fn verify(&self) -> Result<VerifyReport, BitcaskError> {
+	    let _merging = self.merge_lock.lock().unwrap();	// Keep the datafiles where they are while we look
+	    let mut datafiles: Vec<Box<BitcaskDatafile>> = Vec::new();
+	    {
+		let df = self.current.read().unwrap();		// No rotation while we pick the moment
+		if !self.read_only {				// A read-only store's current datafile is a scratch one
+		    let len = {
+			let _numbering = self.numbering.lock().unwrap();	// Between appends, so it's whole records
+			df.written.load(Ordering::SeqCst)
+		    };
+		    datafiles.push(BitcaskDatafile::from_file(df.name.clone(), df.id, Arc::new(StoragePrefix::new(Arc::clone(&df.file), len)), df.cipher.clone())?);
+		}
+		for df in self.datafiles.read().unwrap().values() {
+		    datafiles.push(BitcaskDatafile::from_file(df.name.clone(), df.id, Arc::clone(&df.file), df.cipher.clone())?);
+		}
+	    }							// Drop the reader lock, writes can go on
+	    datafiles.sort_by_key(|df| df.id);
+	    let names = self.storage.list()?;
+	    let mut report = VerifyReport::default();
+	    for df in &datafiles {
+		let records = df.verify(&mut report)?;
+		report.datafiles += 1;
+		let hintsname = format!("{}.hints", df.id);
+		if names.contains(&hintsname) {
+		    BitcaskHintsfile::hintsfile_verify(&*self.storage, &hintsname, df, &records, &mut report)?;
+		    report.hintsfiles += 1;
+		}
+	    }
+	    Ok(report)
+	}
+
+	//
+	// Copy the store into the directory 'dest', creating it if need be, see backup_to().
+	//
+	pub fn backup(&self, dest: &str) -> Result<BackupManifest, BitcaskError> {
+	    std::fs::create_dir_all(dest)?;
+	    self.backup_to(&FileStorage::new(dest))
+	}
+
+	//
+	// Copy the store as it stands into another storage backend, which mustn't hold a store already, while
+	// writes carry on.  The copy is of one moment: the current datafile is flushed and copied only as far as
+	// it had been written then, and merges are held off until the copy is done, so the archived datafiles
+	// and their hints stay just as they were.  The files are copied through the backend rather than linked,
+	// so the copy can be anywhere.  The MANIFEST listing them is written last, a backup without one didn't
+	// finish.  The copy opens as a store of its own, with this one's ID.
+	//
+	pub fn backup_to(&self, dest: &dyn Storage) -> Result<BackupManifest, BitcaskError> {
+	    if dest.list()?.iter().any(|name| name.ends_with(".data") || name.ends_with(".hints") || name == BITCASK_ID_FILE) {
+		return Err(BitcaskError::InvalidArgument("there's a store in the backup destination already".to_string()));
+	    }
+	    Ok(self.copy_backup(dest, None)?)
+	}
+
+	//
+	// Bring the backup in the directory 'dest' up to date, see backup_incremental_to().
+	//
+	pub fn backup_incremental(&self, dest: &str, since_fileid: BitcaskFileID) -> Result<BackupManifest, BitcaskError> {
+	    self.backup_incremental_to(&FileStorage::new(dest), since_fileid)
+	}
+
+	//
+	// Bring an earlier backup of this store up to date, copying only the datafiles made since it was taken,
+	// those numbered above 'since_fileid', which is normally the earlier MANIFEST's highest_fileid.  Those
+	// at or below it are taken to be there already, and only copied again if the copy there is a different
+	// size: the datafile that was current at the time, which has been written to since, or a hints file
+	// that's been written since.  Datafiles the backup has that a merge has removed here are removed from
+	// it, they'd bring back keys deleted since.  The old MANIFEST is removed first and the new one, listing
+	// everything in the backup, written last, so a backup part way through being brought up to date reads
+	// as one that didn't finish.
+	//
+	pub fn backup_incremental_to(&self, dest: &dyn Storage, since_fileid: BitcaskFileID) -> Result<BackupManifest, BitcaskError> {
+	    if Self::backup_id(dest)? != Some(self.id) {
+		return Err(BitcaskError::InvalidArgument("the backup destination holds no finished backup of this store".to_string()));
+	    }
+	    Ok(self.copy_backup(dest, Some(since_fileid))?)
+	}
+
+	//
+	// The ID of the store a finished backup in 'dest' is a copy of, from its MANIFEST.
+	//
+	fn backup_id(dest: &dyn Storage) -> Result<Option<Uuid>, io::Error> {
+	    if !dest.list()?.iter().any(|name| name == BITCASK_MANIFEST_FILE) {
+		return Ok(None);
+	    }
+	    let file = dest.open(BITCASK_MANIFEST_FILE)?;
+	    let mut text = String::new();
+	    StorageReader::new(file.into(), 0).read_to_string(&mut text)?;
+	    Ok(text.lines().find_map(|line| line.strip_prefix("id ")).and_then(|id| Uuid::parse_str(id).ok()))
+	}
+
+	//
+	// The backup itself, for backup_to(), or if 'since' is set for backup_incremental_to().
+	//
+	fn copy_backup(&self, dest: &dyn Storage, since: Option<BitcaskFileID>) -> Result<BackupManifest, io::Error> {
+	    let _merging = self.merge_lock.lock().unwrap();	// Nothing we copy may be merged away in the meantime
+	    let (current, sequence, mut sources) = {
+		let df = self.current.read().unwrap();		// No rotation while we pick the moment
+		let (len, sequence) = {
+		    let _numbering = self.numbering.lock().unwrap();	// Between appends, so it's whole records
+		    (df.written.load(Ordering::SeqCst), self.sequence.load(Ordering::SeqCst))
+		};
+		self.sync_datafile(&df)?;
+		let sources: Vec<(BitcaskFileID, String, Arc<dyn StorageFile>)> = self.datafiles.read().unwrap().values()
+		    .map(|df| (df.id, df.name.clone(), Arc::clone(&df.file))).collect();
+		((df.id, df.name.clone(), Arc::clone(&df.file), len), sequence, sources)
+	    };							// Drop the reader lock, writes can go on
+	    sources.sort_by_key(|(id, ..)| *id);
+	    let names = self.storage.list()?;
+	    let had = dest.list()?;
+	    if had.iter().any(|name| name == BITCASK_MANIFEST_FILE) {
+		dest.remove(BITCASK_MANIFEST_FILE)?;
+	    }
+	    let mut manifest = BackupManifest { id: self.id, sequence, highest_fileid: 0, files: Vec::new() };
+	    let mut copy = |id: BitcaskFileID, file: &dyn StorageFile, len: u64, name: &str| -> Result<(), io::Error> {
+		let there = since.is_some_and(|since| id <= since) && had.iter().any(|have| have == name) && dest.open(name)?.size()? == len;
+		if !there {
+		    Self::copy_file(file, len, dest, name)?;
+		}
+		manifest.files.push((name.to_string(), len));
+		Ok(())
+	    };
+	    for (id, name, file) in &sources {
+		copy(*id, &**file, file.size()?, name)?;
+		let hintsname = format!("{}.hints", id);
+		if names.contains(&hintsname) {
+		    let hints = self.storage.open(&hintsname)?;
+		    copy(*id, &*hints, hints.size()?, &hintsname)?;
+		}
+	    }
+	    for id in BlobStore::ids(&names) {			// After the datafiles, so they have everything those point at
+		let name = BlobStore::name(id);
+		let file = self.storage.open(&name)?;
+		copy(id, &*file, file.size()?, &name)?;
+	    }
+	    if !self.read_only {				// A read-only store's current datafile is a scratch one
+		let (id, name, file, len) = &current;
+		copy(*id, &**file, *len, name)?;
+	    }
+	    for name in [BITCASK_ID_FILE, BITCASK_HORIZON_FILE] {
+		if names.iter().any(|have| have == name) {
+		    let file = self.storage.open(name)?;
+		    copy(BitcaskFileID::MAX, &*file, file.size()?, name)?;	// Small, and may have changed, always copied
+		}
+	    }
+	    manifest.highest_fileid = match self.read_only {
+		true => sources.last().map_or(0, |(id, ..)| *id),
+		false => current.0,
+	    };
+	    for name in had.iter().filter(|name| name.ends_with(".data") || name.ends_with(".hints") || name.ends_with(".blob")) {
+		if !manifest.files.iter().any(|(copied, _)| copied == name) {
+		    dest.remove(name)?;				// Merged away here since the last backup
+		}
+	    }
+	    let file = dest.create(BITCASK_MANIFEST_FILE)?;
+	    file.append(manifest.to_text().as_bytes())?;
+	    file.sync()?;
+	    Ok(manifest)
+	}
+
+	//
+	// Copy the first 'len' bytes of 'src' to a new file 'name' in 'dest', flushed, and return 'len'.
+	//
+	fn copy_file(src: &dyn StorageFile, len: u64, dest: &dyn Storage, name: &str) -> Result<u64, io::Error> {
+	    let file = dest.create(name)?;
+	    let mut buf = vec![0u8; BITCASK_BACKUP_CHUNK_SIZE];
+	    let mut offset: u64 = 0;
+	    while offset < len {
+		let chunk = &mut buf[..(len - offset).min(BITCASK_BACKUP_CHUNK_SIZE as u64) as usize];
+		src.read_exact_at(offset, chunk)?;
+		file.append(chunk)?;
+		offset += chunk.len() as u64;
+	    }
+	    file.sync()?;
+	    Ok(len)
+	}
+
+	//
+	// Write every KV out as newline-delimited JSON, one {"key":...,"value":...} object to a line, in datafile
+	// order as entries() reads them.  A key or value that isn't UTF-8 is written in hex as "key_hex" or
+	// "value_hex" instead, and a KV that expires has "expires" as well, in ms since the epoch.  Returns how
+	// many were written.
+	//
+	pub fn export_json(&self, mut writer: impl Write) -> Result<usize, BitcaskError> {
+	    self.touch();
+	    let mut count: usize = 0;
+	    for rec in self.live_records() {
+		let rec = rec?;
+		let key = match std::str::from_utf8(&rec.key) {
+		    Ok(text) => format!("\"key\":{}", quote(text)),
+		    Err(_) => format!("\"key_hex\":\"{}\"", hex(&rec.key)),
+		};
+		let value = self.value_of(&rec)?;
+		let value = match std::str::from_utf8(&value) {
+		    Ok(text) => format!("\"value\":{}", quote(text)),
+		    Err(_) => format!("\"value_hex\":\"{}\"", hex(&value)),
+		};
+		let expires = match rec.expires {
+		    0 => String::new(),
+		    expires => format!(",\"expires\":{}", expires),
+		};
+		writeln!(writer, "{{{},{}{}}}", key, value, expires)?;
+		count += 1;
+	    }
+	    writer.flush()?;
+	    Ok(count)
+	}
+
+	//
+	// Write a CSV line for every KV, in datafile order as entries() reads them, for auditing what's in the
+	// store: its key, the length of its value, the datafile and offset of its record, and when it was
+	// written, in ms since the epoch, and its value as well if 'values' is set.  A key or value that isn't
+	// UTF-8 is written in 0x-prefixed hex, as the command line does.  The first line names the columns.
+	// Returns how many KVs were written.
+	//
+	pub fn export_csv(&self, mut writer: impl Write, values: bool) -> Result<usize, BitcaskError> {
+	    self.touch();
+	    writeln!(writer, "key,value_size,file_id,offset,timestamp{}", if values { ",value" } else { "" })?;
+	    let mut count: usize = 0;
+	    let mut records = self.live_records();
+	    while let Some(rec) = records.next() {
+		let rec = rec?;
+		let value = self.value_of(&rec)?;
+		write!(writer, "{},{},{},{},{}", csv_bytes(&rec.key), value.len(), records.fileid, records.offset, rec.timestamp)?;
+		match values {
+		    true => writeln!(writer, ",{}", csv_bytes(&value))?,
+		    false => writeln!(writer)?,
+		}
+		count += 1;
+	    }
+	    writer.flush()?;
+	    Ok(count)
+	}
+
+	//
+	// Put every KV export_json() wrote, a line at a time as they're read, expiries and all, skipping any that
+	// have expired since and any blank lines.  A line that isn't a KV stops the import with an
+	// InvalidArgument saying which line it is, the KVs before it stay put.  Returns how many were put.
+	//
+	pub fn import_json(&self, reader: impl BufRead) -> Result<usize, BitcaskError> {
+	    self.writable()?;
+	    let now = now_millis();
+	    let mut count: usize = 0;
+	    for (n, line) in reader.lines().enumerate() {
+		let line = line?;
+		if line.trim().is_empty() {
+		    continue;
+		}
+		let bad = |why: &str| BitcaskError::InvalidArgument(format!("line {}: {}", n + 1, why));
+		let (mut key, mut value, mut expires) = (None, None, 0);
+		for (name, member) in parse_object(&line).map_err(|e| bad(&e))? {
+		    match (name.as_str(), member) {
+			("key", JsonValue::String(text)) => key = Some(text.into_bytes()),
+			("key_hex", JsonValue::String(text)) => key = Some(unhex(&text).ok_or_else(|| bad("key_hex isn't hex"))?),
+			("value", JsonValue::String(text)) => value = Some(text.into_bytes()),
+			("value_hex", JsonValue::String(text)) => value = Some(unhex(&text).ok_or_else(|| bad("value_hex isn't hex"))?),
+			("expires", JsonValue::Number(ms)) => expires = ms,
+			_ => return Err(bad(&format!("unexpected member {}", name))),
+		    }
+		}
+		let (Some(key), Some(value)) = (key, value) else {
+		    return Err(bad("needs a key and a value"));
+		};
+		if expires != 0 && expires <= now {
+		    continue;
+		}
+		let _stripe = self.key_lock(&key);		// Serialize with any other writer of this key
+		self.put_locked(&key, &value, expires)?;
+		count += 1;
+	    }
+	    Ok(count)
+	}
+
+	//
+	// Import every record in a datafile from somewhere else (e.g. another Bitcask's directory) into this one.
+	// The records are appended to the current datafile in batches, and each batch is synced and then installed
+	// into the live keymap before the next one starts, so readers see the imported KVs progressively rather
+	// than all at once at the end.  'progress' is called with the running count after each batch is visible.
+	// Values in blob files are read from the blob files alongside it, and imported into the records.  A value
+	// bigger than BitcaskOptions::max_value_size() allows stops the import with ValueTooLarge, the batches
+	// before it stay put.  Returns the number of records imported.
+	//
+	pub fn import_datafile(&self, filename: &str, mut progress: impl FnMut(usize)) -> Result<usize, BitcaskError> {
+	    self.writable()?;
+	    let src = BitcaskDatafile::from_file(filename.to_string(), 0, FileStorage::open_path(filename)?.into(), self.cipher.clone())?;
+	    src.require_header()?;
+	    let dir = Path::new(filename).parent().and_then(Path::to_str).filter(|dir| !dir.is_empty()).unwrap_or(".");
+	    let storage = FileStorage::new(dir);
+	    let blobs = BlobStore::open(&storage, &storage.list()?)?;
+	    let mut batch: Vec<BitcaskDatafileRecord> = Vec::with_capacity(BITCASK_IMPORT_BATCH_SIZE);
+	    let mut count: usize = 0;
+	    src.scan_committed(|_, rec| {
+		if matches!(rec.op, BitcaskDatafileRectype::BEGIN | BitcaskDatafileRectype::COMMIT) {
+		    return Ok(true);					// What was atomic there needn't be here, they're keyed by sequence anyway
+		}
+		batch.push(self.inlined(rec, &blobs)?);
+		if batch.len() == BITCASK_IMPORT_BATCH_SIZE {
+		    count += self.import_batch(&batch)?;
+		    batch.clear();
+		    progress(count);
+		}
+		Ok(true)
+	    })?;
+	    if !batch.is_empty() {
+		count += self.import_batch(&batch)?;
+		progress(count);
+	    }
+	    Ok(count)
+	}
+
+	//
+	// Append one batch of imported records to the current datafile, between batch markers as write() does,
+	// so a crash part way through leaves none of it, make them durable, and then install and publish them.
+	// A concurrent put or delete of the same key may have landed after our copy of it, so an imported record
+	// only wins if it's later in the log than whatever the keymap already has for that key.
+	//
+	// The records are all given the one new sequence number from this store, as a WriteBatch's are, so
+	// truncate_to_sequence() never cuts a batch in two.
+	//
+	fn import_batch(&self, batch: &[BitcaskDatafileRecord]) -> Result<usize, io::Error> {
+	    let values: Vec<Option<Vec<u8>>> = batch.iter()
+		.map(|rec| match rec.op {
+		    BitcaskDatafileRectype::PUT => rec.value_bytes().map(Some),
+		    _ => Ok(None),
+		})
+		.collect::<Result<_, io::Error>>()?;
+	    if let Some(size) = values.iter().flatten().map(Vec::len).find(|size| *size > self.max_value_size) {
+		return Err(BitcaskError::ValueTooLarge { size, limit: self.max_value_size }.into());
+	    }
+	    let adding: u64 = batch.iter()
+		.filter(|rec| matches!(rec.op, BitcaskDatafileRectype::PUT))
+		.map(|rec| BitcaskDatafileRecord::disk_size(rec.key.len(), rec.value.len() as i32, self.alignment) as u64)
+		.sum();
+	    if adding > 0 {
+		self.make_room(adding)?;				// Before merge_lock, it may have to merge
+	    }
+	    let _merging = self.merge_lock.lock().unwrap();		// A merge must not seal the batch before its keymap entries are installed
+	    let df = self.reserve_current()?;				// Rotated first if it's full, batches carry on in the next one
+	    let (recs, offsets) = {
+		let _numbering = self.numbering.lock().unwrap();	// Keeps other writers from numbering records between ours
+		let seq = self.sequence.load(Ordering::SeqCst) + 1;
+		let mut recs = vec![BitcaskDatafileRecord::new(&[], BitcaskDatafileRectype::BEGIN, "", seq)?];
+		for rec in batch {
+		    let mut rec = Box::new(rec.clone());
+		    rec.seq = seq;
+		    rec.crc = rec.checksum();				// Left as it was, it would no longer match
+		    recs.push(rec);
+		}
+		recs.push(BitcaskDatafileRecord::new(&[], BitcaskDatafileRectype::COMMIT, "", seq)?);
+		let offsets = df.append_all(&recs, false)?;
+		self.sequence.store(seq, Ordering::SeqCst);
+		self.note_dead(df.id, 0, 0);				// Nothing ever looks at the markers again
+		self.note_dead(df.id, 0, 0);
+		(recs, offsets)
+	    };								// Drop the numbering lock
+	    self.sync_datafile(&df)?;					// The batch must be stable before anyone can see it
+	    let mut events: Vec<ChangeEvent> = Vec::with_capacity(batch.len());
+	    {
+		let mut map = self.keymap.write_keys(batch.iter().map(|rec| rec.key.as_slice()));	// Protect the data structure while we do our installs
+		for ((rec, &offset), value) in recs[1..].iter().zip(&offsets[1..]).zip(values) {
+		    let (key, value_size) = (&rec.key, df.stored_value_size(rec));
+		    if map.get(key).is_some_and(|entry| (entry.fileid, entry.offset) > (df.id, offset)) {
+			self.note_dead(df.id, key.len(), value_size);
+			continue;					// Somebody beat us to it
+		    }
+		    self.uncache(key);
+		    let (old, op) = match rec.op {
+			BitcaskDatafileRectype::PUT => {
+			    self.bloom_insert(key);
+			    (map.insert(key.clone(), *BitcaskKeymapEntry::new(value_size, df.id, offset, rec.seq, rec.expires, rec.timestamp))?, ChangeOp::Put)
+			},
+			_ => {
+			    self.note_dead(df.id, key.len(), value_size);
+			    (map.remove(key), ChangeOp::Delete)
+			},
+		    };
+		    if let Some(old) = old {
+			self.note_dead(old.fileid, key.len(), old.value_size);
+		    }
+		    events.push(ChangeEvent { key: key.clone(), op, value, seq: rec.seq, fileid: df.id, offset });
+		}
+	    }								// Drop the writer lock
+	    for event in events {
+		self.publish(event);
+	    }
+	    Ok(batch.len())
+	}
+
+	//
+	// Bring this store, a follower, up to date with the primary whose files are in 'primary', which may be
+	// being written to as we look, by applying every put and delete there newer than anything we have, in
+	// order.  They keep the primary's sequence numbers, so what we've applied is always our own sequence,
+	// whether or not we've been reopened since.  Only datafiles from 'from' on are read, pass the ID this
+	// returned last time along with the count of records applied, 0 the first time.
+	//
+	// A merge there compacts away history, up to its horizon.  If that's further than we've got to, we
+	// can't tell what was deleted in between from the records alone, so every datafile is read and any key
+	// we have that the primary has no record of at all is deleted, as of the horizon.  A datafile merged
+	// away while we're reading it fails with NotFound, the next call picks up where this one got to.
+	//
+	pub fn replicate_from(&self, primary: &dyn Storage, from: BitcaskFileID) -> Result<(usize, BitcaskFileID), BitcaskError> {
+	    self.writable()?;
+	    let _merging = self.merge_lock.lock().unwrap();		// A merge must not seal a batch before its keymap entries are installed
+	    let names = primary.list()?;
+	    let applied = self.sequence.load(Ordering::SeqCst);
+	    let horizon = match Self::load_horizon(primary, &names)? {
+		u64::MAX => return Err(BitcaskError::InvalidFormat { file: BITCASK_HORIZON_FILE.to_string(), reason: "is malformed".to_string() }),
+		horizon => horizon,
+	    };
+	    let resync = horizon > applied;
+	    let mut seen: HashSet<Vec<u8>> = HashSet::new();		// Every key the primary has a record of, when resyncing
+	    let (mut count, mut newest) = (0, from);
+	    let blobs = BlobStore::open(primary, &names)?;			// Its big values come over into our records
+	    for id in Self::datafile_ids(&names).into_iter().filter(|id| resync || *id >= from) {
+		let df = BitcaskDatafile::open(primary, id, self.cipher.clone())?.whole_records()?;
+		df.require_header()?;
+		let mut batch: Vec<BitcaskDatafileRecord> = Vec::new();
+		df.scan_committed(|_, rec| {
+		    if resync {
+			seen.insert(rec.key.clone());
+		    }
+		    if rec.seq > applied && matches!(rec.op, BitcaskDatafileRectype::PUT | BitcaskDatafileRectype::DELETE) {
+			batch.push(self.inlined(rec, &blobs)?);
+		    }
+		    Ok(true)
+		})?;
+		batch.sort_by_key(|rec| rec.seq);			// A merged datafile's aren't in order
+		for chunk in batch.chunks(BITCASK_IMPORT_BATCH_SIZE) {
+		    count += self.replicate_batch(chunk)?;
+		}
+		newest = id;
+	    }
+	    if resync {
+		let gone: Vec<Vec<u8>> = self.keymap.read_all().iter().filter(|(key, _)| !seen.contains(*key)).map(|(key, _)| key.to_vec()).collect();
+		let deletes = gone.iter().map(|key| BitcaskDatafileRecord::new(key, BitcaskDatafileRectype::DELETE, "", horizon).map(|rec| *rec))
+		    .collect::<Result<Vec<BitcaskDatafileRecord>, io::Error>>()?;
+		for chunk in deletes.chunks(BITCASK_IMPORT_BATCH_SIZE) {
+		    count += self.replicate_batch(chunk)?;
+		}
+	    }
+	    Ok((count, newest))
+	}
+
+	//
+	// Append one batch of replicated records to the current datafile as they are, sequence numbers and all,
+	// make them durable, and then install and publish them.  A record only wins if it's newer than what the
+	// keymap has for its key, one read from a hint counts as older than anything replicated.
+	//
+	fn replicate_batch(&self, batch: &[BitcaskDatafileRecord]) -> Result<usize, io::Error> {
+	    let df = self.reserve_current()?;				// Rotated first if it's full, batches carry on in the next one
+	    let mut located: Vec<(&BitcaskDatafileRecord, i32, i64)> = Vec::with_capacity(batch.len());
+	    {
+		let _numbering = self.numbering.lock().unwrap();	// Keeps other writers from numbering records between ours
+		for rec in batch {
+		    let offset = df.append(rec, false)?;
+		    self.sequence.fetch_max(rec.seq, Ordering::SeqCst);
+		    located.push((rec, df.stored_value_size(rec), offset));
+		}
+	    }
+	    self.sync_datafile(&df)?;					// The batch must be stable before anyone can see it
+	    let mut events: Vec<ChangeEvent> = Vec::with_capacity(batch.len());
+	    {
+		let mut map = self.keymap.write_keys(batch.iter().map(|rec| rec.key.as_slice()));	// Protect the data structure while we do our installs
+		for (rec, value_size, offset) in located {
+		    let key = &rec.key;
+		    if map.get(key).is_some_and(|entry| entry.generation >= rec.seq) {
+			self.note_dead(df.id, key.len(), value_size);
+			continue;					// We have it already, or something newer
+		    }
+		    self.uncache(key);
+		    let (old, op, value) = match rec.op {
+			BitcaskDatafileRectype::PUT => {
+			    self.bloom_insert(key);
+			    (map.insert(key.clone(), *BitcaskKeymapEntry::new(value_size, df.id, offset, rec.seq, rec.expires, rec.timestamp))?, ChangeOp::Put, Some(rec.value_bytes()?))
+			},
+			_ => {
+			    self.note_dead(df.id, key.len(), value_size);
+			    (map.remove(key), ChangeOp::Delete, None)
+			},
+		    };
+		    if let Some(old) = old {
+			self.note_dead(old.fileid, key.len(), old.value_size);
+		    }
+		    events.push(ChangeEvent { key: key.clone(), op, value, seq: rec.seq, fileid: df.id, offset });
+		}
+	    }								// Drop the writer lock
+	    for event in events {
+		self.publish(event);
+	    }
+	    Ok(batch.len())
+	}
+
+	//
+	// Rewind the store to the moment just after sequence number 'seq' was written, discarding every later put
+	// and delete as though it never happened.  The datafiles are cut back past the last surviving record and
+	// the keymap is rebuilt from what's left.  Subscribers are not told about the discarded changes.
+	//
+	// A merge destroys the history up to its horizon, so a rewind to before the last merge's horizon is
+	// refused with InvalidInput.  Rewinding to the present or the future is a no-op.
+	//
+	// Writers and merges are held off while this runs, readers only while the keymap is being rebuilt.
+	//
+	pub fn truncate_to_sequence(&self, seq: u64) -> Result<bool, BitcaskError> {
+	    self.writable()?;
+	    let _merging = self.merge_lock.lock().unwrap();		// No merge may move records while we cut
+	    let _rotating = self.rotate_lock.lock().unwrap();		// Nor may a rotation change the set of files
+	    let current = self.current.read().unwrap();
+	    let datafiles = self.datafiles.read().unwrap();
+	    let mut keymap = self.keymap.write_all();			// Writers append under their shard, so none can now
+	    Self::remove_snapshot(&*self.storage)?;			// The datafiles may grow back past the cuts
+	    let horizon = self.horizon.load(Ordering::SeqCst);
+	    if seq < horizon {
+		return Err(BitcaskError::InvalidArgument(format!("sequence {} has already been merged away (horizon {})", seq, horizon)));
+	    }
+	    if seq >= self.sequence.load(Ordering::SeqCst) {
+		return Ok(true);					// Nothing was written after it
+	    }
+
+	    // Work out where to cut every file before touching any of them
+	    let mut files: Vec<&BitcaskDatafile> = datafiles.values().collect();
+	    files.push(&current);
+	    files.sort_by_key(|df| df.id);
+	    let mut cuts: Vec<(&BitcaskDatafile, i64, usize)> = Vec::new();
+	    for df in &files {
+		let mut cut: Option<i64> = None;
+		let mut kept: usize = 0;
+		df.scan(|offset, rec| {
+		    match cut {
+			None if rec.seq > seq => cut = Some(offset),
+			None => kept += 1,
+			Some(_) if rec.seq > seq => {},
+			Some(_) => return Err(df.corruption(offset, "has a sequence number lower than the one before it")),
+		    }
+		    Ok(true)
+		})?;
+		if let Some(cut) = cut {
+		    cuts.push((df, cut, kept));
+		}
+	    }
+
+	    let names = self.storage.list()?;
+	    for (df, cut, kept) in cuts {
+		let hintsname = format!("{}.hints", df.id);
+		if names.contains(&hintsname) {
+		    self.storage.remove(&hintsname)?;			// It speaks for records that are about to go
+		}
+		df.file.truncate(cut as u64)?;
+		df.file.sync()?;
+		df.records.store(kept, Ordering::SeqCst);
+		df.written.store(cut as u64, Ordering::SeqCst);
+		df.synced.fetch_min(cut as u64, Ordering::SeqCst);		// What's appended from here on hasn't been
+		df.newest.store(0, Ordering::SeqCst);			// Work it out again if anyone asks
+	    }
+
+	    let mut rebuilt = keymap.empty_like()?;
+	    let mut max_seq: u64 = 0;
+	    for df in &files {
+		Self::replay_datafile(&mut rebuilt, df, &mut max_seq)?;
+	    }
+	    *self.dead_bytes.lock().unwrap() = Self::count_dead_bytes(files.into_iter(), &rebuilt)?;
+	    keymap.replace(rebuilt)?;
+	    self.rebuild_bloom(&keymap);
+	    if let Some(cache) = &self.cache {
+		cache.lock().unwrap().clear();			// The offsets past the cuts will be used again
+	    }
+	    self.sequence.store(seq, Ordering::SeqCst);
+	    self.changes.lock().unwrap().retain(|event| event.seq <= seq);	// Don't hand out events for changes that are gone
+	    Ok(true)
+	}
+
+	//
+	// Shutdown the whole system: stop the background threads and wait for them, flush and hint every
+	// datafile, the current one too, and leave a note that the store was closed cleanly, with what the next
+	// open would otherwise have to work out, see CleanShutdown.  Then let go of the store, after which
+	// reads still work but every write fails with ShutDown.  Only the first successful call does anything,
+	// later ones return Ok(true) straight away.  If it fails it can be retried.
+	//
+	pub fn shutdown(&self) -> Result<bool, BitcaskError> {
+	    if self.shut_down.load(Ordering::SeqCst) {
+		return Ok(true);
+	    }
+	    self.stop_maintenance();				// Before merge_lock, it may be merging
+	    self.pool.wait();					// Likewise, and hints it's making aren't missing
+	    let _merging = self.merge_lock.lock().unwrap();	// Two shutdowns at once must not both do the work
+	    if self.shut_down.load(Ordering::SeqCst) {
+		return Ok(true);				// The other one got there first
+	    }
+	    if self.read_only {
+		self.shut_down.store(true, Ordering::SeqCst);	// Nothing was written, there's nothing to flush or hint
+		return Ok(true);
+	    }
+	    if let Some(flusher) = &self.flusher {
+		flusher.stop();
+	    }
+	    self.sync()?;
+	    BitcaskHintsfile::hintsfile_find_missing_files(self, &*self.storage)?;
+	    {
+		let df = self.current.read().unwrap();		// It's read-only from the next open on, so it gets hints too
+		BitcaskHintsfile::hintsfile_generate(&*self.storage, &df)?;
+	    }
+	    if self.snapshot_interval.is_some() {
+		self.save_snapshot_locked()?;			// So the next open reads nothing else
+	    }
+	    self.save_clean_shutdown()?;
+	    self.storage_lock.lock().unwrap().take();		// Let the next process in, we're done with the files
+	    self.shut_down.store(true, Ordering::SeqCst);
+	    Ok(true)
+	}
+
+	//
+	// Write down what the next open needs from us, as it is with no writes going on.  A write that gets in
+	// after we let go of them makes it untrue, which the next open sees, and it's ignored.
+	//
+	fn save_clean_shutdown(&self) -> Result<bool, io::Error> {
+	    let clean = {
+		let df = self.current.read().unwrap();			// No rotation while we look
+		let _map = self.keymap.read_all();			// Writers append under their shard, so none can now
+		let mut files: BTreeMap<BitcaskFileID, (u64, u64)> = BTreeMap::new();
+		let dead_bytes = self.dead_bytes.lock().unwrap();
+		for archived in self.datafiles.read().unwrap().values().chain(std::iter::once(&*df)) {
+		    files.insert(archived.id, (archived.size()?, dead_bytes.get(&archived.id).copied().unwrap_or(0)));
+		}
+		CleanShutdown { seq: self.sequence.load(Ordering::SeqCst), files }
+	    };
+	    let file = self.storage.create(BITCASK_CLEAN_TMP_FILE)?;
+	    file.append(&clean.to_bytes())?;
+	    file.sync()?;
+	    self.storage.rename(BITCASK_CLEAN_TMP_FILE, BITCASK_CLEAN_FILE)?;
+	    Ok(true)
+	}
+
+	//
+	// Wait for the hints and merges handed to the pool so far, for tests that count what they did.
+	//
+	#[cfg(test)]
+	pub(crate) fn wait_for_background(&self) {
+	    self.pool.wait();
+	}
+
+	//
+	// Drop the store the way a crash would leave it, without shutting it down, for tests of recovery.
+	//
+	#[cfg(test)]
+	pub(crate) fn crash(self: Box<Bitcask>) {
+	    self.shut_down.store(true, Ordering::SeqCst);
+	}
+    }
+
+    //
+    // A store that goes out of scope is shut down as if shutdown() had been called, if it hasn't been
+    // already, so its writes are flushed, its hints written and its lock let go all the same.  There's no
+    // one to hand an error to, so it's logged.  Unwinding from a panic, the store may be part way through
+    // something, so it's left as a crash would leave it.
+    //
+    impl Drop for Bitcask {
+	fn drop(&mut self) {
+	    if std::thread::panicking() {
+		return;
+	    }
+	    if let Err(e) = self.shutdown() {
+		log::error!("shutting down the store as it was dropped failed: {}", e);
+	    }
 	}
     }
 }
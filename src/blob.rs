@@ -0,0 +1,297 @@
+//
+// Module that keeps values too big to want copying around in files of their own, with the datafiles
+// holding just a pointer to each, see Bitcask::set_blob_threshold().
+//
+// The blob file "<id>.blob" holds the big values put while datafile <id> was current, one after another
+// with nothing in between, and is only ever appended to.  A BlobPointer says which file the value is in,
+// where, how long it is, and the crc of its bytes there, so a value that's been damaged is caught when
+// it's read rather than handed out.  Blob files outlive their datafiles: a merge copies the pointers and
+// not the values, so a blob file only goes once no record a merge kept points into it.
+//
+// A BlobWriter puts a value in a piece at a time, for values streamed in rather than held in memory,
+// and has the file to itself until it's done, so the pieces end up one after another.  A stream can take
+// a while, so until its pointer is in a record the file it's writing to is pinned, and no merge can
+// take it away.
+//
+pub mod blob {
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+    use crate::error::error::BitcaskError;
+    use crate::storage::storage::{Storage, StorageFile};
+
+    // How many bytes a BlobPointer takes: id, flags, offset, len, and crc.
+    pub const BLOB_POINTER_SIZE: usize = 28;
+
+    // The bits of a BlobPointer's flags.
+    pub const BLOB_FLAG_SEALED: u32 = 1;		// The bytes are the value encrypted, see RecordCipher::seal()
+    pub const BLOB_FLAG_CHUNKED: u32 = 2;		// Sealed a BLOB_CHUNK_SIZE piece at a time, see chunk_aad()
+
+    // How much of a value a stream reads or writes at once, and how much a chunked blob seals at once.
+    // NOTE: this IS an on-disk value, chunked blobs can't be opened without it
+    pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+    //
+    // What the chunk 'index' of a chunked blob of 'key' is sealed for, so a chunk can't be swapped for
+    // another, from this blob or anyone else's.
+    //
+    pub fn chunk_aad(key: &[u8], index: u64) -> Vec<u8> {
+	let mut aad = key.to_vec();
+	aad.extend_from_slice(&index.to_le_bytes());
+	aad
+    }
+
+    //
+    // Where a value lives, as a record stores it in place of the value.
+    // NOTE: this IS an on-disk value, it cannot be changed without affecting data retention
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct BlobPointer {
+	pub id: i32,				// The blob file it's in
+	pub flags: u32,				// BLOB_FLAG_*
+	pub offset: u64,			// Where in the file its bytes start
+	pub len: u64,				// How many bytes there are
+	pub crc: u32,				// Of those bytes
+    }
+    impl BlobPointer {
+	pub fn to_bytes(&self) -> Vec<u8> {
+	    let mut buf = Vec::with_capacity(BLOB_POINTER_SIZE);
+	    buf.extend_from_slice(&self.id.to_le_bytes());
+	    buf.extend_from_slice(&self.flags.to_le_bytes());
+	    buf.extend_from_slice(&self.offset.to_le_bytes());
+	    buf.extend_from_slice(&self.len.to_le_bytes());
+	    buf.extend_from_slice(&self.crc.to_le_bytes());
+	    buf
+	}
+
+	//
+	// Decode a pointer, None if 'bytes' can't be one.
+	//
+	pub fn from_bytes(bytes: &[u8]) -> Option<BlobPointer> {
+	    if bytes.len() != BLOB_POINTER_SIZE {
+		return None;
+	    }
+	    Some(BlobPointer {
+		id: i32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+		flags: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+		offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+		len: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+		crc: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+	    })
+	}
+    }
+
+    pub struct BlobStore {
+	files: RwLock<HashMap<i32, Arc<dyn StorageFile>>>,	// Every blob file there is, by ID
+	bytes: AtomicU64,					// How big they all are together
+	appending: Mutex<()>,					// Held while anything is appended, so a BlobWriter's bytes stay together
+	pinned: Mutex<Vec<i32>>,				// The files BlobWriters are writing to, one entry for each
+    }
+    impl BlobStore {
+	//
+	// Open the blob files among 'names', the files in 'storage'.
+	//
+	pub fn open(storage: &dyn Storage, names: &[String]) -> Result<BlobStore, io::Error> {
+	    let mut files: HashMap<i32, Arc<dyn StorageFile>> = HashMap::new();
+	    let mut bytes: u64 = 0;
+	    for id in Self::ids(names) {
+		let file: Arc<dyn StorageFile> = storage.open(&Self::name(id))?.into();
+		bytes += file.size()?;
+		files.insert(id, file);
+	    }
+	    Ok(BlobStore { files: RwLock::new(files), bytes: AtomicU64::new(bytes), appending: Mutex::new(()), pinned: Mutex::new(Vec::new()) })
+	}
+
+	pub fn name(id: i32) -> String {
+	    format!("{}.blob", id)
+	}
+
+	//
+	// The IDs of the blob files among 'names'.
+	//
+	pub fn ids(names: &[String]) -> Vec<i32> {
+	    names.iter().filter_map(|name| name.strip_suffix(".blob")?.parse::<i32>().ok()).collect()
+	}
+
+	//
+	// Append 'bytes' to blob file 'id', making the file if it's the first, and flush them out, so no record
+	// can point at them before they're durable.  'flags' go in the pointer as they are.
+	//
+	pub fn write(&self, storage: &dyn Storage, id: i32, bytes: &[u8], flags: u32) -> Result<BlobPointer, io::Error> {
+	    let file = self.file_for(storage, id)?;
+	    let _appending = self.appending.lock().unwrap();	// Not in the middle of somebody's stream
+	    let offset = file.append(bytes)?;
+	    file.sync()?;
+	    self.bytes.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+	    Ok(BlobPointer { id, flags, offset, len: bytes.len() as u64, crc: crc32fast::hash(bytes) })
+	}
+
+	//
+	// Blob file 'id', made if there isn't one yet.
+	//
+	fn file_for(&self, storage: &dyn Storage, id: i32) -> Result<Arc<dyn StorageFile>, io::Error> {
+	    if let Some(file) = self.files.read().unwrap().get(&id) {
+		return Ok(Arc::clone(file));
+	    }
+	    let mut files = self.files.write().unwrap();		// Protect the data structure while we add the file
+	    match files.get(&id) {
+		Some(file) => Ok(Arc::clone(file)),			// Somebody beat us to it
+		None => {
+		    let file: Arc<dyn StorageFile> = storage.create(&Self::name(id))?.into();
+		    files.insert(id, Arc::clone(&file));
+		    Ok(file)
+		},
+	    }
+	}
+
+	//
+	// Start a value in blob file 'id' to be written a piece at a time.  Nothing else is appended to the file
+	// until the writer is finished, and the file stays until it's dropped, see the module comment.
+	//
+	pub fn writer(&self, storage: &dyn Storage, id: i32, flags: u32) -> Result<BlobWriter<'_>, io::Error> {
+	    self.pinned.lock().unwrap().push(id);			// Before we look, so a merge can't remove it from under us
+	    let mut writer = BlobWriter { store: self, id, flags, file: None, appending: None, offset: 0, len: 0, crc: crc32fast::Hasher::new() };
+	    let file = self.file_for(storage, id)?;
+	    writer.appending = Some(self.appending.lock().unwrap());
+	    writer.offset = file.size()?;				// Where our first append will go, as nobody else can
+	    writer.file = Some(file);
+	    Ok(writer)
+	}
+
+	//
+	// The bytes 'ptr' points at, checked against its crc.
+	//
+	pub fn read(&self, ptr: &BlobPointer) -> Result<Vec<u8>, io::Error> {
+	    let file = self.file_of(ptr)?;
+	    let len = usize::try_from(ptr.len).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "blob too big to read"))?;
+	    let mut buf = vec![0u8; len];
+	    file.read_exact_at(ptr.offset, &mut buf)?;
+	    if crc32fast::hash(&buf) != ptr.crc {
+		return Err(Self::bad_checksum(ptr));
+	    }
+	    Ok(buf)
+	}
+
+	//
+	// Just 'len' of the bytes 'ptr' points at, from 'at' on.  The crc is of all of them, so it can't be
+	// checked.
+	//
+	pub fn read_at(&self, ptr: &BlobPointer, at: u64, len: usize) -> Result<Vec<u8>, io::Error> {
+	    if at.checked_add(len as u64).is_none_or(|end| end > ptr.len) {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "reading past the end of a blob"));
+	    }
+	    let mut buf = vec![0u8; len];
+	    self.file_of(ptr)?.read_exact_at(ptr.offset + at, &mut buf)?;
+	    Ok(buf)
+	}
+
+	//
+	// Hand the bytes 'ptr' points at to 'f' in pieces of 'piece' bytes, the last maybe shorter.  The crc
+	// can only be checked once the last piece is read, so 'f' may have seen all of a damaged blob before
+	// we say it was.
+	//
+	pub fn stream(&self, ptr: &BlobPointer, piece: usize, mut f: impl FnMut(&[u8]) -> Result<(), io::Error>) -> Result<(), io::Error> {
+	    let file = self.file_of(ptr)?;
+	    let mut buf = vec![0u8; piece.min(ptr.len as usize)];
+	    let mut crc = crc32fast::Hasher::new();
+	    let mut done: u64 = 0;
+	    while done < ptr.len {
+		let len = (ptr.len - done).min(piece as u64) as usize;
+		file.read_exact_at(ptr.offset + done, &mut buf[..len])?;
+		crc.update(&buf[..len]);
+		f(&buf[..len])?;
+		done += len as u64;
+	    }
+	    if crc.finalize() != ptr.crc {
+		return Err(Self::bad_checksum(ptr));
+	    }
+	    Ok(())
+	}
+
+	//
+	// The blob file 'ptr' points into.
+	//
+	fn file_of(&self, ptr: &BlobPointer) -> Result<Arc<dyn StorageFile>, io::Error> {
+	    match self.files.read().unwrap().get(&ptr.id) {
+		Some(file) => Ok(Arc::clone(file)),
+		None => Err(BitcaskError::Corruption { file: Self::name(ptr.id), offset: ptr.offset as i64, reason: "points into a blob file that isn't there".to_string() }.into()),
+	    }
+	}
+
+	fn bad_checksum(ptr: &BlobPointer) -> io::Error {
+	    BitcaskError::Corruption { file: Self::name(ptr.id), offset: ptr.offset as i64, reason: "has a blob with a bad checksum".to_string() }.into()
+	}
+
+	//
+	// Remove every blob file that 'keep' says nobody points into any more, returning how many went.
+	//
+	pub fn remove_unless(&self, storage: &dyn Storage, keep: impl Fn(i32) -> bool) -> Result<usize, io::Error> {
+	    let mut files = self.files.write().unwrap();		// Protect the data structure while we do our removals
+	    let pinned = self.pinned.lock().unwrap();			// And keep what's being written to until it is pointed at
+	    let gone: Vec<i32> = files.keys().copied().filter(|id| !keep(*id) && !pinned.contains(id)).collect();
+	    for id in &gone {
+		let size = files[id].size()?;
+		storage.remove(&Self::name(*id))?;
+		files.remove(id);
+		self.bytes.fetch_sub(size, Ordering::SeqCst);
+	    }
+	    Ok(gone.len())
+	}
+
+	//
+	// How many bytes the blob files take up.
+	//
+	pub fn bytes(&self) -> u64 {
+	    self.bytes.load(Ordering::SeqCst)
+	}
+    }
+
+    //
+    // A value being appended to a blob file a piece at a time, see BlobStore::writer().
+    //
+    pub struct BlobWriter<'a> {
+	store: &'a BlobStore,
+	id: i32,
+	flags: u32,
+	file: Option<Arc<dyn StorageFile>>,
+	appending: Option<MutexGuard<'a, ()>>,		// Until we're finished
+	offset: u64,					// Where the value starts
+	len: u64,					// How much of it there is so far
+	crc: crc32fast::Hasher,				// Of that much
+    }
+    impl BlobWriter<'_> {
+	pub fn write(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+	    let Some(file) = &self.file else {
+		return Err(io::Error::other("the blob is already finished"));
+	    };
+	    file.append(bytes)?;
+	    self.store.bytes.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+	    self.len += bytes.len() as u64;
+	    self.crc.update(bytes);
+	    Ok(())
+	}
+
+	//
+	// Flush the value out, so a record can point at it, and let others append to the file again.  The file
+	// stays pinned until the writer is dropped.
+	//
+	pub fn finish(&mut self) -> Result<BlobPointer, io::Error> {
+	    let Some(file) = self.file.take() else {
+		return Err(io::Error::other("the blob is already finished"));
+	    };
+	    file.sync()?;
+	    self.appending = None;
+	    Ok(BlobPointer { id: self.id, flags: self.flags, offset: self.offset, len: self.len, crc: self.crc.clone().finalize() })
+	}
+    }
+    impl Drop for BlobWriter<'_> {
+	fn drop(&mut self) {
+	    self.appending = None;
+	    let mut pinned = self.store.pinned.lock().unwrap();
+	    if let Some(i) = pinned.iter().position(|id| *id == self.id) {
+		pinned.swap_remove(i);
+	    }
+	}
+    }
+}
@@ -0,0 +1,62 @@
+//
+// Module that answers "is this key definitely not in the store?" without looking at the keymap.
+//
+// A Bloom filter: every key sets a handful of bits picked by hashing it, and a key none of whose bits
+// are set was never added.  One whose bits are all set probably was, the odds of being wrong depend on
+// how full the filter is.  Keys can't be taken out again, so a filter only gets fuller until it's built
+// afresh from the keys there are now.  The bits are atomics, adding and asking need no lock at all.
+//
+pub mod bloom {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Bits per key the filter is sized for, and how many of them each key sets.  Together they give about
+    // a 1% false positive rate when the filter holds as many keys as it was sized for.
+    const BLOOM_BITS_PER_KEY: usize = 10;
+    const BLOOM_HASHES: u64 = 7;
+
+    // The fewest keys a filter is sized for, so a new store isn't saturated by its first few puts.
+    const BLOOM_MIN_KEYS: usize = 1024;
+
+    pub struct BloomFilter {
+	bits: Vec<AtomicU64>,
+    }
+    impl BloomFilter {
+	//
+	// An empty filter with room for 'keys' keys at its intended false positive rate.
+	//
+	pub fn new(keys: usize) -> BloomFilter {
+	    let words = (keys.max(BLOOM_MIN_KEYS) * BLOOM_BITS_PER_KEY).div_ceil(64);
+	    BloomFilter { bits: (0..words).map(|_| AtomicU64::new(0)).collect() }
+	}
+
+	pub fn insert(&self, key: &[u8]) {
+	    for bit in self.bits_of(key) {
+		self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::SeqCst);
+	    }
+	}
+
+	//
+	// False only if 'key' was never inserted.
+	//
+	pub fn might_contain(&self, key: &[u8]) -> bool {
+	    self.bits_of(key).all(|bit| self.bits[bit / 64].load(Ordering::SeqCst) & (1 << (bit % 64)) != 0)
+	}
+
+	//
+	// The bits 'key' sets, by double hashing: two hashes of the key, the second stepping from the first.
+	//
+	fn bits_of(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+	    let hash = |seed: u64| {
+		let mut hasher = DefaultHasher::new();
+		seed.hash(&mut hasher);
+		key.hash(&mut hasher);
+		hasher.finish()
+	    };
+	    let (first, step) = (hash(0), hash(1) | 1);	// Never 0, or every bit would be the first
+	    let size = self.bits.len() as u64 * 64;
+	    (0..BLOOM_HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % size) as usize)
+	}
+    }
+}
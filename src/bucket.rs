@@ -0,0 +1,99 @@
+//
+// Module that lets one store hold several keyspaces that keep out of each other's way, each a Bucket.
+//
+// A bucket is only a prefix: its name and a NUL byte go on the front of every key it's handed, and come
+// off every key it hands back, so the store itself neither knows nor cares which buckets there are, and
+// a bucket needs no creating.  Names can't hold a NUL, so no bucket's keys are ever another's.  Keys put
+// without a bucket share the store with them, one that happens to start with a bucket's prefix is that
+// bucket's as far as the bucket can tell.
+//
+pub mod bucket {
+    use std::time::Duration;
+    use crate::bitcask::bitcask::{Bitcask, WriteBatch};
+    use crate::error::error::BitcaskError;
+
+    //
+    // How much of the store one bucket takes up, as Stats has it for the whole store.
+    //
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct BucketStats {
+	pub keys: usize,			// Live keys, those deleted or expired don't count
+	pub live_bytes: u64,			// The bytes of the records they're in
+    }
+
+    pub struct Bucket<'a> {
+	cask: &'a Bitcask,
+	prefix: Vec<u8>,			// The name and a NUL
+    }
+    impl<'a> Bucket<'a> {
+	pub fn new(cask: &'a Bitcask, name: &str) -> Result<Bucket<'a>, BitcaskError> {
+	    if name.is_empty() || name.contains('\0') {
+		return Err(BitcaskError::InvalidArgument(format!("{:?} can't name a bucket", name)));
+	    }
+	    let mut prefix = name.as_bytes().to_vec();
+	    prefix.push(0);
+	    Ok(Bucket { cask, prefix })
+	}
+
+	pub fn name(&self) -> &str {
+	    std::str::from_utf8(&self.prefix[..self.prefix.len() - 1]).unwrap()
+	}
+
+	pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, BitcaskError> {
+	    self.cask.get(self.key(key))
+	}
+
+	pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool, BitcaskError> {
+	    self.cask.put(self.key(key), value)
+	}
+
+	pub fn put_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<bool, BitcaskError> {
+	    self.cask.put_with_ttl(self.key(key), value, ttl)
+	}
+
+	pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool, BitcaskError> {
+	    self.cask.delete(self.key(key))
+	}
+
+	pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+	    self.cask.contains_key(self.key(key))
+	}
+
+	//
+	// The bucket's keys, without the prefix, in no particular order.
+	//
+	pub fn list_keys(&self) -> Vec<Vec<u8>> {
+	    self.cask.list_keys_with_prefix(&self.prefix).into_iter().map(|key| key[self.prefix.len()..].to_vec()).collect()
+	}
+
+	//
+	// The bucket's KVs, in order, as scan_prefix() hands them over.
+	//
+	pub fn entries(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+	    self.cask.scan_prefix(&self.prefix).map(|entry| entry.map(|(key, value)| (key[self.prefix.len()..].to_vec(), value)))
+	}
+
+	//
+	// Delete every key in the bucket, all at once, returning how many there were.  Leaves the rest of the
+	// store alone, and a key put while it runs may or may not survive.
+	//
+	pub fn clear(&self) -> Result<usize, BitcaskError> {
+	    let keys = self.cask.list_keys_with_prefix(&self.prefix);
+	    let mut batch = WriteBatch::new();
+	    for key in &keys {
+		batch.delete(key);
+	    }
+	    self.cask.write(&batch)?;
+	    Ok(keys.len())
+	}
+
+	pub fn stats(&self) -> BucketStats {
+	    let (keys, live_bytes) = self.cask.prefix_usage(&self.prefix);
+	    BucketStats { keys, live_bytes }
+	}
+
+	fn key(&self, key: impl AsRef<[u8]>) -> Vec<u8> {
+	    [&self.prefix, key.as_ref()].concat()
+	}
+    }
+}
@@ -0,0 +1,107 @@
+//
+// Module that keeps recently read values in memory, so the hot keys of a read-heavy store are served
+// without going to the datafiles at all.
+//
+// Each value is kept along with where it was read from, and a lookup says where the keymap has the key
+// now, so a value that has since been overwritten, deleted, or moved by a merge is never handed out:
+// it's as good as gone, whether or not anyone got round to forgetting it.  The least recently used
+// values go first once the keys and values held come to more than the budget.
+//
+pub mod cache {
+    use std::collections::{BTreeMap, HashMap};
+
+    //
+    // One cached value, and where in which datafile it was read from.
+    //
+    struct CachedValue {
+	location: (i32, i64),			// The datafile ID and offset
+	value: Vec<u8>,
+	used: u64,				// The tick it was last handed out or cached at, its place in 'order'
+    }
+
+    pub struct ValueCache {
+	budget: usize,				// The most bytes of keys and values held at once
+	size: usize,				// The bytes of keys and values held now
+	values: HashMap<Vec<u8>, CachedValue>,
+	order: BTreeMap<u64, Vec<u8>>,		// Every key held, least recently used first
+	tick: u64,				// Bumped on every use, so no two in 'order' are the same
+	hits: u64,
+	misses: u64,
+    }
+    impl ValueCache {
+	pub fn new(budget: usize) -> ValueCache {
+	    ValueCache { budget, size: 0, values: HashMap::new(), order: BTreeMap::new(), tick: 0, hits: 0, misses: 0 }
+	}
+
+	//
+	// The value of 'key' if it's held and was read from 'location', which makes it the most recently used.
+	//
+	pub fn get(&mut self, key: &[u8], location: (i32, i64)) -> Option<Vec<u8>> {
+	    let tick = self.next_tick();
+	    match self.values.get_mut(key) {
+		Some(cached) if cached.location == location => {
+		    let key = self.order.remove(&cached.used).unwrap();
+		    cached.used = tick;
+		    self.order.insert(tick, key);
+		    self.hits += 1;
+		    Some(cached.value.clone())
+		},
+		Some(_) => {
+		    self.remove(key);				// Stale, nobody will ever ask for it there again
+		    self.misses += 1;
+		    None
+		},
+		None => {
+		    self.misses += 1;
+		    None
+		},
+	    }
+	}
+
+	//
+	// Hold 'value' as the value of 'key' read from 'location', making room for it if need be.  A value
+	// too big for the whole budget isn't held at all.
+	//
+	pub fn insert(&mut self, key: &[u8], location: (i32, i64), value: &[u8]) {
+	    self.remove(key);
+	    let size = key.len() + value.len();
+	    if size > self.budget {
+		return;
+	    }
+	    while self.size + size > self.budget {
+		let (_, oldest) = self.order.pop_first().unwrap();
+		let cached = self.values.remove(&oldest).unwrap();
+		self.size -= oldest.len() + cached.value.len();
+	    }
+	    let used = self.next_tick();
+	    self.order.insert(used, key.to_vec());
+	    self.values.insert(key.to_vec(), CachedValue { location, value: value.to_vec(), used });
+	    self.size += size;
+	}
+
+	pub fn remove(&mut self, key: &[u8]) {
+	    if let Some(cached) = self.values.remove(key) {
+		self.order.remove(&cached.used);
+		self.size -= key.len() + cached.value.len();
+	    }
+	}
+
+	pub fn clear(&mut self) {
+	    self.values.clear();
+	    self.order.clear();
+	    self.size = 0;
+	}
+
+	//
+	// How many lookups found their value, and how many didn't, since the cache was made.
+	//
+	pub fn counts(&self) -> (u64, u64) {
+	    (self.hits, self.misses)
+	}
+
+	fn next_tick(&mut self) -> u64 {
+	    self.tick += 1;
+	    self.tick
+	}
+    }
+}
@@ -0,0 +1,173 @@
+//
+// Module that compresses record values.
+//
+// The one algorithm is LZ4's block format, written out here rather than pulled in: it's small, fast enough
+// to be worth doing on every put, and its blocks are what any LZ4 library's block API reads and writes.
+// A compressed value is stored as its uncompressed length, 4 bytes little-endian, followed by one block.
+//
+pub mod compress {
+    use std::io;
+
+    // How a store compresses the values it writes.  Which one a record used is in its flags, so a store
+    // can switch and still read everything it wrote before.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub enum Compression {
+	#[default]
+	None,
+	Lz4,
+    }
+
+    // The block format's rules: a match is at least 4 bytes and at most 64KB back, the last 5 bytes are
+    // always literals, and no match starts in the last 12.
+    const LZ4_MIN_MATCH: usize = 4;
+    const LZ4_MAX_OFFSET: usize = 65535;
+    const LZ4_LAST_LITERALS: usize = 5;
+    const LZ4_MATCH_FINDER_LIMIT: usize = 12;
+
+    // log2 of the number of slots in the match finder's hash table.
+    const LZ4_HASH_BITS: u32 = 12;
+
+    //
+    // Compress 'value' into the stored form, or return None if that wouldn't be any smaller.
+    //
+    pub fn lz4_pack(value: &[u8]) -> Option<Vec<u8>> {
+	let len = u32::try_from(value.len()).ok()?;
+	let mut out = Vec::with_capacity(value.len() / 2 + 16);
+	out.extend_from_slice(&len.to_le_bytes());
+	lz4_compress_block(value, &mut out);
+	(out.len() < value.len()).then_some(out)
+    }
+
+    //
+    // Recover the value from its stored form.
+    //
+    pub fn lz4_unpack(stored: &[u8]) -> Result<Vec<u8>, io::Error> {
+	if stored.len() < 4 {
+	    return Err(corrupt("too short"));
+	}
+	let len = u32::from_le_bytes(stored[..4].try_into().unwrap()) as usize;
+	lz4_decompress_block(&stored[4..], len)
+    }
+
+    //
+    // Append the LZ4 block for 'src' to 'out'.  A greedy match finder over a hash table of the 4 byte
+    // sequences seen so far, one candidate per slot.
+    //
+    fn lz4_compress_block(src: &[u8], out: &mut Vec<u8>) {
+	let mut table = vec![0usize; 1 << LZ4_HASH_BITS];	// Position + 1 of the last sequence with each hash, 0 for none
+	let mut anchor: usize = 0;				// Start of the literals not yet emitted
+	let mut i: usize = 0;
+	let limit = src.len().saturating_sub(LZ4_MATCH_FINDER_LIMIT);
+	while i < limit {
+	    let seq = u32::from_le_bytes(src[i..i + 4].try_into().unwrap());
+	    let slot = (seq.wrapping_mul(2654435761) >> (32 - LZ4_HASH_BITS)) as usize;
+	    let candidate = table[slot];
+	    table[slot] = i + 1;
+	    if candidate > 0 {
+		let at = candidate - 1;
+		if i - at <= LZ4_MAX_OFFSET && src[at..at + 4] == src[i..i + 4] {
+		    let mut len = LZ4_MIN_MATCH;
+		    let max = src.len() - LZ4_LAST_LITERALS - i;
+		    while len < max && src[at + len] == src[i + len] {
+			len += 1;
+		    }
+		    lz4_emit(out, &src[anchor..i], Some((i - at, len)));
+		    i += len;
+		    anchor = i;
+		    continue;
+		}
+	    }
+	    i += 1;
+	}
+	lz4_emit(out, &src[anchor..], None);			// The last sequence is only literals
+    }
+
+    //
+    // Append one sequence: the literals, then the match as (offset, length) unless it's the last sequence.
+    //
+    fn lz4_emit(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+	let match_code = matched.map_or(0, |(_, len)| len - LZ4_MIN_MATCH);
+	out.push(((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8);
+	lz4_emit_length(out, literals.len());
+	out.extend_from_slice(literals);
+	if let Some((offset, _)) = matched {
+	    out.extend_from_slice(&(offset as u16).to_le_bytes());
+	    lz4_emit_length(out, match_code);
+	}
+    }
+
+    //
+    // Lengths of 15 and up overflow the token's nibble into a run of bytes, 255 meaning "and more".
+    //
+    fn lz4_emit_length(out: &mut Vec<u8>, len: usize) {
+	if len >= 15 {
+	    let mut rest = len - 15;
+	    while rest >= 255 {
+		out.push(255);
+		rest -= 255;
+	    }
+	    out.push(rest as u8);
+	}
+    }
+
+    fn lz4_read_length(src: &[u8], pos: &mut usize, nibble: usize) -> Result<usize, io::Error> {
+	let mut len = nibble;
+	if nibble == 15 {
+	    loop {
+		let byte = *src.get(*pos).ok_or_else(|| corrupt("length runs off the end"))?;
+		*pos += 1;
+		len += byte as usize;
+		if byte != 255 {
+		    break;
+		}
+	    }
+	}
+	Ok(len)
+    }
+
+    //
+    // Decode an LZ4 block that must come to exactly 'len' bytes.  Nothing in the block is trusted.
+    //
+    fn lz4_decompress_block(src: &[u8], len: usize) -> Result<Vec<u8>, io::Error> {
+	let mut out: Vec<u8> = Vec::with_capacity(len);
+	let mut pos: usize = 0;
+	loop {
+	    let token = *src.get(pos).ok_or_else(|| corrupt("missing token"))? as usize;
+	    pos += 1;
+	    let literals = lz4_read_length(src, &mut pos, token >> 4)?;
+	    let end = pos.checked_add(literals).filter(|end| *end <= src.len()).ok_or_else(|| corrupt("literals run off the end"))?;
+	    if out.len() + literals > len {
+		return Err(corrupt("longer than it claims"));
+	    }
+	    out.extend_from_slice(&src[pos..end]);
+	    pos = end;
+	    if pos == src.len() {
+		break;						// That was the last sequence
+	    }
+	    if pos + 2 > src.len() {
+		return Err(corrupt("offset runs off the end"));
+	    }
+	    let offset = u16::from_le_bytes([src[pos], src[pos + 1]]) as usize;
+	    pos += 2;
+	    if offset == 0 || offset > out.len() {
+		return Err(corrupt("match offset out of range"));
+	    }
+	    let matched = lz4_read_length(src, &mut pos, token & 15)? + LZ4_MIN_MATCH;
+	    if out.len() + matched > len {
+		return Err(corrupt("longer than it claims"));
+	    }
+	    let from = out.len() - offset;
+	    for i in 0..matched {					// Byte by byte, a match may overlap what it produces
+		out.push(out[from + i]);
+	    }
+	}
+	if out.len() != len {
+	    return Err(corrupt("shorter than it claims"));
+	}
+	Ok(out)
+    }
+
+    fn corrupt(what: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("bad compressed value: {}", what))
+    }
+}
@@ -0,0 +1,142 @@
+//
+// Module that seals and opens record payloads for encryption at rest.
+//
+// Everything is AES-256-GCM, and the one thing GCM cannot survive is a key being used twice with the same
+// nonce.  Do that even once and an attacker who sees both ciphertexts gets the XOR of the two plaintexts,
+// and worse, can solve for the authentication subkey, after which they can forge ciphertexts that pass
+// authentication at will.  That's a total loss of confidentiality and integrity for everything under the
+// key, not a gradual weakening, so where nonces come from is pluggable and the rules for it are strict.
+//
+pub mod crypto {
+    use std::io;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+    use aes_gcm::aead::rand_core::RngCore;
+
+    // The size of a GCM nonce, which is stored in front of each sealed payload.
+    pub const NONCE_SIZE: usize = 12;
+
+    // The size of the authentication tag that follows the ciphertext.
+    const TAG_SIZE: usize = 16;
+
+    //
+    // Where the nonces come from.  An implementation must never return the same nonce twice for the life of
+    // an encryption key, across every process that will ever use that key, see the module comment for why.
+    //
+    pub trait NonceSource: Send + Sync {
+	fn next_nonce(&self) -> [u8; NONCE_SIZE];
+    }
+
+    //
+    // A 4 byte prefix followed by a 64-bit counter.  Deterministic, so it's what tests want, and safe in
+    // production only if every process sharing a key is given a different prefix.
+    //
+    pub struct CounterNonceSource {
+	prefix: [u8; 4],
+	counter: AtomicU64,			// The next counter value to hand out
+    }
+    impl CounterNonceSource {
+	pub fn new(prefix: [u8; 4]) -> CounterNonceSource {
+	    CounterNonceSource { prefix, counter: AtomicU64::new(0) }
+	}
+    }
+    impl NonceSource for CounterNonceSource {
+	fn next_nonce(&self) -> [u8; NONCE_SIZE] {
+	    let n = self.counter.fetch_add(1, Ordering::SeqCst);
+	    if n == u64::MAX {
+		panic!("nonce counter exhausted, the key must be rotated");	// Wrapping around would repeat nonces
+	    }
+	    let mut nonce = [0u8; NONCE_SIZE];
+	    nonce[..4].copy_from_slice(&self.prefix);
+	    nonce[4..].copy_from_slice(&n.to_le_bytes());
+	    nonce
+	}
+    }
+
+    //
+    // The production source: a counter under a prefix drawn from the operating system's CSPRNG when the
+    // source is created.  Within one source nonces can't repeat at all, and because each source (each
+    // process, and each open after a fork) draws a fresh prefix, two sources only collide if their random
+    // prefixes do.  Rotate keys long before the number of opens under one key gets anywhere near 2^16.
+    //
+    pub struct RandomNonceSource {
+	inner: CounterNonceSource,
+    }
+    impl RandomNonceSource {
+	pub fn new() -> RandomNonceSource {
+	    let mut prefix = [0u8; 4];
+	    OsRng.fill_bytes(&mut prefix);
+	    RandomNonceSource { inner: CounterNonceSource::new(prefix) }
+	}
+    }
+    impl Default for RandomNonceSource {
+	fn default() -> RandomNonceSource {
+	    RandomNonceSource::new()
+	}
+    }
+    impl NonceSource for RandomNonceSource {
+	fn next_nonce(&self) -> [u8; NONCE_SIZE] {
+	    self.inner.next_nonce()
+	}
+    }
+
+    //
+    // Seals payloads as nonce || ciphertext || tag, and opens them again after checking the tag.
+    // 'aad' is authenticated but not encrypted, it's how a payload gets bound to the record it belongs to.
+    //
+    pub struct RecordCipher {
+	cipher: Aes256Gcm,
+	nonces: Box<dyn NonceSource>,
+    }
+    impl RecordCipher {
+	// How much longer a sealed payload is than the plaintext.
+	pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+	pub fn new(key: &[u8; 32], nonces: Box<dyn NonceSource>) -> RecordCipher {
+	    RecordCipher { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)), nonces }
+	}
+
+	pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, io::Error> {
+	    let nonce = self.nonces.next_nonce();
+	    let sealed = self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large to encrypt"))?;
+	    let mut out = Vec::with_capacity(NONCE_SIZE + sealed.len());
+	    out.extend_from_slice(&nonce);
+	    out.extend_from_slice(&sealed);
+	    Ok(out)
+	}
+
+	pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, io::Error> {
+	    if sealed.len() < Self::OVERHEAD {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "sealed payload is too short"));
+	    }
+	    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+	    self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "payload failed authentication"))
+	}
+    }
+
+    //
+    // How a store encrypts what it writes: the 256-bit AES-GCM key, and where its nonces come from, a
+    // RandomNonceSource unless with_nonce_source() says otherwise.
+    //
+    pub struct EncryptionConfig {
+	key: [u8; 32],
+	nonces: Box<dyn NonceSource>,
+    }
+    impl EncryptionConfig {
+	pub fn new(key: [u8; 32]) -> EncryptionConfig {
+	    EncryptionConfig { key, nonces: Box::new(RandomNonceSource::new()) }
+	}
+
+	pub fn with_nonce_source(mut self, nonces: Box<dyn NonceSource>) -> EncryptionConfig {
+	    self.nonces = nonces;
+	    self
+	}
+
+	pub fn cipher(self) -> RecordCipher {
+	    RecordCipher::new(&self.key, self.nonces)
+	}
+    }
+}
@@ -0,0 +1,338 @@
+//
+// Module that keeps a hash table in files rather than in memory, for a keymap too big to fit in it.
+//
+// Open addressing with linear probing over a table of fixed-size slots, each holding the hash of its key,
+// where the key's bytes are in a second file alongside, and the value itself.  Both files are mapped
+// shared, so it's the page cache that holds them: the parts in use stay in memory, and the rest is
+// written back and dropped as memory runs short, rather than counting against the process.  They're
+// scratch space, unlinked as soon as they're made, so they go when the map does, crash or no crash, and
+// the map is built afresh every time.  A table three quarters full, counting the slots left behind by
+// removals, is rebuilt twice the size, or the same size if it's mostly those, which is also when the
+// bytes of removed keys are let go.
+//
+// The values are kept as their bytes, so they have to be plain data, and the map only works on Linux
+// with the mmap feature.  Elsewhere there's a DiskMap that can't be made.
+//
+pub mod diskmap {
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    pub use self::mapped::DiskMap;
+    #[cfg(not(all(feature = "mmap", target_os = "linux")))]
+    pub use self::unsupported::DiskMap;
+
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    mod mapped {
+	use std::collections::hash_map::DefaultHasher;
+	use std::fs::{File, OpenOptions};
+	use std::hash::{Hash, Hasher};
+	use std::io;
+	use std::marker::PhantomData;
+	use std::os::unix::io::AsRawFd;
+	use std::path::{Path, PathBuf};
+	use uuid::Uuid;
+
+	// The fewest slots a table has, and the fewest bytes set aside for its keys.
+	const DISKMAP_MIN_SLOTS: usize = 1024;
+	const DISKMAP_MIN_KEY_BYTES: usize = 64 * 1024;
+
+	// What's in a slot.  A new file is all zeroes, so every slot starts empty.
+	const DISKMAP_SLOT_EMPTY: u32 = 0;
+	const DISKMAP_SLOT_FULL: u32 = 1;
+	const DISKMAP_SLOT_REMOVED: u32 = 2;		// Was full, a lookup has to probe past it
+
+	#[repr(C)]
+	struct Slot<V> {
+	    hash: u64,
+	    key_offset: u64,			// Where the key's bytes start in the keys file
+	    key_len: u32,
+	    state: u32,
+	    value: V,
+	}
+
+	pub struct DiskMap<V: Copy> {
+	    dir: PathBuf,			// Where the scratch files are made
+	    slots: ScratchMapping,
+	    keys: ScratchMapping,
+	    capacity: usize,			// How many slots, always a power of 2
+	    keys_used: usize,			// How much of the keys file has been handed out
+	    len: usize,				// Full slots
+	    removed: usize,			// Slots a removal left behind
+	    values: PhantomData<V>,
+	}
+	impl<V: Copy> DiskMap<V> {
+	    //
+	    // An empty map whose files are in 'dir'.
+	    //
+	    pub fn new(dir: &Path) -> Result<DiskMap<V>, io::Error> {
+		Self::with_capacity(dir, DISKMAP_MIN_SLOTS, DISKMAP_MIN_KEY_BYTES)
+	    }
+
+	    fn with_capacity(dir: &Path, capacity: usize, key_bytes: usize) -> Result<DiskMap<V>, io::Error> {
+		Ok(DiskMap {
+		    dir: dir.to_path_buf(),
+		    slots: ScratchMapping::new(dir, capacity * std::mem::size_of::<Slot<V>>(), true)?,
+		    keys: ScratchMapping::new(dir, key_bytes, false)?,
+		    capacity,
+		    keys_used: 0,
+		    len: 0,
+		    removed: 0,
+		    values: PhantomData,
+		})
+	    }
+
+	    pub fn dir(&self) -> &Path {
+		&self.dir
+	    }
+
+	    pub fn len(&self) -> usize {
+		self.len
+	    }
+
+	    pub fn is_empty(&self) -> bool {
+		self.len == 0
+	    }
+
+	    pub fn get(&self, key: &[u8]) -> Option<&V> {
+		self.find(key, hash_of(key)).ok().map(|i| &self.slot(i).value)
+	    }
+
+	    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+		self.find(key, hash_of(key)).ok().map(|i| &mut self.slot_mut(i).value)
+	    }
+
+	    //
+	    // Set the value of 'key', returning what it was.  This only fails if there's no room to grow the
+	    // files, in which case the map is as it was.
+	    //
+	    pub fn insert(&mut self, key: &[u8], value: V) -> Result<Option<V>, io::Error> {
+		let hash = hash_of(key);
+		if let Ok(i) = self.find(key, hash) {
+		    return Ok(Some(std::mem::replace(&mut self.slot_mut(i).value, value)));
+		}
+		if (self.len + self.removed + 1) * 4 > self.capacity * 3 {
+		    let capacity = match (self.len + 1) * 2 > self.capacity {
+			true => self.capacity * 2,
+			false => self.capacity,			// Mostly removals, there'll be room once they're gone
+		    };
+		    self.rebuild(capacity)?;
+		}
+		let key_offset = self.push_key(key)?;
+		let i = self.find(key, hash).unwrap_err();
+		if self.slot(i).state == DISKMAP_SLOT_REMOVED {
+		    self.removed -= 1;
+		}
+		*self.slot_mut(i) = Slot { hash, key_offset: key_offset as u64, key_len: key.len() as u32, state: DISKMAP_SLOT_FULL, value };
+		self.len += 1;
+		Ok(None)
+	    }
+
+	    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+		let i = self.find(key, hash_of(key)).ok()?;
+		let slot = self.slot_mut(i);
+		slot.state = DISKMAP_SLOT_REMOVED;
+		let value = slot.value;
+		self.len -= 1;
+		self.removed += 1;
+		Some(value)
+	    }
+
+	    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> + '_ {
+		(0..self.capacity).map(|i| self.slot(i)).filter(|slot| slot.state == DISKMAP_SLOT_FULL).map(|slot| (self.key_of(slot), &slot.value))
+	    }
+
+	    pub fn into_entries(self) -> impl Iterator<Item = (Vec<u8>, V)> {
+		(0..self.capacity).filter_map(move |i| {
+		    let slot = self.slot(i);
+		    (slot.state == DISKMAP_SLOT_FULL).then(|| (self.key_of(slot).to_vec(), slot.value))
+		})
+	    }
+
+	    //
+	    // The slot 'key' is in, or if it isn't there, the slot it would go in.  There's always an empty
+	    // slot to stop at, insert() sees to that.
+	    //
+	    fn find(&self, key: &[u8], hash: u64) -> Result<usize, usize> {
+		let mask = self.capacity - 1;
+		let mut i = hash as usize & mask;
+		let mut vacant = None;
+		loop {
+		    let slot = self.slot(i);
+		    match slot.state {
+			DISKMAP_SLOT_EMPTY => return Err(vacant.unwrap_or(i)),
+			DISKMAP_SLOT_REMOVED => { vacant.get_or_insert(i); },
+			_ => if slot.hash == hash && self.key_of(slot) == key {
+			    return Ok(i);
+			},
+		    }
+		    i = (i + 1) & mask;
+		}
+	    }
+
+	    //
+	    // Copy 'key' to the end of the keys file, growing it if need be, and say where it went.
+	    //
+	    fn push_key(&mut self, key: &[u8]) -> Result<usize, io::Error> {
+		let offset = self.keys_used;
+		if offset + key.len() > self.keys.len {
+		    self.keys.resize((self.keys.len * 2).max(offset + key.len()))?;
+		}
+		self.keys.bytes_mut()[offset..offset + key.len()].copy_from_slice(key);
+		self.keys_used += key.len();
+		Ok(offset)
+	    }
+
+	    //
+	    // Put everything in a fresh table of 'capacity' slots, with only the keys still in use.
+	    //
+	    fn rebuild(&mut self, capacity: usize) -> Result<bool, io::Error> {
+		let key_bytes: usize = self.iter().map(|(key, _)| key.len()).sum();
+		let mut rebuilt = Self::with_capacity(&self.dir, capacity, key_bytes.max(DISKMAP_MIN_KEY_BYTES))?;
+		for (key, value) in self.iter() {
+		    rebuilt.insert(key, *value)?;
+		}
+		*self = rebuilt;
+		Ok(true)
+	    }
+
+	    fn slot(&self, i: usize) -> &Slot<V> {
+		debug_assert!(i < self.capacity);
+		unsafe { &*(self.slots.addr as *const Slot<V>).add(i) }
+	    }
+
+	    fn slot_mut(&mut self, i: usize) -> &mut Slot<V> {
+		debug_assert!(i < self.capacity);
+		unsafe { &mut *(self.slots.addr as *mut Slot<V>).add(i) }
+	    }
+
+	    fn key_of(&self, slot: &Slot<V>) -> &[u8] {
+		&self.keys.bytes()[slot.key_offset as usize..slot.key_offset as usize + slot.key_len as usize]
+	    }
+	}
+	// The mappings are only ever reached through the map, so it's as shareable as its values are.
+	unsafe impl<V: Copy + Send> Send for DiskMap<V> {}
+	unsafe impl<V: Copy + Sync> Sync for DiskMap<V> {}
+
+	//
+	// Seeded, so where a key goes in a table has nothing to do with which shard of the keymap it's in.
+	//
+	fn hash_of(key: &[u8]) -> u64 {
+	    let mut hasher = DefaultHasher::new();
+	    0xd15c_u16.hash(&mut hasher);
+	    key.hash(&mut hasher);
+	    hasher.finish()
+	}
+
+	//
+	// A file made in a directory and unlinked straight away, mapped read-write and shared.
+	//
+	struct ScratchMapping {
+	    file: File,
+	    addr: *mut libc::c_void,
+	    len: usize,
+	    random: bool,			// Whether it's looked at all over the place, so reading ahead is wasted
+	}
+	impl ScratchMapping {
+	    fn new(dir: &Path, len: usize, random: bool) -> Result<ScratchMapping, io::Error> {
+		let path = dir.join(format!("keydir.{}.scratch", Uuid::new_v4()));
+		let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+		std::fs::remove_file(&path)?;
+		let mut mapping = ScratchMapping { file, addr: std::ptr::null_mut(), len: 0, random };
+		mapping.resize(len)?;
+		Ok(mapping)
+	    }
+
+	    //
+	    // Grow the file to 'len' bytes, the new ones zero, and map all of it, maybe somewhere else.
+	    //
+	    fn resize(&mut self, len: usize) -> Result<bool, io::Error> {
+		self.file.set_len(len as u64)?;
+		let addr = match self.len {
+		    0 => unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, self.file.as_raw_fd(), 0) },
+		    _ => unsafe { libc::mremap(self.addr, self.len, len, libc::MREMAP_MAYMOVE) },
+		};
+		if addr == libc::MAP_FAILED {
+		    return Err(io::Error::last_os_error());
+		}
+		if self.random {
+		    unsafe { libc::madvise(addr, len, libc::MADV_RANDOM) };
+		}
+		self.addr = addr;
+		self.len = len;
+		Ok(true)
+	    }
+
+	    fn bytes(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+	    }
+
+	    fn bytes_mut(&mut self) -> &mut [u8] {
+		unsafe { std::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+	    }
+	}
+	impl Drop for ScratchMapping {
+	    fn drop(&mut self) {
+		if self.len > 0 {
+		    unsafe { libc::munmap(self.addr, self.len) };
+		}
+	    }
+	}
+    }
+
+    #[cfg(not(all(feature = "mmap", target_os = "linux")))]
+    mod unsupported {
+	use std::convert::Infallible;
+	use std::io;
+	use std::marker::PhantomData;
+	use std::path::Path;
+
+	pub enum DiskMap<V: Copy> {
+	    Never(Infallible, PhantomData<V>),
+	}
+	impl<V: Copy> DiskMap<V> {
+	    pub fn new(_dir: &Path) -> Result<DiskMap<V>, io::Error> {
+		Err(io::Error::new(io::ErrorKind::Unsupported, "an index on disk needs the mmap feature, on Linux"))
+	    }
+
+	    fn never(&self) -> ! {
+		match self {
+		    DiskMap::Never(never, _) => match *never {},
+		}
+	    }
+
+	    pub fn dir(&self) -> &Path {
+		self.never()
+	    }
+
+	    pub fn len(&self) -> usize {
+		self.never()
+	    }
+
+	    pub fn is_empty(&self) -> bool {
+		self.never()
+	    }
+
+	    pub fn get(&self, _key: &[u8]) -> Option<&V> {
+		self.never()
+	    }
+
+	    pub fn get_mut(&mut self, _key: &[u8]) -> Option<&mut V> {
+		self.never()
+	    }
+
+	    pub fn insert(&mut self, _key: &[u8], _value: V) -> Result<Option<V>, io::Error> {
+		self.never()
+	    }
+
+	    pub fn remove(&mut self, _key: &[u8]) -> Option<V> {
+		self.never()
+	    }
+
+	    pub fn iter(&self) -> std::iter::Empty<(&[u8], &V)> {
+		self.never()
+	    }
+
+	    pub fn into_entries(self) -> std::iter::Empty<(Vec<u8>, V)> {
+		self.never()
+	    }
+	}
+    }
+}
@@ -0,0 +1,112 @@
+//
+// Module that defines the errors the Bitcask API hands back.
+//
+// Inside the store everything is an io::Error, as it is for the storage backends, and the errors worth
+// telling apart travel as the payload of one: converting to a BitcaskError unwraps them again, and
+// anything else is an Io.  Converting back wraps them up, so a BitcaskError survives the round trip.
+//
+pub mod error {
+    use std::error::Error;
+    use std::fmt;
+    use std::io;
+    use std::sync::PoisonError;
+
+    #[derive(Debug)]
+    pub enum BitcaskError {
+	Io(io::Error),						// The storage backend failed, or something we don't single out
+	Corruption { file: String, offset: i64, reason: String },	// A record that's there but can't be believed
+	Truncated { file: String, offset: i64 },		// A record that runs past the end of its file, a torn write
+	InvalidFormat { file: String, reason: String },		// A file that isn't in any format we can read
+	KeyNotFound,						// An operation that needs the key to exist found it didn't
+	KeyTooLarge { size: usize, limit: usize },
+	ValueTooLarge { size: usize, limit: usize },
+	InvalidArgument(String),				// The call itself makes no sense, nothing was changed
+	LockPoisoned,						// A thread panicked holding one of the store's locks
+	ReadOnly,						// A write to a store opened read-only
+	Locked(String),						// The store is already open for writing elsewhere, this says where
+	ShutDown,						// A write to a store that's been shut down
+	QuotaExceeded { usage: u64, quota: u64 },		// A write would take the datafiles past the disk quota
+	UnsupportedFilesystem { dir: String, fstype: String },	// The directory is on a filesystem we can't trust to write to
+    }
+    impl BitcaskError {
+	//
+	// The io::ErrorKind closest to this error, which is what it has once it's converted to an io::Error.
+	//
+	pub fn kind(&self) -> io::ErrorKind {
+	    match self {
+		BitcaskError::Io(e) => e.kind(),
+		BitcaskError::Corruption { .. } | BitcaskError::InvalidFormat { .. } => io::ErrorKind::InvalidData,
+		BitcaskError::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+		BitcaskError::KeyNotFound => io::ErrorKind::NotFound,
+		BitcaskError::KeyTooLarge { .. } | BitcaskError::ValueTooLarge { .. } | BitcaskError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+		BitcaskError::LockPoisoned => io::ErrorKind::Other,
+		BitcaskError::ReadOnly => io::ErrorKind::PermissionDenied,
+		BitcaskError::Locked(_) => io::ErrorKind::WouldBlock,
+		BitcaskError::ShutDown => io::ErrorKind::NotConnected,
+		BitcaskError::QuotaExceeded { .. } => io::ErrorKind::StorageFull,
+		BitcaskError::UnsupportedFilesystem { .. } => io::ErrorKind::Unsupported,
+	    }
+	}
+
+	//
+	// Is this the store's data being wrong, as opposed to the device or the caller?
+	//
+	pub fn is_corruption(&self) -> bool {
+	    matches!(self, BitcaskError::Corruption { .. } | BitcaskError::Truncated { .. } | BitcaskError::InvalidFormat { .. })
+	}
+    }
+
+    impl fmt::Display for BitcaskError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	    match self {
+		BitcaskError::Io(e) => write!(f, "{}", e),
+		BitcaskError::Corruption { file, offset, reason } => write!(f, "{}: record at offset {} {}", file, offset, reason),
+		BitcaskError::Truncated { file, offset } => write!(f, "{}: record at offset {} runs past the end of the file", file, offset),
+		BitcaskError::InvalidFormat { file, reason } => write!(f, "{}: {}", file, reason),
+		BitcaskError::KeyNotFound => write!(f, "no such key"),
+		BitcaskError::KeyTooLarge { size, limit } => write!(f, "key of {} bytes exceeds the {} byte limit", size, limit),
+		BitcaskError::ValueTooLarge { size, limit } => write!(f, "value of {} bytes exceeds the {} byte limit", size, limit),
+		BitcaskError::InvalidArgument(what) => write!(f, "{}", what),
+		BitcaskError::LockPoisoned => write!(f, "a lock was poisoned by a thread that panicked holding it"),
+		BitcaskError::ReadOnly => write!(f, "the store is open read-only"),
+		BitcaskError::Locked(what) => write!(f, "{}", what),
+		BitcaskError::ShutDown => write!(f, "the store has been shut down"),
+		BitcaskError::QuotaExceeded { usage, quota } => write!(f, "the datafiles' {} bytes are at the {} byte quota", usage, quota),
+		BitcaskError::UnsupportedFilesystem { dir, fstype } => write!(f, "{}: unsupported filesystem type {}", dir, fstype),
+	    }
+	}
+    }
+
+    impl Error for BitcaskError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+	    match self {
+		BitcaskError::Io(e) => Some(e),
+		_ => None,
+	    }
+	}
+    }
+
+    impl From<io::Error> for BitcaskError {
+	fn from(e: io::Error) -> BitcaskError {
+	    if e.get_ref().is_some_and(|inner| inner.is::<BitcaskError>()) {
+		return *e.into_inner().unwrap().downcast::<BitcaskError>().unwrap();
+	    }
+	    BitcaskError::Io(e)
+	}
+    }
+
+    impl<T> From<PoisonError<T>> for BitcaskError {
+	fn from(_: PoisonError<T>) -> BitcaskError {
+	    BitcaskError::LockPoisoned
+	}
+    }
+
+    impl From<BitcaskError> for io::Error {
+	fn from(e: BitcaskError) -> io::Error {
+	    match e {
+		BitcaskError::Io(e) => e,
+		e => io::Error::new(e.kind(), e),
+	    }
+	}
+    }
+}
@@ -0,0 +1,160 @@
+//
+// Module that reads and writes the little JSON the store's export format needs.
+//
+// Each exported KV is one flat object on a line of its own, all of whose members are strings or unsigned
+// integers, so that's all this handles: it's not a general JSON parser, and doesn't need to be one.  Keys
+// and values that aren't UTF-8 can't be JSON strings, they're written out as hex instead.
+//
+pub mod json {
+    use std::iter::Peekable;
+    use std::str::Chars;
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub enum JsonValue {
+	String(String),
+	Number(u64),
+    }
+
+    //
+    // 'text' as a JSON string, quotes and all.
+    //
+    pub fn quote(text: &str) -> String {
+	let mut quoted = String::with_capacity(text.len() + 2);
+	quoted.push('"');
+	for c in text.chars() {
+	    match c {
+		'"' => quoted.push_str("\\\""),
+		'\\' => quoted.push_str("\\\\"),
+		'\n' => quoted.push_str("\\n"),
+		'\r' => quoted.push_str("\\r"),
+		'\t' => quoted.push_str("\\t"),
+		c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+		c => quoted.push(c),
+	    }
+	}
+	quoted.push('"');
+	quoted
+    }
+
+    pub fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn unhex(text: &str) -> Option<Vec<u8>> {
+	if !text.len().is_multiple_of(2) {
+	    return None;
+	}
+	(0..text.len()).step_by(2).map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok())).collect()
+    }
+
+    //
+    // The members of the one flat object 'line' holds, in the order they're written, or what's wrong with it.
+    //
+    pub fn parse_object(line: &str) -> Result<Vec<(String, JsonValue)>, String> {
+	let mut chars = line.chars().peekable();
+	let mut members = Vec::new();
+	expect(&mut chars, '{')?;
+	if skip_space(&mut chars) == Some('}') {
+	    chars.next();
+	} else {
+	    loop {
+		skip_space(&mut chars);
+		let name = parse_string(&mut chars)?;
+		expect(&mut chars, ':')?;
+		let value = match skip_space(&mut chars) {
+		    Some('"') => JsonValue::String(parse_string(&mut chars)?),
+		    Some(c) if c.is_ascii_digit() => JsonValue::Number(parse_number(&mut chars)?),
+		    _ => return Err("expected a string or a number".to_string()),
+		};
+		members.push((name, value));
+		match skip_space(&mut chars) {
+		    Some(',') => { chars.next(); },
+		    Some('}') => {
+			chars.next();
+			break;
+		    },
+		    _ => return Err("expected ',' or '}'".to_string()),
+		}
+	    }
+	}
+	match skip_space(&mut chars) {
+	    None => Ok(members),
+	    Some(_) => Err("there's more after the object".to_string()),
+	}
+    }
+
+    //
+    // Skip any whitespace and return the next character, which is left where it is.
+    //
+    fn skip_space(chars: &mut Peekable<Chars>) -> Option<char> {
+	while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+	chars.peek().copied()
+    }
+
+    fn expect(chars: &mut Peekable<Chars>, want: char) -> Result<(), String> {
+	match skip_space(chars) {
+	    Some(c) if c == want => {
+		chars.next();
+		Ok(())
+	    },
+	    _ => Err(format!("expected '{}'", want)),
+	}
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+	expect(chars, '"')?;
+	let mut text = String::new();
+	loop {
+	    match chars.next() {
+		Some('"') => return Ok(text),
+		Some('\\') => match chars.next() {
+		    Some('"') => text.push('"'),
+		    Some('\\') => text.push('\\'),
+		    Some('/') => text.push('/'),
+		    Some('b') => text.push('\u{8}'),
+		    Some('f') => text.push('\u{c}'),
+		    Some('n') => text.push('\n'),
+		    Some('r') => text.push('\r'),
+		    Some('t') => text.push('\t'),
+		    Some('u') => {
+			let high = parse_hex4(chars)?;
+			let code = match high {
+			    0xd800..=0xdbff => {		// The first half of a surrogate pair, the second must follow
+				if chars.next() != Some('\\') || chars.next() != Some('u') {
+				    return Err("unpaired surrogate".to_string());
+				}
+				let low = parse_hex4(chars)?;
+				if !(0xdc00..=0xdfff).contains(&low) {
+				    return Err("unpaired surrogate".to_string());
+				}
+				0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+			    },
+			    code => code,
+			};
+			text.push(char::from_u32(code).ok_or("unpaired surrogate")?);
+		    },
+		    _ => return Err("bad escape".to_string()),
+		},
+		Some(c) if (c as u32) < 0x20 => return Err("control character in a string".to_string()),
+		Some(c) => text.push(c),
+		None => return Err("unterminated string".to_string()),
+	    }
+	}
+    }
+
+    fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+	let digits: String = (0..4).filter_map(|_| chars.next()).collect();
+	match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+	    true => u32::from_str_radix(&digits, 16).map_err(|_| "bad \\u escape".to_string()),
+	    false => Err("bad \\u escape".to_string()),
+	}
+    }
+
+    fn parse_number(chars: &mut Peekable<Chars>) -> Result<u64, String> {
+	let mut digits = String::new();
+	while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+	    digits.push(c);
+	}
+	digits.parse().map_err(|_| "number out of range".to_string())
+    }
+}
@@ -0,0 +1,53 @@
+#[cfg(feature = "async")]
+pub use crate::aio::aio::{AsyncBitcask, BlockingCall};
+pub use crate::bucket::bucket::{Bucket, BucketStats};
+pub use crate::bitcask::bitcask::{BackupManifest, Bitcask, BitcaskOptions, ChangeEvent, ChangeOp, CorruptionPolicy, FormatDescriptor, FormatField, KeyMetadata, MergePolicy, RepairReport, RetentionPolicy, Stats, SyncPolicy, VerifyIssue, VerifyIssueKind, VerifyReport, WriteBatch};
+pub use crate::compress::compress::Compression;
+pub use crate::error::error::BitcaskError;
+pub use crate::crypto::crypto::{CounterNonceSource, EncryptionConfig, NonceSource, RandomNonceSource, RecordCipher};
+pub use crate::pool::pool::{SaturationPolicy, Submitted, ThreadPool};
+pub use crate::replica::replica::Follower;
+#[cfg(feature = "serde")]
+pub use crate::typed::typed::TypedBitcask;
+pub use crate::storage::storage::{FileStorage, FilesystemProbe, MemoryStorage, StatfsProbe, Storage, StorageFile, StorageLock, StoragePrefix, SyncCoordinator};
+#[cfg(feature = "async")]
+#[allow(clippy::module_inception)]
+pub mod aio;
+#[allow(clippy::module_inception)]
+pub mod bitcask;
+#[allow(clippy::module_inception)]
+pub mod blob;
+#[allow(clippy::module_inception)]
+pub mod bloom;
+#[allow(clippy::module_inception)]
+pub mod bucket;
+#[allow(clippy::module_inception)]
+pub mod cache;
+#[allow(clippy::module_inception)]
+pub mod compress;
+#[allow(clippy::module_inception)]
+pub mod crypto;
+#[allow(clippy::module_inception)]
+pub mod diskmap;
+#[allow(clippy::module_inception)]
+pub mod error;
+#[allow(clippy::module_inception)]
+pub mod json;
+#[cfg(feature = "metrics")]
+#[allow(clippy::module_inception)]
+pub mod metrics;
+#[allow(clippy::module_inception)]
+pub mod pool;
+#[allow(clippy::module_inception)]
+pub mod replica;
+#[allow(clippy::module_inception)]
+pub mod server;
+#[allow(clippy::module_inception)]
+pub mod storage;
+#[allow(clippy::module_inception)]
+pub mod trace;
+#[cfg(feature = "serde")]
+#[allow(clippy::module_inception)]
+pub mod typed;
+#[allow(clippy::module_inception)]
+mod tests;
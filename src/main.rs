@@ -1,7 +1,3 @@
-use crate::bitcask::bitcask::Bitcask;
-mod bitcask;
-mod tests;
-
 fn main() {
     println!("All done in this universe, and all others!");
 }
@@ -0,0 +1,96 @@
+//
+// Module that counts what the store does, for exporting to Prometheus and so to dashboards and alerts.
+//
+// Everything is an atomic bumped on the path it counts, so keeping count costs next to nothing and needs no
+// lock, and reading the counts takes a snapshot of each that may be a moment apart from the others.  They
+// are rendered in Prometheus's text exposition format, which whatever serves the deployment's /metrics can
+// hand over as it is.
+//
+pub mod metrics {
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    // The upper bounds, in seconds, of the fsync latency buckets: from what a fast NVMe device manages to a
+    // disk that's in trouble.
+    const METRICS_FSYNC_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+    //
+    // How a duration is distributed over fixed buckets, as a Prometheus histogram is.
+    //
+    pub struct Histogram {
+	bounds: &'static [f64],
+	counts: Vec<AtomicU64>,			// Per bucket, not cumulative, plus one for those above every bound
+	sum_nanos: AtomicU64,
+    }
+    impl Histogram {
+	pub fn new(bounds: &'static [f64]) -> Histogram {
+	    Histogram { bounds, counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(), sum_nanos: AtomicU64::new(0) }
+	}
+
+	pub fn observe(&self, elapsed: Duration) {
+	    let secs = elapsed.as_secs_f64();
+	    let bucket = self.bounds.iter().position(|bound| secs <= *bound).unwrap_or(self.bounds.len());
+	    self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+	    self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+	}
+
+	fn render(&self, out: &mut String, name: &str, help: &str) {
+	    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+	    let mut cumulative: u64 = 0;
+	    for (i, count) in self.counts.iter().enumerate() {
+		cumulative += count.load(Ordering::Relaxed);
+		let bound = self.bounds.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+		let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+	    }
+	    let _ = writeln!(out, "{}_sum {}", name, self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9);
+	    let _ = writeln!(out, "{}_count {}", name, cumulative);
+	}
+    }
+
+    pub struct Metrics {
+	pub gets: AtomicU64,			// Keys looked up for their values
+	pub puts: AtomicU64,			// Committed puts, singly, batched, imported, or replicated
+	pub deletes: AtomicU64,			// Committed deletes, likewise
+	pub bytes_written: AtomicU64,		// The keys and values of those puts and deletes
+	pub merges: AtomicU64,			// Merges started
+	pub fsync: Histogram,			// How long flushing a datafile took
+    }
+    impl Metrics {
+	pub fn new() -> Metrics {
+	    Metrics {
+		gets: AtomicU64::new(0),
+		puts: AtomicU64::new(0),
+		deletes: AtomicU64::new(0),
+		bytes_written: AtomicU64::new(0),
+		merges: AtomicU64::new(0),
+		fsync: Histogram::new(&METRICS_FSYNC_BUCKETS),
+	    }
+	}
+
+	pub fn count(counter: &AtomicU64, n: u64) {
+	    counter.fetch_add(n, Ordering::Relaxed);
+	}
+
+	//
+	// Append every metric to 'out' in the text exposition format, each name starting with 'prefix'.
+	//
+	pub fn render(&self, out: &mut String, prefix: &str) {
+	    for (name, help, counter) in [
+		("gets_total", "Keys looked up for their values.", &self.gets),
+		("puts_total", "Puts committed.", &self.puts),
+		("deletes_total", "Deletes committed.", &self.deletes),
+		("written_bytes_total", "Bytes of keys and values put and deleted.", &self.bytes_written),
+		("merges_total", "Merges started.", &self.merges),
+	    ] {
+		let _ = writeln!(out, "# HELP {}{} {}\n# TYPE {}{} counter\n{}{} {}", prefix, name, help, prefix, name, prefix, name, counter.load(Ordering::Relaxed));
+	    }
+	    self.fsync.render(out, &format!("{}fsync_seconds", prefix), "How long flushing a datafile took.");
+	}
+    }
+    impl Default for Metrics {
+	fn default() -> Metrics {
+	    Metrics::new()
+	}
+    }
+}
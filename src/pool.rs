@@ -0,0 +1,139 @@
+//
+// Module that runs background work (merges, hints generation, ...) on a fixed set of threads.
+//
+// The queue in front of the threads is bounded, so sustained pressure can't make it grow without limit.
+// What happens to a job that arrives when the queue is full is up to the owner of the pool: the submitting
+// thread runs it right there, or waits for room in the queue, either of which naturally slows the producer
+// down to the pool's pace, or it's handed back to be tried again later.
+//
+pub mod pool {
+    use std::sync::Arc;
+    use std::sync::{Condvar, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+    use std::thread::JoinHandle;
+
+    // A unit of background work.
+    pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    //
+    // What submit() does with a job when the queue is full.
+    //
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum SaturationPolicy {
+	Inline,				// Run it on the submitting thread before returning
+	Block,				// Wait on the submitting thread until there's room, then queue it
+	Defer,				// Give it back to the caller, with a warning
+    }
+
+    //
+    // What became of a submitted job.
+    //
+    pub enum Submitted {
+	Queued,				// A pool thread will get to it
+	RanInline,			// The queue was full, so it has already run on the caller's thread
+	Deferred(Job),			// The queue was full, here it is back again
+    }
+
+    pub struct ThreadPool {
+	queue: Option<SyncSender<Job>>,		// None only while we're shutting down
+	depth: Arc<AtomicUsize>,		// Jobs queued but not yet picked up by a thread
+	unfinished: Arc<(Mutex<usize>, Condvar)>,	// Jobs queued or running, signalled whenever one finishes
+	policy: SaturationPolicy,
+	workers: Vec<JoinHandle<()>>,
+    }
+    impl ThreadPool {
+	//
+	// Start 'threads' threads behind a queue of at most 'capacity' waiting jobs.
+	//
+	pub fn new(threads: usize, capacity: usize, policy: SaturationPolicy) -> ThreadPool {
+	    let (tx, rx) = sync_channel::<Job>(capacity);
+	    let rx = Arc::new(Mutex::new(rx));
+	    let depth = Arc::new(AtomicUsize::new(0));
+	    let unfinished = Arc::new((Mutex::new(0), Condvar::new()));
+	    let workers = (0..threads.max(1)).map(|_| {
+		let rx = rx.clone();
+		let depth = depth.clone();
+		let unfinished = unfinished.clone();
+		std::thread::spawn(move || Self::worker(&rx, &depth, &unfinished))
+	    }).collect();
+	    ThreadPool { queue: Some(tx), depth, unfinished, policy, workers }
+	}
+
+	fn worker(rx: &Mutex<Receiver<Job>>, depth: &AtomicUsize, unfinished: &(Mutex<usize>, Condvar)) {
+	    loop {
+		let job = rx.lock().unwrap().recv();		// Only hold the lock while waiting, not while working
+		match job {
+		    Ok(job) => {
+			depth.fetch_sub(1, Ordering::SeqCst);
+			job();
+			*unfinished.0.lock().unwrap() -= 1;
+			unfinished.1.notify_all();
+		    },
+		    Err(_) => return,				// The pool is being dropped
+		}
+	    }
+	}
+
+	//
+	// Queue a job for a pool thread, or if the queue is full, deal with it according to the policy.
+	//
+	pub fn submit(&self, job: impl FnOnce() + Send + 'static) -> Submitted {
+	    let queue = self.queue.as_ref().expect("pool is shutting down");
+	    self.depth.fetch_add(1, Ordering::SeqCst);	// Count it before a thread can pick it up and uncount it
+	    *self.unfinished.0.lock().unwrap() += 1;
+	    match queue.try_send(Box::new(job)) {
+		Ok(()) => Submitted::Queued,
+		Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+		    self.depth.fetch_sub(1, Ordering::SeqCst);
+		    *self.unfinished.0.lock().unwrap() -= 1;
+		    match self.policy {
+			SaturationPolicy::Inline => {
+			    job();
+			    Submitted::RanInline
+			},
+			SaturationPolicy::Block => {
+			    self.depth.fetch_add(1, Ordering::SeqCst);
+			    *self.unfinished.0.lock().unwrap() += 1;
+			    queue.send(job).expect("the pool's threads hold the queue open");
+			    Submitted::Queued
+			},
+			SaturationPolicy::Defer => {
+			    log::warn!("background pool is saturated, deferring a job");
+			    Submitted::Deferred(job)
+			},
+		    }
+		},
+	    }
+	}
+
+	//
+	// How many jobs are waiting for a thread.
+	//
+	pub fn queue_depth(&self) -> usize {
+	    self.depth.load(Ordering::SeqCst)
+	}
+
+	//
+	// Wait until every job queued so far has run.  Called from a job, it doesn't wait for itself.
+	//
+	pub fn wait(&self) {
+	    let mine = self.workers.iter().any(|worker| worker.thread().id() == std::thread::current().id()) as usize;
+	    let (unfinished, finished) = &*self.unfinished;
+	    let _done = finished.wait_while(unfinished.lock().unwrap(), |left| *left > mine).unwrap();
+	}
+    }
+    impl Drop for ThreadPool {
+	//
+	// Let the threads finish what's queued, then wait for them.
+	//
+	fn drop(&mut self) {
+	    self.queue = None;
+	    for worker in self.workers.drain(..) {
+		if worker.thread().id() != std::thread::current().id() {	// A job may be what's dropping us
+		    let _ = worker.join();
+		}
+	    }
+	}
+    }
+}
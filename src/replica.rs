@@ -0,0 +1,100 @@
+//
+// Module that keeps a follower copy of a store up to date with its primary, for disaster recovery and to
+// spread reads over more machines.
+//
+// The follower is a store of its own, in a directory of its own, that only ever takes writes from the
+// primary: each poll reads the primary's files, the sealed datafiles and as much of the current one as
+// has been written, and applies whatever's new, in order, see Bitcask::replicate_from().  The primary
+// needn't know it's being followed, it's read the way a read-only open reads it, so its directory can be
+// anywhere a Storage backend reaches.  Reads are served from the follower's own files, so they carry on
+// even while the primary is down, and the follower is only ever as far behind as its last poll.
+//
+pub mod replica {
+    use std::sync::{Arc, Mutex, Weak};
+    use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+    use std::time::Duration;
+    use crate::bitcask::bitcask::{Bitcask, BitcaskFileID};
+    use crate::error::error::BitcaskError;
+    use crate::storage::storage::{FileStorage, Storage};
+
+    pub struct Follower {
+	replica: Box<Bitcask>,
+	primary: Box<dyn Storage>,
+	next: Mutex<BitcaskFileID>,		// The primary's datafile the next poll starts at, held while polling
+	stop: Mutex<Option<Sender<()>>>,	// Set once start() has a thread polling, dropping it stops the thread
+    }
+    impl Follower {
+	//
+	// Follow the store in the directory 'primary' with a copy in the directory 'replica', which is created
+	// if need be, or carried on with if it's a follower already.  Nothing is copied until the first poll().
+	//
+	pub fn new(primary: &str, replica: &str) -> Result<Follower, BitcaskError> {
+	    std::fs::create_dir_all(replica)?;
+	    Ok(Follower::with_storage(Box::new(FileStorage::new(primary)), Bitcask::new(replica)?))
+	}
+
+	//
+	// As above, but with the primary's files in the given storage backend, and the follower an open store,
+	// which nothing else should write to.
+	//
+	pub fn with_storage(primary: Box<dyn Storage>, replica: Box<Bitcask>) -> Follower {
+	    Follower { replica, primary, next: Mutex::new(0), stop: Mutex::new(None) }
+	}
+
+	//
+	// Catch up with the primary, returning how many records were applied.
+	//
+	pub fn poll(&self) -> Result<usize, BitcaskError> {
+	    let mut next = self.next.lock()?;
+	    let (count, newest) = self.replica.replicate_from(&*self.primary, *next)?;
+	    *next = newest;
+	    Ok(count)
+	}
+
+	//
+	// Poll every 'interval' on a thread of our own until the follower is dropped.  An error is logged and
+	// the next poll tries again.
+	//
+	pub fn start(self: &Arc<Self>, interval: Duration) {
+	    let (tx, rx) = channel::<()>();
+	    let follower: Weak<Follower> = Arc::downgrade(self);
+	    std::thread::spawn(move || loop {
+		match rx.recv_timeout(interval) {
+		    Err(RecvTimeoutError::Timeout) => {},
+		    _ => return,				// Stopped, or the follower's gone
+		}
+		let Some(follower) = follower.upgrade() else {
+		    return;
+		};
+		if let Err(e) = follower.poll() {
+		    log::warn!("follower: can't catch up with the primary: {}", e);
+		}
+	    });
+	    *self.stop.lock().unwrap() = Some(tx);		// Replaces, and so stops, any thread already polling
+	}
+
+	//
+	// The follower's reads, of the primary as it was at the last poll.  Use store() for anything else, but
+	// don't write to it, or the follower is no longer a copy.
+	//
+	pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, BitcaskError> {
+	    self.replica.get(key)
+	}
+
+	pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+	    self.replica.contains_key(key)
+	}
+
+	pub fn len(&self) -> usize {
+	    self.replica.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+	    self.replica.is_empty()
+	}
+
+	pub fn store(&self) -> &Bitcask {
+	    &self.replica
+	}
+    }
+}
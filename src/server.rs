@@ -0,0 +1,341 @@
+//
+// Module that serves a Bitcask over TCP speaking the Redis protocol (RESP), so existing Redis clients and
+// tools can use the store.  Only the handful of commands that map straight onto Bitcask are understood:
+// PING, GET, SET (with EX or PX), DEL, EXISTS, KEYS, EXPIRE, and QUIT.  Anything else gets an error reply.
+//
+// Each connection gets its own thread, up to RESP_MAX_CONNECTIONS of them, and any more are told so and
+// hung up on, as Redis does.  Requests may be RESP arrays of bulk strings, which is what client libraries
+// send, or inline commands separated by spaces, which is what someone typing at telnet sends.  Keys and
+// values can be any bytes.  A request bigger than the limits below drops the connection, and nothing is
+// set aside for a bulk string until its bytes arrive, so a client can't make us hold more than it sends.
+//
+pub mod server {
+    use std::io;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::bitcask::bitcask::Bitcask;
+    use crate::error::error::BitcaskError;
+
+    // The longest line we'll accept, an inline command or the header of a bulk string, as Redis's own limit.
+    const RESP_MAX_LINE_SIZE: usize = 64 * 1024;
+
+    // The most bulk strings in one request, the longest of them, and the most bytes all of one request's lines
+    // and bulk strings come to.
+    const RESP_MAX_ARGUMENTS: usize = 1024 * 1024;
+    const RESP_MAX_ARGUMENT_SIZE: usize = 16 * 1024 * 1024;
+    const RESP_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+
+    // The most connections served at once.
+    pub const RESP_MAX_CONNECTIONS: usize = 128;
+
+    //
+    // A reply, as RESP encodes it.
+    //
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub enum Reply {
+	Status(&'static str),
+	Error(String),
+	Integer(i64),
+	Bulk(Option<Vec<u8>>),			// None is the null bulk string, Redis's "no such key"
+	Array(Vec<Reply>),
+    }
+    impl Reply {
+	pub fn to_bytes(&self) -> Vec<u8> {
+	    let mut out = Vec::new();
+	    self.encode(&mut out);
+	    out
+	}
+
+	fn encode(&self, out: &mut Vec<u8>) {
+	    match self {
+		Reply::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+		Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+		Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+		Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+		Reply::Bulk(Some(bytes)) => {
+		    out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+		    out.extend_from_slice(bytes);
+		    out.extend_from_slice(b"\r\n");
+		},
+		Reply::Array(items) => {
+		    out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+		    for item in items {
+			item.encode(out);
+		    }
+		},
+	    }
+	}
+    }
+
+    //
+    // One of the RESP_MAX_CONNECTIONS, given back when the connection's thread is done with it.
+    //
+    struct ConnectionSlot(Arc<AtomicUsize>);
+    impl ConnectionSlot {
+	fn take(connections: &Arc<AtomicUsize>) -> Option<ConnectionSlot> {
+	    connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < RESP_MAX_CONNECTIONS).then_some(n + 1)).ok()?;
+	    Some(ConnectionSlot(Arc::clone(connections)))
+	}
+    }
+    impl Drop for ConnectionSlot {
+	fn drop(&mut self) {
+	    self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+    }
+
+    //
+    // Accept connections on 'listener' for as long as it lasts, serving each from 'cask' on its own thread.
+    //
+    pub fn serve(cask: Arc<Bitcask>, listener: TcpListener) -> Result<bool, io::Error> {
+	let connections = Arc::new(AtomicUsize::new(0));
+	for stream in listener.incoming() {
+	    let mut stream = stream?;
+	    let Some(slot) = ConnectionSlot::take(&connections) else {
+		let _ = stream.write_all(&Reply::Error("ERR max number of clients reached".to_string()).to_bytes());
+		continue;					// Dropping it hangs up
+	    };
+	    let cask = Arc::clone(&cask);
+	    std::thread::spawn(move || {
+		let _slot = slot;
+		let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+		if let Err(e) = handle_connection(&cask, stream) {
+		    log::warn!("{}: connection dropped: {}", peer, e);
+		}
+	    });
+	}
+	Ok(true)
+    }
+
+    //
+    // Answer requests on one connection until the client goes away or says QUIT.
+    //
+    pub fn handle_connection(cask: &Bitcask, stream: TcpStream) -> Result<bool, io::Error> {
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut writer = stream;
+	while let Some(args) = read_request(&mut reader)? {
+	    if args.is_empty() {
+		continue;					// A blank line, telnet users hit return a lot
+	    }
+	    let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+	    let reply = if quit { Reply::Status("OK") } else { execute(cask, &args) };
+	    writer.write_all(&reply.to_bytes())?;
+	    if quit {
+		break;
+	    }
+	}
+	Ok(true)
+    }
+
+    //
+    // Read one request, returning its arguments, or None at a clean end of stream.
+    //
+    fn read_request(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>, io::Error> {
+	let Some(line) = read_line(reader)? else {
+	    return Ok(None);
+	};
+	let Some(count) = line.strip_prefix(b"*") else {	// An inline command
+	    return Ok(Some(line.split(|b| b.is_ascii_whitespace()).filter(|arg| !arg.is_empty()).map(|arg| arg.to_vec()).collect()));
+	};
+	let count = parse_length(count, RESP_MAX_ARGUMENTS)?;
+	let mut size = line.len();				// What the request has come to so far
+	let mut args = Vec::with_capacity(count.min(1024));
+	for _ in 0..count {
+	    let line = read_line(reader)?.ok_or_else(|| protocol_error("end of stream in the middle of a request"))?;
+	    let Some(len) = line.strip_prefix(b"$") else {
+		return Err(protocol_error("expected a bulk string"));
+	    };
+	    let len = parse_length(len, RESP_MAX_ARGUMENT_SIZE)?;
+	    size += line.len() + len;
+	    if size > RESP_MAX_REQUEST_SIZE {
+		return Err(protocol_error("request too big"));
+	    }
+	    let mut arg = Vec::new();
+	    if reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)? < len + 2 {
+		return Err(protocol_error("end of stream in the middle of a bulk string"));
+	    }
+	    if !arg.ends_with(b"\r\n") {
+		return Err(protocol_error("bulk string isn't followed by CRLF"));
+	    }
+	    arg.truncate(len);
+	    args.push(arg);
+	}
+	Ok(Some(args))
+    }
+
+    //
+    // Read a line up to CRLF (or just LF, for inline commands), without the line ending.
+    //
+    fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, io::Error> {
+	let mut line = Vec::new();
+	if reader.by_ref().take(RESP_MAX_LINE_SIZE as u64).read_until(b'\n', &mut line)? == 0 {
+	    return Ok(None);
+	}
+	if !line.ends_with(b"\n") {
+	    return Err(protocol_error("line too long or cut short"));
+	}
+	line.pop();
+	if line.ends_with(b"\r") {
+	    line.pop();
+	}
+	Ok(Some(line))
+    }
+
+    fn parse_length(digits: &[u8], limit: usize) -> Result<usize, io::Error> {
+	std::str::from_utf8(digits).ok()
+	    .and_then(|digits| digits.parse::<usize>().ok())
+	    .filter(|len| *len <= limit)
+	    .ok_or_else(|| protocol_error("bad length"))
+    }
+
+    fn protocol_error(what: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("protocol error: {}", what))
+    }
+
+    //
+    // Carry out one command against the store.  Failures of the store itself come back as error replies,
+    // the connection carries on.
+    //
+    pub fn execute(cask: &Bitcask, args: &[Vec<u8>]) -> Reply {
+	let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+	let result = match (name.as_str(), args.len()) {
+	    ("PING", 1) => Ok(Reply::Status("PONG")),
+	    ("PING", 2) => Ok(Reply::Bulk(Some(args[1].clone()))),
+	    ("GET", 2) => cask.get(&args[1]).map(Reply::Bulk),
+	    ("SET", 3 | 5) => set(cask, args),
+	    ("DEL", 2..) => count(cask, &args[1..], |key| cask.delete(key)),
+	    ("EXISTS", 2..) => Ok(Reply::Integer(cask.exists_many(&args[1..]).iter().filter(|exists| **exists).count() as i64)),
+	    ("KEYS", 2) => Ok(keys(cask, &args[1])),
+	    ("EXPIRE", 3) => expire(cask, args),
+	    ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "KEYS" | "EXPIRE", _) =>
+		return Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase())),
+	    _ => return Reply::Error(format!("ERR unknown command '{}'", String::from_utf8_lossy(&args[0]))),
+	};
+	result.unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)))
+    }
+
+    //
+    // SET key value [EX seconds | PX milliseconds]
+    //
+    fn set(cask: &Bitcask, args: &[Vec<u8>]) -> Result<Reply, BitcaskError> {
+	let value = &args[2];
+	if args.len() == 3 {
+	    cask.put(&args[1], value)?;
+	    return Ok(Reply::Status("OK"));
+	}
+	let ttl = match (String::from_utf8_lossy(&args[3]).to_ascii_uppercase().as_str(), parse_integer(&args[4])) {
+	    ("EX", Some(secs)) if secs > 0 => Duration::from_secs(secs as u64),
+	    ("PX", Some(ms)) if ms > 0 => Duration::from_millis(ms as u64),
+	    ("EX" | "PX", _) => return Ok(Reply::Error("ERR invalid expire time in 'set' command".to_string())),
+	    _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+	};
+	cask.put_with_ttl(&args[1], value, ttl)?;
+	Ok(Reply::Status("OK"))
+    }
+
+    //
+    // EXPIRE key seconds, where a time that isn't in the future deletes the key, as it does in Redis.
+    //
+    fn expire(cask: &Bitcask, args: &[Vec<u8>]) -> Result<Reply, BitcaskError> {
+	let Some(secs) = parse_integer(&args[2]) else {
+	    return Ok(Reply::Error("ERR value is not an integer or out of range".to_string()));
+	};
+	if secs <= 0 {
+	    return count(cask, &args[1..2], |key| cask.delete(key));
+	}
+	Ok(Reply::Integer(cask.expire(&args[1], Some(Duration::from_secs(secs as u64)))? as i64))
+    }
+
+    //
+    // Apply 'op' to each of the keys that exists, returning how many there were.
+    //
+    fn count(cask: &Bitcask, keys: &[Vec<u8>], op: impl Fn(&[u8]) -> Result<bool, BitcaskError>) -> Result<Reply, BitcaskError> {
+	let mut n: i64 = 0;
+	for (key, exists) in keys.iter().zip(cask.exists_many(keys)) {
+	    if exists {
+		op(key)?;
+		n += 1;
+	    }
+	}
+	Ok(Reply::Integer(n))
+    }
+
+    //
+    // KEYS pattern, in sorted order.
+    //
+    fn keys(cask: &Bitcask, pattern: &[u8]) -> Reply {
+	let mut keys: Vec<Vec<u8>> = cask.list_keys().into_iter().filter(|key| glob_match(pattern, key)).collect();
+	keys.sort();
+	Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect())
+    }
+
+    fn parse_integer(arg: &[u8]) -> Option<i64> {
+	std::str::from_utf8(arg).ok()?.parse().ok()
+    }
+
+    //
+    // Does 'text' match the Redis-style glob 'pattern'?  '*' matches any run of bytes, '?' any one byte,
+    // '[...]' any byte in the set (with ranges like a-z, and '^' in front to negate it), and '\' makes the
+    // next byte literal.
+    //
+    // Only the last '*' is ever backtracked to: whatever an earlier one matched, a later one can match as
+    // well from there, so the whole match takes O(len(pattern) * len(text)) steps however many stars it has.
+    //
+    pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+	let (mut p, mut t) = (0, 0);
+	let mut star: Option<(usize, usize)> = None;		// Just past the last '*', and where in the text it stops
+	while t < text.len() {
+	    if pattern.get(p) == Some(&b'*') {
+		p += 1;
+		star = Some((p, t));				// Matching nothing to begin with
+		continue;
+	    }
+	    if let Some(len) = match_one(&pattern[p..], text[t]) {
+		p += len;
+		t += 1;
+		continue;
+	    }
+	    let Some((after, stop)) = star else {
+		return false;
+	    };
+	    star = Some((after, stop + 1));			// Have the star take one more byte, and try again from there
+	    (p, t) = (after, stop + 1);
+	}
+	pattern[p..].iter().all(|b| *b == b'*')
+    }
+
+    //
+    // How long the element at the front of 'pattern' is, if it matches the byte 'c', and isn't a '*'.
+    //
+    fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+	match pattern {
+	    [] | [b'*', ..] => None,
+	    [b'?', ..] => Some(1),
+	    [b'[', rest @ ..] => {
+		let Some(close) = rest.iter().skip(1).position(|b| *b == b']').map(|i| i + 1) else {
+		    return (c == b'[').then_some(1);		// No set after all, a literal '['
+		};
+		let (negated, set) = match &rest[..close] {
+		    [b'^', set @ ..] => (true, set),
+		    set => (false, set),
+		};
+		let mut found = false;
+		let mut i = 0;
+		while i < set.len() {
+		    if i + 2 < set.len() && set[i + 1] == b'-' {
+			found |= (set[i].min(set[i + 2])..=set[i].max(set[i + 2])).contains(&c);
+			i += 3;
+		    } else {
+			found |= set[i] == c;
+			i += 1;
+		    }
+		}
+		(found != negated).then_some(close + 2)
+	    },
+	    [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+	    [literal, ..] => (*literal == c).then_some(1),
+	}
+    }
+}
@@ -0,0 +1,667 @@
+//
+// Module that abstracts where the Bitcask files actually live.
+//
+// Bitcask only ever needs a flat namespace of files that it can create, append to, read from at an
+// offset, flush, list, and remove, so that's all a backend has to provide.  The default is a directory
+// on a local filesystem, but the same datafile and hints logic runs unchanged on anything else that
+// can offer those operations (a ramdisk, an object store, a fault-injecting wrapper for tests, ...).
+//
+pub mod storage {
+    use std::collections::HashMap;
+    use std::fs::read_dir;
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::fs::TryLockError;
+    use std::sync::Arc;
+    use std::sync::{Condvar, Mutex};
+    use std::sync::RwLock;
+    use std::time::Duration;
+    use std::io;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use crate::error::error::BitcaskError;
+
+    //
+    // The operations on one open file.
+    //
+    pub trait StorageFile: Send + Sync {
+	//
+	// Read up to buf.len() bytes starting at 'offset', returning how many were read (0 at end-of-file).
+	//
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error>;
+
+	//
+	// Write all of 'buf' at the end of the file, returning the offset it was written at.
+	//
+	fn append(&self, buf: &[u8]) -> Result<u64, io::Error>;
+
+	//
+	// Make everything appended so far stable.
+	//
+	fn sync(&self) -> Result<bool, io::Error>;
+
+	//
+	// The current length of the file in bytes.
+	//
+	fn size(&self) -> Result<u64, io::Error>;
+
+	//
+	// Throw away everything from 'len' bytes onwards.
+	//
+	fn truncate(&self, len: u64) -> Result<bool, io::Error>;
+
+	//
+	// Fill all of 'buf' starting at 'offset', failing with UnexpectedEof if the file is too short.
+	//
+	fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<bool, io::Error> {
+	    while !buf.is_empty() {
+		match self.read_at(offset, buf)? {
+		    0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read")),
+		    n => {
+			offset += n as u64;
+			buf = &mut buf[n..];
+		    },
+		}
+	    }
+	    Ok(true)
+	}
+    }
+
+    //
+    // The operations on the namespace of files, which are named relative to the backend's root.
+    //
+    pub trait Storage: Send + Sync {
+	//
+	// Create a new, empty file that can be appended to, replacing any existing file of that name.
+	//
+	fn create(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error>;
+
+	//
+	// Open an existing file, which need only be readable.
+	//
+	fn open(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error>;
+
+	//
+	// Delete a file.
+	//
+	fn remove(&self, name: &str) -> Result<bool, io::Error>;
+
+	//
+	// Return the names of all the files.
+	//
+	fn list(&self) -> Result<Vec<String>, io::Error>;
+
+	//
+	// Keep the files to ourselves, using the file 'name' to do it, until the StorageLock is dropped.  Fails
+	// with WouldBlock, rather than waiting, if another process already has them.  Backends that can't be
+	// shared with anyone else needn't do anything.
+	//
+	fn lock(&self, _name: &str) -> Result<StorageLock, io::Error> {
+	    Ok(StorageLock::new(Box::new(())))
+	}
+
+	//
+	// Throw away everything in an existing file from 'len' bytes onwards.  open() needn't give a handle
+	// that can do that, so backends where it doesn't say how it's done.
+	//
+	fn truncate(&self, name: &str, len: u64) -> Result<bool, io::Error> {
+	    self.open(name)?.truncate(len)
+	}
+
+	//
+	// Read 'file', the open handle of 'name', some quicker way from now on, if the backend has one.  It's
+	// only asked of files that won't be appended to again.  Backends that don't just hand 'file' back.
+	//
+	fn map(&self, _name: &str, file: Arc<dyn StorageFile>) -> Result<Arc<dyn StorageFile>, io::Error> {
+	    Ok(file)
+	}
+
+	//
+	// Give the file 'from' the name 'to' instead, replacing any file already called that, and make the
+	// change durable.  Backends that can should do it atomically, so after a crash there's one or the other
+	// and not both.  Those that don't copy it, sync the copy, and remove the original, which leaves both if
+	// the crash comes in between, but never neither.  Handles open on 'from' needn't work afterwards.
+	//
+	fn rename(&self, from: &str, to: &str) -> Result<bool, io::Error> {
+	    let src = self.open(from)?;
+	    let mut buf: Vec<u8> = vec![0u8; src.size()? as usize];
+	    src.read_exact_at(0, &mut buf)?;
+	    let dst = self.create(to)?;
+	    dst.append(&buf)?;
+	    dst.sync()?;					// The copy must be safe before the original goes
+	    self.remove(from)
+	}
+    }
+
+    //
+    // Whatever a backend has to hold on to for as long as its files are locked, see Storage::lock().
+    //
+    pub struct StorageLock {
+	_held: Box<dyn Send + Sync>,
+    }
+    impl StorageLock {
+	pub fn new(held: Box<dyn Send + Sync>) -> StorageLock {
+	    StorageLock { _held: held }
+	}
+    }
+
+    //
+    // Adapt a StorageFile to io::Read so it can be wrapped in a BufReader for sequential scans.  It holds
+    // the file open, so a scan can outlive whatever it was started from.
+    //
+    pub struct StorageReader {
+	file: Arc<dyn StorageFile>,
+	offset: u64,				// Where the next read will come from
+    }
+    impl StorageReader {
+	pub fn new(file: Arc<dyn StorageFile>, offset: u64) -> StorageReader {
+	    StorageReader { file, offset }
+	}
+    }
+    impl Read for StorageReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    let n = self.file.read_at(self.offset, buf)?;
+	    self.offset += n as u64;
+	    Ok(n)
+	}
+    }
+
+    //
+    // The first 'len' bytes of a file, read-only, for reading a file that's being appended to as though
+    // it stopped there.
+    //
+    pub struct StoragePrefix {
+	file: Arc<dyn StorageFile>,
+	len: u64,
+    }
+    impl StoragePrefix {
+	pub fn new(file: Arc<dyn StorageFile>, len: u64) -> StoragePrefix {
+	    StoragePrefix { file, len }
+	}
+    }
+    impl StorageFile for StoragePrefix {
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    let n = buf.len().min(self.len.saturating_sub(offset) as usize);
+	    self.file.read_at(offset, &mut buf[..n])
+	}
+
+	fn append(&self, _buf: &[u8]) -> Result<u64, io::Error> {
+	    Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't append to a prefix of a file"))
+	}
+
+	fn sync(&self) -> Result<bool, io::Error> {
+	    Ok(true)
+	}
+
+	fn size(&self) -> Result<u64, io::Error> {
+	    Ok(self.file.size()?.min(self.len))
+	}
+
+	fn truncate(&self, _len: u64) -> Result<bool, io::Error> {
+	    Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't truncate a prefix of a file"))
+	}
+    }
+
+    // Filesystems whose locking and flushing don't behave the way a local disk does.
+    const UNSUPPORTED_FILESYSTEMS: &[&str] = &["nfs", "cifs", "smb", "smb2", "fuse", "9p"];
+
+    //
+    // Identifies what kind of filesystem a directory lives on, e.g. "ext4" or "nfs".  None means it can't tell.
+    //
+    pub trait FilesystemProbe {
+	fn filesystem_type(&self, dirpath: &str) -> Option<String>;
+    }
+
+    //
+    // The real probe, which asks statfs(2) and names the filesystems we know about.
+    //
+    pub struct StatfsProbe;
+    impl FilesystemProbe for StatfsProbe {
+	#[cfg(target_os = "linux")]
+	fn filesystem_type(&self, dirpath: &str) -> Option<String> {
+	    let path = std::ffi::CString::new(dirpath).ok()?;
+	    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+	    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } != 0 {
+		return None;
+	    }
+	    let magic = (buf.f_type as u64) & 0xFFFF_FFFF;	// Its width varies by architecture, the values don't
+	    let name = match magic {
+		0x6969 => "nfs",
+		0xFF534D42 => "cifs",
+		0xFE534D42 => "smb2",
+		0x517B => "smb",
+		0x65735546 => "fuse",
+		0x01021997 => "9p",
+		0xEF53 => "ext4",
+		0x58465342 => "xfs",
+		0x9123683E => "btrfs",
+		0x2FC12FC1 => "zfs",
+		0x01021994 => "tmpfs",
+		0x794C7630 => "overlayfs",
+		_ => return Some(format!("0x{:x}", magic)),
+	    };
+	    Some(name.to_string())
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	fn filesystem_type(&self, _dirpath: &str) -> Option<String> {
+	    None
+	}
+    }
+
+    //
+    // The default backend: a directory of ordinary files.
+    //
+    pub struct FileStorage {
+	dirpath: String,			// The directory storing everything
+    }
+    impl FileStorage {
+	pub fn new(dirpath: &str) -> FileStorage {
+	    FileStorage { dirpath: dirpath.to_string() }
+	}
+
+	//
+	// Open a file by its full pathname, which need not be in any particular directory.
+	//
+	pub fn open_path(filename: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    Ok(Box::new(FileStorageFile::new(File::open(filename)?)))
+	}
+
+	//
+	// Look at the filesystem the directory is on.  If it's one we know misbehaves (NFS and friends, where
+	// fsync and flock don't mean what they do locally) then warn, or if 'strict' then refuse with an
+	// UnsupportedFilesystem error.  Returns Ok(false) if it warned.
+	//
+	pub fn check_filesystem(&self, probe: &dyn FilesystemProbe, strict: bool) -> Result<bool, io::Error> {
+	    let fstype = match probe.filesystem_type(&self.dirpath) {
+		Some(fstype) if UNSUPPORTED_FILESYSTEMS.contains(&fstype.as_str()) => fstype,
+		_ => return Ok(true),
+	    };
+	    if strict {
+		return Err(BitcaskError::UnsupportedFilesystem { dir: self.dirpath.clone(), fstype }.into());
+	    }
+	    log::warn!("{}: is on a {} filesystem, flushes and locks may not be reliable", self.dirpath, fstype);
+	    Ok(false)
+	}
+
+	fn path(&self, name: &str) -> String {
+	    format!("{}/{}", self.dirpath, name)
+	}
+    }
+    impl Storage for FileStorage {
+	fn create(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.path(name))?;
+	    Ok(Box::new(FileStorageFile::new(file)))
+	}
+
+	fn open(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    Self::open_path(&self.path(name))
+	}
+
+	fn remove(&self, name: &str) -> Result<bool, io::Error> {
+	    std::fs::remove_file(self.path(name))?;
+	    Ok(true)
+	}
+
+	fn list(&self) -> Result<Vec<String>, io::Error> {
+	    let mut names: Vec<String> = Vec::new();
+	    for entry in read_dir(&self.dirpath)? {
+		let entry = entry?;
+		if entry.metadata()?.is_file() {
+		    names.push(entry.file_name().to_string_lossy().to_string());	// The OsString type is difficult to work with
+		}
+	    }
+	    Ok(names)
+	}
+
+	fn truncate(&self, name: &str, len: u64) -> Result<bool, io::Error> {
+	    let file = OpenOptions::new().write(true).open(self.path(name))?;
+	    file.set_len(len)?;
+	    file.sync_all()?;
+	    Ok(true)
+	}
+
+	//
+	// The filesystem's own rename, which is atomic, and then a sync of the directory, which makes it stick.
+	//
+	fn rename(&self, from: &str, to: &str) -> Result<bool, io::Error> {
+	    std::fs::rename(self.path(from), self.path(to))?;
+	    File::open(&self.dirpath)?.sync_all()?;
+	    Ok(true)
+	}
+
+	//
+	// An advisory lock on the named file, created if need be and left behind afterwards.  The lock goes
+	// with the open file, so it's let go however we stop holding it, a crash included.
+	//
+	fn lock(&self, name: &str) -> Result<StorageLock, io::Error> {
+	    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(self.path(name))?;
+	    match file.try_lock() {
+		Ok(()) => Ok(StorageLock::new(Box::new(file))),
+		Err(TryLockError::WouldBlock) => Err(io::Error::new(io::ErrorKind::WouldBlock,
+								    format!("{}: is locked, the store is open in another process", self.path(name)))),
+		Err(TryLockError::Error(e)) => Err(e),
+	    }
+	}
+
+	//
+	// Map the file into memory, see MappedStorageFile.
+	//
+	#[cfg(all(feature = "mmap", target_os = "linux"))]
+	fn map(&self, name: &str, file: Arc<dyn StorageFile>) -> Result<Arc<dyn StorageFile>, io::Error> {
+	    let source = File::open(self.path(name))?;
+	    let mapping = Mapping::new(&source)?;
+	    Ok(Arc::new(MappedStorageFile { file, source, mapping: RwLock::new(mapping) }))
+	}
+    }
+
+    //
+    // A file that's done being appended to, read through a shared, read-only mapping of it, so that a read
+    // is a copy out of the page cache rather than a system call.  Anything past the end of the mapping,
+    // which is the size the file was when it was mapped, is read from the file as usual, and everything
+    // but reading goes to the file.  A truncate maps the file again, so no read can reach past its end.
+    // Another process truncating the file underneath us would be fatal, as it is with any mapping.
+    //
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    struct MappedStorageFile {
+	file: Arc<dyn StorageFile>,		// The handle it was opened with
+	source: File,				// What the mappings are made from
+	mapping: RwLock<Mapping>,		// Replaced under the write lock by a truncate
+    }
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    impl StorageFile for MappedStorageFile {
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    {
+		let mapping = self.mapping.read().unwrap();
+		let bytes = mapping.bytes();
+		if offset < bytes.len() as u64 {
+		    let start = offset as usize;
+		    let n = buf.len().min(bytes.len() - start);
+		    buf[..n].copy_from_slice(&bytes[start..start + n]);
+		    return Ok(n);
+		}
+	    }
+	    self.file.read_at(offset, buf)
+	}
+
+	fn append(&self, buf: &[u8]) -> Result<u64, io::Error> {
+	    self.file.append(buf)
+	}
+
+	fn sync(&self) -> Result<bool, io::Error> {
+	    self.file.sync()
+	}
+
+	fn size(&self) -> Result<u64, io::Error> {
+	    self.file.size()
+	}
+
+	fn truncate(&self, len: u64) -> Result<bool, io::Error> {
+	    let mut mapping = self.mapping.write().unwrap();
+	    *mapping = Mapping::empty();				// Unmapped before the pages behind it go
+	    self.file.truncate(len)?;
+	    *mapping = Mapping::new(&self.source)?;
+	    Ok(true)
+	}
+    }
+
+    //
+    // One mmap(2) of a whole file, unmapped when it's dropped.
+    //
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    struct Mapping {
+	addr: *mut libc::c_void,
+	len: usize,				// 0 for nothing mapped, mmap() won't map an empty file
+    }
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    impl Mapping {
+	fn new(file: &File) -> Result<Mapping, io::Error> {
+	    use std::os::unix::io::AsRawFd;
+	    let len = usize::try_from(file.metadata()?.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too big to map"))?;
+	    if len == 0 {
+		return Ok(Mapping::empty());
+	    }
+	    let addr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+	    if addr == libc::MAP_FAILED {
+		return Err(io::Error::last_os_error());
+	    }
+	    Ok(Mapping { addr, len })
+	}
+
+	fn empty() -> Mapping {
+	    Mapping { addr: std::ptr::null_mut(), len: 0 }
+	}
+
+	fn bytes(&self) -> &[u8] {
+	    match self.len {
+		0 => &[],
+		len => unsafe { std::slice::from_raw_parts(self.addr as *const u8, len) },
+	    }
+	}
+    }
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    impl Drop for Mapping {
+	fn drop(&mut self) {
+	    if self.len > 0 {
+		unsafe { libc::munmap(self.addr, self.len) };
+	    }
+	}
+    }
+    // The mapping is read-only and nothing else holds its address, so it can go anywhere a &[u8] can.
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    unsafe impl Send for Mapping {}
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    unsafe impl Sync for Mapping {}
+
+    //
+    // Reads are positional, pread(2) or its equivalent, so any number of them can be in flight at once and
+    // none of them moves the file's offset.  Only appends, which go wherever the end is, need the lock.
+    //
+    struct FileStorageFile {
+	file: File,
+	append_lock: Mutex<()>,			// Ensures an append's seek to the end goes with its write
+    }
+    impl FileStorageFile {
+	fn new(file: File) -> FileStorageFile {
+	    FileStorageFile { file, append_lock: Mutex::new(()) }
+	}
+    }
+    impl StorageFile for FileStorageFile {
+	#[cfg(unix)]
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+	}
+
+	#[cfg(windows)]
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)	// Moves the offset, but appends seek anyway
+	}
+
+	#[cfg(not(any(unix, windows)))]
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    let _appending = self.append_lock.lock().unwrap();	// No positional reads here, so a seek and a read it is
+	    (&self.file).seek(SeekFrom::Start(offset))?;
+	    (&self.file).read(buf)
+	}
+
+	fn append(&self, buf: &[u8]) -> Result<u64, io::Error> {
+	    let _appending = self.append_lock.lock().unwrap();
+	    let offset = (&self.file).seek(SeekFrom::End(0))?;	// Capture the offset of this new record
+	    (&self.file).write_all(buf)?;
+	    Ok(offset)
+	}
+
+	fn sync(&self) -> Result<bool, io::Error> {
+	    self.file.sync_all()?;
+	    Ok(true)
+	}
+
+	fn size(&self) -> Result<u64, io::Error> {
+	    Ok(self.file.metadata()?.len())
+	}
+
+	fn truncate(&self, len: u64) -> Result<bool, io::Error> {
+	    let _appending = self.append_lock.lock().unwrap();
+	    self.file.set_len(len)?;
+	    Ok(true)
+	}
+    }
+
+    // The contents of one in-memory file.
+    type MemoryFileData = Arc<RwLock<Vec<u8>>>;
+
+    //
+    // A backend that keeps every file in memory, for tests and for stores that needn't outlive the process.
+    // Clones share the same set of files, so a store can be "reopened" from a clone of its MemoryStorage.
+    //
+    #[derive(Clone, Default)]
+    pub struct MemoryStorage {
+	files: Arc<Mutex<HashMap<String, MemoryFileData>>>,		// Protects the namespace, each file has its own lock
+    }
+    impl MemoryStorage {
+	pub fn new() -> MemoryStorage {
+	    MemoryStorage::default()
+	}
+    }
+    impl Storage for MemoryStorage {
+	fn create(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    let data = Arc::new(RwLock::new(Vec::new()));
+	    self.files.lock().unwrap().insert(name.to_string(), data.clone());
+	    Ok(Box::new(MemoryStorageFile { data }))
+	}
+
+	fn open(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    match self.files.lock().unwrap().get(name) {
+		Some(data) => Ok(Box::new(MemoryStorageFile { data: data.clone() })),
+		None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file", name))),
+	    }
+	}
+
+	fn remove(&self, name: &str) -> Result<bool, io::Error> {
+	    match self.files.lock().unwrap().remove(name) {
+		Some(_) => Ok(true),
+		None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file", name))),
+	    }
+	}
+
+	fn list(&self) -> Result<Vec<String>, io::Error> {
+	    Ok(self.files.lock().unwrap().keys().cloned().collect())
+	}
+
+	fn rename(&self, from: &str, to: &str) -> Result<bool, io::Error> {
+	    let mut files = self.files.lock().unwrap();
+	    match files.remove(from) {
+		Some(data) => {
+		    files.insert(to.to_string(), data);
+		    Ok(true)
+		},
+		None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file", from))),
+	    }
+	}
+    }
+
+    struct MemoryStorageFile {
+	data: MemoryFileData,			// Protects the contents of the file
+    }
+    impl StorageFile for MemoryStorageFile {
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    let data = self.data.read().unwrap();
+	    if offset >= data.len() as u64 {
+		return Ok(0);
+	    }
+	    let avail = &data[offset as usize..];
+	    let n = avail.len().min(buf.len());
+	    buf[..n].copy_from_slice(&avail[..n]);
+	    Ok(n)
+	}
+
+	fn append(&self, buf: &[u8]) -> Result<u64, io::Error> {
+	    let mut data = self.data.write().unwrap();
+	    let offset = data.len() as u64;
+	    data.extend_from_slice(buf);
+	    Ok(offset)
+	}
+
+	fn sync(&self) -> Result<bool, io::Error> {
+	    Ok(true)
+	}
+
+	fn size(&self) -> Result<u64, io::Error> {
+	    Ok(self.data.read().unwrap().len() as u64)
+	}
+
+	fn truncate(&self, len: u64) -> Result<bool, io::Error> {
+	    self.data.write().unwrap().truncate(len as usize);
+	    Ok(true)
+	}
+    }
+
+    //
+    // Schedules fsyncs on behalf of any number of stores, so that several small stores sharing a disk flush
+    // in groups rather than each hammering the device independently.  A caller of sync() joins the batch that
+    // is currently filling up; the first caller to find nobody flushing becomes the leader, waits out the
+    // window so others can pile in, and then syncs every distinct file in the batch exactly once.  Every
+    // caller returns only after a sync of its file that started after its call, so durability is unchanged.
+    //
+    pub struct SyncCoordinator {
+	window: Duration,			// How long a leader waits for company before flushing
+	state: Mutex<SyncState>,		// Protects the batch being filled and who's flushing
+	done: Condvar,				// Signalled whenever a batch finishes
+    }
+    struct SyncState {
+	filling: Arc<SyncBatch>,		// The batch new callers join
+	flushing: bool,				// True while a leader owns a batch
+    }
+    #[derive(Default)]
+    struct SyncBatch {
+	files: Mutex<Vec<Arc<dyn StorageFile>>>,	// Each distinct file only once
+	result: Mutex<Option<Result<(), (io::ErrorKind, String)>>>,	// Filled in when the batch has been synced
+    }
+    impl SyncCoordinator {
+	pub fn new(window: Duration) -> SyncCoordinator {
+	    SyncCoordinator {
+		window,
+		state: Mutex::new(SyncState { filling: Arc::new(SyncBatch::default()), flushing: false }),
+		done: Condvar::new(),
+	    }
+	}
+
+	//
+	// Make everything appended to 'file' so far stable, in company with whatever else is waiting.
+	//
+	pub fn sync(&self, file: &Arc<dyn StorageFile>) -> Result<bool, io::Error> {
+	    let mut state = self.state.lock().unwrap();
+	    let batch = state.filling.clone();
+	    {
+		let mut files = batch.files.lock().unwrap();
+		if !files.iter().any(|f| Arc::ptr_eq(f, file)) {
+		    files.push(file.clone());
+		}
+	    }
+	    loop {
+		if let Some(result) = batch.result.lock().unwrap().clone() {
+		    return result.map(|_| true).map_err(|(kind, msg)| io::Error::new(kind, msg));
+		}
+		if !state.flushing {
+		    state.flushing = true;			// It's our batch, and we're the leader
+		    drop(state);
+		    std::thread::sleep(self.window);
+		    state = self.state.lock().unwrap();
+		    let ours = std::mem::take(&mut state.filling);	// Later callers start the next batch
+		    drop(state);
+		    let mut result = Ok(());
+		    for f in ours.files.lock().unwrap().iter() {
+			if let Err(e) = f.sync() {
+			    result = Err((e.kind(), e.to_string()));
+			}
+		    }
+		    state = self.state.lock().unwrap();
+		    *ours.result.lock().unwrap() = Some(result);
+		    state.flushing = false;
+		    self.done.notify_all();
+		    continue;					// Pick up our own result
+		}
+		state = self.done.wait(state).unwrap();
+	    }
+	}
+    }
+}
@@ -8,55 +8,3329 @@
 //
 #[cfg(test)]
 pub mod tests {
-    use crate::Bitcask;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{Bitcask, BitcaskError, BitcaskOptions, ChangeEvent, ChangeOp, CorruptionPolicy, MergePolicy, RetentionPolicy, Stats, SyncPolicy, VerifyIssueKind, WriteBatch};
+    use crate::{MemoryStorage, Storage, StorageFile, SyncCoordinator};
+    use crate::{SaturationPolicy, Submitted, ThreadPool};
+    use crate::{FileStorage, FilesystemProbe, StatfsProbe};
+    use crate::{CounterNonceSource, EncryptionConfig, NonceSource, RandomNonceSource, RecordCipher};
+    use crate::server::server::{glob_match, serve, RESP_MAX_CONNECTIONS};
+    use crate::Compression;
+    use crate::compress::compress::{lz4_pack, lz4_unpack};
+    use crate::bloom::bloom::BloomFilter;
+    use crate::Follower;
 
     #[test]
     fn test_get() {
-	let testdir = "test_get/".to_string();
-	test_setup(&testdir);
+	with_each_backend("test_get/", |open| {
+	    let bc = open();
+	    let val = bc.get(k(12)).unwrap();
+	    assert_eq!(val, None);
+	});
+    }
+
+    #[test]
+    fn test_add_get() {
+	with_each_backend("test_add_get/", |open| {
+	    let value = "b".to_string();
+	    let bc = open();
+	    bc.put(k(14), &value).unwrap();
+	    let val = bc.get(k(14)).unwrap();
+	    assert_eq!(val, Some(value.into_bytes()));
+	});
+    }
+
+    #[test]
+    fn test_add_delete_get() {
+	with_each_backend("test_add_delete_get/", |open| {
+	    let value = "b".to_string();
+	    let bc = open();
+	    bc.put(k(10), &value).unwrap();
+	    let val = bc.get(k(10)).unwrap();
+	    assert_eq!(val, Some(value.into_bytes()));
+	    bc.delete(k(10)).unwrap();
+	    let val = bc.get(k(10)).unwrap();
+	    assert_eq!(val, None);
+	});
+    }
+
+    #[test]
+    fn test_merge() {
+	with_each_backend("test_merge/", |open| {
+	    let bc = open();
+	    for round in 0..3 {
+		for key in 0..10 {
+		    bc.put(k(key), format!("{}-{}", key, round)).unwrap();
+		}
+	    }
+	    bc.delete(k(3)).unwrap();
+	    bc.merge().unwrap();
+	    for key in 0..10 {
+		let expected = if key == 3 { None } else { Some(format!("{}-2", key).into_bytes()) };
+		assert_eq!(bc.get(k(key)).unwrap(), expected);
+	    }
+	});
+    }
+
+    #[test]
+    fn test_merge_with_filter() {
+	with_each_backend("test_merge_with_filter/", |open| {
+	    {
+		let bc = open();
+		for key in 0..20 {
+		    bc.put(k(key), format!("value{}", key)).unwrap();
+		}
+		bc.merge_with_filter(|key| key[0] % 2 == 1).unwrap();	// The low byte, every key here fits in it
+		let mut keys = bc.list_keys();
+		keys.sort();
+		assert_eq!(keys, (0..20).filter(|key| key % 2 == 1).map(|key| k(key).to_vec()).collect::<Vec<Vec<u8>>>());
+		assert_eq!(bc.get(k(4)).unwrap(), None);
+		assert_eq!(bc.get(k(5)).unwrap(), Some(b"value5".to_vec()));
+		bc.shutdown().unwrap();
+	    }
+	    // The dropped keys must not come back when the datafiles are replayed
+	    let bc = open();
+	    let mut keys = bc.list_keys();
+	    keys.sort();
+	    assert_eq!(keys, (0..20).filter(|key| key % 2 == 1).map(|key| k(key).to_vec()).collect::<Vec<Vec<u8>>>());
+	});
+    }
+
+    #[test]
+    fn test_import_datafile_progressive() {
+	let srcdir = test_setup("test_import_datafile_progressive_src/");
+	let testdir = test_setup("test_import_datafile_progressive/");
+	{
+	    let src = Bitcask::new(&srcdir).expect("REASON");
+	    for key in 0..3000 {
+		src.put(k(key), format!("value{}", key)).unwrap();
+	    }
+	    src.shutdown().unwrap();
+	}
 	let bc = Bitcask::new(&testdir).expect("REASON");
-	let val = bc.get(12);
-	assert_eq!(val, None);
+	let mut batches = 0;
+	let count = bc.import_datafile(&format!("{}/1.data", srcdir), |sofar| {
+	    if batches == 0 {
+		// The first batch is visible while the rest of the import is still to come
+		assert!(sofar < 3000);
+		assert_eq!(bc.get(k(0)).unwrap(), Some(b"value0".to_vec()));
+		assert_eq!(bc.get(k(2999)).unwrap(), None);
+	    }
+	    batches += 1;
+	}).unwrap();
+	assert_eq!(count, 3000);
+	assert!(batches > 1);
+	assert_eq!(bc.get(k(2999)).unwrap(), Some(b"value2999".to_vec()));
+	// Each batch went in between markers, so one cut short by a crash is gone altogether
+	let name = format!("{}/{}.data", testdir, bc.active_file_id());
+	bc.crash();
+	let data = std::fs::read(&name).unwrap();
+	std::fs::write(&name, &data[..data.len() - 1]).unwrap();	// Into the last COMMIT
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(2047)).unwrap(), Some(b"value2047".to_vec()));
+	assert_eq!(bc.get(k(2048)).unwrap(), None);
+	assert_eq!(bc.get(k(2999)).unwrap(), None);
+	drop(bc);
+	// A full datafile is rotated part way through, and a value too big for the store stops the import
+	let otherdir = test_setup("test_import_datafile_progressive_other/");
+	let bc = Bitcask::open_with(BitcaskOptions::new(&otherdir).create_if_missing(true).max_datafile_size(Some(64 * 1024))).unwrap();
+	assert_eq!(bc.import_datafile(&format!("{}/1.data", srcdir), |_| {}).unwrap(), 3000);
+	assert!(bc.stats().unwrap().datafiles > 1);
+	assert_eq!(bc.get(k(2999)).unwrap(), Some(b"value2999".to_vec()));
+	drop(bc);
+	test_teardown(&otherdir);
+	let bc = Bitcask::open_with(BitcaskOptions::new(&otherdir).create_if_missing(true).max_value_size(8)).unwrap();
+	let err = bc.import_datafile(&format!("{}/1.data", srcdir), |_| {});
+	assert!(matches!(err, Err(BitcaskError::ValueTooLarge { size: 9, limit: 8 })));
+	assert_eq!(bc.get(k(0)).unwrap(), None);			// None of the batch "value1000" was in
+	drop(bc);
+	test_teardown(&otherdir);
+	test_teardown(&srcdir);
 	test_teardown(&testdir);
     }
 
     #[test]
-    fn test_add_get() {
-	let testdir = "test_add_get/".to_string();
-	test_setup(&testdir);
-	let value = "b".to_string();
+    fn test_active_file_id() {
+	with_each_backend("test_active_file_id/", |open| {
+	    let bc = open();
+	    let before = bc.active_file_id();
+	    bc.put(k(1), "a").unwrap();
+	    assert_eq!(bc.active_file_id(), before);
+	    bc.rotate().unwrap();
+	    assert_eq!(bc.active_file_id(), before + 1);
+	});
+    }
+
+    #[test]
+    fn test_hints_bad_offset() {
+	let testdir = test_setup("test_hints_bad_offset/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "a").unwrap();
+	    bc.put(k(2), "b").unwrap();
+	    bc.put(k(3), "c").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// Each record is a 44 byte header plus a 4 byte key and 1 byte of value padded to 4, so after the 16 byte
+	// datafile header they're 52 bytes apart
+	let mut hints: Vec<u8> = Vec::new();
+	for (key, offset) in [(1, 16i64), (2, -52), (3, 120)] {
+	    hints.extend_from_slice(&4i32.to_le_bytes());
+	    hints.extend_from_slice(&0i32.to_le_bytes());
+	    hints.extend_from_slice(&1i32.to_le_bytes());
+	    hints.extend_from_slice(&offset.to_le_bytes());
+	    hints.extend_from_slice(&0u64.to_le_bytes());
+	    hints.extend_from_slice(&k(key));
+	}
+	std::fs::write(format!("{}/1.hints", testdir), hints).unwrap();
 	let bc = Bitcask::new(&testdir).expect("REASON");
-	bc.put(14, &value);
-	let val = bc.get(14);
-	assert_eq!(val, Some(value));
+	assert_eq!(bc.hints_rejected(), 1);
+	// Too much of that hints file was bad to trust, so the datafile was scanned instead
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"a".to_vec()));
+	assert_eq!(bc.get(k(2)).unwrap(), Some(b"b".to_vec()));
+	assert_eq!(bc.get(k(3)).unwrap(), Some(b"c".to_vec()));
 	test_teardown(&testdir);
     }
 
     #[test]
-    fn test_add_delete_get() {
-	let testdir = "test_add_delete_get/".to_string();
-	test_setup(&testdir);
-	let value = "b".to_string();
+    fn test_merge_drops_tombstones() {
+	let testdir = test_setup("test_merge_drops_tombstones/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for key in 0..100 {
+		bc.put(k(key), "abcd").unwrap();
+	    }
+	    for key in 10..100 {
+		bc.delete(k(key)).unwrap();
+	    }
+	    bc.merge().unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// Only the 10 surviving 52 byte records are left in the datafiles, none of the 90 DELETEs, plus the
+	// headers of the merged datafile and the current one
+	let total: u64 = std::fs::read_dir(&testdir).unwrap()
+	    .map(|entry| entry.unwrap())
+	    .filter(|entry| entry.file_name().to_string_lossy().ends_with(".data"))
+	    .map(|entry| entry.metadata().unwrap().len())
+	    .sum();
+	assert_eq!(total, 10 * 52 + 2 * 16);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.list_keys().len(), 10);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+	with_each_backend("test_concurrent_writers/", |open| {
+	    let bc = open();
+	    std::thread::scope(|scope| {
+		for thread in 0..8 {
+		    let bc = &bc;
+		    scope.spawn(move || {
+			for i in 0..50 {
+			    let key = thread * 1000 + i;
+			    bc.put(k(key), format!("first{}", key)).unwrap();
+			    bc.put(k(key), format!("second{}", key)).unwrap();
+			    if i % 5 == 0 {
+				bc.delete(k(key)).unwrap();
+			    }
+			}
+		    });
+		}
+	    });
+	    for thread in 0..8 {
+		for i in 0..50 {
+		    let key = thread * 1000 + i;
+		    let expected = if i % 5 == 0 { None } else { Some(format!("second{}", key).into_bytes()) };
+		    assert_eq!(bc.get(k(key)).unwrap(), expected);
+		}
+	    }
+	});
+    }
+
+    #[test]
+    fn test_concurrent_rotate() {
+	let testdir = test_setup("test_concurrent_rotate/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let first = bc.active_file_id();
+	let rotations: usize = std::thread::scope(|scope| {
+	    let workers: Vec<_> = (0..8).map(|thread| {
+		let bc = &bc;
+		scope.spawn(move || {
+		    let mut rotated = 0;
+		    for i in 0..25 {
+			bc.put(k(thread * 1000 + i), "abcd").unwrap();
+			if bc.rotate().unwrap() {
+			    rotated += 1;
+			}
+		    }
+		    rotated
+		})
+	    }).collect();
+	    workers.into_iter().map(|worker| worker.join().unwrap()).sum()
+	});
+	// Every rotation that claims to have happened took exactly one new ID, none were reused or skipped
+	assert_eq!(bc.active_file_id(), first + rotations as i32);
+	let mut ids: Vec<i32> = std::fs::read_dir(&testdir).unwrap()
+	    .filter_map(|entry| entry.unwrap().file_name().to_string_lossy().strip_suffix(".data")?.parse().ok())
+	    .collect();
+	ids.sort();
+	assert_eq!(ids, (first..=bc.active_file_id()).collect::<Vec<i32>>());
+	for thread in 0..8 {
+	    for i in 0..25 {
+		assert_eq!(bc.get(k(thread * 1000 + i)).unwrap(), Some(b"abcd".to_vec()));
+	    }
+	}
+	drop(bc);						// Its pool may still be writing hints
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_compaction_debt() {
+	with_each_backend("test_compaction_debt/", |open| {
+	    let debt = {
+		let bc = open();
+		assert_eq!(bc.compaction_debt(), 0.0);
+		let mut last = 0.0;
+		for round in 0..50 {
+		    for key in 0..10 {
+			bc.put(k(key), format!("{}-{}", key, round)).unwrap();
+		    }
+		    let debt = bc.compaction_debt();
+		    assert!(debt >= last);
+		    last = debt;
+		}
+		bc.delete(k(0)).unwrap();
+		assert!(bc.compaction_debt() > 0.95);
+		bc.shutdown().unwrap();
+		bc.compaction_debt()
+	    };
+	    // Recounting from the keymap on open has to agree with the running count
+	    let bc = open();
+	    assert_eq!(bc.compaction_debt(), debt);
+	    bc.merge().unwrap();
+	    assert!(bc.compaction_debt() < 0.01);
+	});
+    }
+
+    #[test]
+    fn test_get_into() {
+	with_each_backend("test_get_into/", |open| {
+	    let bc = open();
+	    for key in 0..64 {
+		bc.put(k(key), "x".repeat(key as usize * 64)).unwrap();
+	    }
+	    bc.put(k(64), "y".repeat(4096)).unwrap();
+	    let mut buf: Vec<u8> = Vec::new();
+	    assert_eq!(bc.get_into(k(64), &mut buf).unwrap(), Some(4096));
+	    assert_eq!(buf, "y".repeat(4096).as_bytes());
+	    let capacity = buf.capacity();
+	    for _ in 0..10 {
+		for key in 0..64 {
+		    assert_eq!(bc.get_into(k(key), &mut buf).unwrap(), Some(key as usize * 64));
+		    assert_eq!(buf, "x".repeat(key as usize * 64).as_bytes());
+		}
+	    }
+	    assert_eq!(bc.get_into(k(1000), &mut buf).unwrap(), None);
+	    assert!(buf.is_empty());
+	    // Having fit the largest value once, the buffer never had to grow again
+	    assert_eq!(buf.capacity(), capacity);
+	});
+    }
+
+    #[test]
+    fn test_open_fresh_skips_recovery() {
+	let storage = CountingStorage::new();
+	{
+	    let bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+	    assert_eq!(storage.reads.load(Ordering::SeqCst), 0);
+	    bc.put(k(1), "a").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// Whereas one with something in it does have to be read back
+	let bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+	assert!(storage.reads.load(Ordering::SeqCst) > 0);
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn test_shared_sync_coordinator() {
+	// Two stores, each with four writers, and the number of fsyncs it took to make all their puts durable
+	let run = |coordinator: Option<Arc<SyncCoordinator>>| {
+	    let storages = [CountingStorage::new(), CountingStorage::new()];
+	    let casks: Vec<Box<Bitcask>> = storages.iter().map(|storage| {
+		let mut bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+		if let Some(coordinator) = &coordinator {
+		    bc.set_sync_coordinator(coordinator.clone());
+		}
+		bc
+	    }).collect();
+	    let opened: usize = storages.iter().map(|storage| storage.syncs.load(Ordering::SeqCst)).sum();	// Writing down the IDs
+	    std::thread::scope(|scope| {
+		for bc in &casks {
+		    for thread in 0..4 {
+			scope.spawn(move || {
+			    for i in 0..25 {
+				bc.put(k(thread * 100 + i), "abcd").unwrap();
+			    }
+			});
+		    }
+		}
+	    });
+	    for bc in &casks {
+		assert_eq!(bc.list_keys().len(), 100);
+	    }
+	    storages.iter().map(|storage| storage.syncs.load(Ordering::SeqCst)).sum::<usize>() - opened
+	};
+	let independent = run(None);
+	let shared = run(Some(Arc::new(SyncCoordinator::new(Duration::from_millis(2)))));
+	assert!(independent <= 200);				// Group commit may have saved a few already
+	assert!(shared < independent);
+    }
+
+    #[test]
+    fn test_validate_keymap() {
+	with_each_backend("test_validate_keymap/", |open| {
+	    let bc = open();
+	    for round in 0..3 {
+		for key in 0..50 {
+		    bc.put(k(key), format!("{}-{}", key, round)).unwrap();
+		}
+		bc.rotate().unwrap();
+	    }
+	    for key in 0..10 {
+		bc.delete(k(key)).unwrap();
+	    }
+	    assert_eq!(bc.validate_keymap().unwrap(), Vec::<Vec<u8>>::new());
+	    bc.merge().unwrap();
+	    assert_eq!(bc.validate_keymap().unwrap(), Vec::<Vec<u8>>::new());
+	    bc.merge_with_filter(|key| key[0] % 2 == 0).unwrap();
+	    assert_eq!(bc.validate_keymap().unwrap(), Vec::<Vec<u8>>::new());
+	});
+    }
+
+    #[test]
+    fn test_max_records_per_file() {
+	let testdir = test_setup("test_max_records_per_file/");
+	{
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.set_max_records_per_file(Some(10));
+	    std::thread::scope(|scope| {
+		for thread in 0..4 {
+		    let bc = &bc;
+		    scope.spawn(move || {
+			for i in 0..25 {
+			    bc.put(k(thread * 100 + i), "abcd").unwrap();
+			}
+		    });
+		}
+	    });
+	    bc.shutdown().unwrap();
+	}
+	// Each PUT is 52 bytes on disk, so 100 of them fill exactly 10 datafiles, each with a 16 byte header
+	let sizes: Vec<u64> = std::fs::read_dir(&testdir).unwrap()
+	    .map(|entry| entry.unwrap())
+	    .filter(|entry| entry.file_name().to_string_lossy().ends_with(".data"))
+	    .map(|entry| entry.metadata().unwrap().len())
+	    .filter(|len| *len > 0)
+	    .collect();
+	assert_eq!(sizes, vec![10 * 52 + 16; 10]);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.list_keys().len(), 100);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_change_event_location() {
+	with_each_backend("test_change_event_location/", |open| {
+	    let bc = open();
+	    let events = bc.subscribe();
+	    bc.put(k(1), "a").unwrap();
+	    let put1 = bc.location(k(1)).unwrap();
+	    bc.put(k(2), "b").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(1), "c").unwrap();
+	    let put2 = bc.location(k(1)).unwrap();
+	    bc.delete(k(2)).unwrap();
+	    let events: Vec<_> = events.try_iter().collect();
+	    assert_eq!(events.len(), 4);
+	    assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<u64>>(), vec![1, 2, 3, 4]);
+	    assert_eq!((events[0].fileid, events[0].offset), put1);
+	    assert_eq!((events[2].fileid, events[2].offset), put2);
+	    assert_ne!(put1, put2);
+	    assert_eq!((&events[2].key[..], events[2].op, events[2].value.as_deref()), (&k(1)[..], ChangeOp::Put, Some(&b"c"[..])));
+	    assert_eq!((&events[3].key[..], events[3].op, events[3].value.as_deref()), (&k(2)[..], ChangeOp::Delete, None));
+	    assert_eq!(events[3].fileid, put2.0);
+	    assert_eq!(bc.location(k(2)), None);
+	});
+    }
+
+    #[test]
+    fn test_pool_saturation() {
+	for policy in [SaturationPolicy::Inline, SaturationPolicy::Defer] {
+	    let pool = ThreadPool::new(1, 2, policy);
+	    let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+	    let ran = Arc::new(AtomicUsize::new(0));
+	    // Tie up the only thread, and wait until it has taken that job off the queue
+	    assert!(matches!(pool.submit(move || { blocked.recv().unwrap(); }), Submitted::Queued));
+	    while pool.queue_depth() > 0 {
+		std::thread::yield_now();
+	    }
+	    let mut outcomes = (0, 0, 0);
+	    let mut deferred = Vec::new();
+	    for _ in 0..10 {
+		let ran = ran.clone();
+		match pool.submit(move || { ran.fetch_add(1, Ordering::SeqCst); }) {
+		    Submitted::Queued => outcomes.0 += 1,
+		    Submitted::RanInline => outcomes.1 += 1,
+		    Submitted::Deferred(job) => {
+			outcomes.2 += 1;
+			deferred.push(job);
+		    },
+		}
+		assert!(pool.queue_depth() <= 2);
+	    }
+	    match policy {
+		SaturationPolicy::Inline => {
+		    assert_eq!(outcomes, (2, 8, 0));
+		    assert_eq!(ran.load(Ordering::SeqCst), 8);
+		},
+		SaturationPolicy::Defer => {
+		    assert_eq!(outcomes, (2, 0, 8));
+		    assert_eq!(ran.load(Ordering::SeqCst), 0);
+		},
+		SaturationPolicy::Block => unreachable!(),	// It would wait for ever, see below
+	    }
+	    unblock.send(()).unwrap();
+	    pool.wait();					// Until the queue has drained
+	    assert_eq!(pool.queue_depth(), 0);
+	    assert_eq!(ran.load(Ordering::SeqCst), if policy == SaturationPolicy::Inline { 10 } else { 2 });
+	    drop(pool);
+	    for job in deferred {
+		job();
+	    }
+	    assert_eq!(ran.load(Ordering::SeqCst), 10);
+	}
+	// Blocking, the submitter waits for the full queue to make room, and nothing runs on its thread
+	let pool = Arc::new(ThreadPool::new(1, 1, SaturationPolicy::Block));
+	let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+	assert!(matches!(pool.submit(move || { blocked.recv().unwrap(); }), Submitted::Queued));
+	while pool.queue_depth() > 0 {
+	    std::thread::yield_now();
+	}
+	assert!(matches!(pool.submit(|| {}), Submitted::Queued));
+	let (submitted, waiting) = std::sync::mpsc::channel::<std::thread::ThreadId>();
+	let submitter = {
+	    let pool = pool.clone();
+	    std::thread::spawn(move || {
+		assert!(matches!(pool.submit(move || { submitted.send(std::thread::current().id()).unwrap(); }), Submitted::Queued));
+		std::thread::current().id()
+	    })
+	};
+	assert!(waiting.recv_timeout(Duration::from_millis(50)).is_err());	// Neither queued nor run yet
+	unblock.send(()).unwrap();
+	assert_ne!(waiting.recv().unwrap(), submitter.join().unwrap());
+	pool.wait();
+	assert_eq!(pool.queue_depth(), 0);
+    }
+
+    #[test]
+    fn test_check_filesystem() {
+	struct FakeProbe(&'static str);
+	impl FilesystemProbe for FakeProbe {
+	    fn filesystem_type(&self, _dirpath: &str) -> Option<String> {
+		Some(self.0.to_string())
+	    }
+	}
+	let testdir = test_setup("test_check_filesystem/");
+	let storage = FileStorage::new(&testdir);
+	assert!(storage.check_filesystem(&FakeProbe("ext4"), true).unwrap());
+	assert!(!storage.check_filesystem(&FakeProbe("nfs"), false).unwrap());
+	let err = storage.check_filesystem(&FakeProbe("nfs"), true).unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+	assert!(StatfsProbe.filesystem_type(&testdir).is_some() || cfg!(not(target_os = "linux")));
+	let mut options = BitcaskOptions::new(&testdir);
+	assert!(Bitcask::open_with_probe(&options, &FakeProbe("nfs")).is_ok());	// Just a warning
+	options.strict_filesystem(true);
+	assert!(matches!(Bitcask::open_with_probe(&options, &FakeProbe("nfs")), Err(BitcaskError::UnsupportedFilesystem { fstype, .. }) if fstype == "nfs"));
+	assert!(Bitcask::open_with_probe(&options, &FakeProbe("ext4")).is_ok());
+	options.read_only(true);
+	assert!(Bitcask::open_with_probe(&options, &FakeProbe("nfs")).is_ok());	// Readers only warn
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_drain_changes() {
+	with_each_backend("test_drain_changes/", |open| {
+	    let bc = open();
+	    for key in 0..5 {
+		bc.put(k(key), "a").unwrap();
+	    }
+	    bc.delete(k(2)).unwrap();
+	    let drained = bc.drain_changes();
+	    assert_eq!(drained.iter().map(|(seq, _)| *seq).collect::<Vec<u64>>(), vec![1, 2, 3, 4, 5, 6]);
+	    assert_eq!(drained.iter().map(|(_, e)| e.key.clone()).collect::<Vec<Vec<u8>>>(),
+		       [0, 1, 2, 3, 4, 2].map(|key| k(key).to_vec()));
+	    assert_eq!(drained[5].1.op, ChangeOp::Delete);
+	    assert!(bc.drain_changes().is_empty());
+	    assert_eq!(bc.changes_dropped(), 0);
+	});
+	// The ring holds 1024 events, anything beyond that pushes out the oldest
+	let bc = Bitcask::with_storage(Box::new(MemoryStorage::new())).expect("REASON");
+	for key in 0..1034 {
+	    bc.put(k(key), "a").unwrap();
+	}
+	let drained = bc.drain_changes();
+	assert_eq!(drained.len(), 1024);
+	assert_eq!(drained[0].1.key, k(10));
+	assert_eq!(bc.changes_dropped(), 10);
+	assert_eq!(bc.stats().unwrap().changes_dropped, 10);
+	assert_eq!(bc.stats().unwrap().pool_queue_depth, 0);
+    }
+
+    #[test]
+    fn test_misnamed_hintsfile() {
+	let testdir = test_setup("test_misnamed_hintsfile/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "a").unwrap();
+	    bc.put(k(2), "b").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// What the old hints code wrote for 1.data, under the name of the next datafile
+	let mut hints: Vec<u8> = Vec::new();
+	for (key, offset) in [(1i32, 0i64), (2, 28)] {
+	    hints.extend_from_slice(&key.to_le_bytes());
+	    hints.extend_from_slice(&0i32.to_le_bytes());
+	    hints.extend_from_slice(&1i32.to_le_bytes());
+	    hints.extend_from_slice(&offset.to_le_bytes());
+	}
+	std::fs::write(format!("{}/2.data", testdir), &hints).unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"a".to_vec()));
+	assert_eq!(bc.get(k(2)).unwrap(), Some(b"b".to_vec()));
+	assert_eq!(bc.active_file_id(), 3);
+	assert!(!std::path::Path::new(&format!("{}/2.data", testdir)).exists());
+	assert_eq!(std::fs::read(format!("{}/2.data.quarantine", testdir)).unwrap(), hints);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_crc_high_bit() {
+	let testdir = test_setup("test_crc_high_bit/");
+	// The timestamps make the CRCs unpredictable, but about half of them have the high bit set
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for key in 0..100 {
+		bc.put(k(key), "a").unwrap();
+	    }
+	    bc.shutdown().unwrap();
+	}
+	// Each record is 52 bytes after the 16 byte datafile header, the crc covers everything after itself but the padding
+	let bytes = std::fs::read(format!("{}/1.data", testdir)).unwrap();
+	let high: Vec<&[u8]> = bytes[16..].chunks(52)
+	    .filter(|rec| u32::from_le_bytes(rec[0..4].try_into().unwrap()) & 0x8000_0000 != 0)
+	    .collect();
+	assert!(!high.is_empty());
+	assert_eq!(u32::from_le_bytes(high[0][0..4].try_into().unwrap()), crc32fast::hash(&high[0][4..49]));
+	// Replaying the datafile checks every CRC
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for key in 0..100 {
+	    assert_eq!(bc.get(k(key)).unwrap(), Some(b"a".to_vec()));
+	}
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_record_cipher_nonces() {
+	let key = [7u8; 32];
+	let counter = CounterNonceSource::new(*b"test");
+	assert_eq!(counter.next_nonce(), *b"test\0\0\0\0\0\0\0\0");
+	assert_eq!(counter.next_nonce(), *b"test\x01\0\0\0\0\0\0\0");
+	let cipher = RecordCipher::new(&key, Box::new(CounterNonceSource::new(*b"test")));
+	let first = cipher.seal(b"hello", b"key1").unwrap();
+	let second = cipher.seal(b"hello", b"key1").unwrap();
+	assert_eq!(first.len(), 5 + RecordCipher::OVERHEAD);
+	assert_ne!(first, second);				// Fresh nonce, fresh ciphertext
+	assert_eq!(cipher.open(&first, b"key1").unwrap(), b"hello");
+	assert_eq!(cipher.open(&second, b"key1").unwrap(), b"hello");
+	// The same deterministic source reproduces the same ciphertext
+	let again = RecordCipher::new(&key, Box::new(CounterNonceSource::new(*b"test")));
+	assert_eq!(again.seal(b"hello", b"key1").unwrap(), first);
+	// Tampering, or moving the payload to another record, fails authentication
+	let mut tampered = first.clone();
+	tampered[14] ^= 1;
+	assert!(cipher.open(&tampered, b"key1").is_err());
+	assert!(cipher.open(&first, b"key2").is_err());
+	let random = RandomNonceSource::new();
+	assert_ne!(random.next_nonce(), random.next_nonce());
+    }
+
+    #[test]
+    fn test_exists_many() {
+	with_each_backend("test_exists_many/", |open| {
+	    let bc = open();
+	    for key in [1, 3, 5, 7] {
+		bc.put(k(key), "a").unwrap();
+	    }
+	    bc.delete(k(5)).unwrap();
+	    assert_eq!(bc.exists_many(&[0, 1, 3, 5, 7, 1, 9].map(k)), vec![false, true, true, false, true, true, false]);
+	    assert!(bc.exists_many::<[u8; 4]>(&[]).is_empty());
+	});
+    }
+
+    #[test]
+    fn test_read_buffer_pool() {
+	let mut bc = Bitcask::with_storage(Box::new(MemoryStorage::new())).expect("REASON");
+	for key in 0..100 {
+	    bc.put(k(key), format!("value{}", key).repeat(20)).unwrap();
+	}
+	let expected: Vec<usize> = (0..100).map(|key| format!("value{}", key).len() * 20).collect();
+	let allocations_per_get = |bc: &Bitcask| {
+	    let before = ALLOCATIONS.with(|n| n.get());
+	    for _ in 0..10 {
+		for key in 0..100 {
+		    assert_eq!(bc.get(k(key)).unwrap().map(|value| value.len()), Some(expected[key as usize]));
+		}
+	    }
+	    (ALLOCATIONS.with(|n| n.get()) - before) as f64 / 1000.0
+	};
+	let unpooled = allocations_per_get(&bc);
+	bc.set_read_buffer_pool(true);
+	let pooled = allocations_per_get(&bc);
+	// Once the pool is warm only the caller's String is left
+	assert!(pooled < 1.01, "{} allocations per pooled get", pooled);
+	assert!(unpooled >= 2.0, "{} allocations per unpooled get", unpooled);
+    }
+
+    #[test]
+    fn test_truncate_to_sequence() {
+	with_each_backend("test_truncate_to_sequence/", |open| {
+	    {
+		let mut bc = open();
+		bc.set_max_records_per_file(Some(30));		// Spread the cutoff over a few datafiles
+		for key in 0..100 {
+		    bc.put(k(key), format!("value{}", key)).unwrap();
+		}
+		bc.truncate_to_sequence(50).unwrap();
+		assert_eq!(bc.list_keys().len(), 50);
+		bc.put(k(1000), "after").unwrap();
+		assert_eq!(bc.drain_changes().last().map(|(seq, _)| *seq), Some(51));
+		bc.shutdown().unwrap();
+	    }
+	    let bc = open();
+	    for key in 0..100 {
+		let expected = if key < 50 { Some(format!("value{}", key).into_bytes()) } else { None };
+		assert_eq!(bc.get(k(key)).unwrap(), expected);
+	    }
+	    assert_eq!(bc.get(k(1000)).unwrap(), Some(b"after".to_vec()));
+	    // Once merged, the history before the merge is gone
+	    bc.put(k(1001), "later").unwrap();
+	    bc.merge().unwrap();
+	    assert_eq!(bc.truncate_to_sequence(51).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	    bc.truncate_to_sequence(52).unwrap();
+	    assert_eq!(bc.get(k(1001)).unwrap(), Some(b"later".to_vec()));
+	});
+    }
+
+    #[test]
+    fn test_shutdown_twice() {
+	with_each_backend("test_shutdown_twice/", |open| {
+	    let bc = open();
+	    bc.put(k(1), "a").unwrap();
+	    assert!(bc.shutdown().unwrap());
+	    assert!(bc.shutdown().unwrap());
+	    drop(bc);
+	    assert_eq!(open().get(k(1)).unwrap(), Some(b"a".to_vec()));
+	});
+    }
+
+    #[test]
+    fn test_store_id() {
+	with_each_backend("test_store_id/", |open| {
+	    let bc = open();
+	    let id = bc.id();
+	    assert!(!id.is_nil());
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    assert_eq!(open().id(), id);
+	});
+	assert_ne!(Bitcask::with_storage(Box::new(MemoryStorage::new())).unwrap().id(),
+		   Bitcask::with_storage(Box::new(MemoryStorage::new())).unwrap().id());
+    }
+
+    #[test]
+    fn test_mixed_alignment() {
+	let testdir = test_setup("test_mixed_alignment/");
+	for (alignment, base) in [(4, 0), (512, 100)] {
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.set_record_alignment(alignment).unwrap();
+	    for key in base..base + 10 {
+		bc.put(k(key), format!("value{}", key)).unwrap();
+	    }
+	    bc.shutdown().unwrap();
+	}
+	// The second run's datafile is padded to 512, header and records alike
+	let size = std::fs::metadata(format!("{}/2.data", testdir)).unwrap().len();
+	assert_eq!(size, 512 + 10 * 512);
+	let check = |bc: &Bitcask| {
+	    for key in (0..10).chain(100..110) {
+		assert_eq!(bc.get(k(key)).unwrap(), Some(format!("value{}", key).into_bytes()));
+	    }
+	};
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	check(&bc);
+	assert!(bc.validate_keymap().unwrap().is_empty());
+	bc.merge().unwrap();
+	check(&bc);
+	drop(bc);
+	check(&Bitcask::new(&testdir).expect("REASON"));
+	assert!(Bitcask::new(&testdir).unwrap().set_record_alignment(100).is_err());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_max_datafile_age() {
+	with_each_backend("test_max_datafile_age/", |open| {
+	    let archived = Arc::new(Mutex::new(Vec::<(Vec<u8>, Vec<u8>)>::new()));
+	    let mut bc = open();
+	    let sink = archived.clone();
+	    bc.set_max_datafile_age(Duration::from_millis(50), move |_, kvs| {
+		sink.lock().unwrap().extend_from_slice(kvs);
+		Ok(true)
+	    });
+	    for key in 0..10 {
+		bc.put(k(key), "old").unwrap();
+	    }
+	    bc.rotate().unwrap();
+	    assert_eq!(bc.archive_aged().unwrap(), 0);		// Not old enough yet
+	    std::thread::sleep(Duration::from_millis(100));
+	    bc.put(k(5), "new").unwrap();
+	    bc.put(k(10), "new").unwrap();
+	    assert_eq!(bc.archive_aged().unwrap(), 1);
+	    let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..10).filter(|key| *key != 5).map(|key| (k(key).to_vec(), b"old".to_vec())).collect();
+	    assert_eq!(*archived.lock().unwrap(), expected);
+	    let check = |bc: &Bitcask| {
+		assert_eq!(bc.list_keys().len(), 2);
+		assert_eq!(bc.get(k(0)).unwrap(), None);
+		assert_eq!(bc.get(k(5)).unwrap(), Some(b"new".to_vec()));
+		assert_eq!(bc.get(k(10)).unwrap(), Some(b"new".to_vec()));
+	    };
+	    check(&bc);
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    check(&open());
+	});
+    }
+
+    #[test]
+    fn test_compare_and_swap_generation() {
+	with_each_backend("test_compare_and_swap_generation/", |open| {
+	    let bc = open();
+	    assert!(bc.compare_and_swap_generation(k(1), 0, "first").unwrap());	// 0 means it mustn't exist yet
+	    assert!(!bc.compare_and_swap_generation(k(1), 0, "again").unwrap());
+	    let (value, generation) = bc.get_with_metadata(k(1)).unwrap().unwrap();
+	    assert_eq!(value, b"first");
+	    assert!(bc.compare_and_swap_generation(k(1), generation, "second").unwrap());
+	    assert!(!bc.compare_and_swap_generation(k(1), generation, "stale").unwrap());
+	    let (value, newer) = bc.get_with_metadata(k(1)).unwrap().unwrap();
+	    assert_eq!(value, b"second");
+	    assert!(newer > generation);
+	    // Racing writers with the same expected generation, exactly one of them wins
+	    let wins = AtomicUsize::new(0);
+	    std::thread::scope(|scope| {
+		for thread in 0..8 {
+		    let (bc, wins) = (&bc, &wins);
+		    scope.spawn(move || {
+			if bc.compare_and_swap_generation(k(1), newer, format!("thread{}", thread)).unwrap() {
+			    wins.fetch_add(1, Ordering::SeqCst);
+			}
+		    });
+		}
+	    });
+	    assert_eq!(wins.load(Ordering::SeqCst), 1);
+	    // The generation is on disk, so it's the same after a reopen
+	    let latest = bc.get_with_metadata(k(1)).unwrap().unwrap();
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    let bc = open();
+	    assert_eq!(bc.get_with_metadata(k(1)).unwrap(), Some(latest.clone()));
+	    assert!(bc.compare_and_swap_generation(k(1), latest.1, "reopened").unwrap());
+	    assert_eq!(bc.get_with_metadata(k(2)).unwrap(), None);
+	});
+    }
+
+    #[test]
+    fn test_delete_record_size() {
+	let testdir = test_setup("test_delete_record_size/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "x".repeat(1000)).unwrap();
+	let before = std::fs::metadata(format!("{}/1.data", testdir)).unwrap().len();
+	bc.delete(k(1)).unwrap();
+	bc.shutdown().unwrap();
+	let after = std::fs::metadata(format!("{}/1.data", testdir)).unwrap().len();
+	// Just the record header and the key, padded to the alignment, no value bytes at all
+	let desc = Bitcask::format_descriptor();
+	assert_eq!((after - before) as usize, (desc.record_header_size + 4).div_ceil(desc.alignment) * desc.alignment);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(1)).unwrap(), None);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_idle_merge() {
+	let bc: Arc<Bitcask> = Arc::from(Bitcask::with_storage(Box::new(MemoryStorage::new())).expect("REASON"));
+	let wait_for = |done: &dyn Fn() -> bool| {
+	    for _ in 0..2000 {
+		if done() {
+		    return true;
+		}
+		std::thread::sleep(Duration::from_millis(1));
+	    }
+	    false
+	};
+	// Lots of garbage: a merge of this is quick
+	for round in 0..4 {
+	    for key in 0..100 {
+		bc.put(k(key), format!("{}", round)).unwrap();
+	    }
+	}
+	assert!(bc.needs_merge());
+	bc.start_idle_merge(Duration::from_millis(20));
+	assert!(wait_for(&|| !bc.needs_merge()));
+	assert_eq!(bc.idle_merge_counts(), (1, 0));
+	// Lots of live data as well: a merge of this takes long enough to interrupt
+	for key in 0..20000 {
+	    bc.put(k(key), "a").unwrap();
+	}
+	for key in 0..20000 {
+	    bc.put(k(key), "b").unwrap();
+	}
+	let current = bc.active_file_id();
+	assert!(wait_for(&|| bc.idle_merge_counts().0 == 2));
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"b".to_vec()));	// Traffic resumes, the merge gives way
+	assert!(wait_for(&|| bc.idle_merge_counts().1 == 1));
+	assert!(bc.needs_merge());
+	assert!(bc.validate_keymap().unwrap().is_empty());
+	// Left alone again it gets to finish, taking up the IDs of the one that yielded, whose datafile was left empty
+	assert!(wait_for(&|| !bc.needs_merge()));
+	assert_eq!(bc.active_file_id(), current + 2);
+	assert_eq!(bc.stats().unwrap().datafiles, 2);
+	bc.shutdown().unwrap();
+	assert_eq!(bc.get(k(19999)).unwrap(), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn test_large_values() {
+	with_each_backend("test_large_values/", |open| {
+	    let values: Vec<String> = [0, 1, 4096, 4097, 100_000, 1 << 20].iter()
+		.map(|len| (0..*len).map(|i| char::from(b'a' + (i % 26) as u8)).collect())
+		.collect();
+	    {
+		let bc = open();
+		for (key, value) in values.iter().enumerate() {
+		    bc.put(k(key as i32), value).unwrap();
+		}
+		bc.put(k(0), "overwritten").unwrap();
+		bc.merge().unwrap();
+		bc.shutdown().unwrap();
+	    }
+	    let bc = open();				// Replays the merged datafile
+	    assert_eq!(bc.get(k(0)).unwrap(), Some(b"overwritten".to_vec()));
+	    let mut buf = Vec::new();
+	    for (key, value) in values.iter().enumerate().skip(1) {
+		assert_eq!(bc.get(k(key as i32)).unwrap().as_deref(), Some(value.as_bytes()));
+		assert_eq!(bc.get_into(k(key as i32), &mut buf).unwrap(), Some(value.len()));
+		assert_eq!(buf, value.as_bytes());
+	    }
+	});
+    }
+
+    #[test]
+    fn test_byte_keys() {
+	with_each_backend("test_byte_keys/", |open| {
+	    let long = vec![0xffu8; 10_000];
+	    let keys: Vec<&[u8]> = vec![b"", b"a", b"ab", b"a\0b", &[0, 0, 0, 0], &[0, 0, 0, 0, 0], &long];
+	    {
+		let bc = open();
+		for (i, key) in keys.iter().enumerate() {
+		    bc.put(key, format!("value{}", i)).unwrap();
+		}
+		bc.put("user:42", "Ann").unwrap();
+		bc.delete(b"ab").unwrap();
+		bc.rotate().unwrap();
+		bc.merge().unwrap();
+		assert_eq!(bc.exists_many(&["a", "ab", "user:42", "user:4"]), vec![true, false, true, false]);
+		bc.shutdown().unwrap();
+	    }
+	    let bc = open();
+	    for (i, key) in keys.iter().enumerate() {
+		let expected = if *key == b"ab" { None } else { Some(format!("value{}", i).into_bytes()) };
+		assert_eq!(bc.get(key).unwrap(), expected);
+	    }
+	    assert_eq!(bc.get("user:42").unwrap(), Some(b"Ann".to_vec()));
+	    assert_eq!(bc.list_keys().len(), keys.len());
+	    assert!(bc.validate_keymap().unwrap().is_empty());
+	});
+    }
+
+    #[test]
+    fn test_background_merge() {
+	let storage = MemoryStorage::new();
+	let bc: Arc<Bitcask> = Arc::from(Bitcask::with_storage(Box::new(storage.clone())).expect("REASON"));
+	bc.start_background_merge(Duration::from_millis(5));
+	// Never idle, the merges happen anyway
+	let mut round = 0;
+	loop {
+	    for key in 0..10 {
+		bc.put(k(key), format!("{}", round)).unwrap();
+	    }
+	    round += 1;
+	    let merged = storage.list().unwrap().iter().any(|name| name.ends_with(".hints"));
+	    if (merged && !bc.needs_merge()) || round == 10000 {
+		break;
+	    }
+	}
+	assert_eq!(bc.idle_merge_counts(), (0, 0));		// Not that kind of merge
+	bc.shutdown().unwrap();
+	// The datafiles merged away took their hints files with them
+	let names = storage.list().unwrap();
+	for name in names.iter().filter(|name| name.ends_with(".hints")) {
+	    assert!(names.contains(&name.replace(".hints", ".data")));
+	}
+	let bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+	assert_eq!(bc.hints_rejected(), 0);
+	for key in 0..10 {
+	    assert_eq!(bc.get(k(key)).unwrap(), Some(format!("{}", round - 1).into_bytes()));
+	}
+	assert!(bc.validate_keymap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_hints_generation() {
+	let testdir = test_setup("test_hints_generation/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for key in 0..10 {
+		bc.put(k(key), "old").unwrap();
+	    }
+	    bc.rotate().unwrap();
+	    for key in 0..5 {
+		bc.put(k(key), "new").unwrap();
+	    }
+	    bc.delete(k(9)).unwrap();
+	    bc.shutdown().unwrap();
+	}
+	let hints = |id: i32| std::fs::read(format!("{}/{}.hints", testdir, id)).unwrap();
+	assert_eq!(hints(1).len(), 8 + 10 * (36 + 4));		// Every record in 1.data survives in it
+	assert_eq!(hints(2).len(), 8 + 6 * (36 + 4));
+	// Open from the hints alone: a scan of 1.data would trip over this
+	let mut data = std::fs::read(format!("{}/1.data", testdir)).unwrap();
+	data[16 + 48] ^= 0xff;					// The value of the first record, since overwritten
+	std::fs::write(format!("{}/1.data", testdir), &data).unwrap();
+	let check = || {
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for key in 0..9 {
+		assert_eq!(bc.get(k(key)).unwrap(), Some(if key < 5 { "new" } else { "old" }.as_bytes().to_vec()));
+	    }
+	    assert_eq!(bc.get(k(9)).unwrap(), None);
+	    bc.shutdown().unwrap();
+	};
+	check();
+	// Hints that were cut short don't cover the end of their datafile, so it's scanned, and they're redone
+	let full = hints(2);
+	std::fs::write(format!("{}/2.hints", testdir), &full[..full.len() - 40]).unwrap();
+	check();
+	assert_eq!(hints(2), full);
+	// Hints don't carry sequence numbers, the numbering still picks up where it left off
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(0), "newer").unwrap();
+	assert_eq!(bc.drain_changes()[0].0, 17);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_write_batch() {
+	let testdir = test_setup("test_write_batch/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for key in 1..4 {
+		bc.put(k(key), "a").unwrap();
+	    }
+	    let mut batch = WriteBatch::new();
+	    batch.put(k(1), "b").put(k(4), "x").delete(k(2)).put(k(4), "y");
+	    assert_eq!(batch.len(), 4);
+	    bc.write(&batch).unwrap();
+	    assert_eq!(bc.get(k(1)).unwrap(), Some(b"b".to_vec()));
+	    assert_eq!(bc.get(k(2)).unwrap(), None);
+	    assert_eq!(bc.get(k(3)).unwrap(), Some(b"a".to_vec()));
+	    assert_eq!(bc.get(k(4)).unwrap(), Some(b"y".to_vec()));
+	    let seqs: Vec<u64> = bc.drain_changes().iter().map(|(seq, _)| *seq).collect();
+	    assert_eq!(seqs, vec![1, 2, 3, 4, 4, 4, 4]);	// The batch is one step
+	    bc.write(WriteBatch::new().put(k(3), "lost").delete(k(1))).unwrap();
+	    // Crash without a shutdown, and before the COMMIT reached the disk
+	}
+	let name = format!("{}/1.data", testdir);
+	let data = std::fs::read(&name).unwrap();
+	std::fs::write(&name, &data[..data.len() - 44]).unwrap();	// A COMMIT is just a header
+	for _ in 0..2 {							// The second time it's from hints
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(bc.get(k(1)).unwrap(), Some(b"b".to_vec()));
+	    assert_eq!(bc.get(k(3)).unwrap(), Some(b"a".to_vec()));
+	    assert_eq!(bc.get(k(4)).unwrap(), Some(b"y".to_vec()));
+	    bc.shutdown().unwrap();
+	}
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_ttl() {
+	let testdir = test_setup("test_ttl/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put_with_ttl(k(1), "short", Duration::from_millis(50)).unwrap();
+	    bc.put(k(2), "forever").unwrap();
+	    bc.put_with_ttl(k(3), "long", Duration::from_secs(3600)).unwrap();
+	    assert_eq!(bc.get(k(1)).unwrap(), Some(b"short".to_vec()));
+	    std::thread::sleep(Duration::from_millis(100));
+	    assert_eq!(bc.get(k(1)).unwrap(), None);
+	    assert_eq!(bc.exists_many(&[k(1), k(2), k(3)]), vec![false, true, true]);
+	    assert_eq!(bc.list_keys().len(), 2);
+	    bc.shutdown().unwrap();
+	}
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");	// The expiry is in the hints too
+	    assert_eq!(bc.get(k(1)).unwrap(), None);
+	    assert_eq!(bc.get(k(3)).unwrap(), Some(b"long".to_vec()));
+	    bc.merge().unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// The merge kept the two live records, of 56 and 52 bytes, and a 48 byte DELETE in place of the expired one
+	let total: u64 = std::fs::read_dir(&testdir).unwrap()
+	    .map(|entry| entry.unwrap())
+	    .filter(|entry| entry.file_name().to_string_lossy().ends_with(".data"))
+	    .map(|entry| entry.metadata().unwrap().len())
+	    .sum();
+	assert_eq!(total, 56 + 52 + 48 + 2 * 16);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.exists_many(&[k(1), k(2), k(3)]), vec![false, true, true]);
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_resp_server() {
+	use std::io::{BufRead, BufReader, Read, Write};
+	let bc: Arc<Bitcask> = Arc::from(Bitcask::with_storage(Box::new(MemoryStorage::new())).expect("REASON"));
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let served = Arc::clone(&bc);
+	std::thread::spawn(move || serve(served, listener));
+	let stream = std::net::TcpStream::connect(address).unwrap();
+	let mut reader = BufReader::new(stream.try_clone().unwrap());
+	let mut writer = stream;
+	let mut request = |command: &[u8], expected: &str| {
+	    writer.write_all(command).unwrap();
+	    let mut reply = vec![0u8; expected.len()];
+	    reader.read_exact(&mut reply).unwrap();
+	    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+	};
+	request(b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$5\r\nhello\r\n", "+OK\r\n");
+	request(b"*2\r\n$3\r\nGET\r\n$2\r\nk1\r\n", "$5\r\nhello\r\n");
+	request(b"GET nope\r\n", "$-1\r\n");		// Inline, as typed at telnet
+	request(b"SET k2 a PX 50\r\n", "+OK\r\n");
+	request(b"SET k3 b\r\nEXPIRE k3 3600\r\nEXPIRE k4 1\r\n", "+OK\r\n:1\r\n:0\r\n");
+	request(b"*3\r\n$3\r\nSET\r\n$2\r\nk4\r\n$2\r\n\xff\x00\r\n", "+OK\r\n");	// Values needn't be text either
+	assert_eq!(bc.get("k4").unwrap(), Some(vec![0xff, 0]));
+	request(b"KEYS k[12]\r\n", "*2\r\n$2\r\nk1\r\n$2\r\nk2\r\n");
+	std::thread::sleep(Duration::from_millis(100));
+	request(b"EXISTS k1 k2 k3\r\n", ":2\r\n");
+	request(b"DEL k1 k2 k3 k4\r\n", ":3\r\n");
+	request(b"*2\r\n$4\r\nKEYS\r\n$1\r\n*\r\n", "*0\r\n");
+	request(b"GET\r\n", "-ERR wrong number of arguments for 'get' command\r\n");
+	request(b"FLUSHALL\r\n", "-ERR unknown command 'FLUSHALL'\r\n");
+	request(b"QUIT\r\n", "+OK\r\n");
+	let mut rest = String::new();
+	assert_eq!(reader.read_line(&mut rest).unwrap(), 0);		// And the server hung up
+	assert!(bc.list_keys().is_empty());
+	// A bulk string longer than we take is hung up on before any of it is read
+	let mut stream = std::net::TcpStream::connect(address).unwrap();
+	stream.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4000000000\r\n").unwrap();
+	assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+	// Only so many connections at once, any more are told so
+	let mut open: Vec<std::net::TcpStream> = Vec::new();
+	while open.len() < RESP_MAX_CONNECTIONS {
+	    let mut stream = std::net::TcpStream::connect(address).unwrap();
+	    let mut reply = [0u8; 7];
+	    match stream.write_all(b"PING\r\n").and_then(|_| stream.read_exact(&mut reply)) {
+		Ok(()) if &reply == b"+PONG\r\n" => open.push(stream),
+		_ => std::thread::sleep(Duration::from_millis(10)),	// The threads of those above may not have let go yet
+	    }
+	}
+	let mut reply = String::new();
+	std::net::TcpStream::connect(address).unwrap().read_to_string(&mut reply).unwrap();
+	assert_eq!(reply, "-ERR max number of clients reached\r\n");
+	drop(open);
+	for (pattern, text, matches) in [("*", "", true), ("a*c", "abbbc", true), ("a?c", "ac", false), ("[^a-c]x", "dx", true),
+					 ("[^a-c]x", "bx", false), ("\\*", "*", true), ("\\*", "a", false), ("[]x", "[]x", true)] {
+	    assert_eq!(glob_match(pattern.as_bytes(), text.as_bytes()), matches, "{} {}", pattern, text);
+	}
+	assert!(!glob_match("a*".repeat(30).as_bytes(), "a".repeat(29).as_bytes()));	// Exponential, backtracking to every star
+	assert!(glob_match(b"*[0-9]?x*\\*", b"key 12x and *"));
+    }
+
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_bitcask() {
+	use std::future::Future;
+	use std::task::{Context, Poll, Wake, Waker};
+	// The simplest executor there is: park the thread until the future's waker says to look again
+	struct Unpark(std::thread::Thread);
+	impl Wake for Unpark {
+	    fn wake(self: Arc<Self>) {
+		self.0.unpark();
+	    }
+	}
+	fn block_on<F: Future>(future: F) -> F::Output {
+	    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+	    let mut cx = Context::from_waker(&waker);
+	    let mut future = std::pin::pin!(future);
+	    loop {
+		match future.as_mut().poll(&mut cx) {
+		    Poll::Ready(output) => return output,
+		    Poll::Pending => std::thread::park(),
+		}
+	    }
+	}
+	let testdir = test_setup("test_async_bitcask/");
+	block_on(async {
+	    let bc = crate::AsyncBitcask::open(&testdir, 2).await.unwrap();
+	    let puts: Vec<_> = (0..10).map(|key| bc.put(k(key), "a")).collect();	// All in flight at once
+	    for put in puts {
+		put.await.unwrap();
+	    }
+	    bc.delete(k(3)).await.unwrap();
+	    bc.sync().await.unwrap();
+	    assert_eq!(bc.get(k(2)).await.unwrap(), Some(b"a".to_vec()));
+	    assert_eq!(bc.get(k(3)).await.unwrap(), None);
+	    assert_eq!(bc.cask().list_keys().len(), 9);
+	    bc.shutdown().await.unwrap();
+	});
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_compression() {
+	// Round trips, including matches that overlap what they produce and runs longer than a match can reach
+	let mut noise: Vec<u8> = Vec::new();
+	let mut x: u32 = 1;
+	for _ in 0..100_000 {
+	    x = x.wrapping_mul(1103515245).wrapping_add(12345);
+	    noise.push((x >> 16) as u8 % 4 + b'a');			// Compressible, but not trivially
+	}
+	for value in [&b""[..], b"abc", &[b'z'; 1000], b"abcabcabcabcabcabcabcabcabcab", &noise] {
+	    match lz4_pack(value) {
+		Some(packed) => {
+		    assert!(packed.len() < value.len());
+		    assert_eq!(lz4_unpack(&packed).unwrap(), value);
+		},
+		None => assert!(value.len() < 32),		// Too short to gain anything
+	    }
+	}
+	let mut packed = lz4_pack(&[b'z'; 1000]).unwrap();
+	packed[0] ^= 1;						// The length no longer agrees
+	assert!(lz4_unpack(&packed).is_err());
+
+	let testdir = test_setup("test_compression/");
+	let big = "compress me ".repeat(1000);
+	{
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), &big).unwrap();				// Written before compression was on
+	    bc.set_compression(Compression::Lz4);
+	    bc.put(k(2), &big).unwrap();
+	    bc.put(k(3), "tiny").unwrap();				// Not worth it, stored as it is
+	    bc.write(WriteBatch::new().put(k(4), &big)).unwrap();
+	    for key in [1, 2, 4] {
+		assert_eq!(bc.get(k(key)).unwrap(), Some(big.clone().into_bytes()));
+	    }
+	    let mut buf = Vec::new();
+	    assert_eq!(bc.get_into(k(2), &mut buf).unwrap(), Some(big.len()));
+	    assert_eq!(buf, big.as_bytes());
+	    bc.shutdown().unwrap();
+	}
+	// Much less than two more copies went to disk
+	assert!(std::fs::metadata(format!("{}/1.data", testdir)).unwrap().len() < big.len() as u64 + 1000);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.merge().unwrap();
+	for key in [1, 2, 4] {
+	    assert_eq!(bc.get(k(key)).unwrap(), Some(big.clone().into_bytes()));
+	}
+	assert_eq!(bc.get(k(3)).unwrap(), Some(b"tiny".to_vec()));
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_encryption() {
+	let testdir = test_setup("test_encryption/");
+	let key = [7u8; 32];
+	let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put("plainkey", "written in the clear").unwrap();	// Before encryption was turned on
+	    bc.shutdown().unwrap();
+	}
+	{
+	    let mut bc = Bitcask::new_encrypted(&testdir, EncryptionConfig::new(key)).expect("REASON");
+	    bc.set_compression(Compression::Lz4);
+	    bc.put("secretkey", "secret value ".repeat(20)).unwrap();
+	    bc.put("doomed", "gone soon").unwrap();
+	    bc.delete("doomed").unwrap();
+	    bc.write(WriteBatch::new().put("batchkey", "batch value")).unwrap();
+	    assert_eq!(bc.get("plainkey").unwrap(), Some(b"written in the clear".to_vec()));
+	    let mut buf = Vec::new();
+	    assert_eq!(bc.get_into("secretkey", &mut buf).unwrap(), Some(13 * 20));
+	    bc.shutdown().unwrap();
+	}
+	for name in ["2.data", "2.hints"] {
+	    let bytes = std::fs::read(format!("{}/{}", testdir, name)).unwrap();
+	    for needle in [&b"secretkey"[..], b"secret value", b"doomed", b"batchkey", b"batch value"] {
+		assert!(!contains(&bytes, needle), "{} has {:?} in the clear", name, needle);
+	    }
+	}
+	// Reopen from the hints, and then again by scanning the datafiles
+	for pass in 0..2 {
+	    if pass == 1 {
+		std::fs::remove_file(format!("{}/2.hints", testdir)).unwrap();
+	    }
+	    let bc = Bitcask::new_encrypted(&testdir, EncryptionConfig::new(key)).expect("REASON");
+	    assert_eq!(bc.get("secretkey").unwrap(), Some("secret value ".repeat(20).into_bytes()));
+	    assert_eq!(bc.get("batchkey").unwrap(), Some(b"batch value".to_vec()));
+	    assert_eq!(bc.get("doomed").unwrap(), None);
+	    assert_eq!(bc.hints_rejected(), 0);
+	    bc.shutdown().unwrap();
+	}
+	// Without the key, or with the wrong one, the store won't open
+	assert!(matches!(Bitcask::new(&testdir), Err(BitcaskError::InvalidFormat { reason, .. }) if reason.contains("encrypted")));
+	assert!(Bitcask::new_encrypted(&testdir, EncryptionConfig::new([8u8; 32])).is_err());
+
+	// A merge seals what was written in the clear, and a datafile that's tampered with fails authentication
+	let bc = Bitcask::new_encrypted(&testdir, EncryptionConfig::new(key)
+					.with_nonce_source(Box::new(CounterNonceSource::new([1, 2, 3, 4])))).expect("REASON");
+	bc.merge().unwrap();
+	assert_eq!(bc.get("plainkey").unwrap(), Some(b"written in the clear".to_vec()));
+	assert_eq!(bc.get("secretkey").unwrap(), Some("secret value ".repeat(20).into_bytes()));
+	bc.shutdown().unwrap();
+	let merged: Vec<String> = std::fs::read_dir(&testdir).unwrap()
+	    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+	    .filter(|name| name.ends_with(".data")).collect();
+	for name in &merged {
+	    assert!(!contains(&std::fs::read(format!("{}/{}", testdir, name)).unwrap(), b"plainkey"));
+	}
+	let newest = merged.iter().filter_map(|name| name.strip_suffix(".data")?.parse::<i32>().ok()).max().unwrap();
+	let path = format!("{}/{}.data", testdir, newest - 1);	// The merge's output, the newest is an empty current file
+	let mut bytes = std::fs::read(&path).unwrap();
+	let last = bytes.len() - 1;
+	bytes[last - 8] ^= 1;					// Inside the last record's ciphertext
+	std::fs::write(&path, &bytes).unwrap();
+	std::fs::remove_file(format!("{}/{}.hints", testdir, newest - 1)).unwrap();
+	assert!(Bitcask::new_encrypted(&testdir, EncryptionConfig::new(key)).is_err());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_iterators() {
+	let testdir = test_setup("test_iterators/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_records_per_file(Some(7));			// So the walk crosses several datafiles
+	for i in 0..30 {
+	    bc.put(k(i), format!("v{}", i)).unwrap();
+	}
+	for i in 0..30 {
+	    if i % 3 == 0 {
+		bc.delete(k(i)).unwrap();
+	    } else if i % 3 == 1 {
+		bc.put(k(i), format!("w{}", i)).unwrap();
+	    }
+	}
+	bc.put_with_ttl(k(100), "soon gone", Duration::from_millis(1)).unwrap();
+	bc.write(WriteBatch::new().put(k(101), "batched").delete(k(2))).unwrap();
+	std::thread::sleep(Duration::from_millis(5));
+	let mut expected: Vec<(Vec<u8>, Vec<u8>)> = bc.list_keys().into_iter()
+	    .map(|key| { let value = bc.get(&key).unwrap().unwrap(); (key, value) }).collect();
+	expected.sort();
+	let mut entries: Vec<(Vec<u8>, Vec<u8>)> = bc.entries().collect::<Result<_, _>>().unwrap();
+	entries.sort();
+	assert_eq!(entries, expected);
+	let mut keys: Vec<Vec<u8>> = bc.keys().collect::<Result<_, _>>().unwrap();
+	keys.sort();
+	assert_eq!(keys, expected.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>());
+	let mut values: Vec<Vec<u8>> = bc.values().collect::<Result<_, _>>().unwrap();
+	values.sort();
+	let mut expected_values: Vec<Vec<u8>> = expected.iter().map(|(_, value)| value.clone()).collect();
+	expected_values.sort();
+	assert_eq!(values, expected_values);
+
+	// A merge part way through moves what's still to come, and it's still all seen
+	let mut walk = bc.keys();
+	let mut seen: Vec<Vec<u8>> = walk.by_ref().take(3).collect::<Result<_, _>>().unwrap();
+	bc.merge().unwrap();
+	bc.put(k(200), "late").unwrap();
+	seen.extend(walk.collect::<Result<Vec<_>, _>>().unwrap());
+	for (key, _) in &expected {
+	    assert!(seen.contains(key));
+	}
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_ordered_index() {
+	let testdir = test_setup("test_ordered_index/");
+	let kv = |items: Vec<Result<_, BitcaskError>>| -> Vec<(String, String)> {
+	    items.into_iter().map(|item| { let (key, value): (Vec<u8>, Vec<u8>) = item.unwrap(); (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap()) }).collect()
+	};
+	for ordered in [false, true] {
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.set_ordered_index(ordered);
+	    for key in ["apple", "apricot", "banana", "blueberry", "cherry", "ap"] {
+		bc.put(key, key.to_uppercase()).unwrap();
+	    }
+	    bc.delete("blueberry").unwrap();
+	    bc.put_with_ttl("avocado", "gone", Duration::from_millis(1)).unwrap();
+	    std::thread::sleep(Duration::from_millis(5));
+	    assert_eq!(kv(bc.range("apricot".."cherry").collect()),
+		       [("apricot", "APRICOT"), ("banana", "BANANA")].map(|(k, v)| (k.to_string(), v.to_string())));
+	    assert_eq!(kv(bc.range("b"..).collect()).len(), 2);
+	    assert_eq!(kv(bc.range(..="ap").collect()), [("ap".to_string(), "AP".to_string())]);
+	    assert_eq!(kv(bc.scan_prefix("ap").collect()).iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(), ["ap", "apple", "apricot"]);
+	    assert_eq!(kv(bc.scan_prefix("").collect()).len(), 5);
+	    assert_eq!(kv(bc.scan_prefix("zz").collect()).len(), 0);
+	    bc.put([0xffu8, 0xff, 1], "high").unwrap();
+	    assert_eq!(bc.scan_prefix([0xffu8, 0xff]).count(), 1);
+	    bc.delete([0xffu8, 0xff, 1]).unwrap();
+	    bc.delete("avocado").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_stats() {
+	let testdir = test_setup("test_stats/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(bc.stats().unwrap(), Stats { datafiles: 1, disk_bytes: 16, current_file_size: 16, ..Stats::default() });
+	    for i in 0..10 {
+		bc.put(k(i), "a").unwrap();
+	    }
+	    for i in 0..3 {
+		bc.put(k(i), "b").unwrap();
+	    }
+	    bc.delete(k(8)).unwrap();
+	    bc.delete(k(9)).unwrap();
+	    let stats = bc.stats().unwrap();
+	    assert_eq!(stats.keys, 8);
+	    assert_eq!(stats.datafiles, 1);
+	    assert_eq!(stats.disk_bytes, 16 + 13 * 52 + 2 * 48);
+	    assert_eq!(stats.current_file_size, stats.disk_bytes);
+	    assert_eq!(stats.live_bytes, 8 * 52);
+	    assert_eq!(stats.dead_bytes, 5 * 52 + 2 * 48);
+	    assert_eq!(stats.dead_bytes_per_datafile[&1], stats.dead_bytes);
+	    bc.shutdown().unwrap();
+	}
+	// Reopening works the dead bytes out afresh, and they agree
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let stats = bc.stats().unwrap();
+	assert_eq!((stats.keys, stats.datafiles, stats.current_file_size), (8, 2, 16));
+	assert_eq!(stats.disk_bytes, 16 + 13 * 52 + 2 * 48 + 16);
+	assert_eq!((stats.live_bytes, stats.dead_bytes), (8 * 52, 5 * 52 + 2 * 48));
+	bc.merge().unwrap();
+	assert_eq!(bc.stats().unwrap().dead_bytes, 0);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_errors() {
+	let testdir = test_setup("test_errors/");
+	{
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    assert!(matches!(bc.set_record_alignment(6), Err(BitcaskError::InvalidArgument(_))));
+	    bc.put(k(1), "a").unwrap();
+	    bc.put(k(2), "b").unwrap();
+	    bc.rotate().unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// The second record's value is at 16 + 52 + 44 + 4, and in any datafile but the newest, a record that no
+	// longer matches its checksum is corruption, blamed on the file and offset it's at
+	let mut data = std::fs::read(format!("{}/1.data", testdir)).unwrap();
+	data[116] ^= 1;
+	std::fs::write(format!("{}/1.data", testdir), &data).unwrap();
+	std::fs::remove_file(format!("{}/1.hints", testdir)).unwrap();
+	let e = Bitcask::new(&testdir).err().unwrap();
+	assert!(e.is_corruption());
+	match e {
+	    BitcaskError::Corruption { file, offset, .. } => assert_eq!((file.ends_with("1.data"), offset), (true, 68)),
+	    e => panic!("{:?}", e),
+	}
+	// A datafile of a version we don't know isn't corruption of one we do
+	data[116] ^= 1;
+	data[4] = 99;
+	std::fs::write(format!("{}/1.data", testdir), &data).unwrap();
+	let e = Bitcask::new(&testdir).err().unwrap();
+	assert!(matches!(e, BitcaskError::InvalidFormat { .. }));
+	assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+	// And it survives a trip through io::Error
+	assert!(matches!(BitcaskError::from(io::Error::from(e)), BitcaskError::InvalidFormat { .. }));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_open_with() {
+	let testdir = test_setup("test_open_with/");
+	let dbdir = format!("{}db", testdir);
+	let mut options = BitcaskOptions::new(&dbdir);
+	assert_eq!(Bitcask::open_with(options.create_if_missing(false)).err().unwrap().kind(), io::ErrorKind::NotFound);
+	{
+	    // Each record is 52 bytes, so the datafile is full after two of them
+	    let bc = Bitcask::open_with(options.create_if_missing(true).max_datafile_size(Some(16 + 2 * 52)).max_value_size(4).sync(SyncPolicy::Never)).unwrap();
+	    assert!(matches!(bc.put(k(0), "12345"), Err(BitcaskError::ValueTooLarge { size: 5, limit: 4 })));
+	    let first = bc.active_file_id();
+	    for i in 0..5 {
+		bc.put(k(i), "a").unwrap();
+	    }
+	    assert_eq!(bc.active_file_id(), first + 2);
+	    bc.shutdown().unwrap();
+	}
+	let before = std::fs::read_dir(&dbdir).unwrap().count();
+	let bc = Bitcask::open_with(BitcaskOptions::new(&dbdir).read_only(true)).unwrap();
+	assert_eq!(bc.get(k(4)).unwrap(), Some(b"a".to_vec()));
+	assert_eq!(bc.keys().count(), 5);
+	assert!(matches!(bc.put(k(5), "a"), Err(BitcaskError::ReadOnly)));
+	assert!(matches!(bc.delete(k(4)), Err(BitcaskError::ReadOnly)));
+	assert!(matches!(bc.merge(), Err(BitcaskError::ReadOnly)));
+	bc.shutdown().unwrap();
+	assert_eq!(std::fs::read_dir(&dbdir).unwrap().count(), before);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_max_datafile_size() {
+	let testdir = test_setup("test_max_datafile_size/");
+	{
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.set_max_datafile_size(Some(200));
+	    for i in 0..10 {
+		bc.put(k(i), "a").unwrap();
+	    }
+	    // At 16 + 3 * 52 = 172 bytes there's still room, the fourth record takes it over and closes it,
+	    // and every closed datafile gets its hints from the pool without waiting for shutdown
+	    bc.wait_for_background();
+	    let first = bc.active_file_id() - 2;
+	    for id in first..first + 2 {
+		assert_eq!(std::fs::metadata(format!("{}/{}.data", testdir, id)).unwrap().len(), 16 + 4 * 52);
+		assert!(std::fs::metadata(format!("{}/{}.hints", testdir, id)).is_ok());
+	    }
+	    assert_eq!(bc.stats().unwrap().current_file_size, 16 + 2 * 52);
+	}
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.list_keys().len(), 10);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_lock_file() {
+	let testdir = test_setup("test_lock_file/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "a").unwrap();
+	// The lock is per open file, so a second open fails even in the same process, but a reader is let in
+	let e = Bitcask::new(&testdir).err().unwrap();
+	assert!(matches!(e, BitcaskError::Locked(_)));
+	assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+	let reader = Bitcask::open_with(BitcaskOptions::new(&testdir).read_only(true)).unwrap();
+	assert_eq!(reader.get(k(1)).unwrap(), Some(b"a".to_vec()));
+	bc.shutdown().unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	drop(bc);						// However it's closed
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"a".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_torn_write_recovery() {
+	let testdir = test_setup("test_torn_write_recovery/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for i in 0..3 {
+		bc.put(k(i), "a").unwrap();
+	    }
+	    bc.crash();						// No shutdown and so no hints
+	}
+	// The first 48 bytes of a 52 byte record, as if the process died part way through writing it
+	let name = format!("{}/1.data", testdir);
+	let mut data = std::fs::read(&name).unwrap();
+	let torn = data[16..16 + 48].to_vec();
+	data.extend_from_slice(&torn);
+	std::fs::write(&name, &data).unwrap();
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(std::fs::metadata(&name).unwrap().len(), 16 + 3 * 52);
+	    assert_eq!(bc.list_keys().len(), 3);
+	    bc.put(k(3), "b").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(2)).unwrap(), Some(b"a".to_vec()));
+	assert_eq!(bc.get(k(3)).unwrap(), Some(b"b".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_get_checksum() {
+	let testdir = test_setup("test_get_checksum/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "a").unwrap();
+	    bc.put(k(2), "b").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(3), "c").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// Flip a bit of the second record's value, which with hints nothing reads until a get
+	let name = format!("{}/1.data", testdir);
+	let mut data = std::fs::read(&name).unwrap();
+	data[16 + 52 + 44 + 4] ^= 1;
+	std::fs::write(&name, &data).unwrap();
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(matches!(bc.get(k(2)), Err(BitcaskError::Corruption { offset: 68, .. })));
+	assert!(bc.get_into(k(2), &mut Vec::new()).unwrap_err().is_corruption());
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"a".to_vec()));
+	bc.set_corruption_policy(CorruptionPolicy::Skip);
+	assert_eq!(bc.get(k(2)).unwrap(), None);
+	assert_eq!(bc.get_with_metadata(k(2)).unwrap(), None);
+	let mut buf = b"stale".to_vec();
+	assert_eq!(bc.get_into(k(2), &mut buf).unwrap(), None);
+	assert!(buf.is_empty());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+	let testdir = test_setup("test_open_read_only/");
+	assert_eq!(Bitcask::open_read_only(&format!("{}missing", testdir)).err().unwrap().kind(), io::ErrorKind::NotFound);
+	let writer = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..3 {
+	    writer.put(k(i), "a").unwrap();
+	}
+	// Catch the writer part way through an append of a fourth record
+	let name = format!("{}/1.data", testdir);
+	let mut data = std::fs::read(&name).unwrap();
+	let partial = data[16..16 + 48].to_vec();
+	data.extend_from_slice(&partial);
+	std::fs::write(&name, &data).unwrap();
+	let reader = Bitcask::open_read_only(&testdir).unwrap();
+	assert_eq!(reader.list_keys().len(), 3);
+	assert_eq!(reader.values().collect::<Result<Vec<_>, _>>().unwrap(), [b"a", b"a", b"a"]);
+	assert!(matches!(reader.put(k(3), "b"), Err(BitcaskError::ReadOnly)));
+	assert!(matches!(reader.rotate(), Err(BitcaskError::ReadOnly)));
+	reader.shutdown().unwrap();
+	assert_eq!(std::fs::metadata(&name).unwrap().len(), data.len() as u64);
+	drop(writer);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+	let testdir = test_setup("test_concurrent_readers/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..200 {
+	    bc.put(k(i), format!("value {}", i)).unwrap();
+	}
+	// Reads don't share a file offset, so none of them can land on another's record, even while appending
+	std::thread::scope(|scope| {
+	    scope.spawn(|| {
+		for i in 200..400 {
+		    bc.put(k(i), format!("value {}", i)).unwrap();
+		}
+	    });
+	    for thread in 0..8 {
+		let bc = &bc;
+		scope.spawn(move || {
+		    for i in 0..200 {
+			let i = (i * 7 + thread * 25) % 200;
+			assert_eq!(bc.get(k(i)).unwrap(), Some(format!("value {}", i).into_bytes()));
+		    }
+		});
+	    }
+	});
+	assert_eq!(bc.get(k(399)).unwrap(), Some(b"value 399".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_keymap_shards() {
+	let testdir = test_setup("test_keymap_shards/");
+	let mut options = BitcaskOptions::new(&testdir);
+	options.keymap_shards(4);
+	let mut bc = Bitcask::open_with(&options).unwrap();
+	bc.set_ordered_index(true);
+	std::thread::scope(|scope| {
+	    for thread in 0..8 {
+		let bc = &bc;
+		scope.spawn(move || {
+		    for i in 0..50 {
+			bc.put(k(thread * 50 + i), format!("value {}", thread * 50 + i)).unwrap();
+		    }
+		});
+	    }
+	});
+	assert_eq!(bc.list_keys().len(), 400);
+	// Keys from every shard come back in order
+	let keys: Vec<Vec<u8>> = bc.range(k(100)..k(110)).map(|kv| kv.unwrap().0).collect();
+	let mut expected: Vec<Vec<u8>> = (0..400).map(|i| k(i).to_vec()).filter(|key| (&k(100)[..]..&k(110)[..]).contains(&key.as_slice())).collect();
+	expected.sort();
+	assert_eq!(keys, expected);
+	// Writers to different shards still numbered their records in log order, or this would find them out of it
+	bc.truncate_to_sequence(100).unwrap();
+	assert_eq!(bc.list_keys().len(), 100);
+	bc.set_keymap_shards(1);
+	assert_eq!(bc.range(k(0)..=[255; 4]).count(), 100);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_mapped_datafiles() {
+	let testdir = test_setup("test_mapped_datafiles/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..200 {
+	    bc.put(k(i), format!("value {}", i)).unwrap();
+	    if i % 50 == 49 {
+		bc.rotate().unwrap();				// Sealed, so read through a mapping from here on
+	    }
+	}
+	bc.put(k(200), "still being written").unwrap();
+	for i in 0..200 {
+	    assert_eq!(bc.get(k(i)).unwrap(), Some(format!("value {}", i).into_bytes()));
+	}
+	// Cutting into a sealed file maps it again, and nothing past the cut can be read
+	bc.truncate_to_sequence(120).unwrap();
+	assert_eq!(bc.get(k(119)).unwrap(), Some(b"value 119".to_vec()));
+	assert_eq!(bc.get(k(120)).unwrap(), None);
+	bc.put(k(120), "again").unwrap();
+	bc.merge().unwrap();
+	assert_eq!(bc.get(k(120)).unwrap(), Some(b"again".to_vec()));
+	assert_eq!(bc.get(k(60)).unwrap(), Some(b"value 60".to_vec()));
+	bc.shutdown().unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.list_keys().len(), 121);
+	assert_eq!(bc.get(k(0)).unwrap(), Some(b"value 0".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_value_cache() {
+	let testdir = test_setup("test_value_cache/");
+	let mut options = BitcaskOptions::new(&testdir);
+	options.cache_size(100);				// Room for ten 4 byte keys with 6 byte values
+	let bc = Bitcask::open_with(&options).unwrap();
+	for i in 0..20 {
+	    bc.put(k(i), format!("val {:02}", i)).unwrap();
+	}
+	let counts = |bc: &Bitcask| { let stats = bc.stats().unwrap(); (stats.cache_hits, stats.cache_misses) };
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"val 01".to_vec()));
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"val 01".to_vec()));
+	assert_eq!(counts(&bc), (1, 1));
+	// Overwritten or deleted, the cached value is never handed out again
+	bc.put(k(1), "new 01").unwrap();
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"new 01".to_vec()));
+	bc.delete(k(1)).unwrap();
+	assert_eq!(bc.get(k(1)).unwrap(), None);
+	assert_eq!(counts(&bc), (1, 2));
+	// Reading more than fits pushes out the least recently used
+	for i in 2..12 {
+	    bc.get(k(i)).unwrap();
+	}
+	bc.get(k(2)).unwrap();
+	assert_eq!(counts(&bc), (2, 12));
+	bc.get(k(12)).unwrap();					// In place of 3, the least recently used
+	bc.get(k(2)).unwrap();
+	bc.get(k(3)).unwrap();
+	assert_eq!(counts(&bc), (3, 14));
+	// A merge moves the values, they're read again from where they went
+	bc.rotate().unwrap();
+	bc.merge().unwrap();
+	let mut buf = Vec::new();
+	assert_eq!(bc.get_into(k(2), &mut buf).unwrap(), Some(6));
+	assert_eq!(buf, b"val 02");
+	assert_eq!(bc.get(k(2)).unwrap(), Some(b"val 02".to_vec()));
+	assert_eq!(counts(&bc), (4, 15));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+	let bloom = BloomFilter::new(1000);
+	for i in 0..1000 {
+	    bloom.insert(&k(i));
+	}
+	assert!((0..1000).all(|i| bloom.might_contain(&k(i))));
+	let false_positives = (1000..11000).filter(|&i| bloom.might_contain(&k(i))).count();
+	assert!(false_positives < 300, "{} false positives out of 10000", false_positives);
+
+	let testdir = test_setup("test_bloom_filter/");
+	let mut options = BitcaskOptions::new(&testdir);
+	options.bloom_filter(true);
+	let bc = Bitcask::open_with(&options).unwrap();
+	let mut batch = WriteBatch::new();
+	for i in 0..100 {
+	    bc.put(k(i), "single").unwrap();
+	    batch.put(k(i + 100), "batched");
+	}
+	bc.write(&batch).unwrap();
+	assert_eq!(bc.get(k(5)).unwrap(), Some(b"single".to_vec()));
+	assert_eq!(bc.get(k(150)).unwrap(), Some(b"batched".to_vec()));
+	assert_eq!(bc.get(k(500)).unwrap(), None);
+	bc.delete(k(5)).unwrap();
+	assert_eq!(bc.get(k(5)).unwrap(), None);
+	// Merging rebuilds it from what's left, without losing anything that's still there
+	bc.rotate().unwrap();
+	bc.merge().unwrap();
+	assert_eq!(bc.get(k(5)).unwrap(), None);
+	assert_eq!(bc.get(k(6)).unwrap(), Some(b"single".to_vec()));
+	assert_eq!(bc.get(k(199)).unwrap(), Some(b"batched".to_vec()));
+	bc.put(k(5), "back").unwrap();
+	assert_eq!(bc.get(k(5)).unwrap(), Some(b"back".to_vec()));
+	bc.shutdown().unwrap();
+	let bc = Bitcask::open_with(&options).unwrap();
+	assert_eq!(bc.get(k(5)).unwrap(), Some(b"back".to_vec()));
+	assert_eq!(bc.get(k(199)).unwrap(), Some(b"batched".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_sync_policies() {
+	// How many fsyncs 20 puts and a rotation cost under each policy, not counting those opening it took
+	let run = |policy: SyncPolicy, settle: Duration| {
+	    let storage = CountingStorage::new();
+	    let mut bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+	    bc.set_sync_policy(policy);
+	    let opened = storage.syncs.load(Ordering::SeqCst);
+	    for i in 0..10 {
+		bc.put(k(i), "abcd").unwrap();
+	    }
+	    bc.rotate().unwrap();
+	    for i in 10..20 {
+		bc.put(k(i), "abcd").unwrap();
+	    }
+	    bc.wait_for_background();				// The hints file is written on the pool
+	    std::thread::sleep(settle);
+	    storage.syncs.load(Ordering::SeqCst) - opened
+	};
+	let none = Duration::ZERO;
+	let never = run(SyncPolicy::Never, none);		// Only the sealed datafile's hints file
+	assert_eq!(run(SyncPolicy::OnRotate, none), never + 1);	// And the sealed datafile
+	assert_eq!(run(SyncPolicy::Always, none), never + 21);	// And every put
+	let periodic = run(SyncPolicy::Every(Duration::from_millis(5)), Duration::from_millis(100)) - never;
+	assert!((2..=5).contains(&periodic), "{} syncs", periodic);	// The rotation, then the new datafile once or a few times
+    }
+
+    #[test]
+    fn test_group_commit() {
+	// Eight writers whose puts must each be durable before they return, on a disk that takes 2ms an fsync
+	let storage = CountingStorage::with_sync_delay(Duration::from_millis(2));
+	let bc = Bitcask::with_storage(Box::new(storage.clone())).expect("REASON");
+	let opened = storage.syncs.load(Ordering::SeqCst);
+	std::thread::scope(|scope| {
+	    for thread in 0..8 {
+		let bc = &bc;
+		scope.spawn(move || {
+		    for i in 0..25 {
+			bc.put(k(thread * 100 + i), "abcd").unwrap();
+		    }
+		});
+	    }
+	});
+	let syncs = storage.syncs.load(Ordering::SeqCst) - opened;
+	assert!(syncs < 100, "{} syncs for 200 puts", syncs);	// Most of them shared one
+	assert_eq!(bc.list_keys().len(), 200);
+	// One writer on its own still gets an fsync of its own for every put
+	for i in 0..10 {
+	    bc.put(k(i), "efgh").unwrap();
+	}
+	assert_eq!(storage.syncs.load(Ordering::SeqCst) - opened - syncs, 10);
+    }
+
+    #[test]
+    fn test_multi_get() {
+	let testdir = test_setup("test_multi_get/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..30 {
+	    bc.put(k(i), format!("value {}", i)).unwrap();
+	    if i % 10 == 9 {
+		bc.rotate().unwrap();
+	    }
+	}
+	bc.delete(k(3)).unwrap();
+	bc.put_with_ttl(k(4), "gone soon", Duration::from_millis(1)).unwrap();
+	std::thread::sleep(Duration::from_millis(5));
+	let keys = [k(25), k(3), k(0), k(99), k(4), k(12), k(0)];
+	let values = bc.multi_get(&keys).unwrap();
+	let expect = |value: Option<&str>| value.map(|value| value.as_bytes().to_vec());
+	assert_eq!(values, vec![expect(Some("value 25")), None, expect(Some("value 0")), None, None, expect(Some("value 12")), expect(Some("value 0"))]);
+	assert!(bc.multi_get::<[u8; 4]>(&[]).unwrap().is_empty());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+	let testdir = test_setup("test_compare_and_swap/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(bc.compare_and_swap(k(1), None, Some(b"one")).unwrap());
+	assert!(!bc.compare_and_swap(k(1), None, Some(b"uno")).unwrap());	// It exists now
+	assert!(!bc.compare_and_swap(k(1), Some(b"uno"), Some(b"eins")).unwrap());
+	assert!(bc.compare_and_swap(k(1), Some(b"one"), Some(b"eins")).unwrap());
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"eins".to_vec()));
+	assert!(bc.compare_and_swap(k(1), Some(b"eins"), None).unwrap());
+	assert_eq!(bc.get(k(1)).unwrap(), None);
+	assert!(bc.compare_and_swap(k(1), None, None).unwrap());
+	assert!(bc.compare_and_swap(k(1), None, Some(&[0xff, 0])).unwrap());		// Values needn't be text
+	assert_eq!(bc.get(k(1)).unwrap(), Some(vec![0xff, 0]));
+	// Racing writers from the same value, exactly one of them wins each round
+	bc.put(k(2), "0").unwrap();
+	let wins = AtomicUsize::new(0);
+	std::thread::scope(|scope| {
+	    for _ in 0..4 {
+		scope.spawn(|| {
+		    for round in 0..50 {
+			let (from, to) = (round.to_string(), (round + 1).to_string());
+			if bc.compare_and_swap(k(2), Some(from.as_bytes()), Some(to.as_bytes())).unwrap() {
+			    wins.fetch_add(1, Ordering::SeqCst);
+			}
+			while bc.get(k(2)).unwrap().as_deref() == Some(from.as_bytes()) {
+			    std::thread::yield_now();
+			}
+		    }
+		});
+	    }
+	});
+	assert_eq!(wins.load(Ordering::SeqCst), 50);
+	assert_eq!(bc.get(k(2)).unwrap(), Some(b"50".to_vec()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+	let testdir = test_setup("test_put_if_absent/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(bc.put_if_absent(k(1), "first").unwrap());
+	assert!(!bc.put_if_absent(k(1), "second").unwrap());
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"first".to_vec()));
+	bc.put_with_ttl(k(2), "brief", Duration::from_millis(1)).unwrap();
+	std::thread::sleep(Duration::from_millis(5));
+	assert!(bc.put_if_absent(k(2), "after").unwrap());		// Expired counts as absent
+	// Racing claimants, only one of them gets it
+	let winners: Vec<bool> = std::thread::scope(|scope| {
+	    let claims: Vec<_> = (0..8).map(|thread| {
+		let bc = &bc;
+		scope.spawn(move || bc.put_if_absent(k(3), format!("claimant {}", thread)).unwrap())
+	    }).collect();
+	    claims.into_iter().map(|claim| claim.join().unwrap()).collect()
+	});
+	assert_eq!(winners.iter().filter(|won| **won).count(), 1);
+	let winner = winners.iter().position(|won| *won).unwrap();
+	assert_eq!(bc.get(k(3)).unwrap(), Some(format!("claimant {}", winner).into_bytes()));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_update() {
+	let testdir = test_setup("test_update/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.update(k(1), |old| { assert_eq!(old, None); Some(b"a".to_vec()) }).unwrap(), Some(b"a".to_vec()));
+	assert_eq!(bc.update(k(1), |old| old.map(|old| [old, b"b"].concat())).unwrap(), Some(b"ab".to_vec()));
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"ab".to_vec()));
+	assert_eq!(bc.update(k(1), |_| None).unwrap(), None);
+	assert_eq!(bc.get(k(1)).unwrap(), None);
+	assert_eq!(bc.update(k(1), |_| Some(vec![0xff])).unwrap(), Some(vec![0xff]));
+	assert_eq!(bc.get(k(1)).unwrap(), Some(vec![0xff]));
+	bc.delete(k(1)).unwrap();
+	// Concurrent read-modify-writes of the same key, none of them lost
+	std::thread::scope(|scope| {
+	    for _ in 0..4 {
+		scope.spawn(|| {
+		    for _ in 0..25 {
+			bc.update(k(2), |old| Some([old.unwrap_or_default(), b"x"].concat())).unwrap();
+		    }
+		});
+	    }
+	});
+	assert_eq!(bc.get(k(2)).unwrap().map(|value| value.len()), Some(100));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_counters() {
+	let testdir = test_setup("test_counters/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.incr(k(1), 5).unwrap(), 5);
+	assert_eq!(bc.decr(k(1), 7).unwrap(), -2);
+	let mut buf = Vec::new();
+	assert_eq!(bc.get_into(k(1), &mut buf).unwrap(), Some(8));
+	assert_eq!(buf, (-2i64).to_le_bytes());				// The raw bytes, not digits
+	assert_eq!(bc.decr(k(2), 1).unwrap(), -1);
+	bc.put(k(3), "one").unwrap();
+	assert!(matches!(bc.incr(k(3), 1), Err(BitcaskError::InvalidArgument(_))));
+	assert_eq!(bc.incr(k(4), i64::MAX).unwrap(), i64::MAX);
+	assert!(matches!(bc.incr(k(4), 1), Err(BitcaskError::InvalidArgument(_))));
+	bc.get_into(k(4), &mut buf).unwrap();
+	assert_eq!(buf, i64::MAX.to_le_bytes());
+	std::thread::scope(|scope| {
+	    for _ in 0..4 {
+		scope.spawn(|| {
+		    for _ in 0..50 {
+			bc.incr(k(5), 1).unwrap();
+		    }
+		});
+	    }
+	});
+	assert_eq!(bc.incr(k(5), 0).unwrap(), 200);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_counter_reads() {
+	let testdir = test_setup("test_counter_reads/");
+	let otherdir = test_setup("test_counter_reads_other/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "text").unwrap();
+	assert_eq!(bc.incr(k(2), 0x1ff).unwrap(), 0x1ff);	// Its bytes aren't UTF-8
+	let count = 0x1ffi64.to_le_bytes().to_vec();
+	assert_eq!(bc.get(k(2)).unwrap(), Some(count.clone()));
+	let mut values = bc.fold(|mut acc, _, value| { acc.push(value); acc }, Vec::new()).unwrap();
+	values.sort();
+	assert_eq!(values, [b"text".to_vec(), count.clone()]);
+	assert_eq!(bc.values().count(), 2);
+	let mut out = Vec::new();
+	assert_eq!(bc.export_json(&mut out).unwrap(), 2);
+	let text = String::from_utf8(out).unwrap();
+	assert!(text.contains(&format!("\"value_hex\":\"{}\"", "ff01000000000000")));
+	assert!(text.contains("\"value\":\"text\""));
+	let other = Bitcask::new(&otherdir).expect("REASON");
+	assert_eq!(other.import_json(text.as_bytes()).unwrap(), 2);
+	assert_eq!(other.incr(k(2), 1).unwrap(), 0x200);
+	let mut out = Vec::new();
+	assert_eq!(bc.export_csv(&mut out, true).unwrap(), 2);
+	let text = String::from_utf8(out).unwrap();
+	assert!(text.lines().any(|line| line.ends_with(",0xff01000000000000")));
+	assert!(text.lines().any(|line| line.ends_with(",text")));
+	assert!(bc.expire(k(2), Some(Duration::from_secs(3600))).unwrap());
+	assert_eq!(bc.incr(k(2), 1).unwrap(), 0x200);
+	test_teardown(&otherdir);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_incr_throughput() {
+	// Not a check, a measurement: cargo test --release -- --ignored --nocapture test_incr_throughput
+	const THREADS: i32 = 8;
+	const KEYS: i32 = 1024;
+	const ROUNDS: i32 = 100;
+	let mut bc = Bitcask::with_storage(Box::new(MemoryStorage::new())).expect("REASON");
+	bc.set_sync_policy(SyncPolicy::Never);
+	let started = std::time::Instant::now();
+	std::thread::scope(|scope| {
+	    for thread in 0..THREADS {
+		let bc = &bc;
+		scope.spawn(move || {
+		    for _ in 0..ROUNDS {
+			for key in (thread..KEYS).step_by(THREADS as usize) {	// Each thread a share of the keys
+			    bc.incr(k(key), 1).unwrap();
+			}
+		    }
+		});
+	    }
+	});
+	let elapsed = started.elapsed();
+	let ops = (KEYS * ROUNDS) as f64;
+	println!("incr: {} threads, {} keys, {:.0} ops/s", THREADS, KEYS, ops / elapsed.as_secs_f64());
+	for key in 0..KEYS {
+	    assert_eq!(bc.incr(k(key), 0).unwrap(), ROUNDS as i64);
+	}
+    }
+
+    #[test]
+    fn test_append() {
+	let testdir = test_setup("test_append/");
 	let bc = Bitcask::new(&testdir).expect("REASON");
-	bc.put(10, &value);
-	let val = bc.get(10);
-	assert_eq!(val, Some(value));
-	bc.delete(10);
-	let val = bc.get(10);
-	assert_eq!(val, None);
+	assert_eq!(bc.append(k(1), "a").unwrap(), 1);
+	assert_eq!(bc.append(k(1), b"bc").unwrap(), 3);
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"abc".to_vec()));
+	assert_eq!(bc.append(k(1), [0xff]).unwrap(), 4);
+	assert_eq!(bc.get(k(1)).unwrap(), Some(b"abc\xff".to_vec()));
+	std::thread::scope(|scope| {
+	    for t in 0..4 {
+		let bc = &bc;
+		scope.spawn(move || {
+		    for _ in 0..25 {
+			bc.append(k(2), t.to_string()).unwrap();
+		    }
+		});
+	    }
+	});
+	let value = String::from_utf8(bc.get(k(2)).unwrap().unwrap()).unwrap();
+	for t in 0..4 {
+	    assert_eq!(value.matches(&t.to_string()).count(), 25);
+	}
 	test_teardown(&testdir);
     }
 
+    #[test]
+    fn test_contains_key_and_len() {
+	let testdir = test_setup("test_contains_key_and_len/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(bc.is_empty());
+	assert_eq!(bc.len(), 0);
+	for i in 0..10 {
+	    bc.put(k(i), "x").unwrap();
+	}
+	bc.delete(k(3)).unwrap();
+	bc.put_with_ttl(k(20), "x", Duration::from_millis(1)).unwrap();
+	std::thread::sleep(Duration::from_millis(5));
+	assert!(bc.contains_key(k(2)));
+	assert!(!bc.contains_key(k(3)));
+	assert!(!bc.contains_key(k(20)));
+	assert_eq!(bc.len(), 9);
+	assert!(!bc.is_empty());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_fold() {
+	let testdir = test_setup("test_fold/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(200));
+	for i in 0..20 {
+	    bc.put(k(i), i.to_string()).unwrap();
+	}
+	bc.delete(k(7)).unwrap();
+	bc.put(k(8), "100").unwrap();
+	assert!(bc.stats().unwrap().datafiles > 1);
+	let (count, sum) = bc.fold(|(count, sum), key, value| {
+	    assert_ne!(key, k(7).to_vec());
+	    (count + 1, sum + String::from_utf8(value).unwrap().parse::<u64>().unwrap())
+	}, (0, 0)).unwrap();
+	assert_eq!(count, 19);
+	assert_eq!(sum, (0..20).sum::<u64>() - 7 - 8 + 100);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_backup() {
+	let testdir = test_setup("test_backup/");
+	let backupdir = test_setup("test_backup_copy/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(300));
+	for i in 0..20 {
+	    bc.put(k(i), i.to_string()).unwrap();
+	}
+	bc.merge().unwrap();
+	for i in 10..30 {
+	    bc.put(k(i), "new").unwrap();
+	}
+	bc.delete(k(0)).unwrap();
+	let manifest = bc.backup(&backupdir).unwrap();
+	assert_eq!(manifest.id, bc.id());
+	assert_eq!(manifest.highest_fileid, bc.active_file_id());
+	assert!(manifest.files.iter().any(|(name, _)| name.ends_with(".hints")));
+	bc.put(k(1), "after").unwrap();			// Not in the backup
+	bc.put(k(100), "after").unwrap();
+	assert!(matches!(bc.backup(&backupdir), Err(BitcaskError::InvalidArgument(_))));
+	let copy = Bitcask::new(&backupdir).expect("REASON");
+	assert_eq!(copy.id(), bc.id());
+	assert_eq!(copy.len(), 29);
+	assert_eq!(copy.get(k(0)).unwrap(), None);
+	assert_eq!(copy.get(k(1)).unwrap(), Some(b"1".to_vec()));
+	assert_eq!(copy.get(k(25)).unwrap(), Some(b"new".to_vec()));
+	assert_eq!(copy.get(k(100)).unwrap(), None);
+	test_teardown(&backupdir);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_backup_incremental() {
+	let testdir = test_setup("test_backup_incremental/");
+	let backupdir = test_setup("test_backup_incremental_copy/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(300));
+	for i in 0..20 {
+	    bc.put(k(i), "old").unwrap();
+	}
+	let full = bc.backup(&backupdir).unwrap();
+	bc.delete(k(0)).unwrap();					// Merged away below, the backup mustn't keep the PUT
+	for i in 10..30 {
+	    bc.put(k(i), "new").unwrap();
+	}
+	bc.merge().unwrap();
+	bc.put(k(40), "newest").unwrap();
+	let other = test_setup("test_backup_incremental_other/");
+	assert!(matches!(bc.backup_incremental(&other, full.highest_fileid), Err(BitcaskError::InvalidArgument(_))));
+	let incremental = bc.backup_incremental(&backupdir, full.highest_fileid).unwrap();
+	assert!(incremental.highest_fileid > full.highest_fileid);
+	let mut listed: Vec<String> = incremental.files.iter().map(|(name, _)| name.clone()).collect();
+	let mut there: Vec<String> = std::fs::read_dir(&backupdir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap())
+	    .filter(|name| name != "MANIFEST" && name != "LOCK").collect();
+	listed.sort();
+	there.sort();
+	assert_eq!(listed, there);
+	let copy = Bitcask::new(&backupdir).expect("REASON");
+	assert_eq!(copy.len(), 30);
+	assert_eq!(copy.get(k(0)).unwrap(), None);
+	assert_eq!(copy.get(k(5)).unwrap(), Some(b"old".to_vec()));
+	assert_eq!(copy.get(k(25)).unwrap(), Some(b"new".to_vec()));
+	assert_eq!(copy.get(k(40)).unwrap(), Some(b"newest".to_vec()));
+	test_teardown(&other);
+	test_teardown(&backupdir);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_json_export_import() {
+	let testdir = test_setup("test_json_export_import/");
+	let otherdir = test_setup("test_json_export_import_other/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put("plain", "value").unwrap();
+	bc.put("quoted", "a \"b\"\\\n\t\u{1} é 🦀").unwrap();
+	bc.put([0xff, 0x00], "binary key").unwrap();
+	bc.put_with_ttl("ttl", "later", Duration::from_secs(3600)).unwrap();
+	bc.put("gone", "x").unwrap();
+	bc.delete("gone").unwrap();
+	let mut out = Vec::new();
+	assert_eq!(bc.export_json(&mut out).unwrap(), 4);
+	let text = String::from_utf8(out).unwrap();
+	assert_eq!(text.lines().count(), 4);
+	assert!(text.contains("{\"key_hex\":\"ff00\",\"value\":\"binary key\"}"));
+	let other = Bitcask::new(&otherdir).expect("REASON");
+	assert_eq!(other.import_json(format!("{}\n\n", text).as_bytes()).unwrap(), 4);
+	for key in [b"plain".to_vec(), b"quoted".to_vec(), vec![0xff, 0x00], b"ttl".to_vec()] {
+	    assert_eq!(other.get(&key).unwrap(), bc.get(&key).unwrap());
+	}
+	assert!(other.location("ttl").is_some());
+	assert_eq!(other.import_json("{\"key\":\"\\ud83e\\udd80\",\"value\":\"\\u00e9\"}".as_bytes()).unwrap(), 1);
+	assert_eq!(other.get("🦀").unwrap(), Some("é".as_bytes().to_vec()));
+	assert_eq!(other.import_json("{\"key\":\"old\",\"value\":\"x\",\"expires\":1}".as_bytes()).unwrap(), 0);
+	let err = other.import_json("{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n".as_bytes());
+	assert!(matches!(err, Err(BitcaskError::InvalidArgument(why)) if why.starts_with("line 2")));
+	assert_eq!(other.get("a").unwrap(), Some(b"1".to_vec()));
+	test_teardown(&otherdir);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_csv_export() {
+	let testdir = test_setup("test_csv_export/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put("plain", "value").unwrap();
+	bc.put("a,b", "say \"hi\"\nbye").unwrap();
+	bc.put([0xff], "x").unwrap();
+	let mut out = Vec::new();
+	assert_eq!(bc.export_csv(&mut out, false).unwrap(), 3);
+	let text = String::from_utf8(out).unwrap();
+	let lines: Vec<&str> = text.lines().collect();
+	assert_eq!(lines[0], "key,value_size,file_id,offset,timestamp");
+	let (fileid, offset) = bc.location("plain").unwrap();
+	let fields: Vec<&str> = lines[1].split(',').collect();
+	assert_eq!(fields[..4], ["plain", "5", &fileid.to_string(), &offset.to_string()]);
+	assert!(fields[4].parse::<u64>().unwrap() > 0);
+	assert!(lines[2].starts_with("\"a,b\",12,"));
+	assert!(lines[3].starts_with("0xff,1,"));
+	let mut out = Vec::new();
+	bc.export_csv(&mut out, true).unwrap();
+	let text = String::from_utf8(out).unwrap();
+	assert!(text.starts_with("key,value_size,file_id,offset,timestamp,value\n"));
+	assert!(text.contains("\n\"a,b\",12,"));
+	assert!(text.contains(",\"say \"\"hi\"\"\nbye\"\n"));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_follower() {
+	let primarydir = test_setup("test_follower/");
+	let replicadir = test_setup("test_follower_replica/");
+	let mut primary = Bitcask::new(&primarydir).expect("REASON");
+	primary.set_max_datafile_size(Some(300));
+	for i in 0..20 {
+	    primary.put(k(i), "one").unwrap();
+	}
+	let follower = Follower::new(&primarydir, &replicadir).unwrap();
+	let events = follower.store().subscribe();
+	assert_eq!(follower.poll().unwrap(), 20);
+	assert_eq!(follower.len(), 20);
+	assert_eq!(events.try_iter().count(), 20);
+	primary.put(k(1), "two").unwrap();
+	primary.delete(k(2)).unwrap();
+	assert_eq!(follower.poll().unwrap(), 2);
+	assert_eq!(follower.poll().unwrap(), 0);
+	assert_eq!(follower.get(k(1)).unwrap(), Some(b"two".to_vec()));
+	assert!(!follower.contains_key(k(2)));
+	// Behind a merge that compacted away a delete, the follower has to resync
+	primary.delete(k(3)).unwrap();
+	primary.put(k(4), "three").unwrap();
+	primary.merge().unwrap();
+	primary.put(k(5), "four").unwrap();
+	follower.poll().unwrap();
+	assert!(!follower.contains_key(k(3)));
+	assert_eq!(follower.get(k(4)).unwrap(), Some(b"three".to_vec()));
+	assert_eq!(follower.get(k(5)).unwrap(), Some(b"four".to_vec()));
+	assert_eq!(follower.len(), primary.len());
+	// Reopened, it carries on from where it got to
+	drop(follower);
+	primary.put(k(60), "five").unwrap();
+	let follower = Arc::new(Follower::new(&primarydir, &replicadir).unwrap());
+	follower.start(Duration::from_millis(5));
+	for _ in 0..200 {
+	    if follower.get(k(60)).unwrap().is_some() {
+		break;
+	    }
+	    std::thread::sleep(Duration::from_millis(5));
+	}
+	assert_eq!(follower.get(k(60)).unwrap(), Some(b"five".to_vec()));
+	assert_eq!(follower.get(k(1)).unwrap(), Some(b"two".to_vec()));
+	assert_eq!(follower.len(), primary.len());
+	drop(follower);
+	test_teardown(&replicadir);
+	test_teardown(&primarydir);
+    }
+
+    #[test]
+    fn test_change_events_for_imports() {
+	let testdir = test_setup("test_change_events_for_imports/");
+	let otherdir = test_setup("test_change_events_for_imports_other/");
+	let other = Bitcask::new(&otherdir).expect("REASON");
+	other.put(k(1), "one").unwrap();
+	other.put(k(2), "two").unwrap();
+	other.delete(k(1)).unwrap();
+	let datafile = format!("{}/{}.data", otherdir, other.active_file_id());
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "mine").unwrap();
+	let events = bc.subscribe();
+	assert_eq!(bc.import_datafile(&datafile, |_| {}).unwrap(), 3);
+	let events: Vec<(Vec<u8>, ChangeOp, Option<Vec<u8>>)> = events.try_iter().map(|event| (event.key, event.op, event.value)).collect();
+	assert_eq!(events, vec![
+	    (k(1).to_vec(), ChangeOp::Put, Some(b"one".to_vec())),
+	    (k(2).to_vec(), ChangeOp::Put, Some(b"two".to_vec())),
+	    (k(1).to_vec(), ChangeOp::Delete, None),
+	]);
+	test_teardown(&otherdir);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_watch() {
+	let testdir = test_setup("test_watch/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let key = bc.watch("config/level");
+	let prefix = bc.watch_prefix("config/");
+	let everything = bc.subscribe();
+	bc.put("config/level", "3").unwrap();
+	bc.put("config/name", "x").unwrap();
+	bc.put("other", "y").unwrap();
+	bc.delete("config/level").unwrap();
+	let ops = |rx: &std::sync::mpsc::Receiver<ChangeEvent>| rx.try_iter().map(|event| (String::from_utf8(event.key).unwrap(), event.op)).collect::<Vec<_>>();
+	assert_eq!(ops(&key), vec![("config/level".to_string(), ChangeOp::Put), ("config/level".to_string(), ChangeOp::Delete)]);
+	assert_eq!(ops(&prefix).len(), 3);
+	assert_eq!(ops(&everything).len(), 4);
+	let waiting = std::thread::scope(|scope| {
+	    let waiter = scope.spawn(move || key.recv_timeout(Duration::from_secs(5)).map(|event| event.value));
+	    bc.put("config/level", "4").unwrap();
+	    waiter.join().unwrap()
+	});
+	assert_eq!(waiting, Ok(Some(b"4".to_vec())));
+	test_teardown(&testdir);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+	let testdir = test_setup("test_metrics/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "one").unwrap();
+	bc.put(k(2), "two").unwrap();
+	bc.delete(k(1)).unwrap();
+	bc.write(WriteBatch::new().put(k(3), "three").delete(k(2))).unwrap();
+	bc.get(k(3)).unwrap();
+	bc.get(k(4)).unwrap();
+	bc.merge().unwrap();
+	let text = bc.metrics();
+	let metric = |name: &str| text.lines().find_map(|line| line.strip_prefix(&format!("{} ", name))).map(|value| value.to_string());
+	assert_eq!(metric("bitcask_gets_total").as_deref(), Some("2"));
+	assert_eq!(metric("bitcask_puts_total").as_deref(), Some("3"));
+	assert_eq!(metric("bitcask_deletes_total").as_deref(), Some("2"));
+	assert_eq!(metric("bitcask_written_bytes_total").as_deref(), Some((4 * 5 + 3 + 3 + 5).to_string().as_str()));
+	assert_eq!(metric("bitcask_merges_total").as_deref(), Some("1"));
+	assert_eq!(metric("bitcask_keys").as_deref(), Some("1"));
+	assert!(text.contains("# TYPE bitcask_fsync_seconds histogram\n"));
+	let count: u64 = metric("bitcask_fsync_seconds_count").unwrap().parse().unwrap();
+	assert!(count >= 4);
+	assert_eq!(metric("bitcask_fsync_seconds_bucket{le=\"+Inf\"}"), Some(count.to_string()));
+	test_teardown(&testdir);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_trace_spans() {
+	struct Capture(Mutex<Vec<String>>);
+	impl log::Log for Capture {
+	    fn enabled(&self, metadata: &log::Metadata) -> bool {
+		metadata.target() == "chroma_bitcask::trace"
+	    }
+	    fn log(&self, record: &log::Record) {
+		if self.enabled(record.metadata()) {
+		    self.0.lock().unwrap().push(record.args().to_string());
+		}
+	    }
+	    fn flush(&self) {}
+	}
+	static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+	log::set_logger(&CAPTURE).unwrap();
+	log::set_max_level(log::LevelFilter::Trace);
+	let testdir = test_setup("test_trace_spans/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(300));
+	bc.put("key", "value").unwrap();
+	bc.get("key").unwrap();
+	bc.delete("key").unwrap();
+	for i in 0..20 {
+	    bc.put(k(i), "some value or other").unwrap();
+	}
+	bc.merge().unwrap();
+	let ours = |op: &str| CAPTURE.0.lock().unwrap().iter().filter(|line| line.starts_with(&format!("{} ", op))).cloned().collect::<Vec<String>>();
+	assert!(!ours("recover").is_empty());
+	assert!(ours("put").iter().any(|line| line.contains(" key_size=3 bytes=5 file_id=") && line.contains(" duration_us=")));
+	assert!(ours("get").iter().any(|line| line.contains(" key_size=3 file_id=")));
+	assert!(ours("delete").iter().any(|line| line.contains(" key_size=3 file_id=")));
+	assert!(ours("rotate").iter().any(|line| line.contains(" file_id=")));
+	assert!(ours("merge").iter().any(|line| line.contains(" inputs=")));
+	test_teardown(&testdir);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_typed_bitcask() {
+	use serde::{Deserialize, Serialize};
+	use crate::TypedBitcask;
+	#[derive(Serialize, Deserialize, Debug, PartialEq)]
+	struct Point { x: i32, y: i32, label: Option<String> }
+	let testdir = test_setup("test_typed_bitcask/");
+	let bc: TypedBitcask<(String, u64), Point> = TypedBitcask::new(&testdir).expect("REASON");
+	let key = |n: u64| ("point".to_string(), n);
+	bc.put(&key(7), &Point { x: 1, y: -2, label: Some("here".to_string()) }).unwrap();
+	bc.put(&key(8), &Point { x: 3, y: 4, label: None }).unwrap();
+	assert_eq!(bc.get(&key(7)).unwrap(), Some(Point { x: 1, y: -2, label: Some("here".to_string()) }));
+	assert_eq!(bc.get(&key(9)).unwrap(), None);
+	assert!(bc.contains_key(&key(8)).unwrap());
+	let mut keys = bc.keys().unwrap();
+	keys.sort();
+	assert_eq!(keys, vec![key(7), key(8)]);
+	assert_eq!(bc.entries().count(), 2);
+	assert!(bc.delete(&key(7)).unwrap());
+	assert!(!bc.contains_key(&key(7)).unwrap());
+	// The raw store underneath reads it all as bytes
+	assert_eq!(bc.raw().values().count(), 1);
+	assert_eq!(bc.raw().export_json(&mut Vec::new()).unwrap(), 1);
+	bc.raw().put("bad", "1,2").unwrap();				// Not a (String, u64)
+	assert!(matches!(bc.keys(), Err(BitcaskError::InvalidArgument(_))));
+	bc.raw().delete("bad").unwrap();
+	let mut raw = bincode::serde::encode_to_vec(Point { x: 5, y: 6, label: None }, bincode::config::standard()).unwrap();
+	raw.push(0);							// One byte too many for a Point
+	bc.raw().put(bincode::serde::encode_to_vec(key(10), bincode::config::standard()).unwrap(), raw).unwrap();
+	assert!(matches!(bc.get(&key(10)), Err(BitcaskError::InvalidArgument(_))));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_buckets() {
+	let testdir = test_setup("test_buckets/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let users = bc.bucket("users").unwrap();
+	let groups = bc.bucket("groups").unwrap();
+	users.put("alice", "admin").unwrap();
+	users.put("bob", "guest").unwrap();
+	groups.put("alice", "wheel").unwrap();
+	bc.put("alice", "unbucketed").unwrap();
+	assert_eq!(users.get("alice").unwrap().as_deref(), Some("admin".as_bytes()));
+	assert_eq!(groups.get("alice").unwrap().as_deref(), Some("wheel".as_bytes()));
+	assert_eq!(bc.get("users\0bob").unwrap().as_deref(), Some("guest".as_bytes()));
+	let mut keys = users.list_keys();
+	keys.sort();
+	assert_eq!(keys, vec![b"alice".to_vec(), b"bob".to_vec()]);
+	assert_eq!(users.entries().map(|entry| entry.unwrap().0).collect::<Vec<Vec<u8>>>(), keys);
+	assert_eq!(users.stats().keys, 2);
+	assert!(users.stats().live_bytes > groups.stats().live_bytes);
+	assert_eq!(users.clear().unwrap(), 2);
+	assert!(users.list_keys().is_empty());
+	assert!(!users.contains_key("bob"));
+	assert!(groups.contains_key("alice"));
+	assert_eq!(bc.get("alice").unwrap().as_deref(), Some("unbucketed".as_bytes()));
+	assert!(matches!(bc.bucket("a\0b"), Err(BitcaskError::InvalidArgument(_))));
+	assert!(matches!(bc.bucket(""), Err(BitcaskError::InvalidArgument(_))));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_merge_policy() {
+	let testdir = test_setup("test_merge_policy/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(300));
+	for i in 0..20 {
+	    bc.put(k(i), "some value or other").unwrap();
+	}
+	bc.put(k(0), "another value").unwrap();
+	assert!(!bc.needs_merge());
+	bc.set_merge_policy(MergePolicy { debt: None, file_dead_ratio: Some(0.6), dead_bytes: None });
+	assert!(!bc.needs_merge());				// A quarter of the first datafile is dead
+	bc.put(k(1), "another value").unwrap();
+	bc.put(k(2), "another value").unwrap();
+	assert!(bc.needs_merge());
+	bc.set_merge_policy(MergePolicy { debt: None, file_dead_ratio: None, dead_bytes: Some(1 << 30) });
+	assert!(!bc.needs_merge());
+	bc.set_merge_policy(MergePolicy { debt: None, file_dead_ratio: None, dead_bytes: Some(bc.stats().unwrap().dead_bytes) });
+	assert!(bc.needs_merge());
+	bc.merge().unwrap();
+	bc.set_merge_policy(MergePolicy { debt: None, file_dead_ratio: Some(0.6), dead_bytes: None });
+	assert!(!bc.needs_merge());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_merge_temporary_files() {
+	let testdir = test_setup("test_merge_temporary_files/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	bc.set_max_datafile_size(Some(300));
+	for i in 0..20 {
+	    bc.put(k(i), "some value or other").unwrap();
+	}
+	for i in 0..10 {
+	    bc.delete(k(i)).unwrap();
+	}
+	bc.merge().unwrap();
+	let names = || std::fs::read_dir(&testdir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect::<Vec<String>>();
+	assert!(!names().iter().any(|name| name.ends_with(".merge.tmp")));
+	drop(bc);
+	std::fs::write(format!("{}/99.data.merge.tmp", testdir), b"half a merge").unwrap();	// As a crash part way through leaves it
+	std::fs::write(format!("{}/99.hints.merge.tmp", testdir), b"").unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(!names().iter().any(|name| name.ends_with(".merge.tmp")));
+	assert_eq!(bc.len(), 10);
+	assert_eq!(bc.get(k(15)).unwrap().as_deref(), Some("some value or other".as_bytes()));
+	assert_eq!(bc.get(k(5)).unwrap(), None);
+	assert_eq!(bc.hints_rejected(), 0);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_tombstone_retention() {
+	let testdir = test_setup("test_tombstone_retention/");
+	let mut bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..4 {
+	    bc.put(k(i), "some value or other").unwrap();
+	}
+	bc.delete(k(0)).unwrap();
+	bc.delete(k(1)).unwrap();
+	bc.put(k(1), "back again").unwrap();
+	bc.merge().unwrap();
+	assert_eq!(bc.stats().unwrap().dead_bytes, 0);		// Every tombstone went
+	bc.delete(k(2)).unwrap();
+	bc.set_tombstone_retention(Some(Duration::from_secs(3600)));
+	bc.merge().unwrap();
+	let stats = bc.stats().unwrap();
+	assert_eq!(stats.dead_bytes_per_datafile.len(), 1);	// Only k(2)'s, k(0)'s was gone already
+	assert!(stats.dead_bytes > 0);
+	drop(bc);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(2)).unwrap(), None);
+	assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("back again".as_bytes()));
+	assert_eq!(bc.len(), 2);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_key_metadata() {
+	let testdir = test_setup("test_key_metadata/");
+	let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(1), "value").unwrap();
+	bc.put_with_ttl(k(2), "fleeting", Duration::from_secs(3600)).unwrap();
+	let one = bc.key_metadata(k(1)).unwrap().unwrap();
+	assert!(one.timestamp >= before);
+	assert_eq!(one.expires, 0);
+	assert_eq!(one.value_size, 5);
+	let two = bc.key_metadata(k(2)).unwrap().unwrap();
+	assert!(two.expires > two.timestamp);
+	assert_eq!(bc.key_metadata(k(3)).unwrap(), None);
+	bc.merge().unwrap();
+	assert_eq!(bc.key_metadata(k(1)).unwrap().unwrap().timestamp, one.timestamp);	// Moved, but still the same write
+	bc.shutdown().unwrap();
+	drop(bc);
+	let bc = Bitcask::new(&testdir).expect("REASON");		// From the hints this time
+	assert_eq!(bc.key_metadata(k(1)).unwrap().unwrap().timestamp, one.timestamp);
+	assert_eq!(bc.key_metadata(k(2)).unwrap().unwrap().timestamp, two.timestamp);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_datafile_header() {
+	let testdir = test_setup("test_datafile_header/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "value").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	let path = format!("{}/1.data", testdir);
+	let good = std::fs::read(&path).unwrap();
+	assert_eq!(&good[0..4], b"BCDF");
+	assert_eq!(&good[12..16], &0u32.to_le_bytes());		// Not encrypted
+	let reopen = |bytes: &[u8]| {
+	    std::fs::write(&path, bytes).unwrap();
+	    let _ = std::fs::remove_file(format!("{}/1.hints", testdir));
+	    Bitcask::new(&testdir).map(|_| ())
+	};
+	let mut flagged = good.clone();
+	flagged[12] = 0x80;
+	assert!(matches!(reopen(&flagged), Err(BitcaskError::InvalidFormat { reason, .. }) if reason.contains("flags")));
+	assert!(matches!(reopen(b"not a datafile at all, whatever it is"), Err(BitcaskError::InvalidFormat { file, .. }) if file == "1.data"));
+	assert!(reopen(&good).is_ok());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_migrate() {
+	let testdir = test_setup("test_migrate/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "one").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(2), "two").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	let version = Bitcask::format_descriptor().version;
+	assert_eq!(Bitcask::migrate(&testdir, version).unwrap(), 0);		// Nothing to do
+	assert!(matches!(Bitcask::migrate(&testdir, version + 1), Err(BitcaskError::InvalidArgument(_))));
+	// Make 1.data look as version 9 wrote it, with hints in the layout from before they had a header
+	let path = format!("{}/1.data", testdir);
+	let mut data = std::fs::read(&path).unwrap();
+	data[4..8].copy_from_slice(&9u32.to_le_bytes());
+	std::fs::write(&path, &data).unwrap();
+	std::fs::remove_file(format!("{}/1.hints", testdir)).unwrap();
+	std::fs::write(format!("{}/1.data.migrate.tmp", testdir), b"from a crash").unwrap();
+	{
+	    let _bc = Bitcask::new(&testdir).expect("REASON");
+	    assert!(matches!(Bitcask::migrate(&testdir, version), Err(BitcaskError::Locked(_))));
+	}
+	assert_eq!(Bitcask::migrate(&testdir, version).unwrap(), 1);
+	let migrated = std::fs::read(&path).unwrap();
+	assert_eq!(&migrated[4..8], &version.to_le_bytes());
+	assert_eq!(&migrated[16..], &data[16..]);				// The records themselves are untouched
+	assert!(std::fs::read(format!("{}/1.hints", testdir)).unwrap().starts_with(b"BCHT"));
+	assert!(!std::path::Path::new(&format!("{}/1.data.migrate.tmp", testdir)).exists());
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("one".as_bytes()));
+	assert_eq!(bc.get(k(2)).unwrap().as_deref(), Some("two".as_bytes()));
+	assert_eq!(bc.hints_rejected(), 0);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_verify() {
+	let testdir = test_setup("test_verify/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "one").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(2), "two").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(3), "three").unwrap();
+	    bc.wait_for_background();				// For the hints of the rotated datafiles
+	    let report = bc.verify().unwrap();
+	    assert!(report.is_ok(), "{:?}", report.issues);
+	    assert_eq!((report.datafiles, report.hintsfiles, report.records), (3, 2, 3));
+	    bc.shutdown().unwrap();
+	}
+	// Spoil a value in 1.data, whose hints still get it opened, and re-key the hint in 2.hints
+	let patch = |name: &str, from: &[u8], to: &[u8]| {
+	    let path = format!("{}/{}", testdir, name);
+	    let mut buf = std::fs::read(&path).unwrap();
+	    let at = buf.windows(from.len()).rposition(|window| window == from).unwrap();
+	    buf[at..at + to.len()].copy_from_slice(to);
+	    std::fs::write(&path, &buf).unwrap();
+	    at as i64
+	};
+	patch("1.data", b"one", b"xne");
+	let hint = patch("2.hints", &k(2), &k(99)) - 36;
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let report = bc.verify().unwrap();
+	assert_eq!(report.records, 2);
+	let found: Vec<(&str, i64, VerifyIssueKind)> = report.issues.iter().map(|issue| (issue.file.as_str(), issue.offset, issue.kind)).collect();
+	assert_eq!(found, vec![("1.data", 16, VerifyIssueKind::Checksum), ("2.hints", hint, VerifyIssueKind::Hint)]);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_repair() {
+	let testdir = test_setup("test_repair/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "one").unwrap();
+	    bc.put(k(2), "two").unwrap();
+	    bc.put(k(3), "three").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(4), "four").unwrap();
+	    bc.rotate().unwrap();
+	    bc.put(k(5), "five").unwrap();
+	    bc.shutdown().unwrap();
+	}
+	// A bad sector in the middle of 1.data, and 2.data's header gone
+	let path = |name: &str| format!("{}/{}", testdir, name);
+	let mut data = std::fs::read(path("1.data")).unwrap();
+	let at = data.windows(3).position(|window| window == b"two").unwrap();
+	data[at] = b'x';
+	std::fs::write(path("1.data"), &data).unwrap();
+	let lost = std::fs::metadata(path("2.data")).unwrap().len();
+	let mut header = std::fs::read(path("2.data")).unwrap();
+	header[0..4].copy_from_slice(b"XXXX");
+	std::fs::write(path("2.data"), &header).unwrap();
+	std::fs::remove_file(path("1.hints")).unwrap();
+	assert!(Bitcask::new(&testdir).is_err());
+	let report = Bitcask::repair(&testdir).unwrap();
+	assert_eq!(report.datafiles, 2);
+	assert_eq!(report.sidecars, vec!["1.data.corrupt".to_string(), "2.data.corrupt".to_string()]);
+	let cut = data.len() as u64 - std::fs::metadata(path("1.data")).unwrap().len();
+	assert!(cut > 0);
+	assert_eq!(report.corrupt_bytes, cut + lost);
+	assert_eq!(std::fs::metadata(path("1.data.corrupt")).unwrap().len(), 16 + cut);
+	assert!(std::path::Path::new(&path("1.hints")).exists());
+	assert!(!std::path::Path::new(&path("2.data")).exists() && !std::path::Path::new(&path("2.hints")).exists());
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("one".as_bytes()));
+	    assert_eq!(bc.get(k(2)).unwrap(), None);
+	    assert_eq!(bc.get(k(3)).unwrap().as_deref(), Some("three".as_bytes()));
+	    assert_eq!(bc.get(k(4)).unwrap(), None);
+	    assert_eq!(bc.get(k(5)).unwrap().as_deref(), Some("five".as_bytes()));
+	    assert!(bc.verify().unwrap().is_ok());
+	    bc.shutdown().unwrap();
+	}
+	let report = Bitcask::repair(&testdir).unwrap();			// Nothing more to do
+	assert_eq!((report.datafiles, report.corrupt_bytes), (0, 0));
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_parallel_hints_loading() {
+	let testdir = test_setup("test_parallel_hints_loading/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for file in 0..20 {
+		for i in 0..5 {
+		    bc.put(k(i), format!("{}-{}", i, file)).unwrap();
+		}
+		if file == 15 {
+		    bc.delete(k(3)).unwrap();
+		}
+		bc.rotate().unwrap();
+	    }
+	    bc.delete(k(4)).unwrap();
+	    bc.rotate().unwrap();
+	    bc.shutdown().unwrap();
+	}
+	std::fs::write(format!("{}/10.hints", testdir), b"BCHT").unwrap();	// Replayed instead, in its place
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..4 {
+	    assert_eq!(bc.get(k(i)).unwrap(), Some(format!("{}-19", i).into_bytes()));
+	}
+	assert_eq!(bc.get(k(4)).unwrap(), None);
+	assert_eq!(bc.len(), 4);
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_parallel_recovery_scan() {
+	let testdir = test_setup("test_parallel_recovery_scan/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    for file in 0..16 {
+		for i in 0..8 {
+		    bc.put(k(i), format!("{}-{}", i, file)).unwrap();
+		}
+		bc.delete(k(file % 8)).unwrap();
+		bc.rotate().unwrap();
+	    }
+	    bc.shutdown().unwrap();
+	}
+	for entry in std::fs::read_dir(&testdir).unwrap() {
+	    let path = entry.unwrap().path();
+	    if path.extension().is_some_and(|ext| ext == "hints") {
+		std::fs::remove_file(path).unwrap();
+	    }
+	}
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..7 {
+	    assert_eq!(bc.get(k(i)).unwrap(), Some(format!("{}-15", i).into_bytes()));
+	}
+	assert_eq!(bc.get(k(7)).unwrap(), None);			// Deleted last, in the newest datafile
+	bc.put(k(7), "seven").unwrap();
+	assert_eq!(bc.get_with_metadata(k(7)).unwrap().unwrap().1, 16 * 9 + 1);	// Numbering carries on from the scans
+	assert!(std::path::Path::new(&format!("{}/8.hints", testdir)).exists());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    fn test_keydir_snapshot() {
+	let testdir = test_setup("test_keydir_snapshot/");
+	let path = |name: &str| format!("{}/{}", testdir, name);
+	{
+	    let mut bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.set_keydir_snapshot_interval(Some(Duration::from_secs(3600)));
+	    bc.put(k(1), "one").unwrap();
+	    bc.put(k(2), "two").unwrap();
+	    bc.rotate().unwrap();				// Never saved before, so it's due
+	    assert!(std::path::Path::new(&path("keydir.snapshot")).exists());
+	    bc.put(k(3), "three").unwrap();
+	    bc.rotate().unwrap();				// Not due again for an hour
+	    bc.put(k(4), "four").unwrap();
+	    bc.crash();						// No shutdown
+	}
+	// One that doesn't survive its checksum is no use, everything's read as usual
+	let mut snapshot = std::fs::read(path("keydir.snapshot")).unwrap();
+	let last = snapshot.len() - 1;
+	snapshot[last] ^= 0xff;
+	std::fs::write(path("keydir.snapshot"), &snapshot).unwrap();
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(bc.len(), 4);
+	    bc.save_keydir_snapshot().unwrap();
+	    bc.put(k(5), "five").unwrap();
+	    bc.crash();
+	}
+	// What the snapshot covers isn't read at all, not even a damaged record with its hints gone
+	let good = std::fs::read(path("1.data")).unwrap();
+	let mut data = good.clone();
+	let at = data.windows(3).position(|window| window == b"one").unwrap();
+	data[at] = b'x';
+	std::fs::write(path("1.data"), &data).unwrap();
+	std::fs::remove_file(path("1.hints")).unwrap();
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    assert_eq!(bc.len(), 5);
+	    assert!(bc.get(k(1)).is_err());
+	    for (i, value) in [(2, "two"), (3, "three"), (4, "four"), (5, "five")] {
+		assert_eq!(bc.get(k(i)).unwrap().as_deref(), Some(value.as_bytes()));
+	    }
+	}
+	std::fs::write(path("1.data"), &good).unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let (_, seq) = bc.get_with_metadata(k(5)).unwrap().unwrap();
+	bc.put(k(6), "six").unwrap();
+	bc.truncate_to_sequence(seq).unwrap();			// Which the snapshot wouldn't notice, so it goes
+	assert!(!std::path::Path::new(&path("keydir.snapshot")).exists());
+	test_teardown(&testdir);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mmap", target_os = "linux"))]
+    fn test_disk_index() {
+	let testdir = test_setup("test_disk_index/");
+	let mut options = BitcaskOptions::new(&testdir);
+	options.disk_index(true).keymap_shards(2);
+	let bc = Bitcask::open_with(&options).unwrap();
+	for i in 0..5000 {						// Enough that the tables are rebuilt a few times
+	    bc.put(k(i), format!("value {}", i)).unwrap();
+	}
+	for i in (0..5000).step_by(3) {
+	    bc.delete(k(i)).unwrap();
+	}
+	bc.put(k(1), "rewritten").unwrap();
+	assert_eq!(bc.get(k(1)).unwrap().unwrap(), b"rewritten");
+	assert_eq!(bc.get(k(3)).unwrap(), None);
+	assert_eq!(bc.get(k(4999)).unwrap().unwrap(), b"value 4999");
+	assert_eq!(bc.list_keys().len(), 3333);
+	bc.merge().unwrap();
+	assert_eq!(bc.get(k(4)).unwrap().unwrap(), b"value 4");
+	bc.shutdown().unwrap();
+	// The index files are gone already, and built afresh from the hints
+	let names: Vec<String> = std::fs::read_dir(&testdir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+	assert!(!names.iter().any(|name| name.starts_with("keydir.")), "{:?}", names);
+	let mut bc = Bitcask::open_with(&options).unwrap();
+	assert_eq!(bc.list_keys().len(), 3333);
+	assert_eq!(bc.get(k(1)).unwrap().unwrap(), b"rewritten");
+	assert_eq!(bc.get(k(6)).unwrap(), None);
+	bc.set_ordered_index(true);					// Refused, it's still the one on disk
+	assert_eq!(bc.range(k(10)..=k(10)).count(), 1);
+	let (_, seq) = bc.get_with_metadata(k(1)).unwrap().unwrap();
+	bc.put(k(3), "back").unwrap();
+	bc.truncate_to_sequence(seq).unwrap();				// The keymap is rebuilt on disk too
+	assert_eq!(bc.get(k(3)).unwrap(), None);
+	assert_eq!(bc.list_keys().len(), 3333);
+	bc.shutdown().unwrap();
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_drop_shuts_down() {
+	let testdir = test_setup("test_drop_shuts_down/");
+	{
+	    let bc = Bitcask::new(&testdir).expect("REASON");
+	    bc.put(k(1), "kept").unwrap();
+	}							// Out of scope, never shut down by hand
+	assert!(std::path::Path::new(&format!("{}/1.hints", testdir)).exists());
+	let bc = Bitcask::new(&testdir).expect("REASON");		// The lock was let go
+	assert_eq!(bc.hints_rejected(), 0);
+	assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("kept".as_bytes()));
+	bc.shutdown().unwrap();
+	drop(bc);						// Already shut down, so it does nothing
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(2), "lost").unwrap();
+	bc.crash();
+	assert!(!std::path::Path::new(&format!("{}/3.hints", testdir)).exists());
+	assert_eq!(Bitcask::new(&testdir).unwrap().get(k(2)).unwrap().as_deref(), Some("lost".as_bytes()));	// From the datafile itself
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_clean_shutdown() {
+	let testdir = test_setup("test_clean_shutdown/");
+	let clean = format!("{}/clean", testdir);
+	let dead = |stats: Stats| stats.dead_bytes_per_datafile.into_iter().filter(|(_, dead)| *dead > 0).collect::<Vec<(i32, u64)>>();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	for i in 0..20 {
+	    bc.put(k(i), "first").unwrap();
+	}
+	bc.rotate().unwrap();
+	for i in 0..10 {
+	    bc.put(k(i), "second").unwrap();
+	}
+	bc.delete(k(15)).unwrap();
+	let (_, seq) = bc.get_with_metadata(k(9)).unwrap().unwrap();
+	let before = bc.stats().unwrap();
+	bc.shutdown().unwrap();
+	assert!(std::path::Path::new(&clean).exists());
+	assert!(matches!(bc.put(k(1), "too late").unwrap_err(), BitcaskError::ShutDown));
+	assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("second".as_bytes()));	// Reads still work
+	drop(bc);
+	// Taken from the note, which the writer removes straight away, and the same as counting them up again
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert!(!std::path::Path::new(&clean).exists());
+	assert_eq!(dead(bc.stats().unwrap()), dead(before));
+	bc.put(k(20), "new").unwrap();
+	assert!(bc.get_with_metadata(k(20)).unwrap().unwrap().1 > seq);
+	bc.shutdown().unwrap();
+	drop(bc);
+	std::fs::remove_file(&clean).unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	let expected = bc.stats().unwrap();
+	bc.shutdown().unwrap();
+	drop(bc);
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(dead(bc.stats().unwrap()), dead(expected));
+	bc.shutdown().unwrap();
+	drop(bc);
+	// A datafile that's changed since means the note is wrong, and the torn write is found as usual
+	let newest = std::fs::read_dir(&testdir).unwrap()
+	    .filter_map(|entry| entry.unwrap().file_name().into_string().unwrap().strip_suffix(".data").and_then(|id| id.parse::<i32>().ok()))
+	    .max().unwrap();
+	let name = format!("{}/{}.data", testdir, newest);
+	let mut data = std::fs::read(&name).unwrap();
+	let size = data.len();
+	data.extend_from_slice(&[7; 10]);
+	std::fs::write(&name, &data).unwrap();
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	assert_eq!(std::fs::metadata(&name).unwrap().len(), size as u64);
+	assert_eq!(bc.get(k(20)).unwrap().as_deref(), Some("new".as_bytes()));
+	assert_eq!(bc.len(), 20);
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_rotation_interval() {
+	let testdir = test_setup("test_rotation_interval/");
+	let mut options = BitcaskOptions::new(&testdir);
+	options.rotation_interval(Some(Duration::from_millis(100)));
+	let bc = Bitcask::open_with(&options).unwrap();
+	bc.put(k(1), "one").unwrap();
+	bc.put(k(2), "two").unwrap();
+	let first = bc.active_file_id();
+	std::thread::sleep(Duration::from_millis(150));
+	assert_eq!(bc.active_file_id(), first);			// Nothing rotates until there's a write
+	bc.put(k(3), "three").unwrap();				// Which goes to the new datafile
+	assert_eq!(bc.active_file_id(), first + 1);
+	bc.wait_for_background();
+	assert!(std::path::Path::new(&format!("{}/{}.hints", testdir, first)).exists());
+	bc.put(k(4), "four").unwrap();
+	assert_eq!(bc.active_file_id(), first + 1);		// Its interval starts over
+	assert_eq!(bc.get(k(1)).unwrap().as_deref(), Some("one".as_bytes()));
+	assert_eq!(bc.get(k(3)).unwrap().as_deref(), Some("three".as_bytes()));
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_retention_policy() {
+	with_each_backend("test_retention_policy/", |open| {
+	    let archive = MemoryStorage::new();
+	    let mut bc = open();
+	    bc.set_retention_policy(Some(RetentionPolicy { max_datafiles: Some(1), max_age: None }), Some(Box::new(archive.clone())));
+	    for key in 0..10 {
+		bc.put(k(key), "a").unwrap();
+	    }
+	    bc.rotate().unwrap();
+	    for key in 0..9 {
+		bc.put(k(key), "b").unwrap();
+	    }
+	    bc.rotate().unwrap();
+	    bc.put(k(20), "c").unwrap();
+	    bc.rotate().unwrap();
+	    assert_eq!(bc.apply_retention().unwrap(), 2);
+	    assert_eq!(bc.apply_retention().unwrap(), 0);
+	    assert_eq!(archive.list().unwrap().iter().filter(|name| name.ends_with(".data")).count(), 2);
+	    let check = |bc: &Bitcask| {
+		assert_eq!(bc.list_keys().len(), 11);
+		assert_eq!(bc.get(k(0)).unwrap(), Some(b"b".to_vec()));
+		assert_eq!(bc.get(k(9)).unwrap(), Some(b"a".to_vec()));	// Carried forward, not lost
+		assert_eq!(bc.get(k(20)).unwrap(), Some(b"c".to_vec()));
+	    };
+	    check(&bc);
+	    assert_eq!(bc.stats().unwrap().datafiles, 2);		// The one sealed datafile kept, and the current one
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    check(&open());
+	});
+    }
+
+
+    #[test]
+    fn test_disk_quota() {
+	let testdir = test_setup("test_disk_quota/");
+	let bc = Bitcask::open_with(BitcaskOptions::new(&testdir).disk_quota(Some(8192))).unwrap();
+	let value = "v".repeat(1000);
+	for _ in 0..20 {
+	    bc.put(k(0), &value).unwrap();			// Overwrites, which merges can reclaim
+	}
+	let stats = bc.stats().unwrap();
+	assert_eq!(stats.disk_quota, Some(8192));
+	assert!(stats.disk_bytes <= 8192);
+	let mut key = 1;
+	let err = loop {
+	    match bc.put(k(key), &value) {
+		Ok(_) => key += 1,
+		Err(e) => break e,
+	    }
+	};
+	assert!(matches!(err, BitcaskError::QuotaExceeded { quota: 8192, .. }));
+	assert!(key > 1 && key < 8);
+	assert!(bc.stats().unwrap().disk_bytes <= 8192);
+	assert!(bc.delete(k(1)).unwrap());			// Deletes go through, and make room
+	bc.put(k(key), &value).unwrap();
+	assert_eq!(bc.get(k(0)).unwrap(), Some(value.clone().into_bytes()));
+	assert_eq!(bc.get(k(1)).unwrap(), None);
+	assert_eq!(bc.get(k(key)).unwrap(), Some(value.into_bytes()));
+	assert!(bc.stats().unwrap().disk_bytes <= 8192);
+	drop(bc);
+	test_teardown(&testdir);
+    }
+
+
+    #[test]
+    fn test_blob_threshold() {
+	with_each_backend("test_blob_threshold/", |open| {
+	    let mut bc = open();
+	    bc.set_blob_threshold(Some(1000));
+	    let big = |key: i32, round: usize| format!("{}:{}:", key, round).repeat(400);
+	    for key in 0..10 {
+		bc.put(k(key), big(key, 0)).unwrap();
+		bc.put(k(key + 100), "small").unwrap();
+	    }
+	    let stats = bc.stats().unwrap();
+	    assert!(stats.blob_bytes >= 10 * 1600);
+	    assert!(stats.disk_bytes < 2000);			// Just the pointers
+	    let check = |bc: &Bitcask, round: usize| {
+		let mut buf = Vec::new();
+		for key in 0..10 {
+		    assert_eq!(bc.get(k(key)).unwrap(), Some(big(key, round).into_bytes()));
+		    assert_eq!(bc.get_into(k(key), &mut buf).unwrap(), Some(big(key, round).len()));
+		    assert_eq!(buf, big(key, round).as_bytes());
+		    assert_eq!(bc.get(k(key + 100)).unwrap(), Some(b"small".to_vec()));
+		}
+		assert_eq!(bc.multi_get(&[k(3), k(103)]).unwrap(), vec![Some(big(3, round).into_bytes()), Some(b"small".to_vec())]);
+		assert_eq!(bc.entries().filter(|kv| kv.as_ref().unwrap().1 == big(4, round).as_bytes()).count(), 1);
+	    };
+	    check(&bc, 0);
+	    bc.merge().unwrap();
+	    assert_eq!(bc.stats().unwrap().blob_bytes, stats.blob_bytes);	// The values stay put
+	    check(&bc, 0);
+	    for key in 0..10 {
+		bc.put(k(key), big(key, 1)).unwrap();
+	    }
+	    bc.merge().unwrap();
+	    let after = bc.stats().unwrap();
+	    assert!(after.blob_bytes < stats.blob_bytes * 3 / 2);	// The first round's blob file is gone
+	    check(&bc, 1);
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    check(&open(), 1);
+	});
+	let storage = MemoryStorage::new();
+	let mut bc = Bitcask::with_storage_encrypted(Box::new(storage.clone()), EncryptionConfig::new([3u8; 32])).unwrap();
+	bc.set_blob_threshold(Some(100));
+	bc.put(k(1), "secret".repeat(100)).unwrap();
+	let blob = storage.open("1.blob").unwrap();
+	let mut bytes = vec![0u8; blob.size().unwrap() as usize];
+	blob.read_exact_at(0, &mut bytes).unwrap();
+	assert!(!bytes.windows(6).any(|w| w == b"secret"));	// Sealed like the records
+	assert_eq!(bc.get(k(1)).unwrap(), Some("secret".repeat(100).into_bytes()));
+    }
+
+
+    #[test]
+    fn test_put_reader_get_writer() {
+	let value = format!("a{}", "é".repeat(100_000));	// Characters split across the chunks
+	with_each_backend("test_put_reader_get_writer/", |open| {
+	    let bc = open();
+	    bc.put(k(1), "old").unwrap();
+	    assert!(bc.put_reader(k(1), value.as_bytes(), value.len() as u64).unwrap());
+	    let check = |bc: &Bitcask| {
+		let mut out = Vec::new();
+		assert_eq!(bc.get_writer(k(1), &mut out).unwrap(), Some(value.len() as u64));
+		assert_eq!(out, value.as_bytes());
+		assert_eq!(bc.get(k(1)).unwrap(), Some(value.clone().into_bytes()));
+		assert_eq!(bc.get_writer(k(2), &mut out).unwrap(), None);
+	    };
+	    check(&bc);
+	    assert!(bc.put_reader(k(1), &b"short"[..], 10).is_err());	// Runs out early
+	    check(&bc);
+	    assert!(bc.put_reader(k(4), &[b'x', 0xff][..], 2).unwrap());	// Needn't be text
+	    assert_eq!(bc.get(k(4)).unwrap(), Some(vec![b'x', 0xff]));
+	    bc.put(k(3), "inline").unwrap();
+	    let mut out = Vec::new();
+	    assert_eq!(bc.get_writer(k(3), &mut out).unwrap(), Some(6));
+	    assert_eq!(out, b"inline");
+	    bc.merge().unwrap();
+	    check(&bc);
+	    bc.shutdown().unwrap();
+	    drop(bc);
+	    check(&open());
+	});
+	let storage = MemoryStorage::new();
+	let bc = Bitcask::with_storage_encrypted(Box::new(storage.clone()), EncryptionConfig::new([3u8; 32])).unwrap();
+	let secret = "secret".repeat(30_000);
+	bc.put_reader(k(1), secret.as_bytes(), secret.len() as u64).unwrap();
+	let blob = storage.open("1.blob").unwrap();
+	let mut bytes = vec![0u8; blob.size().unwrap() as usize];
+	blob.read_exact_at(0, &mut bytes).unwrap();
+	assert!(!bytes.windows(6).any(|w| w == b"secret"));	// Each chunk sealed
+	let mut out = Vec::new();
+	assert_eq!(bc.get_writer(k(1), &mut out).unwrap(), Some(secret.len() as u64));
+	assert_eq!(out, secret.as_bytes());
+    }
+
+
+    #[test]
+    fn test_get_range() {
+	let value: String = (0..200_000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+	let check = |bc: &Bitcask, key: i32| {
+	    assert_eq!(bc.get_range(k(key), 0, 10).unwrap(), Some(value.as_bytes()[..10].to_vec()));
+	    assert_eq!(bc.get_range(k(key), 65_530, 20).unwrap(), Some(value.as_bytes()[65_530..65_550].to_vec()));	// Across a chunk
+	    assert_eq!(bc.get_range(k(key), 199_990, 100).unwrap(), Some(value.as_bytes()[199_990..].to_vec()));
+	    assert_eq!(bc.get_range(k(key), 300_000, 10).unwrap(), Some(Vec::new()));
+	};
+	with_each_backend("test_get_range/", |open| {
+	    let mut bc = open();
+	    bc.put(k(1), &value).unwrap();
+	    bc.put_reader(k(2), value.as_bytes(), value.len() as u64).unwrap();
+	    bc.set_blob_threshold(Some(1000));
+	    bc.put(k(3), &value).unwrap();
+	    bc.set_compression(Compression::Lz4);
+	    bc.set_blob_threshold(None);
+	    bc.put(k(4), &value).unwrap();
+	    for key in 1..=4 {
+		check(&bc, key);
+	    }
+	    assert_eq!(bc.get_range(k(5), 0, 10).unwrap(), None);
+	});
+	let bc = Bitcask::with_storage_encrypted(Box::new(MemoryStorage::new()), EncryptionConfig::new([3u8; 32])).unwrap();
+	bc.put(k(1), &value).unwrap();
+	bc.put_reader(k(2), value.as_bytes(), value.len() as u64).unwrap();
+	check(&bc, 1);
+	check(&bc, 2);
+    }
+
+
+    #[test]
+    fn test_format_descriptor() {
+	let testdir = test_setup("test_format_descriptor/");
+	let bc = Bitcask::new(&testdir).expect("REASON");
+	bc.put(k(7), "hello").unwrap();
+	bc.shutdown().unwrap();
+	let desc = Bitcask::format_descriptor();
+	let bytes = std::fs::read(format!("{}/1.data", testdir)).unwrap();
+	let rec = &bytes[desc.file_header_size..];
+	let bytes_of = |name: &str| {
+	    let f = desc.record_header.iter().find(|f| f.name == name).unwrap();
+	    assert_eq!(desc.endianness, "little");
+	    (f.kind, &rec[f.offset..f.offset + f.size])
+	};
+	let field = |name: &str| {
+	    let (kind, bytes) = bytes_of(name);
+	    assert_eq!(kind, if name == "crc" { "u32" } else { "i32" });
+	    i32::from_le_bytes(bytes.try_into().unwrap())
+	};
+	let put = desc.record_types.iter().find(|(name, _)| *name == "PUT").unwrap().1;
+	assert_eq!(field("key_size"), 4);
+	assert_eq!(field("op"), put);
+	assert_eq!(field("value_size"), 5);
+	assert_eq!(bytes_of("seq"), ("u64", &1u64.to_le_bytes()[..]));
+	assert_eq!(bytes_of("timestamp").0, "u64");
+	assert_eq!(&rec[desc.record_header_size..desc.record_header_size + 4], k(7));
+	assert_eq!(&rec[desc.record_header_size + 4..desc.record_header_size + 9], b"hello");
+	assert_eq!(rec.len(), (desc.record_header_size + 9).div_ceil(desc.alignment) * desc.alignment);
+	assert_eq!(desc.checksum, "crc32");
+	let mut hasher = crc32fast::Hasher::new();
+	for name in desc.checksum_covers {
+	    match *name {
+		"key" => hasher.update(&k(7)),
+		"value" => hasher.update(b"hello"),
+		_ => hasher.update(bytes_of(name).1),
+	    }
+	}
+	assert_eq!(field("crc") as u32, hasher.finalize());
+	assert_eq!(&bytes[..desc.magic.len()], desc.magic);
+	assert!(desc.to_json().contains("\"version\": 12"));
+	test_teardown(&testdir);
+    }
+
+    //
+    // Run a test body once against a directory and once against an in-memory store.  The body is handed
+    // a function that opens the store, so calling it again after a shutdown reopens the same data.
+    //
+    fn with_each_backend(dirname: &str, body: impl Fn(&dyn Fn() -> Box<Bitcask>)) {
+	let testdir = test_setup(dirname);
+	body(&|| Bitcask::new(&testdir).expect("REASON"));
+	test_teardown(&testdir);
+	let storage = MemoryStorage::new();
+	body(&|| Bitcask::with_storage(Box::new(storage.clone())).expect("REASON"));
+    }
+
+    //
+    // Count the allocations made by each thread, so tests can see what a code path costs the allocator.
+    //
+    struct CountingAlloc;
+    thread_local! {
+	static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+    unsafe impl GlobalAlloc for CountingAlloc {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+	    let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+	    System.alloc(layout)
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+	    System.dealloc(ptr, layout)
+	}
+    }
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    //
+    // A MemoryStorage that counts every read and sync made on any of its files, and can make the syncs
+    // take as long as a real disk's.
+    //
+    #[derive(Clone)]
+    struct CountingStorage {
+	inner: MemoryStorage,
+	reads: Arc<AtomicUsize>,
+	syncs: Arc<AtomicUsize>,
+	sync_delay: Duration,
+    }
+    impl CountingStorage {
+	fn new() -> CountingStorage {
+	    CountingStorage::with_sync_delay(Duration::ZERO)
+	}
+	fn with_sync_delay(sync_delay: Duration) -> CountingStorage {
+	    CountingStorage { inner: MemoryStorage::new(), reads: Arc::new(AtomicUsize::new(0)), syncs: Arc::new(AtomicUsize::new(0)), sync_delay }
+	}
+	fn wrap(&self, inner: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+	    Box::new(CountingFile { inner, reads: self.reads.clone(), syncs: self.syncs.clone(), sync_delay: self.sync_delay })
+	}
+    }
+    impl Storage for CountingStorage {
+	fn create(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    Ok(self.wrap(self.inner.create(name)?))
+	}
+	fn open(&self, name: &str) -> Result<Box<dyn StorageFile>, io::Error> {
+	    Ok(self.wrap(self.inner.open(name)?))
+	}
+	fn remove(&self, name: &str) -> Result<bool, io::Error> {
+	    self.inner.remove(name)
+	}
+	fn list(&self) -> Result<Vec<String>, io::Error> {
+	    self.inner.list()
+	}
+    }
+    struct CountingFile {
+	inner: Box<dyn StorageFile>,
+	reads: Arc<AtomicUsize>,
+	syncs: Arc<AtomicUsize>,
+	sync_delay: Duration,
+    }
+    impl StorageFile for CountingFile {
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+	    self.reads.fetch_add(1, Ordering::SeqCst);
+	    self.inner.read_at(offset, buf)
+	}
+	fn append(&self, buf: &[u8]) -> Result<u64, io::Error> {
+	    self.inner.append(buf)
+	}
+	fn sync(&self) -> Result<bool, io::Error> {
+	    self.syncs.fetch_add(1, Ordering::SeqCst);
+	    std::thread::sleep(self.sync_delay);
+	    self.inner.sync()
+	}
+	fn size(&self) -> Result<u64, io::Error> {
+	    self.inner.size()
+	}
+	fn truncate(&self, len: u64) -> Result<bool, io::Error> {
+	    self.inner.truncate(len)
+	}
+    }
+
+    //
+    // Most tests just want a few distinct keys, so they number them and use the bytes of the number.
+    //
+    fn k(key: i32) -> [u8; 4] {
+	key.to_le_bytes()
+    }
+
     //
     // Setup and teardown of tests, takes a unique directory name to
     // isolate ech test from the others since they all run in parallel.
+    // Setup returns the real directory name to hand to Bitcask::new().
     //
-    fn test_setup(dirname: &String) {
+    fn test_setup(dirname: &str) -> String {
 	let realdirname = "TeStDiR/".to_string() + dirname;
-	let _ = std::fs::create_dir_all(realdirname);
+	let _ = std::fs::remove_dir_all(&realdirname);
+	let _ = std::fs::create_dir_all(&realdirname);
+	realdirname
     }
-    fn test_teardown(dirname: &String) {
-	let realdirname = "TeStDiR/".to_string() + dirname;
+    fn test_teardown(realdirname: &str) {
 	let _ = std::fs::remove_dir_all(realdirname);
     }
 }
@@ -0,0 +1,57 @@
+//
+// Module that times the store's operations and reports each as a trace-level log record once it's done,
+// for finding where latency spikes come from.
+//
+// An OpSpan is started as an operation starts, picks up fields (key sizes, file IDs, byte counts) as they
+// become known, and logs itself, with how long it took, when it's dropped, which is whenever and however
+// the operation returns.  The records go through the log crate under the target "chroma_bitcask::trace",
+// so any logger sees them, and a tracing subscriber does too through the tracing-log bridge.  Without the
+// "trace" feature, or with trace logging switched off for the target, a span costs nothing at all.
+//
+pub mod trace {
+    #[cfg(feature = "trace")]
+    use std::time::Instant;
+
+    #[cfg(feature = "trace")]
+    pub const TRACE_TARGET: &str = "chroma_bitcask::trace";
+
+    // The operation's name, when it started, and the fields recorded so far.
+    #[cfg(feature = "trace")]
+    type OpenSpan = (&'static str, Instant, Vec<(&'static str, u64)>);
+
+    pub struct OpSpan {
+	#[cfg(feature = "trace")]
+	open: Option<OpenSpan>,				// None if nobody's listening
+    }
+    impl OpSpan {
+	#[inline]
+	pub fn start(op: &'static str) -> OpSpan {
+	    #[cfg(feature = "trace")]
+	    return OpSpan { open: log::log_enabled!(target: TRACE_TARGET, log::Level::Trace).then(|| (op, Instant::now(), Vec::new())) };
+	    #[cfg(not(feature = "trace"))]
+	    {
+		let _ = op;
+		OpSpan {}
+	    }
+	}
+
+	#[inline]
+	pub fn record(&mut self, field: &'static str, value: u64) {
+	    #[cfg(feature = "trace")]
+	    if let Some((_, _, fields)) = &mut self.open {
+		fields.push((field, value));
+	    }
+	    #[cfg(not(feature = "trace"))]
+	    let _ = (field, value);
+	}
+    }
+    #[cfg(feature = "trace")]
+    impl Drop for OpSpan {
+	fn drop(&mut self) {
+	    if let Some((op, started, fields)) = self.open.take() {
+		let fields: String = fields.iter().map(|(field, value)| format!(" {}={}", field, value)).collect();
+		log::trace!(target: TRACE_TARGET, "{}{} duration_us={}", op, fields, started.elapsed().as_micros());
+	    }
+	}
+    }
+}
@@ -0,0 +1,100 @@
+//
+// Module that puts and gets keys and values of the caller's own types, so nobody need hand roll the
+// encoding of every KV they keep.
+//
+// A TypedBitcask wraps a store, and serializes each key and value with serde on the way in and
+// deserializes it on the way out.  The encoding is bincode's standard one, so anything that derives
+// Serialize and Deserialize can be a key or a value.  Integers are variable length and little-endian in
+// it, so the keys of a TypedBitcask don't sort the way the numbers they hold do.  The raw store is still
+// there underneath, see raw(), for anything the typed calls don't cover.
+//
+pub mod typed {
+    use std::marker::PhantomData;
+    use std::time::Duration;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use crate::bitcask::bitcask::Bitcask;
+    use crate::error::error::BitcaskError;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, BitcaskError> {
+	bincode::serde::encode_to_vec(value, bincode::config::standard())
+	    .map_err(|e| BitcaskError::InvalidArgument(format!("can't encode the value: {}", e)))
+    }
+
+    //
+    // The T 'bytes' holds, all of them, or InvalidArgument if they aren't one.
+    //
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BitcaskError> {
+	match bincode::serde::decode_from_slice(bytes, bincode::config::standard()) {
+	    Ok((value, len)) if len == bytes.len() => Ok(value),
+	    Ok((_, len)) => Err(BitcaskError::InvalidArgument(format!("{} bytes left over after the value", bytes.len() - len))),
+	    Err(e) => Err(BitcaskError::InvalidArgument(format!("can't decode the value: {}", e))),
+	}
+    }
+
+    pub struct TypedBitcask<K, V> {
+	cask: Box<Bitcask>,
+	types: PhantomData<fn() -> (K, V)>,		// Neither is held, so neither need be Send or Sync
+    }
+    impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedBitcask<K, V> {
+	//
+	// Open or create the store in the directory 'dir', as Bitcask::new() does.
+	//
+	pub fn new(dir: &str) -> Result<TypedBitcask<K, V>, BitcaskError> {
+	    Ok(TypedBitcask::from_store(Bitcask::new(dir)?))
+	}
+
+	//
+	// Key and value an open store with K and V.  Nothing checks that the KVs it holds already are of them,
+	// those that aren't are an error when they're read.
+	//
+	pub fn from_store(cask: Box<Bitcask>) -> TypedBitcask<K, V> {
+	    TypedBitcask { cask, types: PhantomData }
+	}
+
+	pub fn put(&self, key: &K, value: &V) -> Result<bool, BitcaskError> {
+	    self.cask.put(encode(key)?, encode(value)?)
+	}
+
+	pub fn put_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<bool, BitcaskError> {
+	    self.cask.put_with_ttl(encode(key)?, encode(value)?, ttl)
+	}
+
+	pub fn get(&self, key: &K) -> Result<Option<V>, BitcaskError> {
+	    self.cask.get(encode(key)?)?.map(|value| decode(&value)).transpose()
+	}
+
+	pub fn delete(&self, key: &K) -> Result<bool, BitcaskError> {
+	    self.cask.delete(encode(key)?)
+	}
+
+	pub fn contains_key(&self, key: &K) -> Result<bool, BitcaskError> {
+	    Ok(self.cask.contains_key(encode(key)?))
+	}
+
+	//
+	// Every key in the store, in no particular order.  A key that isn't a K is an error.
+	//
+	pub fn keys(&self) -> Result<Vec<K>, BitcaskError> {
+	    self.cask.list_keys().iter().map(|key| decode(key)).collect()
+	}
+
+	//
+	// Every KV in the store, as Bitcask::entries() has them.
+	//
+	pub fn entries(&self) -> impl Iterator<Item = Result<(K, V), BitcaskError>> + '_ {
+	    self.cask.entries().map(|entry| {
+		let (key, value) = entry?;
+		Ok((decode(&key)?, decode(&value)?))
+	    })
+	}
+
+	pub fn raw(&self) -> &Bitcask {
+	    &self.cask
+	}
+
+	pub fn into_raw(self) -> Box<Bitcask> {
+	    self.cask
+	}
+    }
+}